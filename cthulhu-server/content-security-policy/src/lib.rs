@@ -1697,9 +1697,18 @@ impl HashAlgorithm {
             _ => None,
         }
     }
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha384 => "sha384",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
     pub fn apply(self, value: &str) -> String {
+        self.apply_bytes(value.as_bytes())
+    }
+    pub fn apply_bytes(self, bytes: &[u8]) -> String {
         use base64::Engine as _;
-        let bytes = value.as_bytes();
         let standard = base64::engine::general_purpose::STANDARD;
         match self {
             HashAlgorithm::Sha256 => standard.encode(sha2::Sha256::digest(bytes)),
@@ -1717,6 +1726,15 @@ pub struct HashFunction {
     // The spec defines a third member, options, but defines no values.
 }
 
+impl HashFunction {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
 /// https://www.w3.org/TR/SRI/#parse-metadata
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SubresourceIntegrityMetadata {
@@ -0,0 +1,48 @@
+-- 随程序发布的数据库已经是这个结构，从空数据库开始时才会建表
+-- 插件表
+CREATE TABLE IF NOT EXISTS `plugin` (
+	`id` TEXT PRIMARY KEY,
+	`name` TEXT NOT NULL,
+	`intro` TEXT NOT NULL,
+	`version` TEXT NOT NULL,
+	`path` TEXT NOT NULL ,
+	`logo_path` TEXT NOT NULL DEFAULT '',
+	`web_root` TEXT NOT NULL DEFAULT '',
+	`web_index` TEXT NOT NULL DEFAULT '',
+	`server_path` TEXT NOT NULL DEFAULT '',
+	`worker_path` TEXT NOT NULL DEFAULT '',
+	`context_paths` TEXT NOT NULL DEFAULT '',
+	`dynamic_links` TEXT NOT NULL DEFAULT '',
+	`matches` TEXT NOT NULL DEFAULT '',
+	`net_monitor` INTEGER NOT NULL DEFAULT 0,
+	`net_modify` INTEGER NOT NULL DEFAULT 0,
+	`enable` INTEGER NOT NULL DEFAULT 0,
+	`install_time` TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+
+-- 配置表
+CREATE TABLE IF NOT EXISTS `config` (
+	`id` INTEGER  PRIMARY KEY AUTOINCREMENT,
+	`key` VARCHAR(50) NOT NULL DEFAULT '',
+	`parent_id` TINYINT NOT NULL DEFAULT 0,
+	`label` VARCHAR(50) NOT NULL DEFAULT '',
+	`type` VARCHAR(5) NOT NULL, 
+	`value` TEXT NOT NULL DEFAULT '',
+	UNIQUE (`key`,`parent_id`)
+);
+
+
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(1, 'workspace', 0, '工作目录', 'str', '".\\workspace"'),
+	(2, 'port', 0, '工作端口', 'num', '3000'),
+	(3, 'systemProxy', 0, '设置为系统代理', 'bool', 'true'),
+	(4, 'blackList', 0, '域名黑名单', 'obj', ''),
+	(5, 'whiteList', 0, '域名白名单', 'obj', ''),
+	(6, 'enabled', 4, '是否启用', 'bool', 'false'),
+	(7, 'list', 4, '域名列表', 'list', ''),
+	(8, 'enabled', 5, '是否启用', 'bool', 'true'),
+	(9, 'list', 5, '域名列表', 'list', ''),
+	(10, 'certificate', 0, 'CA证书', 'obj', ''),
+	(11, 'key', 10, '私钥', 'str', '".\\ca\\cthulhu.key"'),
+	(12, 'cert', 10, '证书', 'str', '".\\ca\\cthulhu.cer"');
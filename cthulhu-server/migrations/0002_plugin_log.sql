@@ -0,0 +1,6 @@
+-- 插件日志的轮转配置
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(13, 'pluginLog', 0, '插件日志', 'obj', ''),
+	(14, 'maxSize', 13, '单个日志文件大小(MB)', 'num', '10'),
+	(15, 'maxFiles', 13, '保留的归档文件数', 'num', '10'),
+	(16, 'maxDays', 13, '日志保留天数', 'num', '7');
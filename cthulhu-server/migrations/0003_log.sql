@@ -0,0 +1,6 @@
+-- 运行日志的级别、格式和 OTLP 导出地址
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(17, 'log', 0, '运行日志', 'obj', ''),
+	(18, 'level', 17, '日志级别', 'str', '"error"'),
+	(19, 'format', 17, '日志格式(pretty/compact/json)', 'str', '"pretty"'),
+	(20, 'otlpEndpoint', 17, 'OTLP地址(为空不导出)', 'str', '""');
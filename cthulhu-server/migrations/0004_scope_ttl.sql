@@ -0,0 +1,3 @@
+-- 域的空闲超时
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(21, 'scopeTtl', 0, '域的空闲超时(秒)', 'num', '1800');
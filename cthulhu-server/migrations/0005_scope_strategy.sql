@@ -0,0 +1,7 @@
+-- 域的划分方式
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(22, 'scopeStrategy', 0, '域的划分方式', 'obj', ''),
+	(23, 'mode', 22, '方式(default/ip/cookie/header/plugin)', 'str', '"default"'),
+	(24, 'cookie', 22, 'cookie方式使用的cookie名', 'str', '"cthulhu_profile"'),
+	(25, 'header', 22, 'header方式使用的请求头', 'str', '"cthulhu-profile"'),
+	(26, 'plugin', 22, 'plugin方式使用的插件id(为空时使用域名对应的插件)', 'str', '""');
@@ -0,0 +1,4 @@
+-- 断点超时
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(27, 'breakpoint', 0, '断点', 'obj', ''),
+	(28, 'timeout', 27, '超时自动放行(秒)', 'num', '60');
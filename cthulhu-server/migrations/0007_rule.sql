@@ -0,0 +1,14 @@
+-- 调试规则表，本地映射、远程映射和头部改写
+CREATE TABLE IF NOT EXISTS `rule` (
+	`id` INTEGER PRIMARY KEY AUTOINCREMENT,
+	`kind` VARCHAR(10) NOT NULL,
+	`pattern` TEXT NOT NULL,
+	`regex` INTEGER NOT NULL DEFAULT 0,
+	`target` TEXT NOT NULL DEFAULT '',
+	`phase` VARCHAR(10) NOT NULL DEFAULT 'request',
+	`action` VARCHAR(10) NOT NULL DEFAULT '',
+	`name` TEXT NOT NULL DEFAULT '',
+	`value` TEXT NOT NULL DEFAULT '',
+	`enable` INTEGER NOT NULL DEFAULT 1,
+	`remark` TEXT NOT NULL DEFAULT ''
+);
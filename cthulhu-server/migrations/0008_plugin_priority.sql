@@ -0,0 +1,5 @@
+-- 旧的建表语句把 content_paths 写成了 context_paths
+ALTER TABLE `plugin` RENAME COLUMN `context_paths` TO `content_paths`;
+-- 注入顺序和每个脚本的注入时机
+ALTER TABLE `plugin` ADD COLUMN `content_scripts` TEXT NOT NULL DEFAULT '';
+ALTER TABLE `plugin` ADD COLUMN `priority` INTEGER NOT NULL DEFAULT 0;
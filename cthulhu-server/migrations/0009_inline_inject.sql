@@ -0,0 +1,4 @@
+-- 内联注入
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(29, 'inject', 0, '脚本注入', 'obj', ''),
+	(30, 'inline', 29, '合成带nonce的内联脚本(不放宽页面的CSP)', 'bool', 'false');
@@ -0,0 +1,2 @@
+-- 插件修改后需要改写 integrity 的资源
+ALTER TABLE `plugin` ADD COLUMN `sri` TEXT NOT NULL DEFAULT '';
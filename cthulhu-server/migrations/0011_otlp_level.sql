@@ -0,0 +1,3 @@
+-- OTLP导出单独的链路级别
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(31, 'otlpLevel', 17, 'OTLP导出的链路级别', 'str', '"info"');
@@ -0,0 +1,3 @@
+-- REPL 可以在插件中执行任意代码，默认关闭
+INSERT OR IGNORE INTO `config` (`id`, `key`, `parent_id`, `label`, `type`, `value`) VALUES
+	(32, 'repl', 0, '允许通过REPL在插件中执行代码', 'bool', 'false');
@@ -1,4 +1,4 @@
-
+﻿
 INSERT INTO "config" ("id", "key", "parent_id", "label", "type", "value") VALUES
 	(1, 'workspace', 0, '工作目录', 'str', '".\\workspace"'),
 	(2, 'port', 0, '工作端口', 'num', '3000'),
@@ -11,5 +11,25 @@ INSERT INTO "config" ("id", "key", "parent_id", "label", "type", "value") VALUES
 	(9, 'list', 5, '域名列表', 'list', ''),
 	(10, 'certificate', 0, 'CA证书', 'obj', ''),
 	(11, 'key', 10, '私钥', 'str', '".\\ca\\cthulhu.key"'),
-	(12, 'cert', 10, '证书', 'str', '".\\ca\\cthulhu.cer"');
+	(12, 'cert', 10, '证书', 'str', '".\\ca\\cthulhu.cer"'),
+	(13, 'pluginLog', 0, '插件日志', 'obj', ''),
+	(14, 'maxSize', 13, '单个日志文件大小(MB)', 'num', '10'),
+	(15, 'maxFiles', 13, '保留的归档文件数', 'num', '10'),
+	(16, 'maxDays', 13, '日志保留天数', 'num', '7'),
+	(17, 'log', 0, '运行日志', 'obj', ''),
+	(18, 'level', 17, '日志级别', 'str', '"error"'),
+	(19, 'format', 17, '日志格式(pretty/compact/json)', 'str', '"pretty"'),
+	(20, 'otlpEndpoint', 17, 'OTLP地址(为空不导出)', 'str', '""'),
+	(21, 'scopeTtl', 0, '域的空闲超时(秒)', 'num', '1800'),
+	(22, 'scopeStrategy', 0, '域的划分方式', 'obj', ''),
+	(23, 'mode', 22, '方式(default/ip/cookie/header/plugin)', 'str', '"default"'),
+	(24, 'cookie', 22, 'cookie方式使用的cookie名', 'str', '"cthulhu_profile"'),
+	(25, 'header', 22, 'header方式使用的请求头', 'str', '"cthulhu-profile"'),
+	(26, 'plugin', 22, 'plugin方式使用的插件id(为空时使用域名对应的插件)', 'str', '""'),
+	(27, 'breakpoint', 0, '断点', 'obj', ''),
+	(28, 'timeout', 27, '超时自动放行(秒)', 'num', '60'),
+	(29, 'inject', 0, '脚本注入', 'obj', ''),
+	(30, 'inline', 29, '合成带nonce的内联脚本(不放宽页面的CSP)', 'bool', 'false'),
+	(31, 'otlpLevel', 17, 'OTLP导出的链路级别', 'str', '"info"'),
+	(32, 'repl', 0, '允许通过REPL在插件中执行代码', 'bool', 'false');
 
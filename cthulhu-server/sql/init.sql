@@ -10,11 +10,14 @@ CREATE TABLE IF NOT EXISTS `plugin` (
 	`web_index` TEXT NOT NULL DEFAULT '',
 	`server_path` TEXT NOT NULL DEFAULT '',
 	`worker_path` TEXT NOT NULL DEFAULT '',
-	`context_paths` TEXT NOT NULL DEFAULT '',
+	`content_paths` TEXT NOT NULL DEFAULT '',
 	`dynamic_links` TEXT NOT NULL DEFAULT '',
+	`content_scripts` TEXT NOT NULL DEFAULT '',
 	`matches` TEXT NOT NULL DEFAULT '',
+	`priority` INTEGER NOT NULL DEFAULT 0,
 	`net_monitor` INTEGER NOT NULL DEFAULT 0,
 	`net_modify` INTEGER NOT NULL DEFAULT 0,
+	`sri` TEXT NOT NULL DEFAULT '',
 	`enable` INTEGER NOT NULL DEFAULT 0,
 	`install_time` TIMESTAMP DEFAULT CURRENT_TIMESTAMP
 );
@@ -31,3 +34,19 @@ CREATE TABLE IF NOT EXISTS `config` (
 	UNIQUE (`key`,`parent_id`)
 );
 
+
+
+-- 调试规则表，本地映射、远程映射和头部改写
+CREATE TABLE IF NOT EXISTS `rule` (
+	`id` INTEGER PRIMARY KEY AUTOINCREMENT,
+	`kind` VARCHAR(10) NOT NULL,
+	`pattern` TEXT NOT NULL,
+	`regex` INTEGER NOT NULL DEFAULT 0,
+	`target` TEXT NOT NULL DEFAULT '',
+	`phase` VARCHAR(10) NOT NULL DEFAULT 'request',
+	`action` VARCHAR(10) NOT NULL DEFAULT '',
+	`name` TEXT NOT NULL DEFAULT '',
+	`value` TEXT NOT NULL DEFAULT '',
+	`enable` INTEGER NOT NULL DEFAULT 1,
+	`remark` TEXT NOT NULL DEFAULT ''
+);
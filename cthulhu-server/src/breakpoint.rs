@@ -0,0 +1,364 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Local};
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH},
+    Body, Method, Request, Response, StatusCode, Uri,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tracing::error;
+
+use crate::{
+    auto_result,
+    handle::{api::config, response_content},
+    net_proxy::{decode_request, decode_response},
+    utils,
+};
+
+//没有配置时断点的超时时间，秒
+const DEFAULT_TIMEOUT: u64 = 60;
+
+///断点所在的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Request,
+    Response,
+}
+
+///断点规则，域名和路径都为空时匹配所有请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreakpointRule {
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    //域名通配，例如 *.example.com
+    #[serde(default)]
+    pub host: String,
+    //路径正则
+    #[serde(default)]
+    pub path: String,
+    //是否暂停请求
+    #[serde(default)]
+    pub request: bool,
+    //是否暂停响应
+    #[serde(default)]
+    pub response: bool,
+}
+fn enabled() -> bool {
+    true
+}
+
+///被暂停的请求或响应，编辑后原样提交给 resume
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PausedMessage {
+    //请求才有
+    #[serde(default)]
+    pub method: String,
+    #[serde(default)]
+    pub url: String,
+    //响应才有
+    #[serde(default)]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub body: String,
+    //body 不是utf-8文本时为true，此时 body 不可编辑
+    #[serde(default)]
+    pub binary: bool,
+}
+
+///断点列表中的一项
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PausedInfo {
+    //请求id
+    pub id: String,
+    pub phase: Phase,
+    pub scope: String,
+    pub created: DateTime<Local>,
+    pub message: PausedMessage,
+}
+
+///对断点的处理
+#[derive(Debug)]
+pub enum Resolution {
+    //继续，带上编辑后的内容
+    Resume(Option<PausedMessage>),
+    //丢弃，客户端收到错误响应
+    Drop,
+    //不发往上游，直接返回模拟的响应
+    Respond(PausedMessage),
+}
+
+struct Paused {
+    info: PausedInfo,
+    tx: oneshot::Sender<Resolution>,
+}
+
+///断点管理器，规则和被暂停的请求都只保存在内存中
+#[derive(Default)]
+pub struct BreakpointManager {
+    rules: RwLock<Vec<(BreakpointRule, Option<Regex>)>>,
+    //请求id 映射 被暂停的请求或响应
+    paused: Mutex<HashMap<String, Paused>>,
+    //插件要求暂停响应的请求id
+    response_marks: Mutex<Vec<String>>,
+}
+impl BreakpointManager {
+    pub async fn rules(&self) -> Vec<BreakpointRule> {
+        let rules = self.rules.read().await;
+        rules.iter().map(|(rule, _)| rule.clone()).collect()
+    }
+    pub async fn set_rules(&self, rules: Vec<BreakpointRule>) -> Result<(), String> {
+        let mut compiled = vec![];
+        for rule in rules {
+            let path = if rule.path.is_empty() {
+                None
+            } else {
+                let regex = auto_result!(Regex::new(&rule.path),err=>{
+                    return Err(format!("路径正则 '{}' 无效:{err}", rule.path));
+                });
+                Some(regex)
+            };
+            compiled.push((rule, path));
+        }
+        *self.rules.write().await = compiled;
+        Ok(())
+    }
+    ///是否有启用的规则匹配该地址
+    pub async fn matches(&self, uri: &Uri, phase: Phase) -> bool {
+        let host = uri.host().unwrap_or("");
+        let path = uri.path();
+        let rules = self.rules.read().await;
+        rules.iter().any(|(rule, regex)| {
+            rule.enabled
+                && match phase {
+                    Phase::Request => rule.request,
+                    Phase::Response => rule.response,
+                }
+                && (rule.host.is_empty() || utils::mini_match(&rule.host, host))
+                && regex.as_ref().map(|v| v.is_match(path)).unwrap_or(true)
+        })
+    }
+    ///插件要求暂停该请求的响应
+    pub async fn mark_response(&self, request_id: &str) {
+        self.response_marks
+            .lock()
+            .await
+            .push(request_id.to_string());
+    }
+    ///取出响应暂停标记，请求失败时也需要调用以免残留
+    pub async fn take_response_mark(&self, request_id: &str) -> bool {
+        let mut marks = self.response_marks.lock().await;
+        let len = marks.len();
+        marks.retain(|v| v != request_id);
+        marks.len() != len
+    }
+    pub async fn list(&self) -> Vec<PausedInfo> {
+        let paused = self.paused.lock().await;
+        let mut list = paused
+            .values()
+            .map(|v| v.info.clone())
+            .collect::<Vec<PausedInfo>>();
+        list.sort_by(|a, b| a.created.cmp(&b.created));
+        list
+    }
+    ///处理断点，断点不存在或已超时返回false
+    pub async fn resolve(&self, id: &str, resolution: Resolution) -> bool {
+        let paused = self.paused.lock().await.remove(id);
+        match paused {
+            Some(paused) => paused.tx.send(resolution).is_ok(),
+            None => false,
+        }
+    }
+    ///域关闭时原样放行它的断点
+    pub async fn release_scope(&self, scope_id: &str) {
+        let mut paused = self.paused.lock().await;
+        paused.retain(|_, v| v.info.scope != scope_id);
+    }
+    //等待处理，超时或者被放弃时原样放行
+    async fn wait(&self, info: PausedInfo) -> Resolution {
+        let id = info.id.clone();
+        let (tx, rx) = oneshot::channel();
+        self.paused
+            .lock()
+            .await
+            .insert(id.clone(), Paused { info, tx });
+        match tokio::time::timeout(timeout().await, rx).await {
+            Ok(Ok(resolution)) => resolution,
+            Ok(Err(_)) => Resolution::Resume(None),
+            Err(_) => {
+                self.paused.lock().await.remove(&id);
+                Resolution::Resume(None)
+            }
+        }
+    }
+}
+
+//断点超时时间，对应配置 breakpoint.timeout
+async fn timeout() -> Duration {
+    let secs = config::get_config("breakpoint")
+        .await
+        .and_then(|v| v.get("timeout").and_then(|v| v.as_u64()))
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
+fn headers_to_vec(headers: &hyper::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.to_string(),
+                String::from_utf8_lossy(v.as_bytes()).to_string(),
+            )
+        })
+        .collect()
+}
+
+fn apply_headers(
+    target: &mut hyper::HeaderMap,
+    headers: &[(String, String)],
+) -> Result<(), String> {
+    target.clear();
+    for (k, v) in headers {
+        let key = HeaderName::from_str(k).map_err(|e| format!("请求头 '{k}' 无效:{e}"))?;
+        let value = HeaderValue::from_str(v).map_err(|e| format!("请求头 '{k}' 的值无效:{e}"))?;
+        target.append(key, value);
+    }
+    Ok(())
+}
+
+//编辑后的body，二进制body不可编辑
+fn edited_body(original: Vec<u8>, binary: bool, message: &PausedMessage) -> Vec<u8> {
+    if binary {
+        original
+    } else {
+        message.body.clone().into_bytes()
+    }
+}
+
+fn body_text(bytes: &[u8]) -> (String, bool) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (text.to_string(), false),
+        Err(_) => (String::new(), true),
+    }
+}
+
+//用断点中编辑的内容构造模拟响应
+fn mock_response(message: &PausedMessage) -> Response<Body> {
+    let status = StatusCode::from_u16(message.status).unwrap_or(StatusCode::OK);
+    let mut res = Response::new(Body::from(message.body.clone()));
+    *res.status_mut() = status;
+    if let Err(err) = apply_headers(res.headers_mut(), &message.headers) {
+        return response_content(500, &err);
+    }
+    res.headers_mut().remove(CONTENT_LENGTH);
+    res
+}
+
+impl BreakpointManager {
+    ///暂停请求直到被处理，返回Err时不再发往上游，直接把响应返回给客户端
+    pub async fn pause_request(
+        &self,
+        id: &str,
+        scope: &str,
+        req: Request<Body>,
+    ) -> Result<Request<Body>, Response<Body>> {
+        let req = auto_result!(decode_request(req),err=>{
+            error!("decode request failed {err}");
+            return Err(response_content(500, "<breakpoint error>"));
+        });
+        let (mut parts, body) = req.into_parts();
+        let bytes = auto_result!(hyper::body::to_bytes(body).await,err=>{
+            error!("read request body failed {err}");
+            return Err(response_content(500, "<breakpoint error>"));
+        })
+        .to_vec();
+        let (text, binary) = body_text(&bytes);
+        let info = PausedInfo {
+            id: id.to_string(),
+            phase: Phase::Request,
+            scope: scope.to_string(),
+            created: Local::now(),
+            message: PausedMessage {
+                method: parts.method.to_string(),
+                url: parts.uri.to_string(),
+                status: 0,
+                headers: headers_to_vec(&parts.headers),
+                body: text,
+                binary,
+            },
+        };
+        match self.wait(info).await {
+            Resolution::Resume(None) => Ok(Request::from_parts(parts, Body::from(bytes))),
+            Resolution::Resume(Some(message)) => {
+                parts.method = Method::from_str(&message.method)
+                    .map_err(|e| response_content(500, &format!("请求方法无效:{e}")))?;
+                parts.uri = Uri::from_str(&message.url)
+                    .map_err(|e| response_content(500, &format!("请求地址无效:{e}")))?;
+                apply_headers(&mut parts.headers, &message.headers)
+                    .map_err(|e| response_content(500, &e))?;
+                parts.headers.remove(CONTENT_LENGTH);
+                let body = edited_body(bytes, binary, &message);
+                Ok(Request::from_parts(parts, Body::from(body)))
+            }
+            Resolution::Drop => Err(response_content(502, "<breakpoint dropped>")),
+            Resolution::Respond(message) => Err(mock_response(&message)),
+        }
+    }
+
+    ///暂停响应直到被处理
+    pub async fn pause_response(
+        &self,
+        id: &str,
+        scope: &str,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let res = auto_result!(decode_response(res),err=>{
+            error!("decode response failed {err}");
+            return response_content(500, "<breakpoint error>");
+        });
+        let (mut parts, body) = res.into_parts();
+        let bytes = auto_result!(hyper::body::to_bytes(body).await,err=>{
+            error!("read response body failed {err}");
+            return response_content(500, "<breakpoint error>");
+        })
+        .to_vec();
+        let (text, binary) = body_text(&bytes);
+        let info = PausedInfo {
+            id: id.to_string(),
+            phase: Phase::Response,
+            scope: scope.to_string(),
+            created: Local::now(),
+            message: PausedMessage {
+                method: String::new(),
+                url: String::new(),
+                status: parts.status.as_u16(),
+                headers: headers_to_vec(&parts.headers),
+                body: text,
+                binary,
+            },
+        };
+        match self.wait(info).await {
+            Resolution::Resume(None) => Response::from_parts(parts, Body::from(bytes)),
+            Resolution::Resume(Some(message)) => {
+                parts.status = StatusCode::from_u16(message.status).unwrap_or(parts.status);
+                if let Err(err) = apply_headers(&mut parts.headers, &message.headers) {
+                    return response_content(500, &err);
+                }
+                parts.headers.remove(CONTENT_LENGTH);
+                let body = edited_body(bytes, binary, &message);
+                Response::from_parts(parts, Body::from(body))
+            }
+            Resolution::Drop => response_content(502, "<breakpoint dropped>"),
+            Resolution::Respond(message) => mock_response(&message),
+        }
+    }
+}
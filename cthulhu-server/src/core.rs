@@ -2,20 +2,24 @@ use std::{
     any::Any,
     collections::{HashMap, HashSet},
     fmt::Debug,
-    net::SocketAddr,
-    sync::{Arc, Condvar},
+    sync::Arc,
     time::Duration,
 };
 
+use chrono::{DateTime, Local};
+use futures::SinkExt;
 use hyper::http::Uri;
+use hyper_tungstenite::tungstenite::Message;
 use rquickjs::{AsyncContext, AsyncRuntime};
+use serde::Serialize;
 use sled::Db;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{oneshot, Mutex, RwLock};
 
 use crate::{
-    handle::model::Plugin,
-    jsbind::{self, server::Scope},
-    utils, Sink,
+    handle::model::{ContentScript, Plugin},
+    jsbind::{self, http::JsRequest, server::Scope},
+    matcher::{MatchTarget, PluginMatcher},
+    Sink, BREAKPOINTS,
 };
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
@@ -35,18 +39,161 @@ pub struct ClientManager {
 
     ///客户端标识 映射 代理key
     pub proxy_datas: RwLock<HashMap<Scope, ProxyCfg>>,
-    //客户端对应的标志地址和信号，用来阻塞请求
-    pub locks: RwLock<HashMap<Scope, Arc<(std::sync::Mutex<bool>, Condvar)>>>,
-    //一次请求中 其所在的域
-    pub ctx_map_scope_keys: RwLock<HashMap<(SocketAddr, Uri), Scope>>,
+    //请求id 映射 请求所在的域
+    pub ctx_map_scope_keys: RwLock<HashMap<String, Scope>>,
+    //请求id 映射 插件修改后实际发出的请求，响应钩子取出后删除
+    pub requests: RwLock<HashMap<String, JsRequest>>,
+    //请求id 映射 需要随响应下发的身份cookie
+    pub cookies: RwLock<HashMap<String, String>>,
     //id map scope
     pub scope_keys: RwLock<HashMap<String, Scope>>,
+    //scope id 映射 创建时间和最后活跃时间
+    pub activities: RwLock<HashMap<String, ScopeActivity>>,
+    //插件调用页面方法的 会话id/rpc id 映射 等待页面回复的通道
+    pub rpc_calls:
+        Mutex<HashMap<String, oneshot::Sender<Result<serde_json::Value, serde_json::Value>>>>,
+    //正在向页面推送的流式rpc结果 会话id/rpc id，页面取消或会话关闭时删除
+    pub rpc_streams: Mutex<HashSet<String>>,
+}
+
+///域的生命周期信息
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScopeActivity {
+    pub created: DateTime<Local>,
+    pub last_active: DateTime<Local>,
+}
+
+///域的信息，供 /server/scopes 返回
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScopeInfo {
+    pub scope: Scope,
+    #[serde(flatten)]
+    pub activity: ScopeActivity,
+    pub sessions: usize,
 }
 
 impl ClientManager {
-    pub async fn set_scope_key(&self, key: Scope) {
+    ///记录域并刷新活跃时间，第一次出现时返回true
+    pub async fn set_scope_key(&self, key: Scope) -> bool {
+        let now = Local::now();
+        let created = {
+            let mut activities = self.activities.write().await;
+            match activities.get_mut(&key.id) {
+                Some(v) => {
+                    v.last_active = now;
+                    false
+                }
+                None => {
+                    activities.insert(
+                        key.id.clone(),
+                        ScopeActivity {
+                            created: now,
+                            last_active: now,
+                        },
+                    );
+                    true
+                }
+            }
+        };
         let mut guard = self.scope_keys.write().await;
         guard.insert(key.id.clone(), key);
+        created
+    }
+    ///刷新域的活跃时间，例如收到websocket消息
+    pub async fn touch_scope(&self, id: &str) {
+        let mut activities = self.activities.write().await;
+        if let Some(v) = activities.get_mut(id) {
+            v.last_active = Local::now();
+        }
+    }
+    pub async fn scope_infos(&self) -> Vec<ScopeInfo> {
+        let scope_keys = self.scope_keys.read().await;
+        let activities = self.activities.read().await;
+        let sessions = self.sessions.read().await;
+        let mut infos = scope_keys
+            .values()
+            .filter_map(|scope| {
+                Some(ScopeInfo {
+                    scope: scope.clone(),
+                    activity: activities.get(&scope.id)?.clone(),
+                    sessions: sessions.get(scope).map(|v| v.len()).unwrap_or(0),
+                })
+            })
+            .collect::<Vec<ScopeInfo>>();
+        infos.sort_by(|a, b| b.activity.last_active.cmp(&a.activity.last_active));
+        infos
+    }
+    ///关闭域，清理与它相关的代理配置、请求映射和websocket会话
+    pub async fn close_scope(&self, id: &str) -> Option<Scope> {
+        let scope = self.scope_keys.write().await.remove(id)?;
+        self.activities.write().await.remove(id);
+        self.proxy_datas.write().await.remove(&scope);
+        BREAKPOINTS.release_scope(&scope.id).await;
+        let request_ids = {
+            let mut ctx_map = self.ctx_map_scope_keys.write().await;
+            let ids = ctx_map
+                .iter()
+                .filter(|(_, v)| v.id == scope.id)
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<String>>();
+            for id in ids.iter() {
+                ctx_map.remove(id);
+            }
+            ids
+        };
+        let mut requests = self.requests.write().await;
+        let mut cookies = self.cookies.write().await;
+        for id in request_ids {
+            requests.remove(&id);
+            cookies.remove(&id);
+        }
+        drop(cookies);
+        drop(requests);
+        let sessions = self.sessions.write().await.remove(&scope).unwrap_or_default();
+        let mut sinks = self.sinks.write().await;
+        for session_id in sessions {
+            if let Some(sink) = sinks.remove(&session_id) {
+                let _ = sink.lock().await.send(Message::Close(None)).await;
+            }
+        }
+        Some(scope)
+    }
+    ///关闭超过ttl没有活动且没有websocket会话的域
+    pub async fn evict_expired_scopes(&self, ttl: Duration) -> Vec<Scope> {
+        let deadline = Local::now() - chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::days(365));
+        let expired = {
+            let activities = self.activities.read().await;
+            let scope_keys = self.scope_keys.read().await;
+            let sessions = self.sessions.read().await;
+            activities
+                .iter()
+                .filter(|(_, v)| v.last_active < deadline)
+                .filter(|(id, _)| {
+                    scope_keys
+                        .get(*id)
+                        .and_then(|scope| sessions.get(scope))
+                        .map(|v| v.is_empty())
+                        .unwrap_or(true)
+                })
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<String>>()
+        };
+        let mut scopes = vec![];
+        for id in expired {
+            if let Some(scope) = self.close_scope(&id).await {
+                scopes.push(scope);
+            }
+        }
+        scopes
+    }
+    ///向websocket会话发送消息
+    pub async fn send_message(&self, session_id: &str, msg: Message) -> Result<(), String> {
+        let sinks = self.sinks.read().await;
+        let sink = sinks.get(session_id).ok_or("invalid sessionId")?;
+        let mut sink = sink.lock().await;
+        sink.send(msg).await.map_err(|e| e.to_string())
     }
     pub async fn add_session_sink(&self, scope_key: Scope, session_id: String, sink: Sink) {
         let mut guard = self.sessions.write().await;
@@ -70,39 +217,78 @@ impl ClientManager {
 
 pub struct PluginCtx {
     pub plugin: Plugin,
+    //由 plugin.matches 编译而来
+    pub matcher: PluginMatcher,
+    //内容脚本和各自的匹配规则，按 manifest 中的顺序
+    pub scripts: Vec<(ContentScript, PluginMatcher)>,
     pub ctx: Option<Mutex<AsyncContext>>,
     pub rt: Option<AsyncRuntime>,
     pub db: Option<Db>,
+    //server 上是否定义了 onScript，加载时确定，避免每个脚本响应都进入js上下文查询
+    pub on_script: bool,
+    //同上，没有定义 onResponse 时不需要为 integrity 保留响应的body
+    pub on_response: bool,
 }
 impl Debug for PluginCtx {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("PluginCtx")
             .field("plugin", &self.plugin)
+            .field("matcher", &self.matcher)
             .field("ctx", &self.ctx.type_id())
             .field("rt", &self.rt.type_id())
             .field("db", &self.db)
+            .field("on_script", &self.on_script)
+            .field("on_response", &self.on_response)
             .finish()
     }
 }
 impl PluginCtx {
     pub async fn new(plugin: Plugin) -> Result<Self, String> {
+        let matcher = PluginMatcher::new(&plugin.matches)
+            .map_err(|e| format!("插件 '{}' 的 matches 无效:{e}", plugin.name))?;
+        let mut scripts = vec![];
+        for script in plugin.content_scripts() {
+            let script_matcher = PluginMatcher::compile(&script.matches).map_err(|e| {
+                format!("插件 '{}' 的 {} 匹配规则无效:{e}", plugin.name, script.path)
+            })?;
+            scripts.push((script, script_matcher));
+        }
         if plugin.server_path.is_empty() {
             return Ok(Self {
                 plugin,
+                matcher,
+                scripts,
                 ctx: None,
                 rt: None,
                 db: None,
+                on_script: false,
+                on_response: false,
             });
         }
         let (ctx, rt, db) = jsbind::content(&plugin).await?;
         println!("loaded plugin: {}, ID = '{}'", &plugin.name, &plugin.id);
         let ctx = Mutex::new(ctx);
-        Ok(Self {
+        let mut plugin_ctx = Self {
             plugin,
+            matcher,
+            scripts,
             ctx: Some(ctx),
             rt: Some(rt),
             db: Some(db),
-        })
+            on_script: false,
+            on_response: false,
+        };
+        plugin_ctx.on_script = plugin_ctx.has_hook("onScript").await;
+        plugin_ctx.on_response = plugin_ctx.has_hook("onResponse").await;
+        Ok(plugin_ctx)
+    }
+    ///页面需要注入的内容脚本
+    pub fn content_scripts(&self, target: &MatchTarget) -> Vec<&ContentScript> {
+        self.scripts
+            .iter()
+            .filter(|(_, matcher)| matcher.is_match(target))
+            .map(|(script, _)| script)
+            .collect()
     }
 }
 
@@ -113,6 +299,7 @@ pub struct PluginManager {
 }
 
 impl PluginManager {
+    ///只按请求所属域的域名匹配插件
     pub async fn ctxs_by_host(
         &self,
         host: &str,
@@ -120,6 +307,17 @@ impl PluginManager {
         Vec<Arc<PluginCtx>>,
         Vec<Arc<PluginCtx>>,
         Option<Arc<PluginCtx>>,
+    ) {
+        self.ctxs_by_target(&MatchTarget::origin(host)).await
+    }
+    ///按请求的地址、方法和资源类型匹配插件
+    pub async fn ctxs_by_target(
+        &self,
+        target: &MatchTarget,
+    ) -> (
+        Vec<Arc<PluginCtx>>,
+        Vec<Arc<PluginCtx>>,
+        Option<Arc<PluginCtx>>,
     ) {
         let guard = self.ctxs.read().await;
         let mut matched_ctxs = vec![];
@@ -131,8 +329,7 @@ impl PluginManager {
                 if ctx.plugin.server_path.is_empty() {
                     return false;
                 }
-                let matches = &ctx.plugin.matches;
-                matches.is_empty() || matches.split(",").any(|m| utils::mini_match(m, host))
+                ctx.matcher.is_match(target)
             })
             .map(|(_k, v)| v.clone())
             .for_each(|ctx| {
@@ -144,6 +341,14 @@ impl PluginManager {
                 }
                 matched_ctxs.push(ctx);
             });
+        //优先级高的在前，相同时先安装的在前，保证注入顺序不受HashMap遍历顺序影响
+        matched_ctxs.sort_by(|a, b| {
+            b.plugin
+                .priority
+                .cmp(&a.plugin.priority)
+                .then_with(|| a.plugin.install_time.cmp(&b.plugin.install_time))
+                .then_with(|| a.plugin.id.cmp(&b.plugin.id))
+        });
         net_modify_ctxs.sort_by(|a, b| {
             a.plugin
                 .net_modify
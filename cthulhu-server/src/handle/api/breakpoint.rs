@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use hyper::http::{Request, Response};
+use hyper::Body;
+
+use crate::{
+    auto_option, auto_result,
+    breakpoint::{BreakpointRule, PausedMessage, Resolution},
+    handle::{response_data, response_msg},
+    net_proxy::HttpContext,
+    wrap, BREAKPOINTS,
+};
+
+use super::detect;
+
+///被暂停的请求和响应
+async fn list(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
+    response_data(&BREAKPOINTS.list().await, "")
+}
+async fn rules(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
+    response_data(&BREAKPOINTS.rules().await, "")
+}
+///请求体为规则数组，整体替换
+async fn set_rules(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (_params, body) = auto_result!(detect(req).await);
+    let rules = auto_result!(serde_json::from_value::<Vec<BreakpointRule>>(body),err=>{
+        return response_msg(500, format!("断点规则格式错误:{err}"));
+    });
+    auto_result!(BREAKPOINTS.set_rules(rules).await,err=>{
+        return response_msg(500, err);
+    });
+    response_msg(200, "断点规则已保存")
+}
+
+//请求体 {id, message}，message 为编辑后的内容
+fn resolve_body(body: serde_json::Value) -> Result<(String, Option<PausedMessage>), String> {
+    let id = body
+        .get("id")
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+        .ok_or("缺少断点id".to_string())?;
+    let message = match body.get("message") {
+        Some(v) if !v.is_null() => {
+            let message = serde_json::from_value::<PausedMessage>(v.clone())
+                .map_err(|e| format!("断点内容格式错误:{e}"))?;
+            Some(message)
+        }
+        _ => None,
+    };
+    Ok((id, message))
+}
+
+///继续执行，带 message 时使用编辑后的内容
+async fn resume(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (_params, body) = auto_result!(detect(req).await);
+    let (id, message) = auto_result!(resolve_body(body),err=>{
+        return response_msg(500, err);
+    });
+    if !BREAKPOINTS.resolve(&id, Resolution::Resume(message)).await {
+        return response_msg(500, "断点不存在或已超时");
+    }
+    response_data(&true, "")
+}
+///丢弃，客户端收到错误响应
+async fn drop_paused(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (mut params, _body) = auto_result!(detect(req).await);
+    let id = auto_option!(params.remove("id"), response_msg(500, "缺少断点id"));
+    if !BREAKPOINTS.resolve(&id, Resolution::Drop).await {
+        return response_msg(500, "断点不存在或已超时");
+    }
+    response_data(&true, "")
+}
+///直接返回 message 描述的模拟响应
+async fn respond(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (_params, body) = auto_result!(detect(req).await);
+    let (id, message) = auto_result!(resolve_body(body),err=>{
+        return response_msg(500, err);
+    });
+    let message = auto_option!(message, response_msg(500, "缺少模拟响应的内容"));
+    if !BREAKPOINTS.resolve(&id, Resolution::Respond(message)).await {
+        return response_msg(500, "断点不存在或已超时");
+    }
+    response_data(&true, "")
+}
+
+pub fn route(router: &mut HashMap<&'static str, Box<super::AsyncFn>>) {
+    router.insert("/breakpoint/list", wrap!(list));
+    router.insert("/breakpoint/rules", wrap!(rules));
+    router.insert("/breakpoint/setRules", wrap!(set_rules));
+    router.insert("/breakpoint/resume", wrap!(resume));
+    router.insert("/breakpoint/drop", wrap!(drop_paused));
+    router.insert("/breakpoint/respond", wrap!(respond));
+}
@@ -16,6 +16,7 @@ use crate::{
         api::{config, detect},
         model::Config,
         response_data, response_msg,
+        scope::ScopeStrategy,
     },
     wrap, DBPOOL,
 };
@@ -203,6 +204,8 @@ pub async fn update_config_by_id(id: i64, val: &str) -> Result<(), String> {
         return Err("修改配置异常".into())
 
     });
+    //修改的可能是域划分方式的属性
+    ScopeStrategy::invalidate().await;
     Ok(())
 }
 #[async_recursion]
@@ -15,8 +15,10 @@ use crate::{auto_option, auto_result};
 
 use super::{response_headers, response_msg};
 
+pub mod breakpoint;
 pub mod config;
 pub mod plugin;
+pub mod rules;
 pub mod server;
 
 pub async fn detect(
@@ -73,6 +75,8 @@ lazy_static::lazy_static! {
         server::route(&mut router);
         plugin::route(&mut router);
         config::route(&mut router);
+        breakpoint::route(&mut router);
+        rules::route(&mut router);
         router
     };
 }
@@ -10,18 +10,30 @@ use std::{
 use crate::net_proxy::HttpContext;
 use hyper::http::{Request, Response};
 
+use futures::{SinkExt, StreamExt};
 use hyper::Body;
+use hyper_tungstenite::tungstenite::Message;
+use tokio::sync::broadcast;
 use tracing::{error, instrument};
 
 use crate::{
     auto_option, auto_result,
     core::PluginCtx,
-    handle::{model::Plugin, response_data, response_msg},
-    utils::{self, mini_match},
-    wrap, DBPOOL, PLUGIN_MANAGER,
+    handle::{
+        model::{ContentScript, Plugin},
+        response_data, response_msg,
+    },
+    jsbind::{
+        console::{self, LogRecord},
+        typescript,
+    },
+    matcher::{self, MatchTarget},
+    sri::SriPolicy,
+    utils,
+    wrap, DBPOOL, METRICS, PLUGIN_MANAGER,
 };
 
-use super::detect;
+use super::{config, detect};
 #[instrument]
 pub async fn get_enabled_plugins() -> Vec<Plugin> {
     let pool = &DBPOOL.clone();
@@ -35,10 +47,13 @@ pub async fn get_enabled_plugins() -> Vec<Plugin> {
 }
 #[instrument]
 pub async fn get_plugins_by_host(host: &str) -> Vec<Plugin> {
-    let vec = get_enabled_plugins().await;
-
-    vec.into_iter()
-        .filter(|p| p.matches.is_empty() || p.matches.split(";").any(|m| mini_match(m, host)))
+    //只有启用的插件有 PluginCtx，匹配规则在启用或重新加载时已经编译
+    let target = MatchTarget::origin(host);
+    let guard = PLUGIN_MANAGER.ctxs.read().await;
+    guard
+        .values()
+        .filter(|ctx| ctx.matcher.is_match(&target))
+        .map(|ctx| ctx.plugin.clone())
         .collect()
 }
 #[instrument]
@@ -96,14 +111,128 @@ pub async fn get_plugin_by_id(id: &str) -> Option<Plugin> {
     });
     res
 }
-#[instrument]
-pub async fn install(dir: &str) {
-    let dir = std::path::Path::new(dir);
+///写入/更新插件目录中的 cthulhu.d.ts
+pub fn write_declarations(dir: &Path) -> io::Result<()> {
+    let path = dir.join("cthulhu.d.ts");
+    if path.exists() {
+        utils::remove_path(&path)?;
+    }
+    utils::write_bytes(path, typescript::declarations().as_bytes(), None)
+}
+
+///生成TypeScript插件模板
+pub fn init(dir: &Path) {
+    if dir.join("plugin.json").exists() {
+        println!("{} 已经存在 plugin.json", dir.to_str().unwrap());
+        return;
+    }
+    let name = dir
+        .file_name()
+        .and_then(|v| v.to_str())
+        .unwrap_or("cthulhu-plugin");
+    let manifest = serde_json::json!({
+        "name": name,
+        "version": "0.0.1",
+        "intro": "",
+        "permissions": { "netMonitor": true, "netModify": 0 },
+        "script": {
+            "server": "src/server.ts",
+            "contents": ["content/content.ts"],
+        },
+        "matches": [],
+    });
+    let tsconfig = serde_json::json!({
+        "compilerOptions": {
+            "target": "ES2022",
+            "module": "ES2022",
+            "moduleResolution": "bundler",
+            "lib": ["ES2022"],
+            "types": [],
+            "strict": true,
+            "noEmit": true,
+        },
+        "include": ["cthulhu.d.ts", "src/**/*.ts", "test/**/*.ts"],
+    });
+    //内容脚本运行在页面中，使用单独的配置引入DOM类型
+    let content_tsconfig = serde_json::json!({
+        "compilerOptions": {
+            "target": "ES2022",
+            "module": "ES2022",
+            "lib": ["ES2022", "DOM"],
+            "strict": true,
+            "noEmit": true,
+        },
+        "include": ["./**/*.ts"],
+    });
+    let server = r#"import { HttpAction } from "cthulhu:http";
+
+server.onRequest = async (req: Request, scope: Scope): Promise<HttpAction> => {
+    console.log(scope.id, req.method, req.uri.toString());
+    return HttpAction.release(req);
+};
+
+server.onResponse = async (res: Response, scope: Scope): Promise<Response> => {
+    return res;
+};
+"#;
+    let content = r#"console.log("cthulhu content script loaded:", location.href);
+"#;
+    let test = r#"import { test, assert, fixtures } from "cthulhu:test";
+
+test("onRequest releases the request", async () => {
+    const req = fixtures.request({ url: "https://example.com/" });
+    const action = await server.onRequest(req, fixtures.scope());
+    assert.equal(action.name, "release");
+});
+"#;
+    let files = [
+        (
+            "plugin.json",
+            serde_json::to_string_pretty(&manifest).unwrap(),
+        ),
+        (
+            "tsconfig.json",
+            serde_json::to_string_pretty(&tsconfig).unwrap(),
+        ),
+        (
+            "content/tsconfig.json",
+            serde_json::to_string_pretty(&content_tsconfig).unwrap(),
+        ),
+        ("src/server.ts", server.to_string()),
+        ("content/content.ts", content.to_string()),
+        ("test/server.test.ts", test.to_string()),
+        (".gitignore", ".cthulhu/\nSTORE/\nLOGS/\n".to_string()),
+    ];
+    for (path, data) in files {
+        let path = dir.join(path);
+        if path.exists() {
+            continue;
+        }
+        auto_result!(utils::write_bytes(&path, data.as_bytes(), None),err=>{
+            println!("写入 {} 失败:{err}", path.to_str().unwrap());
+            return;
+        });
+    }
+    auto_result!(write_declarations(dir),err=>{
+        println!("写入 cthulhu.d.ts 失败:{err}");
+        return;
+    });
+    println!("插件模板已生成：{}", dir.to_str().unwrap());
+}
+
+///读取插件目录中的 plugin.json，ts脚本会先编译，返回的插件还没有id
+pub fn read_manifest(dir: &Path) -> Option<Plugin> {
     let plugin_info_path = relative_path::RelativePath::new("plugin.json").to_path(&dir);
     let json = {
         let path = plugin_info_path.to_str().unwrap();
-        let bytes = utils::read_bytes(path).expect(path);
-        let value = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+        let bytes = auto_result!(utils::read_bytes(path),err=>{
+            println!("读取 {path} 失败:{err}");
+            return None;
+        });
+        let value = auto_result!(serde_json::from_slice::<serde_json::Value>(&bytes),err=>{
+            println!("解析 {path} 失败:{err}");
+            return None;
+        });
         value
     };
     fn check_path(dir: &Path, path: &str, is_file: bool) -> Option<String> {
@@ -134,11 +263,14 @@ pub async fn install(dir: &str) {
 
     let name = auto_option!(
         json.get("name").map(|v| v.as_str().unwrap_or("").trim()),
-        println!("插件名称不能为空")
+        {
+            println!("插件名称不能为空");
+            return None;
+        }
     );
     if name.is_empty() {
         println!("插件名称不能为空");
-        return;
+        return None;
     }
     let version = json
         .get("version")
@@ -159,12 +291,12 @@ pub async fn install(dir: &str) {
         if logo.starts_with("http://") || logo.starts_with("https://") {
             auto_result!(  hyper::Uri::from_str(logo),err=>{
                 eprintln!("{err}");
-                return;
+                return None;
             });
             logo.to_owned()
         } else {
             let logo = auto_option!(check_path(&dir, &logo, true), {
-                return;
+                return None;
             });
             logo
         }
@@ -178,7 +310,7 @@ pub async fn install(dir: &str) {
             .map(|v| v.as_str().unwrap_or("").trim())
             .unwrap_or_default();
         let root = auto_option!(check_path(&dir, &root, false), {
-            return;
+            return None;
         });
         let index = if root.is_empty() {
             ""
@@ -188,7 +320,7 @@ pub async fn install(dir: &str) {
                 .map(|v| v.as_str().unwrap_or("").trim())
                 .unwrap_or_default();
             let _index = auto_option!(check_path(&dir, &format!("{root}/{index}"), true), {
-                return;
+                return None;
             });
             index
         };
@@ -196,7 +328,7 @@ pub async fn install(dir: &str) {
         (root, index)
     };
 
-    let (net_monitor, net_modify) = {
+    let (net_monitor, net_modify, sri) = {
         let permissions = json.get("permissions").unwrap_or(&empty_obj);
         let net_monitor = permissions
             .get("netMonitor")
@@ -209,7 +341,17 @@ pub async fn install(dir: &str) {
         if net_modify < 0 {
             net_modify = 0;
         }
-        (net_monitor, net_modify)
+        //修改了带 integrity 的脚本或样式后如何处理页面中的 integrity
+        let sri = permissions
+            .get("sri")
+            .map(|v| v.as_str().unwrap_or(""))
+            .unwrap_or_default()
+            .trim();
+        auto_result!(SriPolicy::parse(sri),err=>{
+            println!("{err}");
+            return None;
+        });
+        (net_monitor, net_modify, sri.to_string())
     };
 
     let (server, worker, contents, dynamic_links) = {
@@ -220,7 +362,7 @@ pub async fn install(dir: &str) {
                 .map(|v| v.as_str().unwrap_or("").trim())
                 .unwrap_or_default();
             let server = auto_option!(check_path(&dir, &server, true), {
-                return;
+                return None;
             });
             server
         };
@@ -231,28 +373,50 @@ pub async fn install(dir: &str) {
                 .map(|v| v.as_str().unwrap_or("").trim())
                 .unwrap_or_default();
             let worker = auto_option!(check_path(&dir, &worker, true), {
-                return;
+                return None;
             });
             worker
         };
+        //字符串为脚本路径，对象可以指定 runAt、world 和 matches
         let contents = {
             let contents = script
                 .get("contents")
                 .map(|v| v.as_array().unwrap())
                 .unwrap_or(&empty);
-            let mut paths = vec![];
+            let mut scripts = vec![];
             for content in contents {
-                let content = content.as_str().unwrap_or("").trim();
-                if content.is_empty() {
+                let mut content = match content {
+                    serde_json::Value::String(path) => ContentScript {
+                        path: path.trim().to_string(),
+                        ..Default::default()
+                    },
+                    _ => {
+                        let mut content = content.clone();
+                        let matches = content
+                            .as_object_mut()
+                            .and_then(|v| v.remove("matches"))
+                            .unwrap_or(serde_json::Value::Array(vec![]));
+                        let mut script = auto_result!(serde_json::from_value::<ContentScript>(content),err=>{
+                            println!("script.contents 格式错误:{err}");
+                            return None;
+                        });
+                        script.matches = auto_result!(matcher::parse_manifest(&matches),err=>{
+                            println!("{} {err}", script.path);
+                            return None;
+                        });
+                        script.path = script.path.trim().to_string();
+                        script
+                    }
+                };
+                if content.path.is_empty() {
                     continue;
                 }
-                let content = auto_option!(check_path(&dir, &content, true), {
-                    return;
+                content.path = auto_option!(check_path(&dir, &content.path, true), {
+                    return None;
                 });
-
-                paths.push(content);
+                scripts.push(content);
             }
-            paths
+            scripts
         };
         let dynamic_links = {
             let dynamic_links = script
@@ -271,18 +435,110 @@ pub async fn install(dir: &str) {
         };
         (server, worker, contents, dynamic_links)
     };
+    //ts脚本先编译成js，数据库里记录编译后的路径
+    let (server, worker, contents) = {
+        let has_ts = typescript::is_ts(&server)
+            || typescript::is_ts(&worker)
+            || contents.iter().any(|v| typescript::is_ts(&v.path));
+        if has_ts {
+            auto_result!(typescript::build_plugin(dir),err=>{
+                println!("编译TypeScript脚本失败:{err}");
+                return None;
+            });
+        }
+        let to_js = |path: String| {
+            if typescript::is_ts(&path) {
+                typescript::output_path(&path)
+            } else {
+                path
+            }
+        };
+        (
+            to_js(server),
+            to_js(worker),
+            contents
+                .into_iter()
+                .map(|mut v| {
+                    v.path = to_js(v.path);
+                    v
+                })
+                .collect::<Vec<ContentScript>>(),
+        )
+    };
+    //字符串为域名通配，对象可以按地址、资源类型和请求方法匹配
     let matches = {
         let matches = json
             .get("matches")
-            .map(|v| v.as_array().unwrap())
-            .unwrap_or(&empty);
-        matches
-            .into_iter()
-            .map(|v| v.as_str().unwrap_or("").trim())
-            .filter(|v| !v.is_empty())
-            .collect::<Vec<&str>>()
+            .cloned()
+            .unwrap_or(serde_json::Value::Array(vec![]));
+        let rules = auto_result!(matcher::parse_manifest(&matches),err=>{
+            println!("{err}");
+            return None;
+        });
+        if rules.is_empty() {
+            String::new()
+        } else {
+            serde_json::to_string(&rules).unwrap()
+        }
     };
 
+    let priority = json
+        .get("priority")
+        .map(|v| v.as_i64().unwrap_or(0))
+        .unwrap_or(0);
+
+    Some(Plugin {
+        id: String::new(),
+        name: name.to_string(),
+        version: version.to_string(),
+        intro: intro.to_string(),
+        logo_path: logo,
+        web_root,
+        web_index: web_index.to_string(),
+        path: dir.to_str().unwrap().to_string(),
+        server_path: server,
+        worker_path: worker,
+        content_paths: contents
+            .iter()
+            .map(|v| v.path.as_str())
+            .collect::<Vec<&str>>()
+            .join(","),
+        dynamic_links: dynamic_links.join(","),
+        content_scripts: serde_json::to_string(&contents).unwrap(),
+        matches,
+        priority,
+        net_monitor: net_monitor as i64,
+        net_modify,
+        sri,
+        enable: 1,
+        install_time: chrono::Local::now(),
+    })
+}
+
+#[instrument]
+pub async fn install(dir: &str) {
+    let dir = std::path::Path::new(dir);
+    let Plugin {
+        name,
+        version,
+        intro,
+        logo_path: logo,
+        web_root,
+        web_index,
+        server_path: server,
+        worker_path: worker,
+        content_paths: contents,
+        dynamic_links,
+        content_scripts,
+        matches,
+        priority,
+        net_monitor,
+        net_modify,
+        sri,
+        ..
+    } = auto_option!(read_manifest(dir), {
+        return;
+    });
     let id = {
         let id = uuid::Uuid::new_v4().simple().to_string();
         utils::hash(id.as_bytes(), 10, 12)
@@ -300,8 +556,8 @@ pub async fn install(dir: &str) {
         });
         if let Some(plugin) = op {
             let sql = r"update `plugin` set `name`=?,`intro`=?,`version`=?,
-        `logo_path`=?,`web_root`=?,`web_index`=?,`server_path`=?,`content_paths`=?,`worker_path`=?,`dynamic_links`=?,`matches`=?,
-        `net_monitor`=?,`net_modify`=? where `id`=?";
+        `logo_path`=?,`web_root`=?,`web_index`=?,`server_path`=?,`content_paths`=?,`worker_path`=?,`dynamic_links`=?,`content_scripts`=?,`matches`=?,
+        `priority`=?,`net_monitor`=?,`net_modify`=?,`sri`=? where `id`=?";
             let res = sqlx::query(sql)
                 .bind(name)
                 .bind(intro)
@@ -310,12 +566,15 @@ pub async fn install(dir: &str) {
                 .bind(web_root)
                 .bind(web_index)
                 .bind(server)
-                .bind(contents.join(","))
+                .bind(contents)
                 .bind(worker)
-                .bind(dynamic_links.join(","))
-                .bind(matches.join(","))
+                .bind(dynamic_links)
+                .bind(content_scripts)
+                .bind(matches)
+                .bind(priority)
                 .bind(net_monitor)
                 .bind(net_modify)
+                .bind(sri)
                 .bind(&plugin.id)
                 .execute(pool)
                 .await;
@@ -329,8 +588,8 @@ pub async fn install(dir: &str) {
     }
 
     let sql = r"insert into `plugin`(`id`,`name`,`version`,`intro`,`path`,`logo_path`,`web_root`,
-        `web_index`,`server_path`,`content_paths`,`worker_path`,`dynamic_links`,`matches`,`net_monitor`,`net_modify`)
-        values(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)";
+        `web_index`,`server_path`,`content_paths`,`worker_path`,`dynamic_links`,`content_scripts`,`matches`,`priority`,`net_monitor`,`net_modify`,`sri`)
+        values(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)";
 
     let res = sqlx::query(sql)
         .bind(id)
@@ -342,12 +601,15 @@ pub async fn install(dir: &str) {
         .bind(web_root)
         .bind(web_index)
         .bind(server)
-        .bind(contents.join(","))
+        .bind(contents)
         .bind(worker)
-        .bind(dynamic_links.join(","))
-        .bind(matches.join(","))
+        .bind(dynamic_links)
+        .bind(content_scripts)
+        .bind(matches)
+        .bind(priority)
         .bind(net_monitor)
         .bind(net_modify)
+        .bind(sri)
         .execute(pool)
         .await;
     auto_result!(res,err=>{
@@ -436,40 +698,97 @@ pub async fn del(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
     //     });
     // }
     PLUGIN_MANAGER.del_ctx(&id).await;
+    METRICS.plugins.remove(&id);
     response_msg(200, "删除插件成功")
 }
 
+//日志查询条件，level可以用逗号分隔多个等级，keyword在消息中搜索
+struct LogFilter {
+    levels: Vec<String>,
+    keyword: String,
+    scope: String,
+    request: String,
+}
+impl LogFilter {
+    fn from_params(params: &mut HashMap<String, String>) -> Result<Self, String> {
+        let levels = params
+            .remove("level")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .collect::<Vec<String>>();
+        let valid = vec!["info", "debug", "error", "warn"];
+        if let Some(level) = levels.iter().find(|v| !valid.contains(&v.as_str())) {
+            return Err(format!("无效的日志等级:{level}"));
+        }
+        Ok(Self {
+            levels,
+            keyword: params.remove("keyword").unwrap_or_default(),
+            scope: params.remove("scope").unwrap_or_default(),
+            request: params.remove("request").unwrap_or_default(),
+        })
+    }
+    fn matches(&self, record: &LogRecord) -> bool {
+        (self.levels.is_empty() || self.levels.contains(&record.level))
+            && (self.scope.is_empty() || record.scope == self.scope)
+            && (self.request.is_empty() || record.request == self.request)
+            && (self.keyword.is_empty() || record.msg.contains(&self.keyword))
+    }
+}
+
+//websocket升级前只能从uri中取参数
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .unwrap_or("")
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+        .map(|(k, v)| {
+            let v = urlencoding::decode(v).map(|v| v.to_string()).unwrap_or(v.to_string());
+            (k.to_string(), v)
+        })
+        .collect()
+}
+
+///分页查询插件日志，按时间顺序跨归档文件读取
 #[instrument(skip_all)]
 pub async fn log(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
     let (mut params, _body) = auto_result!(detect(req).await);
 
     let id = auto_option!(params.remove("id"), response_msg(500, "缺少插件id"));
-    let level = params.remove("level").unwrap_or_default();
-    let index = params.remove("index").unwrap_or_default();
-    let index = auto_result!(index.parse::<u64>(),err=>{
+    let index = params.remove("index").unwrap_or("0".to_string());
+    let index = auto_result!(index.parse::<usize>(),err=>{
         error!("异常：{}",err);
         return response_msg(500, "index传参异常");
     });
-    let levels = vec!["info", "debug", "error", "warn"];
-    if !levels.contains(&level.as_str()) {
-        return response_msg(500, "无效的日志等级");
-    }
+    let size = params.remove("size").unwrap_or("150".to_string());
+    let size = auto_result!(size.parse::<usize>(),err=>{
+        error!("异常：{}",err);
+        return response_msg(500, "size传参异常");
+    });
+    let filter = auto_result!(LogFilter::from_params(&mut params),err=>{
+        return response_msg(500, err);
+    });
     let plugin = auto_option!(get_plugin_by_id(&id).await, response_msg(500, "插件不存在"));
 
-    let path = format!("{}\\LOGS\\{level}.log", &plugin.path);
-
-    let path = Path::new(&path);
-    let file = auto_result!(File::open(&path), response_data::<Vec<String>>(&vec![], ""));
-    let size = 150;
-    let index = index as usize;
-    let reader = io::BufReader::new(file);
-    let lines = reader
-        .lines()
-        .skip(index)
-        .take(size)
-        .map(|s| s.unwrap_or_default())
-        .collect::<Vec<String>>();
-    let log = lines;
+    let dir = Path::new(&plugin.path).join("LOGS");
+    let mut records = vec![];
+    for path in console::log_files(&dir) {
+        let file = auto_result!(File::open(&path), continue);
+        let reader = io::BufReader::new(file);
+        records.extend(
+            reader
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str::<LogRecord>(&line).ok())
+                .filter(|record| filter.matches(record)),
+        );
+        if records.len() >= index + size {
+            break;
+        }
+    }
+    let log = records.into_iter().skip(index).take(size).collect::<Vec<LogRecord>>();
 
     response_data(&log, "")
 }
@@ -478,20 +797,91 @@ pub async fn clear_log(_ctx: HttpContext, req: Request<Body>) -> Response<Body>
     let (mut params, _body) = auto_result!(detect(req).await);
 
     let id = auto_option!(params.remove("id"), response_msg(500, "缺少插件id"));
-    let level = params.remove("level").unwrap_or_default();
-
-    let levels = vec!["info", "debug", "error", "warn"];
-    if !levels.contains(&level.as_str()) {
-        return response_msg(500, "无效的日志等级");
-    }
+    let filter = auto_result!(LogFilter::from_params(&mut params),err=>{
+        return response_msg(500, err);
+    });
     let plugin = auto_option!(get_plugin_by_id(&id).await, response_msg(500, "插件不存在"));
 
-    let path = format!("{}\\LOGS\\{level}.log", &plugin.path);
-    auto_result!(  utils::remove_path(&path),err=>{
-        return response_msg(500, err.to_string());
+    let dir = Path::new(&plugin.path).join("LOGS");
+    if filter.levels.is_empty() {
+        for path in console::log_files(&dir) {
+            auto_result!(  utils::remove_path(&path),err=>{
+                return response_msg(500, err.to_string());
+            });
+        }
+        return response_msg(200, "日志已清空");
+    }
+    //指定了等级时只删除这些等级的记录，其它记录原样保留
+    for path in console::log_files(&dir) {
+        let text = auto_result!(std::fs::read_to_string(&path), continue);
+        let kept = text
+            .lines()
+            .filter(|line| {
+                serde_json::from_str::<LogRecord>(line)
+                    .map(|record| !filter.levels.contains(&record.level))
+                    .unwrap_or(true)
+            })
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+        auto_result!(std::fs::write(&path, kept),err=>{
+            return response_msg(500, err.to_string());
+        });
+    }
+    //旧版本按等级分文件保存的日志
+    for level in &filter.levels {
+        let _ = utils::remove_path(dir.join(format!("{level}.log")));
+    }
+    response_msg(200, format!("{} 日志已清空", filter.levels.join(",")))
+}
+///实时推送插件日志的websocket，查询条件与 /plugin/log 相同
+#[instrument(skip_all)]
+pub async fn log_tail(_ctx: HttpContext, mut req: Request<Body>) -> Response<Body> {
+    let mut params = query_params(&req);
+    let id = auto_option!(params.remove("id"), response_msg(500, "缺少插件id"));
+    let filter = auto_result!(LogFilter::from_params(&mut params),err=>{
+        return response_msg(500, err);
     });
-
-    response_msg(200, format!("{level} 日志已清空"))
+    let plugin = auto_option!(
+        PLUGIN_MANAGER.get_ctx(&id).await,
+        response_msg(500, "插件未被启用加载")
+    );
+    let mut console = auto_option!(
+        plugin.subscribe_console().await,
+        response_msg(500, "插件没有server脚本")
+    );
+    let (res, websocket) = auto_result!(hyper_tungstenite::upgrade(&mut req, None),err=>{
+        error!("WebSocket upgrade error: {}", err);
+        return response_msg(400, "需要websocket连接");
+    });
+    tokio::spawn(async move {
+        let ws = auto_result!(websocket.await,err=>{
+            error!("WebSocket error: {}", err);
+            return;
+        });
+        let (mut sink, mut stream) = ws.split();
+        loop {
+            let record = tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => continue,
+                },
+                record = console.recv() => match record {
+                    Ok(record) => record,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            };
+            if !filter.matches(&record) {
+                continue;
+            }
+            let text = auto_result!(serde_json::to_string(&record), continue);
+            if let Err(err) = sink.send(Message::Text(text)).await {
+                error!("WebSocket send error: {}", err);
+                break;
+            }
+        }
+    });
+    res
 }
 
 // 重新加载插件
@@ -548,14 +938,86 @@ pub async fn tree_list(_ctx: HttpContext, req: Request<Body>) -> Response<Body>
     }
     response_data(&kvs, "")
 }
+///插件钩子的耗时、异常率和动作统计，不传id时返回全部插件
+#[instrument(skip_all)]
+pub async fn stats(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (mut params, _body) = auto_result!(detect(req).await);
+    let id = params.remove("id");
+    response_data(&METRICS.plugins.stats(id.as_deref()), "")
+}
+///在运行中的插件上下文里执行代码的websocket，收到的文本作为代码执行，console输出同时推送。
+///可以执行任意代码，需要先把配置项 repl 设置为 true
+#[instrument(skip_all)]
+pub async fn repl(_ctx: HttpContext, mut req: Request<Body>) -> Response<Body> {
+    let enabled = config::get_config("repl")
+        .await
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if !enabled {
+        return response_msg(403, "REPL未开启，请先执行 cthulhu config set repl true");
+    }
+    let id = auto_option!(query_params(&req).remove("id"), response_msg(500, "缺少插件id"));
+    let plugin = auto_option!(
+        PLUGIN_MANAGER.get_ctx(&id).await,
+        response_msg(500, "插件未被启用加载")
+    );
+    let mut console = auto_option!(
+        plugin.subscribe_console().await,
+        response_msg(500, "插件没有server脚本")
+    );
+    let (res, websocket) = auto_result!(hyper_tungstenite::upgrade(&mut req, None),err=>{
+        error!("WebSocket upgrade error: {}", err);
+        return response_msg(400, "需要websocket连接");
+    });
+    tokio::spawn(async move {
+        let ws = auto_result!(websocket.await,err=>{
+            error!("WebSocket error: {}", err);
+            return;
+        });
+        let (mut sink, mut stream) = ws.split();
+        loop {
+            let reply = tokio::select! {
+                msg = stream.next() => {
+                    let msg = match msg {
+                        Some(Ok(Message::Text(code))) => code,
+                        Some(Ok(Message::Ping(_))) => continue,
+                        Some(Ok(_)) | Some(Err(_)) | None => break,
+                    };
+                    match plugin.eval(msg).await {
+                        Ok(value) => serde_json::json!({ "type": "result", "value": value }),
+                        Err(err) => serde_json::json!({ "type": "error", "value": err }),
+                    }
+                }
+                log = console.recv() => {
+                    match log {
+                        Ok(record) => serde_json::json!({ "type": "log", "level": record.level, "value": record.msg }),
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            serde_json::json!({ "type": "log", "level": "warn", "value": format!("{n} 条日志被丢弃") })
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            };
+            if let Err(err) = sink.send(Message::Text(reply.to_string())).await {
+                error!("WebSocket send error: {}", err);
+                break;
+            }
+        }
+    });
+    res
+}
+
 pub fn route(router: &mut HashMap<&'static str, Box<super::AsyncFn>>) {
     router.insert("/plugin/list", wrap!(list));
     router.insert("/plugin/enable", wrap!(enable));
     router.insert("/plugin/del", wrap!(del));
     router.insert("/plugin/log", wrap!(log));
     router.insert("/plugin/clearLog", wrap!(clear_log));
+    router.insert("/plugin/logTail", wrap!(log_tail));
 
     router.insert("/plugin/reload", wrap!(reload));
     router.insert("/plugin/treeNames", wrap!(tree_names));
     router.insert("/plugin/treeList", wrap!(tree_list));
+    router.insert("/plugin/repl", wrap!(repl));
+    router.insert("/plugin/stats", wrap!(stats));
 }
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use hyper::http::{Request, Response};
+use hyper::Body;
+use tracing::error;
+
+use crate::{
+    auto_option, auto_result,
+    handle::{model::Rule, response_data, response_msg},
+    net_proxy::HttpContext,
+    rules, wrap, DBPOOL, RULES,
+};
+
+use super::detect;
+
+pub async fn get_rules() -> Result<Vec<Rule>, sqlx::Error> {
+    let pool = &DBPOOL.clone();
+    sqlx::query_as::<_, Rule>("select * from `rule` order by `id`")
+        .fetch_all(pool)
+        .await
+}
+///启用的规则，按添加顺序排列
+pub async fn enabled_rules() -> Vec<Rule> {
+    let pool = &DBPOOL.clone();
+    let vec = sqlx::query_as::<_, Rule>("select * from `rule` where `enable`=1 order by `id`")
+        .fetch_all(pool)
+        .await;
+    auto_result!(vec,err=>{
+        error!("异常:{:?}", err);
+        vec![]
+    })
+}
+pub async fn get_rule_by_id(id: i64) -> Option<Rule> {
+    let pool = &DBPOOL.clone();
+    let res = sqlx::query_as::<_, Rule>("select * from `rule` where `id`=?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await;
+    auto_result!(res,err=>{
+        error!("异常:{:?}", err);
+        None
+    })
+}
+///id为0时新增，否则修改，返回规则id
+pub async fn save_rule(rule: &Rule) -> Result<i64, String> {
+    rules::validate(rule)?;
+    let pool = &DBPOOL.clone();
+    let sql = if rule.id == 0 {
+        "insert into `rule` (`kind`,`pattern`,`regex`,`target`,`phase`,`action`,`name`,`value`,`enable`,`remark`) values (?,?,?,?,?,?,?,?,?,?)"
+    } else {
+        "update `rule` set `kind`=?,`pattern`=?,`regex`=?,`target`=?,`phase`=?,`action`=?,`name`=?,`value`=?,`enable`=?,`remark`=? where `id`=?"
+    };
+    let query = sqlx::query(sql)
+        .bind(&rule.kind)
+        .bind(&rule.pattern)
+        .bind(rule.regex)
+        .bind(&rule.target)
+        .bind(&rule.phase)
+        .bind(&rule.action)
+        .bind(&rule.name)
+        .bind(&rule.value)
+        .bind(rule.enable)
+        .bind(&rule.remark);
+    let query = if rule.id == 0 {
+        query
+    } else {
+        query.bind(rule.id)
+    };
+    let res = query.execute(pool).await.map_err(|e| e.to_string())?;
+    RULES.invalidate().await;
+    if rule.id == 0 {
+        return Ok(res.last_insert_rowid());
+    }
+    if res.rows_affected() == 0 {
+        return Err("规则不存在".to_string());
+    }
+    Ok(rule.id)
+}
+pub async fn del_rule(id: i64) -> Result<bool, String> {
+    let pool = &DBPOOL.clone();
+    let res = sqlx::query("delete from `rule` where `id`=?")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    RULES.invalidate().await;
+    Ok(res.rows_affected() > 0)
+}
+///切换规则的启用状态，返回切换后的状态
+pub async fn toggle_rule(id: i64) -> Result<i64, String> {
+    let rule = get_rule_by_id(id).await.ok_or("规则不存在".to_string())?;
+    let enable = if rule.enable == 0 { 1 } else { 0 };
+    let pool = &DBPOOL.clone();
+    sqlx::query("update `rule` set `enable`=? where `id`=?")
+        .bind(enable)
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    RULES.invalidate().await;
+    Ok(enable)
+}
+
+async fn list(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
+    let rules = auto_result!(get_rules().await,err=>{
+        error!("{err}");
+        return response_msg(500, "查询规则异常");
+    });
+    response_data(&rules, "")
+}
+fn parse_rule(body: serde_json::Value) -> Result<Rule, Response<Body>> {
+    serde_json::from_value::<Rule>(body).map_err(|e| response_msg(500, format!("规则格式错误:{e}")))
+}
+///请求体为规则，新增的规则默认启用
+async fn add(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (_params, mut body) = auto_result!(detect(req).await);
+    if let Some(obj) = body.as_object_mut() {
+        obj.entry("enable").or_insert(1.into());
+    }
+    let mut rule = auto_result!(parse_rule(body));
+    rule.id = 0;
+    let id = auto_result!(save_rule(&rule).await,err=>{
+        return response_msg(500, err);
+    });
+    response_data(&id, "规则已添加")
+}
+///请求体为带id的完整规则
+async fn update(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (_params, body) = auto_result!(detect(req).await);
+    let rule = auto_result!(parse_rule(body));
+    if rule.id == 0 {
+        return response_msg(500, "缺少规则id");
+    }
+    let id = auto_result!(save_rule(&rule).await,err=>{
+        return response_msg(500, err);
+    });
+    response_data(&id, "规则已保存")
+}
+async fn del(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (mut params, _body) = auto_result!(detect(req).await);
+    let id = auto_option!(
+        params.remove("id").and_then(|v| v.parse::<i64>().ok()),
+        response_msg(500, "缺少规则id")
+    );
+    match del_rule(id).await {
+        Ok(true) => response_msg(200, "删除规则成功"),
+        Ok(false) => response_msg(500, "规则不存在"),
+        Err(err) => {
+            error!("{err}");
+            response_msg(500, "删除规则异常")
+        }
+    }
+}
+async fn enable(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (mut params, _body) = auto_result!(detect(req).await);
+    let id = auto_option!(
+        params.remove("id").and_then(|v| v.parse::<i64>().ok()),
+        response_msg(500, "缺少规则id")
+    );
+    let enable = auto_result!(toggle_rule(id).await,err=>{
+        return response_msg(500, err);
+    });
+    response_data(&enable, "")
+}
+
+pub fn route(router: &mut HashMap<&'static str, Box<super::AsyncFn>>) {
+    router.insert("/rules/list", wrap!(list));
+    router.insert("/rules/add", wrap!(add));
+    router.insert("/rules/update", wrap!(update));
+    router.insert("/rules/del", wrap!(del));
+    router.insert("/rules/enable", wrap!(enable));
+}
@@ -13,12 +13,12 @@ use serde_json::json;
 use tracing::error;
 
 use crate::{
-    auto_result,
-    handle::{response_data, response_download_file, response_msg},
-    wrap, AUTH, CLIENT_MANAGER,
+    auto_option, auto_result,
+    handle::{net_agent, response_content, response_data, response_download_file, response_msg},
+    wrap, AUTH, CLIENT_MANAGER, METRICS,
 };
 
-use super::config;
+use super::{config, detect};
 
 async fn download_ca(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
     let auth = AUTH.get().unwrap().clone();
@@ -42,6 +42,25 @@ async fn restart(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
 
     return response_msg(200, "正在重启中...");
 }
+///列出所有域及其创建时间、最后活跃时间和websocket会话数
+async fn scopes(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
+    response_data(&CLIENT_MANAGER.scope_infos().await, "")
+}
+///关闭域，断开它的websocket会话并通知插件
+async fn kill_scope(_ctx: HttpContext, req: Request<Body>) -> Response<Body> {
+    let (mut params, _body) = auto_result!(detect(req).await);
+    let id = auto_option!(params.remove("id"), response_msg(500, "缺少域id"));
+    let scope = auto_option!(
+        CLIENT_MANAGER.close_scope(&id).await,
+        response_msg(500, "域不存在")
+    );
+    net_agent::on_scope_expire(&scope, "closed").await;
+    response_msg(200, "域已关闭")
+}
+///Prometheus 文本格式的运行指标
+async fn metrics(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
+    response_content(200, &METRICS.render())
+}
 async fn server_info(_ctx: HttpContext, _req: Request<Body>) -> Response<Body> {
     let local_ip = auto_result!(local_ip(),err=>{
         error!("获取本机内网地址失败：{}",err);
@@ -67,4 +86,7 @@ pub fn route(router: &mut HashMap<&'static str, Box<super::AsyncFn>>) {
     router.insert("/server/serverInfo", wrap!(server_info));
     router.insert("/server/restart", wrap!(restart));
     router.insert("/server/downloadCa", wrap!(download_ca));
+    router.insert("/metrics", wrap!(metrics));
+    router.insert("/server/scopes", wrap!(scopes));
+    router.insert("/server/killScope", wrap!(kill_scope));
 }
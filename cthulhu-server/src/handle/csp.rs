@@ -0,0 +1,420 @@
+use std::sync::Arc;
+
+use content_security_policy::{Directive, Policy, PolicyDisposition, PolicySource};
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY_REPORT_ONLY,
+    },
+    HeaderMap,
+};
+use rquickjs::{async_with, Object};
+use tracing::error;
+
+use crate::{auto_result, core::PluginCtx};
+
+const HTTPS_SOURCE: &str = "https://*.cthulhu.server";
+const WSS_SOURCE: &str = "wss://*.cthulhu.server";
+
+///在页面原有的csp上只添加注入脚本需要的来源，其它指令和来源保持不变。
+///脚本优先用nonce放行，页面本来就允许内联脚本时改为添加 cthulhu 的域名，避免 'unsafe-inline' 失效
+#[derive(Debug, Clone)]
+pub struct CspRewriter {
+    //为空时脚本按 cthulhu 的域名放行
+    nonce: String,
+    //为true时只添加nonce，脚本都内联在页面中
+    inline: bool,
+    //插件通过 server.csp 声明需要的来源
+    plugin_policies: Vec<Policy>,
+}
+
+impl CspRewriter {
+    pub async fn new(plugins: &[Arc<PluginCtx>], nonce: &str, inline: bool) -> Self {
+        let mut plugin_policies = vec![];
+        if !inline {
+            for plugin in plugins {
+                if let Some(policy) = plugin_policy(plugin).await {
+                    plugin_policies.push(policy);
+                }
+            }
+        }
+        Self {
+            nonce: nonce.to_string(),
+            inline,
+            plugin_policies,
+        }
+    }
+
+    ///worker 中的脚本没有nonce，模块 worker 导入的注入脚本按域名放行
+    pub async fn worker(plugins: &[Arc<PluginCtx>]) -> Self {
+        Self::new(plugins, "", false).await
+    }
+
+    ///改写响应头中所有的 Content-Security-Policy 和 Content-Security-Policy-Report-Only
+    pub fn rewrite_headers(&self, headers: &mut HeaderMap) {
+        self.rewrite_header(headers, CONTENT_SECURITY_POLICY, PolicyDisposition::Enforce);
+        self.rewrite_header(
+            headers,
+            CONTENT_SECURITY_POLICY_REPORT_ONLY,
+            PolicyDisposition::Report,
+        );
+    }
+    fn rewrite_header(
+        &self,
+        headers: &mut HeaderMap,
+        name: HeaderName,
+        disposition: PolicyDisposition,
+    ) {
+        let values = headers
+            .get_all(&name)
+            .iter()
+            .cloned()
+            .collect::<Vec<HeaderValue>>();
+        if values.is_empty() {
+            return;
+        }
+        //多个头部各自生效，逐个改写并保持原来的顺序
+        headers.remove(&name);
+        for value in values {
+            let csp = self.rewrite(&String::from_utf8_lossy(value.as_bytes()), disposition);
+            let csp = auto_result!(HeaderValue::from_str(&csp),err=>{
+                error!("rewrite csp failed {err} {csp}");
+                value
+            });
+            headers.append(&name, csp);
+        }
+    }
+
+    ///改写逗号分隔的多个策略，没有改动的策略原样保留
+    pub fn rewrite(&self, list: &str, disposition: PolicyDisposition) -> String {
+        split_policies(list)
+            .into_iter()
+            .map(|serialized| {
+                let mut policy = Policy::parse(serialized, PolicySource::Header, disposition);
+                if self.rewrite_policy(&mut policy) {
+                    serialize(&policy)
+                } else {
+                    serialized.trim().to_string()
+                }
+            })
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn rewrite_policy(&self, policy: &mut Policy) -> bool {
+        let mut changed = false;
+        //<script> 元素由 script-src-elem 限制，没有时回退到 script-src 和 default-src
+        if let Some(governing) = governing(policy, "script-src-elem") {
+            let name = if governing.name == "script-src-elem" {
+                "script-src-elem"
+            } else {
+                "script-src"
+            };
+            let sources = if self.nonce.is_empty() {
+                vec![HTTPS_SOURCE.to_string()]
+            } else if !allows_inline(&governing.value) {
+                vec![format!("'nonce-{}'", self.nonce)]
+            } else if self.inline {
+                vec![]
+            } else {
+                vec![HTTPS_SOURCE.to_string()]
+            };
+            changed |= allow(policy, name, &sources);
+        }
+        if self.inline {
+            return changed;
+        }
+        //content.js 的消息通道和插件请求，悬浮球的样式、图片和页面
+        let https = [HTTPS_SOURCE.to_string()];
+        changed |= allow(
+            policy,
+            "connect-src",
+            &[HTTPS_SOURCE.to_string(), WSS_SOURCE.to_string()],
+        );
+        changed |= allow(policy, "style-src-elem", &https);
+        changed |= allow(policy, "img-src", &https);
+        changed |= allow(policy, "frame-src", &https);
+        for plugin_policy in &self.plugin_policies {
+            for directive in &plugin_policy.directive_set {
+                changed |= allow(policy, &directive.name, &directive.value);
+            }
+        }
+        changed
+    }
+}
+
+//取出插件在 server.csp 中声明的策略
+async fn plugin_policy(plugin: &PluginCtx) -> Option<Policy> {
+    let ctx = plugin.ctx.as_ref()?;
+    let ctx = ctx.lock().await;
+    async_with!(ctx=> |ctx|{
+        let globals=ctx.globals();
+        let server=globals.get::<_,Object>("server").ok()?;
+        let csp= server.get::<_,String>("csp").unwrap_or_default();
+        if csp.trim().is_empty() {
+            return None;
+        }
+        let policy = Policy::parse(csp.as_str(), PolicySource::Header, PolicyDisposition::Enforce);
+        if !policy.is_valid() {
+            let _=ctx.throw(rquickjs::Value::from_string(rquickjs::String::from_str(ctx.clone(), "invalid content security policy").unwrap()));
+            return None;
+        }
+        Some(policy)
+    })
+    .await
+}
+
+//指令的回退顺序，页面没有声明该指令时由后面的指令限制
+fn fallbacks(name: &str) -> Vec<&str> {
+    match name {
+        "script-src-elem" | "script-src-attr" => vec![name, "script-src", "default-src"],
+        "style-src-elem" | "style-src-attr" => vec![name, "style-src", "default-src"],
+        "frame-src" => vec![name, "child-src", "default-src"],
+        "worker-src" => vec![name, "child-src", "script-src", "default-src"],
+        "script-src" | "style-src" | "child-src" | "connect-src" | "font-src" | "img-src"
+        | "manifest-src" | "media-src" | "object-src" => vec![name, "default-src"],
+        _ => vec![name],
+    }
+}
+//实际限制 name 的指令
+fn governing<'a>(policy: &'a Policy, name: &str) -> Option<&'a Directive> {
+    fallbacks(name)
+        .into_iter()
+        .find_map(|name| policy.directive_set.iter().find(|v| v.name == name))
+}
+
+//给限制 name 的指令添加来源，返回是否有改动。
+//限制它的是回退的指令时新建 name 指令并复制回退指令的来源，不放宽其它类型的资源
+fn allow(policy: &mut Policy, name: &str, sources: &[String]) -> bool {
+    if sources.is_empty() {
+        return false;
+    }
+    let governing = match governing(policy, name) {
+        Some(v) => v.clone(),
+        //没有限制
+        None => return false,
+    };
+    let index = match policy.directive_set.iter().position(|v| v.name == name) {
+        Some(index) => index,
+        None => {
+            policy.directive_set.push(Directive {
+                name: name.to_string(),
+                value: governing.value.clone(),
+            });
+            policy.directive_set.len() - 1
+        }
+    };
+    let directive = &mut policy.directive_set[index];
+    let mut changed = directive.value != governing.value || directive.name != governing.name;
+    for source in sources {
+        if allows_source(&directive.value, source) {
+            continue;
+        }
+        //'none' 和其它来源同时存在时会被忽略，这里直接去掉
+        directive
+            .value
+            .retain(|v| !v.eq_ignore_ascii_case("'none'"));
+        directive.value.push(source.clone());
+        changed = true;
+    }
+    changed
+}
+
+fn allows_source(values: &[String], source: &str) -> bool {
+    values.iter().any(|v| v.eq_ignore_ascii_case(source))
+}
+
+//只有 'unsafe-inline' 时已经允许内联脚本，加上nonce反而会让 'unsafe-inline' 失效
+fn allows_inline(values: &[String]) -> bool {
+    let mut unsafe_inline = false;
+    for value in values {
+        let value = value.to_ascii_lowercase();
+        if value == "'unsafe-inline'" {
+            unsafe_inline = true;
+        } else if value.starts_with("'nonce-")
+            || value.starts_with("'sha")
+            || value == "'strict-dynamic'"
+        {
+            return false;
+        }
+    }
+    unsafe_inline
+}
+
+//已知的指令名，策略之间的逗号后面总是一个指令
+const DIRECTIVES: [&str; 31] = [
+    "base-uri",
+    "block-all-mixed-content",
+    "child-src",
+    "connect-src",
+    "default-src",
+    "fenced-frame-src",
+    "font-src",
+    "form-action",
+    "frame-ancestors",
+    "frame-src",
+    "img-src",
+    "manifest-src",
+    "media-src",
+    "navigate-to",
+    "object-src",
+    "plugin-types",
+    "prefetch-src",
+    "report-to",
+    "report-uri",
+    "require-trusted-types-for",
+    "sandbox",
+    "script-src",
+    "script-src-attr",
+    "script-src-elem",
+    "style-src",
+    "style-src-attr",
+    "style-src-elem",
+    "trusted-types",
+    "upgrade-insecure-requests",
+    "webrtc",
+    "worker-src",
+];
+
+//拆分逗号分隔的多个策略。只有逗号后面紧跟指令名时才是策略之间的分隔，
+//地址中的逗号，例如 report-uri /csp?a=1,2，仍属于当前策略
+fn split_policies(list: &str) -> Vec<&str> {
+    let mut policies = vec![];
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        if c != ',' || !starts_with_directive(&list[i + 1..]) {
+            continue;
+        }
+        policies.push(&list[start..i]);
+        start = i + 1;
+    }
+    policies.push(&list[start..]);
+    policies
+}
+
+//空策略也算作分隔，和按逗号拆分的结果一致
+fn starts_with_directive(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|c: char| c.is_ascii_whitespace() || c == ';' || c == ',')
+        .unwrap_or(rest.len());
+    let name = rest[..end].to_ascii_lowercase();
+    name.is_empty() || DIRECTIVES.contains(&name.as_str())
+}
+
+fn serialize(policy: &Policy) -> String {
+    policy
+        .directive_set
+        .iter()
+        .map(|v| {
+            if v.value.is_empty() {
+                v.name.clone()
+            } else {
+                format!("{} {}", v.name, v.value.join(" "))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(inline: bool) -> CspRewriter {
+        CspRewriter {
+            nonce: "abc".to_string(),
+            inline,
+            plugin_policies: vec![],
+        }
+    }
+    fn rewrite(csp: &str, inline: bool) -> String {
+        rewriter(inline).rewrite(csp, PolicyDisposition::Enforce)
+    }
+
+    #[test]
+    fn keeps_strict_policy_and_adds_nonce() {
+        let csp = rewrite("script-src 'strict-dynamic' 'nonce-page' 'sha256-x'", true);
+        assert_eq!(
+            csp,
+            "script-src 'strict-dynamic' 'nonce-page' 'sha256-x' 'nonce-abc'"
+        );
+    }
+
+    #[test]
+    fn adds_host_when_inline_is_allowed() {
+        let csp = rewrite("script-src 'self' 'unsafe-inline'", false);
+        assert_eq!(
+            csp,
+            format!("script-src 'self' 'unsafe-inline' {HTTPS_SOURCE}")
+        );
+        let csp = rewrite("script-src 'self' 'unsafe-inline'", true);
+        assert_eq!(csp, "script-src 'self' 'unsafe-inline'");
+    }
+
+    #[test]
+    fn creates_directive_instead_of_widening_default_src() {
+        let csp = rewrite("default-src 'none'", true);
+        assert_eq!(csp, "default-src 'none'; script-src 'nonce-abc'");
+    }
+
+    #[test]
+    fn worker_scripts_are_allowed_by_host() {
+        let mut rewriter = rewriter(false);
+        rewriter.nonce = String::new();
+        let csp = rewriter.rewrite("script-src 'self'", PolicyDisposition::Enforce);
+        assert_eq!(csp, format!("script-src 'self' {HTTPS_SOURCE}"));
+    }
+
+    #[test]
+    fn unrelated_policies_are_untouched() {
+        let csp = rewrite("frame-ancestors 'self' ,  script-src 'self'", true);
+        assert_eq!(csp, "frame-ancestors 'self', script-src 'self' 'nonce-abc'");
+    }
+
+    #[test]
+    fn commas_inside_sources_do_not_split_policies() {
+        let csp = rewrite(
+            "script-src 'self'; report-uri /csp?a=1,2,script-src x",
+            true,
+        );
+        assert_eq!(
+            csp,
+            "script-src 'self' 'nonce-abc'; report-uri /csp?a=1,2, script-src x 'nonce-abc'"
+        );
+    }
+
+    #[test]
+    fn multiple_headers_are_rewritten_separately() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("default-src 'self', frame-ancestors 'none'"),
+        );
+        headers.append(
+            CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("script-src 'self'; report-uri https://r.com/a,b"),
+        );
+        headers.append(
+            CONTENT_SECURITY_POLICY_REPORT_ONLY,
+            HeaderValue::from_static("script-src 'none'"),
+        );
+        rewriter(true).rewrite_headers(&mut headers);
+        let values = headers
+            .get_all(CONTENT_SECURITY_POLICY)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            values,
+            [
+                "default-src 'self'; script-src 'self' 'nonce-abc', frame-ancestors 'none'",
+                "script-src 'self' 'nonce-abc'; report-uri https://r.com/a,b",
+            ]
+        );
+        assert_eq!(
+            headers[CONTENT_SECURITY_POLICY_REPORT_ONLY],
+            "script-src 'nonce-abc'"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use hyper::{
+    header::{ACCEPT, UPGRADE},
+    HeaderMap, Uri,
+};
+
+///content.js 和 worker.js 创建 worker 时在脚本地址上加的参数，值为 worker 的类型
+pub const WORKER_PARAM: &str = "CTHULHU_DEST";
+pub const WORKER_TYPES: &[&str] = &["worker", "sharedworker", "serviceworker"];
+///content.js 和 worker.js 在 service worker 和它 importScripts 的脚本地址上加的参数，值为页面所属域的id
+pub const SCOPE_PARAM: &str = "CTHULHU_SCOPE_ID";
+
+///取出地址中名称为 key 并且 accept 认可的第一个参数，返回参数值和去掉这一对参数后的地址。
+///按原始的查询字符串比较完整的参数名，其它参数的顺序和编码原样保留
+pub fn take_param(uri: &Uri, key: &str, accept: impl Fn(&str) -> bool) -> Option<(String, Uri)> {
+    let pairs = uri.query()?.split('&').collect::<Vec<&str>>();
+    let (index, value) = pairs.iter().enumerate().find_map(|(i, pair)| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        if k != key {
+            return None;
+        }
+        let v = urlencoding::decode(v).ok()?;
+        accept(&v).then(|| (i, v.to_string()))
+    })?;
+    let rest = pairs
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != index)
+        .map(|(_, v)| *v)
+        .collect::<Vec<&str>>()
+        .join("&");
+    let path_and_query = if rest.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{rest}", uri.path())
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Some((value, Uri::from_parts(parts).ok()?))
+}
+
+///请求的资源类型，取值和 sec-fetch-dest 相同，无法识别时为空。
+///Firefox 和 Safari 等浏览器不一定发送 sec-fetch-dest，
+///这时依次按 Service-Worker 头部、Accept 和地址后缀推断，
+///worker 脚本地址上 WORKER_PARAM 标记的类型在 scope_from_param 中补上
+pub fn request_dest(uri: &Uri, headers: &HeaderMap) -> String {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    };
+    let dest = header("sec-fetch-dest");
+    if !dest.is_empty() {
+        return dest;
+    }
+    //各浏览器请求 service worker 脚本时都会带上 Service-Worker: script
+    if header("service-worker") == "script" {
+        return "serviceworker".to_string();
+    }
+    if header(UPGRADE.as_str()) == "websocket" {
+        return "websocket".to_string();
+    }
+    if !header("x-requested-with").is_empty() {
+        return "empty".to_string();
+    }
+    guess_dest(uri.path(), &header(ACCEPT.as_str()))
+        .unwrap_or_default()
+        .to_string()
+}
+
+//浏览器对页面、样式、图片和音视频发送各自的 Accept，
+//脚本、xhr 和 fetch 一般为 */*，这时再看地址后缀
+fn guess_dest(path: &str, accept: &str) -> Option<&'static str> {
+    let first = accept
+        .split(',')
+        .next()
+        .and_then(|v| v.split(';').next())
+        .unwrap_or_default()
+        .trim();
+    let by_accept = match first {
+        "text/html" | "application/xhtml+xml" => Some("document"),
+        "text/css" => Some("style"),
+        "application/manifest+json" => Some("manifest"),
+        "application/font-woff2" | "application/font-woff" => Some("font"),
+        v if v.starts_with("image/") => Some("image"),
+        v if v.starts_with("video/") => Some("video"),
+        v if v.starts_with("audio/") => Some("audio"),
+        v if v.starts_with("font/") => Some("font"),
+        _ => None,
+    };
+    if by_accept.is_some() {
+        return by_accept;
+    }
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let ext = name.rsplit_once('.')?.1.to_ascii_lowercase();
+    match ext.as_str() {
+        "js" | "mjs" => Some("script"),
+        "css" => Some("style"),
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "svg" | "ico" | "bmp" => Some("image"),
+        "woff" | "woff2" | "ttf" | "otf" | "eot" => Some("font"),
+        "mp4" | "webm" | "ogv" | "m4v" => Some("video"),
+        "mp3" | "ogg" | "wav" | "m4a" | "flac" | "aac" => Some("audio"),
+        "vtt" => Some("track"),
+        _ => None,
+    }
+}
+
+///请求时没能识别的资源，按响应的 Content-Type 补上类型。
+///html 不在其中，xhr 和 fetch 也可能拿到 html
+pub fn response_dest(ctype: &str) -> Option<&'static str> {
+    let ctype = ctype.trim().to_ascii_lowercase();
+    match ctype.as_str() {
+        v if v.contains("javascript") || v.contains("ecmascript") => Some("script"),
+        "text/css" => Some("style"),
+        v if v.starts_with("image/") => Some("image"),
+        v if v.starts_with("font/") => Some("font"),
+        v if v.starts_with("video/") => Some("video"),
+        v if v.starts_with("audio/") => Some("audio"),
+        "text/vtt" => Some("track"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn navigation_accept_is_document() {
+        let accept = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(guess_dest("/index", accept), Some("document"));
+    }
+
+    #[test]
+    fn firefox_subresource_accepts() {
+        assert_eq!(guess_dest("/a.css", "text/css,*/*;q=0.1"), Some("style"));
+        assert_eq!(guess_dest("/a", "image/avif,image/webp,*/*"), Some("image"));
+        assert_eq!(
+            guess_dest(
+                "/a",
+                "application/font-woff2;q=1.0,application/font-woff;q=0.9,*/*;q=0.8"
+            ),
+            Some("font")
+        );
+    }
+
+    #[test]
+    fn take_param_removes_only_the_exact_pair() {
+        let uri = Uri::from_static(
+            "https://a.com/sw.js?b=%20x&MY_CTHULHU_SCOPE_ID=1&CTHULHU_SCOPE_ID=s1&a=2+3&CTHULHU_SCOPE_ID=s2",
+        );
+        let (value, rest) = take_param(&uri, SCOPE_PARAM, |v| v == "s2").unwrap();
+        assert_eq!(value, "s2");
+        assert_eq!(
+            rest,
+            "https://a.com/sw.js?b=%20x&MY_CTHULHU_SCOPE_ID=1&CTHULHU_SCOPE_ID=s1&a=2+3"
+        );
+
+        //只有这一个参数时去掉整个查询字符串
+        let uri = Uri::from_static("/sw.js?CTHULHU_SCOPE_ID=s1");
+        let (_, rest) = take_param(&uri, SCOPE_PARAM, |_| true).unwrap();
+        assert_eq!(rest, "/sw.js");
+
+        //参数名只是包含 key 或者值不被认可时不改动
+        let uri = Uri::from_static("https://a.com/?XCTHULHU_SCOPE_ID=s1&CTHULHU_SCOPE_ID=s3");
+        assert!(take_param(&uri, SCOPE_PARAM, |v| v == "s1").is_none());
+        assert!(take_param(&Uri::from_static("https://a.com/"), SCOPE_PARAM, |_| true).is_none());
+
+        let uri = Uri::from_static("https://a.com/w.js?CTHULHU_DEST=page&v=1&CTHULHU_DEST=worker");
+        let (value, rest) = take_param(&uri, WORKER_PARAM, |v| WORKER_TYPES.contains(&v)).unwrap();
+        assert_eq!(value, "worker");
+        assert_eq!(rest, "https://a.com/w.js?CTHULHU_DEST=page&v=1");
+    }
+
+    #[test]
+    fn wildcard_accept_falls_back_to_extension() {
+        assert_eq!(guess_dest("/static/app.min.JS", "*/*"), Some("script"));
+        assert_eq!(guess_dest("/api/list", "*/*"), None);
+        assert_eq!(guess_dest("/v1.2/list", ""), None);
+    }
+}
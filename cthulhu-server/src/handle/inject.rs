@@ -0,0 +1,610 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use content_security_policy::PolicyDisposition;
+use encoding_rs::{Encoding, UTF_8};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::CONTENT_LENGTH,
+    Body, Response,
+};
+use lazy_static::lazy_static;
+use lol_html::{
+    element, end, html_content::ContentType, AsciiCompatibleEncoding, HtmlRewriter, Settings,
+};
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::{
+    auto_option, auto_result,
+    core::PluginCtx,
+    handle::{
+        api::config,
+        csp::CspRewriter,
+        model::{ContentScript, RunAt, World},
+    },
+    jsbind::{script, server::Scope},
+    matcher::MatchTarget,
+    sri::SriRewriter,
+    utils, PLUGIN_MANAGER,
+};
+
+//判断内容是否为html文档时读取的长度，和浏览器嗅探 meta charset 的范围一致
+const SNIFF_LEN: usize = 1024;
+//带 integrity 的脚本和样式，以及解析它们的相对地址用到的 <base>
+const INTEGRITY_SELECTOR: &str = "base[href], script[integrity][src], link[integrity][href]";
+
+///外部脚本标签，defer 为true时在文档解析完成后按顺序执行
+fn script_tag(nonce: &str, src: &str, defer: bool) -> String {
+    format!(
+        r#"<script nonce="{}" src="{}" charset="utf-8" type="text/javascript"{}></script>"#,
+        escape_attr(nonce),
+        escape_attr(src),
+        if defer { " defer" } else { "" }
+    )
+}
+///内联脚本标签，脚本内容中的 </script 会被转义
+pub fn inline_script_tag(nonce: &str, code: &str) -> String {
+    let code = code.replace("</script", "<\\/script");
+    format!(
+        r#"<script nonce="{}" type="text/javascript">{code}</script>"#,
+        escape_attr(nonce)
+    )
+}
+//DOMContentLoaded 之后依次插入，async 为false时按插入顺序执行，动态插入的脚本同样需要nonce
+fn idle_script_tag(nonce: &str, srcs: &[String]) -> String {
+    let srcs = serde_json::to_string(srcs).unwrap();
+    inline_script_tag(
+        nonce,
+        &format!(
+            r#"(()=>{{const nonce=document.currentScript.nonce;const run=()=>setTimeout(()=>{{for(const src of {srcs}){{const s=document.createElement("script");s.src=src;s.nonce=nonce;s.charset="utf-8";s.async=false;document.head.appendChild(s)}}}});document.readyState==="loading"?document.addEventListener("DOMContentLoaded",run,{{once:true}}):run()}})()"#
+        ),
+    )
+}
+fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+///插件的文件地址，isolated 的脚本由插件服务包在独立的作用域中返回
+pub fn content_script_url(ctx: &PluginCtx, script: &ContentScript) -> String {
+    let url = format!(
+        "https://{}.plugin.cthulhu.server/{}",
+        ctx.plugin.id, script.path
+    );
+    match script.world {
+        World::Page => url,
+        World::Isolated => format!("{url}?world=isolated"),
+    }
+}
+///把脚本包在独立的作用域中，顶层的变量和函数不会成为页面的全局变量
+pub fn isolate(code: &str) -> String {
+    format!("(function(){{\n{code}\n}}).call(self);\n")
+}
+
+///按注入顺序生成页面需要的脚本：scope id、content.js，
+///然后按插件优先级依次是插件的动态脚本和匹配当前页面的内容脚本。
+///document_start 的脚本在页面脚本之前执行，document_end 和 document_idle 的脚本分别推迟到解析完成后和 DOMContentLoaded 之后
+pub fn page_scripts(
+    nonce: &str,
+    scope_id: &str,
+    all: &[Arc<PluginCtx>],
+    target: &MatchTarget,
+) -> String {
+    let mut start = vec![
+        inline_script_tag(nonce, &format!("self['CTHULHU_SCOPE_ID']='{scope_id}'")),
+        script_tag(nonce, "https://web.cthulhu.server/content.js", false),
+    ];
+    let mut end = vec![];
+    let mut idle = vec![];
+    for ctx in all {
+        let id = &ctx.plugin.id;
+        ctx.plugin
+            .dynamic_links
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .for_each(|link| {
+                let url = format!("https://{id}.plugin.cthulhu.server/dynamic/{link}");
+                start.push(script_tag(nonce, &url, false));
+            });
+        for script in ctx.content_scripts(target) {
+            let url = content_script_url(ctx, script);
+            match script.run_at {
+                RunAt::DocumentStart => start.push(script_tag(nonce, &url, false)),
+                RunAt::DocumentEnd => end.push(script_tag(nonce, &url, true)),
+                RunAt::DocumentIdle => idle.push(url),
+            }
+        }
+    }
+    start.extend(end);
+    if !idle.is_empty() {
+        start.push(idle_script_tag(nonce, &idle));
+    }
+    start.join("")
+}
+
+///是否把脚本内联到页面中，对应配置 inject.inline
+pub async fn inline_enabled() -> bool {
+    config::get_config("inject")
+        .await
+        .and_then(|v| v.get("inline").and_then(|v| v.as_bool()))
+        .unwrap_or(false)
+}
+
+//工作目录中的文件
+async fn read_workspace(name: &str) -> Result<String, String> {
+    let workspace = config::get_config("workspace")
+        .await
+        .and_then(|v| v.as_str().map(|v| v.to_string()))
+        .filter(|v| !v.is_empty())
+        .ok_or("请设置cthulhu server工作目录".to_string())?;
+    let bytes = utils::read_bytes(format!("{workspace}/{name}")).map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+///把 page_scripts 中的脚本按相同的顺序拼成一段代码。
+///defer 为true时 document_end 和 document_idle 的脚本在文档解析中推迟到 DOMContentLoaded 执行，
+///文档已经解析完成时立即执行，这样内联注入的代码在子页面中复用时也会执行。
+///这两类脚本会被包在函数中，顶层变量不再是全局变量；为false时所有脚本立即执行
+pub async fn bundle_scripts(
+    scope_key: &Scope,
+    all: &[Arc<PluginCtx>],
+    target: &MatchTarget,
+    defer: bool,
+) -> Result<String, String> {
+    let mut scripts = vec![
+        format!("self['CTHULHU_SCOPE_ID']='{}'", scope_key.id),
+        read_workspace("content.js").await?,
+    ];
+    for ctx in all {
+        if !ctx.plugin.dynamic_links.trim().is_empty() {
+            scripts.extend(ctx.dynamic_scripts(scope_key.clone()).await);
+        }
+        for script in ctx.content_scripts(target) {
+            let code = auto_result!(ctx.plugin.read_content(&script.path),err=>{
+                error!("read content script {} of plugin {} failed {err}", script.path, ctx.plugin.id);
+                continue;
+            });
+            let code = match script.world {
+                World::Page => code,
+                World::Isolated => isolate(&code),
+            };
+            let code = match script.run_at {
+                _ if !defer => code,
+                RunAt::DocumentStart => code,
+                RunAt::DocumentEnd => format!(
+                    "(function(run){{document.readyState==='loading'?document.addEventListener('DOMContentLoaded',run,{{once:true}}):run()}})(function(){{\n{code}\n}})"
+                ),
+                RunAt::DocumentIdle => format!(
+                    "(function(run){{document.readyState==='loading'?document.addEventListener('DOMContentLoaded',()=>setTimeout(run),{{once:true}}):setTimeout(run)}})(function(){{\n{code}\n}})"
+                ),
+            };
+            scripts.push(code);
+        }
+    }
+    let mut code = scripts.join(";\n");
+    code.push_str(";\n");
+    Ok(code)
+}
+
+///worker 中注入的脚本：域id、worker.js、插件的动态脚本和 worker 脚本
+pub async fn worker_bundle(scope_key: &Scope, all: &[Arc<PluginCtx>]) -> Result<String, String> {
+    let mut scripts = vec![
+        format!("self['CTHULHU_SCOPE_ID']='{}'", scope_key.id),
+        read_workspace("worker.js").await?,
+    ];
+    for ctx in all {
+        if !ctx.plugin.dynamic_links.trim().is_empty() {
+            scripts.extend(ctx.dynamic_scripts(scope_key.clone()).await);
+        }
+        if let Ok(worker) = ctx.plugin.read_worker() {
+            scripts.push(worker);
+        }
+    }
+    let mut code = scripts.join(";\n");
+    code.push_str(";\n");
+    Ok(code)
+}
+
+///在 worker 脚本前加上 worker_bundle，并按注入需要改写 worker 的csp。
+///模块中的 import 先于模块本身的代码执行，模块 worker 改为在开头导入 web.cthulhu.server/worker-bundle.js
+pub async fn inject_worker(
+    res: Response<Body>,
+    scope_key: &Scope,
+    target: &MatchTarget,
+) -> Result<Response<Body>, String> {
+    if !res.status().is_success() {
+        return Ok(res);
+    }
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(target).await;
+    let (mut parts, body) = res.into_parts();
+    let bytes = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| e.to_string())?;
+    let prefix = if script::is_module(&String::from_utf8_lossy(&bytes)) {
+        let url = target.url.as_deref().unwrap_or_default();
+        let dest = target.dest.as_deref().unwrap_or("worker");
+        format!(
+            "import \"https://web.cthulhu.server/worker-bundle.js?scope={}&dest={dest}&url={}\";\n",
+            scope_key.id,
+            urlencoding::encode(url)
+        )
+    } else {
+        worker_bundle(scope_key, &all).await?
+    };
+    CspRewriter::worker(&all)
+        .await
+        .rewrite_headers(&mut parts.headers);
+    let mut body = prefix.into_bytes();
+    body.extend_from_slice(&bytes);
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+//被标记为html的内容不一定是完整的文档，比如接口返回的片段，这类内容不注入
+fn looks_like_document(head: &[u8], is_document: bool) -> bool {
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let start = head.iter().position(|v| !v.is_ascii_whitespace());
+    let head = match start {
+        Some(start) => &head[start..],
+        None => return false,
+    };
+    if !head.starts_with(b"<") || head.starts_with(b"<?xml") {
+        return false;
+    }
+    if is_document {
+        return true;
+    }
+    let lower = head.to_ascii_lowercase();
+    let contains = |tag: &[u8]| lower.windows(tag.len()).any(|v| v == tag);
+    contains(b"<!doctype html") || contains(b"<html") || contains(b"<head")
+}
+
+//把已经读取的开头和剩余的内容重新拼成body
+fn rejoin(head: Vec<u8>, body: Body) -> Body {
+    if head.is_empty() {
+        return body;
+    }
+    let head = futures::stream::once(async move { Ok::<Bytes, hyper::Error>(Bytes::from(head)) });
+    Body::wrap_stream(futures::StreamExt::chain(head, body))
+}
+
+///把 scripts 注入到html文档的 <head> 开头，没有 <head> 时在第一个元素前补上。
+///is_document 为true表示请求的资源类型是文档，取自 sec-fetch-dest 或代理推断的类型。
+///charset 为响应头中声明的编码，没有时按 <meta charset> 切换。
+///页面中 <meta http-equiv="Content-Security-Policy"> 的策略由 csp 改写，
+///被插件修改的脚本和样式上的 integrity 由 sri 改写。
+///返回的body是流式改写的，不是html文档或者编码不支持时原样返回
+pub async fn inject_html(
+    mut body: Body,
+    charset: Option<&str>,
+    scripts: String,
+    is_document: bool,
+    csp: CspRewriter,
+    sri: SriRewriter,
+) -> Result<Body, hyper::Error> {
+    let mut head = vec![];
+    while head.len() < SNIFF_LEN {
+        match body.data().await {
+            Some(chunk) => head.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    if !looks_like_document(&head, is_document) {
+        return Ok(rejoin(head, body));
+    }
+    let declared = charset.and_then(|v| Encoding::for_label(v.trim().as_bytes()));
+    let encoding = auto_option!(AsciiCompatibleEncoding::new(declared.unwrap_or(UTF_8)), {
+        return Ok(rejoin(head, body));
+    });
+
+    let (sender, out) = Body::channel();
+    let job = RewriteJob {
+        head,
+        body,
+        sender,
+        scripts,
+        encoding,
+        adjust_charset: declared.is_none(),
+        csp,
+        sri,
+    };
+    //按顺序分给各个改写线程
+    let index = NEXT_REWRITER.fetch_add(1, Ordering::Relaxed) % REWRITERS.len();
+    if let Err(err) = REWRITERS[index].send(job) {
+        //改写线程已经退出，只能放弃这个响应
+        error!("html rewriter is gone");
+        err.0.sender.abort();
+    }
+    Ok(out)
+}
+
+//一个html文档的改写任务
+struct RewriteJob {
+    //嗅探时已经读出的开头
+    head: Vec<u8>,
+    body: Body,
+    sender: hyper::body::Sender,
+    scripts: String,
+    encoding: AsciiCompatibleEncoding,
+    //响应头没有声明编码时按 <meta charset> 切换
+    adjust_charset: bool,
+    csp: CspRewriter,
+    sri: SriRewriter,
+}
+
+//改写线程数的上限
+const MAX_REWRITERS: usize = 4;
+
+lazy_static! {
+    static ref REWRITERS: Vec<mpsc::UnboundedSender<RewriteJob>> = {
+        let count = std::thread::available_parallelism()
+            .map(|v| v.get())
+            .unwrap_or(1)
+            .min(MAX_REWRITERS);
+        (0..count).map(spawn_rewriter).collect()
+    };
+}
+static NEXT_REWRITER: AtomicUsize = AtomicUsize::new(0);
+
+//lol_html 的改写器不能跨线程，每个文档在分到的线程的 LocalSet 上改写，
+//读取上游和发送给浏览器都是异步的，不会占住线程等待网络
+fn spawn_rewriter(index: usize) -> mpsc::UnboundedSender<RewriteJob> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<RewriteJob>();
+    std::thread::Builder::new()
+        .name(format!("html-rewriter-{index}"))
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build html rewriter runtime");
+            let local = tokio::task::LocalSet::new();
+            local.block_on(&rt, async move {
+                while let Some(job) = rx.recv().await {
+                    tokio::task::spawn_local(rewrite(job));
+                }
+            });
+        })
+        .expect("Failed to spawn html rewriter thread");
+    tx
+}
+
+async fn rewrite(job: RewriteJob) {
+    let RewriteJob {
+        head,
+        mut body,
+        mut sender,
+        scripts,
+        encoding,
+        adjust_charset,
+        csp,
+        sri,
+    } = job;
+    let injected = Rc::new(Cell::new(false));
+    let on_element = {
+        let injected = injected.clone();
+        let scripts = scripts.clone();
+        element!("*", move |el| {
+            if injected.get() {
+                return Ok(());
+            }
+            match el.tag_name().as_str() {
+                "html" => return Ok(()),
+                "head" => el.prepend(&scripts, ContentType::Html),
+                _ => el.before(&format!("<head>{scripts}</head>"), ContentType::Html),
+            }
+            injected.set(true);
+            Ok(())
+        })
+    };
+    //meta 中的策略只限制它之后的内容，注入的脚本之后动态创建的脚本和请求仍然受它限制
+    let on_meta = element!("meta[http-equiv]", move |el| {
+        let is_csp = el
+            .get_attribute("http-equiv")
+            .map(|v| v.trim().eq_ignore_ascii_case("content-security-policy"))
+            .unwrap_or(false);
+        if !is_csp {
+            return Ok(());
+        }
+        if let Some(content) = el.get_attribute("content") {
+            el.set_attribute(
+                "content",
+                &csp.rewrite(&content, PolicyDisposition::Enforce),
+            )?;
+        }
+        Ok(())
+    });
+    //插件修改后的内容和原来的摘要不一致，浏览器会拒绝加载
+    let on_integrity = {
+        let mut sri = sri;
+        element!(INTEGRITY_SELECTOR, move |el| {
+            //相对地址按 <base href> 解析，它出现在引用资源的标签之前才生效
+            if el.tag_name() == "base" {
+                sri.set_base(&el.get_attribute("href").unwrap_or_default());
+                return Ok(());
+            }
+            let (src, dest) = if el.tag_name() == "script" {
+                (el.get_attribute("src"), "script")
+            } else {
+                let rel = el
+                    .get_attribute("rel")
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                let dest = if rel.contains("modulepreload") {
+                    "script"
+                } else if rel.contains("stylesheet") {
+                    "style"
+                } else {
+                    match el.get_attribute("as").as_deref() {
+                        Some("script") => "script",
+                        Some("style") => "style",
+                        _ => return Ok(()),
+                    }
+                };
+                (el.get_attribute("href"), dest)
+            };
+            let src = auto_option!(src, Ok(()));
+            let integrity = el.get_attribute("integrity").unwrap_or_default();
+            match sri.rewrite(&src, &integrity, dest) {
+                Some(v) if v == integrity => {}
+                Some(v) => el.set_attribute("integrity", &v)?,
+                None => el.remove_attribute("integrity"),
+            }
+            Ok(())
+        })
+    };
+    let on_end = {
+        let injected = injected.clone();
+        end!(move |end| {
+            if !injected.get() {
+                end.append(&scripts, ContentType::Html);
+            }
+            Ok(())
+        })
+    };
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let sink = output.clone();
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![on_element, on_meta, on_integrity],
+            document_content_handlers: vec![on_end],
+            encoding,
+            strict: false,
+            adjust_charset_on_meta_tag: adjust_charset,
+            ..Settings::default()
+        },
+        move |chunk: &[u8]| sink.borrow_mut().extend_from_slice(chunk),
+    );
+
+    let mut pending = Some(Bytes::from(head));
+    loop {
+        let chunk = match pending.take() {
+            Some(chunk) => chunk,
+            None => match body.data().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(err)) => {
+                    error!("read html failed {err}");
+                    sender.abort();
+                    return;
+                }
+                None => break,
+            },
+        };
+        if let Err(err) = rewriter.write(&chunk) {
+            error!("rewrite html failed {err}");
+            sender.abort();
+            return;
+        }
+        let bytes = std::mem::take(&mut *output.borrow_mut());
+        if !bytes.is_empty() && sender.send_data(bytes.into()).await.is_err() {
+            return;
+        }
+    }
+    if let Err(err) = rewriter.end() {
+        error!("rewrite html failed {err}");
+        sender.abort();
+        return;
+    }
+    let bytes = std::mem::take(&mut *output.borrow_mut());
+    if !bytes.is_empty() {
+        let _ = sender.send_data(bytes.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Uri;
+
+    use super::*;
+
+    const SCRIPTS: &str = "<script>inject()</script>";
+
+    async fn inject(chunks: &[&'static str], is_document: bool) -> String {
+        let chunks = chunks
+            .iter()
+            .map(|v| Ok::<Bytes, hyper::Error>(Bytes::from_static(v.as_bytes())))
+            .collect::<Vec<_>>();
+        let body = Body::wrap_stream(futures::stream::iter(chunks));
+        let csp = CspRewriter::new(&[], "abc", true).await;
+        let sri = SriRewriter::new("scope", "a.com", &Uri::from_static("https://a.com/")).await;
+        let out = inject_html(body, None, SCRIPTS.to_string(), is_document, csp, sri)
+            .await
+            .unwrap();
+        let bytes = hyper::body::to_bytes(out).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn sniff_documents() {
+        assert!(looks_like_document(
+            b"\xEF\xBB\xBF \n<!DOCTYPE html><p>",
+            false
+        ));
+        assert!(looks_like_document(b"<HTML>", false));
+        assert!(looks_like_document(b"<div>", true));
+        assert!(!looks_like_document(b"<div>", false));
+        assert!(!looks_like_document(b"<?xml version=\"1.0\"?><html>", true));
+        assert!(!looks_like_document(b"{\"html\": 1}", true));
+        assert!(!looks_like_document(b"  ", true));
+    }
+
+    #[tokio::test]
+    async fn injects_at_head_start() {
+        let html = inject(
+            &[
+                "<html><he",
+                "ad><title>t</title></head>",
+                "<body></body></html>",
+            ],
+            true,
+        )
+        .await;
+        assert_eq!(
+            html,
+            format!("<html><head>{SCRIPTS}<title>t</title></head><body></body></html>")
+        );
+    }
+
+    #[tokio::test]
+    async fn adds_head_when_missing() {
+        let html = inject(&["<!doctype html><p>hi</p>"], true).await;
+        assert_eq!(
+            html,
+            format!("<!doctype html><head>{SCRIPTS}</head><p>hi</p>")
+        );
+        //没有任何元素时追加在末尾
+        let html = inject(&["<!-- empty -->"], true).await;
+        assert_eq!(html, format!("<!-- empty -->{SCRIPTS}"));
+    }
+
+    #[tokio::test]
+    async fn rewrites_meta_csp() {
+        let html = inject(
+            &["<head><meta http-equiv=\"Content-Security-Policy\" content=\"script-src 'self'\"></head>"],
+            true,
+        )
+        .await;
+        assert!(
+            html.contains("content=\"script-src 'self' 'nonce-abc'\""),
+            "{html}"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_other_content() {
+        let html = inject(&["console.log('<html>')"], false).await;
+        assert_eq!(html, "console.log('<html>')");
+        let html = inject(&["<svg></svg>"], false).await;
+        assert_eq!(html, "<svg></svg>");
+    }
+}
@@ -1,11 +1,7 @@
 use async_trait::async_trait;
-use encoding_rs::Encoding;
 
 use hyper::{
-    header::{
-        CONTENT_SECURITY_POLICY_REPORT_ONLY, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH,
-        IF_UNMODIFIED_SINCE, LOCATION,
-    },
+    header::{HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LOCATION},
     service::Service,
     Body, Version,
 };
@@ -15,52 +11,58 @@ use crate::{
     handle::model::HostList,
     net_proxy::{
         decode_request, decode_response, encode_body, encode_response, Answer, HttpContext,
-        HttpHandler, WebSocketContext, WebSocketHandler,
+        HttpHandler, UpstreamInfo, WebSocketContext, WebSocketHandler,
     },
     reqwest_request_from_hyper, reqwest_response_to_hyper, DOC_URL,
 };
 
-use html5ever::{namespace_url, ns};
-
 use hyper::http::{
     header::{
         HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
         ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING,
-        CONTENT_SECURITY_POLICY, CONTENT_TYPE, ORIGIN, REFERER, REFERRER_POLICY, USER_AGENT,
+        CONTENT_LENGTH, CONTENT_TYPE, ORIGIN, REFERER, REFERRER_POLICY, SET_COOKIE, USER_AGENT,
     },
     Extensions, Method, Request, Response, StatusCode, Uri,
 };
 
 use hyper_tungstenite::tungstenite::Message;
-use kuchiki::{traits::*, Attribute, ExpandedName, NodeRef};
-
-use markup5ever::{local_name, QualName};
 
 use serde::Serialize;
 use serde_json::{json, to_value, Value};
 use std::{collections::HashSet, net::SocketAddr, path::Path, str::FromStr};
 use tokio::{fs::File, sync::RwLock};
 
+use encoding_rs::{Encoding, UTF_8};
 use tracing::{error, instrument};
 
 use tokio_util::codec::{BytesCodec, FramedRead};
 
 use crate::{
     auto_option, auto_result,
+    breakpoint::Phase,
     handle::{api::handle_api, socket::handle_socket, web::handle_web},
     jsbind::{http::*, server::Scope, ws::*},
-    utils, ASYNC_TASK_MANNAGER, CLIENT_MANAGER, HTTP_CLIENT, PLUGIN_MANAGER,
+    matcher::MatchTarget,
+    rules,
+    sri::SriRewriter,
+    utils, ASYNC_TASK_MANNAGER, BREAKPOINTS, CLIENT_MANAGER, HTTP_CLIENT, PLUGIN_MANAGER,
 };
 
 use self::{
     api::config,
     net_agent::{on_message, on_request, on_response, watch_request, watch_response},
+    scope::ScopeStrategy,
 };
 
 pub mod api;
+pub mod csp;
+pub mod dest;
+pub mod inject;
 pub mod model;
 pub mod net_agent;
 pub mod plugin_web;
+pub mod rpc;
+pub mod scope;
 pub mod socket;
 pub mod web;
 
@@ -164,6 +166,21 @@ where
         .body(body)
         .unwrap()
 }
+///按扩展名推断 Content-Type，未知的扩展名返回空
+pub fn content_type_by_ext(ext: &str) -> String {
+    match ext {
+        "html" | "htm" | "xml" => "text/html".to_string(),
+        "svg" => "image/svg+xml".to_string(),
+        "txt" => "text/plain".to_string(),
+        "css" => "text/css".to_string(),
+        "js" => "application/javascript".to_string(),
+        "json" => "application/json".to_string(),
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp" | "tiff" | "heif" => {
+            format!("image/{ext}")
+        }
+        _ => String::new(),
+    }
+}
 #[instrument]
 pub async fn response_file(file_path: &str) -> Response<Body> {
     let path = file_path.split(&['/', '\\']).filter(|v| !v.is_empty());
@@ -178,29 +195,14 @@ pub async fn response_file(file_path: &str) -> Response<Body> {
            let err =format!("{file_path}: {err}");
            return response_msg(500, &err);
     });
-    let mut temp = String::new();
-    let content_type = match ext {
-        "html" | "htm" | "xml" => "text/html",
-        "svg" => "image/svg+xml",
-        "txt" => "text/plain",
-        "css" => "text/css",
-        "js" => "application/javascript",
-        "json" => "application/json",
-        "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp" | "tiff" | "heif" => {
-            let len = temp.len();
-            let ext = "image/".to_owned() + ext;
-            temp = temp + &ext;
-            &temp[len..len + &ext.len()]
-        }
-        _ => "",
-    };
+    let content_type = content_type_by_ext(ext);
 
     let stream = FramedRead::new(file, BytesCodec::new());
     let body = Body::wrap_stream(stream);
     let any = HeaderValue::from_str("*").unwrap();
     Response::builder()
         .status(200)
-        .header(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap())
+        .header(CONTENT_TYPE, HeaderValue::from_str(&content_type).unwrap())
         .header(ACCESS_CONTROL_ALLOW_ORIGIN, any.clone())
         .header(ACCESS_CONTROL_ALLOW_METHODS, any.clone())
         .header(ACCESS_CONTROL_ALLOW_HEADERS, any.clone())
@@ -323,93 +325,69 @@ async fn handle_server(ctx: &HttpContext, mut req: Request<Body>) -> Response<Bo
     };
     res
 }
-async fn handle_worker(
+//service worker 和它 importScripts 的脚本不带页面的身份信息，
+//由 content.js 和 worker.js 在地址中带上域id，去掉这个参数后再发出请求。
+//识别不出类型的请求也可能是 importScripts 加载的脚本。
+//只认代理分配过的域id，页面自己的同名参数原样发出。
+//content.js 和 worker.js 创建的 worker 在地址中带上类型，没有 sec-fetch-dest 时用它补上 dest，同样去掉后再发出请求
+async fn scope_from_param(req: &mut Request<Body>, dest: &mut String) -> Option<Scope> {
+    //类型标记同样只认 worker 的类型
+    let mark = dest::take_param(req.uri(), dest::WORKER_PARAM, |v| {
+        dest::WORKER_TYPES.contains(&v)
+    });
+    if let Some((mark, uri)) = mark {
+        if !dest.ends_with("worker") {
+            *dest = mark;
+        }
+        *req.uri_mut() = uri;
+    }
+    if !matches!(dest.as_str(), "serviceworker" | "script" | "") {
+        return None;
+    }
+    let guard = CLIENT_MANAGER.scope_keys.read().await;
+    let (scope_id, uri) =
+        dest::take_param(req.uri(), dest::SCOPE_PARAM, |v| guard.contains_key(v))?;
+    *req.uri_mut() = uri;
+    guard.get(&scope_id).cloned()
+}
+//worker 脚本每次都要完整下载才能注入，去掉缓存验证。
+//返回 Some 时直接响应，/cthulhu.js 只包含注入的脚本
+async fn prepare_worker(
     ctx: &HttpContext,
-    mut req: Request<Body>,
-    mut scope_key: Scope,
+    req: &mut Request<Body>,
+    scope_key: &Scope,
     dest: &str,
-) -> Response<Body> {
-    if dest == "serviceworker" {
-        let mut uri_parser = JsUri::from(req.uri());
-        let scope_id = auto_option!(
-            uri_parser.params.remove("SCOPE_ID"),
-            response_msg(500, "Invalid scope id")
-        );
-        let guard = CLIENT_MANAGER.scope_keys.read().await;
-        scope_key =
-            auto_option!(guard.get(&scope_id[0]), response_msg(500, "Invalid scope id")).clone();
-        *req.uri_mut() = hyper::Uri::from_str(&uri_parser.assemble().unwrap()).unwrap();
+) -> Option<Response<Body>> {
+    req.headers_mut().remove(IF_MODIFIED_SINCE);
+    req.headers_mut().remove(IF_NONE_MATCH);
+    req.headers_mut().remove(IF_UNMODIFIED_SINCE);
+    if ctx.uri.path() != "/cthulhu.js" {
+        return None;
     }
-    let path = ctx.uri.path();
     let host = ctx.uri.host().unwrap_or_default();
-    let (parts, body) = {
-        let response = if path == "/cthulhu.js" {
-            Response::builder()
-                .status(200)
-                .header(
-                    CONTENT_TYPE,
-                    HeaderValue::from_str("application/javascript; charset=utf-8").unwrap(),
-                )
-                .body(hyper::Body::empty())
-                .unwrap()
-        } else {
-            //去除缓存控制
-            req.headers_mut().remove(IF_MODIFIED_SINCE);
-            req.headers_mut().remove(IF_NONE_MATCH);
-            req.headers_mut().remove(IF_UNMODIFIED_SINCE);
-            let req = reqwest_request_from_hyper(req).await;
-            let response = HTTP_CLIENT.clone().call(req).await;
-
-            let response = auto_result!(response,err=>response_msg(500, err.to_string()).into());
-            let response = reqwest_response_to_hyper(response).await.unwrap();
-            auto_result!( decode_response(response),err=>response_msg(500, err.to_string()).into())
-        };
-        response.into_parts()
+    let target = MatchTarget::origin(host)
+        .with_uri(&ctx.uri)
+        .with_dest(Some(dest));
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
+    let res = match inject::worker_bundle(scope_key, &all).await {
+        Ok(code) => Response::builder()
+            .status(200)
+            .header(
+                CONTENT_TYPE,
+                HeaderValue::from_str("application/javascript; charset=utf-8").unwrap(),
+            )
+            .body(Body::from(code))
+            .unwrap(),
+        Err(err) => response_msg(500, err),
     };
-    let bytes = auto_result!(hyper::body::to_bytes(body).await,err=>response_msg(500, err.to_string()).into());
-    let worker_init_js = {
-        let workspace = auto_option!(config::get_config("workspace").await, {
-            return response_msg(500, "请设置CthulhuRs server工作目录").into();
-        });
-        let workspace = workspace.as_str().unwrap_or("").to_string();
-        if workspace.is_empty() {
-            return response_msg(500, "请设置CthulhuRs server工作目录").into();
-        }
-        let worker_js = format!("{workspace}/worker.js");
-        let bytes = auto_result!(utils::read_bytes(worker_js),err=>response_msg(500, err.to_string()).into());
-        String::from_utf8(bytes).unwrap()
-    };
-    let scope_id_inject_js = {
-        let hash = scope_key.id.clone();
-        format!("self['CTHULHU_SCOPE_ID']='{}';\n", hash)
-    };
-    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(host).await;
-    let (mut sender, body) = Body::channel();
-
-    tokio::spawn(async move {
-        auto_result!(sender.send_data(scope_id_inject_js.into()).await, ());
-        auto_result!(sender.send_data(worker_init_js.into()).await, ());
-
-        for ctx in all {
-            //注入插件动态脚本
-            let scripts = ctx.dynamic_scripts(scope_key.clone()).await;
-            for mut script in scripts {
-                script.push_str(";\n");
-                auto_result!(sender.send_data(script.into()).await, ());
-            }
-            //注入插件worker脚本
-            if let Ok(mut worker) = ctx.plugin.read_worker() {
-                worker.push_str(";\n");
-                auto_result!(sender.send_data(worker.into()).await, ());
-            }
-        }
-        auto_result!(sender.send_data(bytes.into()).await, ());
-    });
-
-    Response::from_parts(parts, body)
+    Some(finish_early(&ctx.request_id, res).await)
 }
 
-pub fn scope_key_from_request(addr: &SocketAddr, req: &mut Request<Body>) -> Result<Scope, String> {
+///按配置的划分方式计算请求所属的域，第二个返回值是需要下发给浏览器的 Set-Cookie
+pub async fn scope_key_from_request(
+    addr: &SocketAddr,
+    req: &mut Request<Body>,
+) -> Result<(Scope, Option<String>), String> {
     let (email, custom, window, tab, frame) = {
         let headers = req.headers_mut();
         if let Some(extra) = headers.remove("cthulhu-extra-scope") {
@@ -443,37 +421,43 @@ pub fn scope_key_from_request(addr: &SocketAddr, req: &mut Request<Body>) -> Res
     let ua = headers
         .get(USER_AGENT)
         .map(|v| v.to_str().unwrap_or(""))
-        .unwrap_or("");
-    let origin = {
-        if let Some(origin) = headers.get(ORIGIN) {
-            origin.to_str().unwrap()
-        } else {
-            if let Some(referrer) = headers.get(REFERER) {
-                referrer.to_str().unwrap()
-            } else {
-                //什么都没有 猜测是文档请求 直接使用请求地址本身构建scopekey
-                let uri = req.uri();
-                let ip = addr.ip().to_string();
-                let scheme = uri.scheme_str().unwrap_or("").to_string();
-                let host = uri.host().unwrap_or("").to_string();
-                let ua = ua.to_string();
-
-                let scope_key = Scope::new(ip, scheme, host, ua, email, custom, window, tab, frame);
-                return Ok(scope_key);
-            }
+        .unwrap_or("")
+        .to_string();
+    let origin = headers
+        .get(ORIGIN)
+        .or_else(|| headers.get(REFERER))
+        .map(|v| v.to_str().unwrap_or("").to_string());
+    let (scheme, host) = match origin {
+        Some(origin) => {
+            let origin = auto_result!(
+                Uri::from_str(&origin),
+                Err("Origin 或 Referrer uri地址解析错误，代理服务器无法辨识来源".into())
+            );
+            (
+                origin.scheme_str().unwrap_or("").to_string(),
+                origin.host().unwrap_or("").to_string(),
+            )
+        }
+        None => {
+            //什么都没有 猜测是文档请求 直接使用请求地址本身构建scopekey
+            let uri = req.uri();
+            (
+                uri.scheme_str().unwrap_or("").to_string(),
+                uri.host().unwrap_or("").to_string(),
+            )
         }
     };
-    let origin = auto_result!(
-        Uri::from_str(origin),
-        Err("Origin 或 Referrer uri地址解析错误，代理服务器无法辨识来源".into())
-    );
     let ip = addr.ip().to_string();
-    let scheme = origin.scheme_str().unwrap_or("").to_string();
-    let host = origin.host().unwrap_or("").to_string();
-    let ua = ua.to_string();
 
+    let strategy = ScopeStrategy::load().await;
+    let identity = scope::resolve_identity(&strategy, addr, &scheme, &host, req).await;
     let scope_key = Scope::new(ip, scheme, host, ua, email, custom, window, tab, frame);
-    Ok(scope_key)
+    let scope_key = if identity.value.is_empty() {
+        scope_key
+    } else {
+        scope_key.with_identity(&identity.value)
+    };
+    Ok((scope_key, identity.set_cookie))
 }
 
 pub const BRAND: &str = "cthulhu.server";
@@ -489,6 +473,8 @@ impl HttpHandler for Handler {
     // #[instrument(skip(err),parent=None)]
     async fn handle_error(&mut self, ctx: &HttpContext, err: String) -> Response<Body> {
         error!("{err}, {:?}", ctx);
+        //请求失败不会走到handle_response，这里释放请求与域的关联
+        release_request(&ctx.request_id).await;
         response_msg(500, err.as_str())
     }
 
@@ -521,27 +507,44 @@ impl HttpHandler for Handler {
         // if uri.path() == "/creepjs/creep.js" {
         //     println!("{:#?}", &req)
         // }
+        //没有 sec-fetch-dest 的浏览器按其它信息推断
+        let mut dest = dest::request_dest(uri, req.headers());
         let scope_key = {
             //处理scope
-            let scope_key = auto_result!(scope_key_from_request(&ctx.client_addr,&mut req),err=>{
+            let (scope_key, set_cookie) = auto_result!(scope_key_from_request(&ctx.client_addr,&mut req).await,err=>{
                 return response_msg(500, err).into();
             });
+            let scope_key = scope_from_param(&mut req, &mut dest).await.unwrap_or(scope_key);
             //将scopekey与客户端地址和接口关联起来，方便response找到自己的scopekey
             let mut guard = CLIENT_MANAGER.ctx_map_scope_keys.write().await;
-            guard.insert((ctx.client_addr, ctx.uri.clone()), scope_key.clone());
-            //方便插件端通过scopeid得到scopekey
-            CLIENT_MANAGER.set_scope_key(scope_key.clone()).await;
+            guard.insert(ctx.request_id.clone(), scope_key.clone());
+            drop(guard);
+            //按cookie划分域时，新分配的身份随响应下发
+            if let Some(cookie) = set_cookie {
+                let mut cookies = CLIENT_MANAGER.cookies.write().await;
+                cookies.insert(ctx.request_id.clone(), cookie);
+            }
+            //方便插件端通过scopeid得到scopekey，新域的 onScopeCreate 在后台执行，不阻塞这次请求
+            if CLIENT_MANAGER.set_scope_key(scope_key.clone()).await {
+                let new_one = scope_key.clone();
+                let join = tokio::task::spawn(async move {
+                    net_agent::on_scope_create(&new_one).await;
+                });
+                let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
+                tasks.push(join);
+                drop(tasks);
+            }
             scope_key
         };
-        let headers = req.headers();
-        //dest在谷歌系列浏览器上生效
-        let dest = headers
-            .get("sec-fetch-dest")
-            .map(|v| v.to_str().unwrap_or(""))
-            .unwrap_or("")
-            .to_string();
+        //本地映射、远程映射和头部改写规则先于插件生效
+        if let Some(res) = rules::apply_request(&mut req).await {
+            return finish_early(&ctx.request_id, res).await.into();
+        }
+        //worker 和页面一样经过插件处理，响应时再注入脚本
         if dest.ends_with("worker") {
-            return handle_worker(ctx, req, scope_key, &dest).await.into();
+            if let Some(res) = prepare_worker(ctx, &mut req, &scope_key, &dest).await {
+                return res.into();
+            }
         }
         let extensions = {
             //将extensions保留起来，因为http Request转为JsRequest 这些数据会丢失影响网络连接 比如ws协议升级
@@ -550,21 +553,31 @@ impl HttpHandler for Handler {
             extensions
         };
 
-        let js_req = JsRequest::from_hyper(req);
+        let mut js_req = JsRequest::from_hyper(req);
+        js_req.id = ctx.request_id.clone();
+        js_req.dest = dest;
         let action = on_request(&scope_key, js_req).await;
+        let scope_id = scope_key.id.clone();
 
         match action.action {
-            HttpAction::Reject => return response_content(500, "<server rejected>").into(),
+            HttpAction::Reject => {
+                let res = response_content(500, "<server rejected>");
+                return finish_early(&ctx.request_id, res).await.into();
+            }
             HttpAction::Proxy(req, proxy_data) => {
                 let mut keys = CLIENT_MANAGER.proxy_datas.write().await;
                 keys.insert(scope_key.clone(), proxy_data);
+                drop(keys);
                 let new_one = req.clone();
                 let join = tokio::task::spawn(async move {
                     watch_request(&scope_key, new_one).await;
                 });
                 let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
                 tasks.push(join);
+                drop(tasks);
 
+                let req = auto_result!(request_breakpoint(ctx, &scope_id, req, false).await, res => finish_early(&ctx.request_id, res).await.into());
+                remember_request(&ctx.request_id, &req).await;
                 let mut req: Request<Body> = req.into_hyper().await;
                 *req.extensions_mut() = extensions;
                 req.into()
@@ -576,8 +589,11 @@ impl HttpHandler for Handler {
                 });
                 let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
                 tasks.push(join);
+                drop(tasks);
 
                 tokio::time::sleep(tokio::time::Duration::from_millis(ms)).await;
+                let req = auto_result!(request_breakpoint(ctx, &scope_id, req, false).await, res => finish_early(&ctx.request_id, res).await.into());
+                remember_request(&ctx.request_id, &req).await;
                 let mut req: Request<Body> = req.into_hyper().await;
                 *req.extensions_mut() = extensions;
                 req.into()
@@ -585,13 +601,13 @@ impl HttpHandler for Handler {
             HttpAction::Respond(res) => {
                 let new_one = res.clone();
                 let join = tokio::task::spawn(async move {
-                    watch_response(&scope_key, new_one).await;
+                    watch_response(&scope_key, new_one, None, JsUpstreamInfo::default()).await;
                 });
                 let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
                 tasks.push(join);
 
                 let res: Response<Body> = res.into_hyper().await;
-                res.into()
+                finish_early(&ctx.request_id, res).await.into()
             }
             HttpAction::Release(req) => {
                 let new_one = req.clone();
@@ -600,6 +616,28 @@ impl HttpHandler for Handler {
                 });
                 let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
                 tasks.push(join);
+                drop(tasks);
+                let req = auto_result!(request_breakpoint(ctx, &scope_id, req, false).await, res => finish_early(&ctx.request_id, res).await.into());
+                remember_request(&ctx.request_id, &req).await;
+                let mut req: Request<Body> = req.into_hyper().await;
+                *req.extensions_mut() = extensions;
+                req.into()
+            }
+            HttpAction::Pause(req, pause_request, pause_response) => {
+                let new_one = req.clone();
+                let join = tokio::task::spawn(async move {
+                    watch_request(&scope_key, new_one).await;
+                });
+                let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
+                tasks.push(join);
+                drop(tasks);
+
+                let req = auto_result!(request_breakpoint(ctx, &scope_id, req, pause_request).await, res => finish_early(&ctx.request_id, res).await.into());
+                //请求断点放行后才标记，被丢弃或直接响应的请求不会留下标记
+                if pause_response {
+                    BREAKPOINTS.mark_response(&ctx.request_id).await;
+                }
+                remember_request(&ctx.request_id, &req).await;
                 let mut req: Request<Body> = req.into_hyper().await;
                 *req.extensions_mut() = extensions;
                 req.into()
@@ -619,6 +657,22 @@ impl HttpHandler for Handler {
         if !allow {
             return res;
         }
+        rules::apply_response(&ctx.uri, res.headers_mut()).await;
+        //命中断点或插件要求暂停响应时，在插件修改响应之前暂停
+        let marked = BREAKPOINTS.take_response_mark(&ctx.request_id).await;
+        if marked || BREAKPOINTS.matches(&ctx.uri, Phase::Response).await {
+            let scope_id = CLIENT_MANAGER
+                .ctx_map_scope_keys
+                .read()
+                .await
+                .get(&ctx.request_id)
+                .map(|v| v.id.clone());
+            if let Some(scope_id) = scope_id {
+                res = BREAKPOINTS
+                    .pause_response(&ctx.request_id, &scope_id, res)
+                    .await;
+            }
+        }
 
         let headers = res.headers();
         let empty = HeaderValue::from_str("").unwrap();
@@ -634,42 +688,49 @@ impl HttpHandler for Handler {
             .to_str()
             .unwrap()
             .split(";");
-        let ctype = split.next().unwrap_or("");
-
-        let charset = {
-            let charset = split.next().unwrap_or("").trim();
-            if charset.len() > 8 {
-                let charset = &charset[8..].trim();
-                if charset.is_empty() {
-                    "utf-8"
-                } else {
-                    charset
-                }
-            } else {
-                "utf-8"
-            }
-        }
-        .to_owned();
+        let ctype = split.next().unwrap_or("").to_string();
+
+        //响应头中声明的编码，没有时由注入时按 <meta charset> 判断
+        let charset = split
+            .find_map(|v| v.trim().strip_prefix("charset="))
+            .map(|v| v.trim().trim_matches('"').to_string())
+            .filter(|v| !v.is_empty());
         let mut guard = CLIENT_MANAGER.ctx_map_scope_keys.write().await;
-        let scope_key = auto_option!(guard.remove(&(ctx.client_addr, ctx.uri.clone())), res);
+        let scope_key = auto_option!(guard.remove(&ctx.request_id), res);
+        drop(guard);
+        append_scope_cookie(&ctx.request_id, &mut res).await;
+        //插件修改后实际发出的请求和上游的耗时、连接信息，交给响应钩子
+        let js_req = CLIENT_MANAGER.requests.write().await.remove(&ctx.request_id);
+        let info = res
+            .extensions()
+            .get::<UpstreamInfo>()
+            .map(JsUpstreamInfo::from)
+            .unwrap_or_default();
+
+        let mut target = match &js_req {
+            Some(js_req) => MatchTarget::from_request(host, js_req),
+            None => MatchTarget::origin(host).with_uri(&ctx.uri),
+        };
+        //请求时没能识别类型的，按响应类型补上
+        if target.dest.is_none() {
+            target = target.with_dest(dest::response_dest(&ctype));
+        }
 
         // println!("URL:{:?},{}", _ctx.uri, ctype);
         if ctype.starts_with("text/html") {
-            let res = decode_response(res).unwrap();
-            let (mut parts, mut body) = res.into_parts();
-            parts.headers.remove(CONTENT_SECURITY_POLICY_REPORT_ONLY); //刪除 禁止报告
-            let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(host).await;
-
-            {
-                //修改内容安全策略
-                if let Some(val) = parts.headers.get_mut(CONTENT_SECURITY_POLICY) {
-                    let csp = val.to_str().unwrap_or("");
-                    if csp != "" {
-                        let csp = net_agent::content_security_policy(&all, csp).await;
-                        *val = HeaderValue::from_str(&csp).unwrap();
-                    }
-                }
-            }
+            let res = auto_result!(decode_response(res),err=>{
+                error!("decode response failed {err}");
+                return response_content(500, "<proxy server error>");
+            });
+            let (mut parts, body) = res.into_parts();
+            let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
+            //注入的脚本都带上nonce，页面的csp只需要放行这个nonce。
+            //内联注入时所有脚本合成一个内联脚本，csp中不再添加其它来源
+            let inline = inject::inline_enabled().await;
+            let nonce = uuid::Uuid::new_v4().simple().to_string();
+            let csp = csp::CspRewriter::new(&all, &nonce, inline).await;
+            //报告模式的策略同样改写，避免注入的脚本产生违规报告
+            csp.rewrite_headers(&mut parts.headers);
             {
                 //修改referrer policy
                 let policy = parts
@@ -685,109 +746,60 @@ impl HttpHandler for Handler {
                 }
             }
 
-            let encoder = Encoding::for_label(charset.as_bytes()).expect("charset not support");
-
-            let bytes = auto_result!(hyper::body::to_bytes(&mut body).await,err=>{
+            //按插件优先级和 runAt 生成，插到 <head> 的最前面
+            let scripts = if inline {
+                let code = auto_result!(inject::bundle_scripts(&scope_key, &all, &target, true).await,err=>{
+                    error!("{err}");
+                    return response_content(500, "<proxy server error>");
+                });
+                inject::inline_script_tag(&nonce, &code)
+            } else {
+                inject::page_scripts(&nonce, &scope_key.id, &all, &target)
+            };
+            let is_document = matches!(
+                target.dest.as_deref(),
+                Some("document") | Some("iframe") | Some("frame")
+            );
+            //页面中引用的被插件修改过的脚本和样式，按插件的 sri 配置改写 integrity
+            let sri = SriRewriter::new(&scope_key.id, host, &ctx.uri).await;
+            let body = auto_result!(inject::inject_html(body, charset.as_deref(), scripts, is_document, csp, sri).await,err=>{
                 error!("{err}");
                 return response_content(500, "<proxy server error>");
             });
-            let bytes = bytes.to_vec();
-            let (decoding, _, err) = encoder.decode(&bytes);
-            if err {
-                return Response::from_parts(parts, Body::from(bytes));
-            }
+            parts.headers.remove(CONTENT_LENGTH);
 
-            let html = {
-                let body_str = decoding.into_owned();
-
-                let html_inject = |html: String| -> String {
-                    //有的接口响应头返回的是text/html 实际却是其他格式 所以通过<!DOCTYPE html>区分
-                    let ht=html.trim_start();
-                    if !(ht.len()>=15) {
-                        return html;
-                    }
-                    let tag = &ht[0..15];
-                    if !tag.eq_ignore_ascii_case("<!doctype html>") {
-                        return html;
-                    }
-                    let document = kuchiki::parse_html().one(html);
-
-                    let head = document.select_first("head").unwrap();
-                    {
-                        //注入插件js 3
-                        for ctx in all {
-                            let id = &ctx.plugin.id;
-                            //注入静态js
-                            ctx.plugin
-                                .content_paths
-                                .split(",")
-                                .map(|v| format!("https://{id}.plugin.cthulhu.server/{v}"))
-                                .for_each(|url| {
-                                    let script = new_script(url.as_str(), &charset);
-                                    head.as_node().prepend(script);
-                                });
-                            //注入动态js
-                            ctx.plugin
-                                .dynamic_links
-                                .split(",")
-                                .map(|v| v.trim())
-                                .filter(|v| !v.is_empty())
-                                .map(|link| {
-                                    format!("https://{id}.plugin.cthulhu.server/dynamic/{link}")
-                                })
-                                .for_each(|url| {
-                                    let script = new_script(url.as_str(), &charset);
-                                    head.as_node().prepend(script);
-                                });
-                        }
-                    }
-                    {
-                        //注入全局初始化js 2
-                        let script = new_script("https://web.cthulhu.server/content.js", &charset);
-                        head.as_node().prepend(script);
-                    }
-
-                    {
-                        //将分配的scope_key直接注入到html中 1
-                        let scope_id = scope_key.id;
-                        let set_scope_key_js = format!("self['CTHULHU_SCOPE_ID']='{}'", scope_id);
-                        let script = new_text_script(set_scope_key_js.as_str(), &charset);
-                        head.as_node().prepend(script);
-                    }
-                    let html = document.to_string();
-                    println!("injected html '{host}'");
-                    // println!("head:\n{}",head.as_node().to_string());
-                    html
-                };
-                html_inject(body_str)
-            };
-
-            let (decoding, _e, _err) = encoder.encode(&html);
-
-            // if err {
-            //     eprintln!("编码失败：{charset}");
-            //     return Response::from_parts(parts, Body::from(html));
-            // }
-
-            let html = decoding.into_owned();
             if encodings == "" {
-                let res = Response::from_parts(parts, Body::from(html));
+                let res = Response::from_parts(parts, body);
                 return encode_response("gzip", res).expect("compress failed");
             }
-            for code in encodings.split(",") {
-                let body = auto_result!(encode_body(code, Body::from(html.clone())),err=>{
-                    error!("compress respond failed {err}");
-                    continue
-                });
-                parts
-                    .headers
-                    .append(CONTENT_ENCODING, HeaderValue::from_str(code).unwrap());
-
-                return Response::from_parts(parts, body);
-            }
-            return response_msg(500, "compress respond failed");
+            //按原响应的第一种压缩方式重新压缩
+            let code = encodings.split(",").next().unwrap_or("").trim();
+            let body = auto_result!(encode_body(code, body),err=>{
+                error!("compress respond failed {err}");
+                return response_msg(500, "compress respond failed");
+            });
+            parts
+                .headers
+                .append(CONTENT_ENCODING, HeaderValue::from_str(code).unwrap());
+            return Response::from_parts(parts, body);
+        }
+        let is_worker = target
+            .dest
+            .as_deref()
+            .map(|v| v.ends_with("worker"))
+            .unwrap_or(false);
+        if is_worker {
+            res = auto_result!(decode_response(res),err=>{
+                error!("decode response failed {err}");
+                return response_content(500, "<proxy server error>");
+            });
+        }
+        //脚本先交给插件的 onScript 改写源码，之后再交给 onResponse。
+        //worker 脚本总是按utf-8解码
+        if is_script(&ctype) || is_worker {
+            let charset = if is_worker { None } else { charset.as_deref() };
+            res = rewrite_script(&scope_key, &target, &ctx.request_id, res, charset).await;
         }
-        if ctype.ends_with("application/javascript") {}
         let res = {
             let extensions = {
                 //将extensions保留起来，因为http Response转为JsResponse 这些数据会丢失影响网络连接 比如ws升级
@@ -796,13 +808,14 @@ impl HttpHandler for Handler {
                 extensions
             };
 
-            let res = JsResponse::from_hyper(res);
-            let js_res = on_response(&scope_key, res.into()).await;
+            let mut res = JsResponse::from_hyper(res);
+            res.id = ctx.request_id.clone();
+            let js_res = on_response(&scope_key, res.into(), js_req.clone(), info.clone()).await;
             let new_one = js_res.clone();
             let scope_key = scope_key.clone();
 
             let join = tokio::task::spawn(async move {
-                watch_response(&scope_key, new_one).await;
+                watch_response(&scope_key, new_one, js_req, info).await;
             });
             let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
             tasks.push(join);
@@ -811,65 +824,135 @@ impl HttpHandler for Handler {
             *res.extensions_mut() = extensions;
             res
         };
+        //插件处理完 worker 自己的脚本后，再在前面加上注入的脚本
+        if is_worker {
+            return auto_result!(inject::inject_worker(res, &scope_key, &target).await,err=>{
+                error!("{err}");
+                response_content(500, "<proxy server error>")
+            });
+        }
 
         res
     }
 }
-fn new_script(src: &str, charset: &str) -> NodeRef {
-    let tag = QualName::new(None, ns!(html), local_name!("script"));
-    let script = NodeRef::new_element(
-        tag,
-        vec![
-            (
-                ExpandedName::new("", "src"),
-                Attribute {
-                    prefix: None,
-                    value: src.to_string(),
-                },
-            ),
-            (
-                ExpandedName::new("", "charset"),
-                Attribute {
-                    prefix: None,
-                    value: charset.to_owned(),
-                },
-            ),
-            (
-                ExpandedName::new("", "type"),
-                Attribute {
-                    prefix: None,
-                    value: "text/javascript".to_owned(),
-                },
-            ),
-        ],
+//脚本的 Content-Type，只有这些响应交给 onScript
+fn is_script(ctype: &str) -> bool {
+    matches!(
+        ctype.trim().to_ascii_lowercase().as_str(),
+        "application/javascript"
+            | "text/javascript"
+            | "application/x-javascript"
+            | "application/ecmascript"
+            | "text/ecmascript"
+    )
+}
+//按声明的编码解码脚本，没有声明时按utf-8，带BOM时以BOM为准
+fn script_source(bytes: &[u8], charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|v| Encoding::for_label(v.trim().as_bytes()))
+        .unwrap_or(UTF_8);
+    let (source, _, _) = encoding.decode(bytes);
+    source.into_owned()
+}
+///把脚本响应交给插件的 onScript 改写，修改后统一按utf-8输出。
+///没有插件定义 onScript 或者都没有修改时原样返回
+async fn rewrite_script(
+    scope_key: &Scope,
+    target: &MatchTarget,
+    request_id: &str,
+    res: Response<Body>,
+    charset: Option<&str>,
+) -> Response<Body> {
+    if !res.status().is_success() {
+        return res;
+    }
+    let plugins = net_agent::script_plugins(target).await;
+    if plugins.is_empty() {
+        return res;
+    }
+    let res = auto_result!(decode_response(res),err=>{
+        error!("decode response failed {err}");
+        return response_content(500, "<proxy server error>");
+    });
+    let (mut parts, body) = res.into_parts();
+    let bytes = auto_result!(hyper::body::to_bytes(body).await,err=>{
+        error!("read script failed {err}");
+        return response_content(500, "<proxy server error>");
+    });
+    let url = target.url.clone().unwrap_or_default();
+    let source = script_source(&bytes, charset);
+    let source = auto_option!(
+        net_agent::on_script(scope_key, &plugins, &url, request_id, source).await,
+        Response::from_parts(parts, Body::from(bytes))
+    );
+    let mime = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or("application/javascript")
+        .trim()
+        .to_string();
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(&format!("{mime}; charset=utf-8")).unwrap(),
     );
-    script
+    parts.headers.insert(CONTENT_LENGTH, source.len().into());
+    Response::from_parts(parts, Body::from(source))
+}
+//命中断点时暂停请求，返回Err时直接把响应返回给客户端
+async fn request_breakpoint(
+    ctx: &HttpContext,
+    scope_id: &str,
+    req: JsRequest,
+    force: bool,
+) -> Result<JsRequest, Response<Body>> {
+    if !force && !BREAKPOINTS.matches(&ctx.uri, Phase::Request).await {
+        return Ok(req);
+    }
+    let req: Request<Body> = req.into_hyper().await;
+    let req = BREAKPOINTS
+        .pause_request(&ctx.request_id, scope_id, req)
+        .await?;
+    let mut js_req = JsRequest::from_hyper(req);
+    js_req.id = ctx.request_id.clone();
+    Ok(js_req)
 }
 
-fn new_text_script(text: &str, charset: &str) -> NodeRef {
-    let tag = QualName::new(None, ns!(html), local_name!("script"));
-    let node = kuchiki::NodeRef::new_text(text);
-    let script = NodeRef::new_element(
-        tag,
-        vec![
-            (
-                ExpandedName::new("", "charset"),
-                Attribute {
-                    prefix: None,
-                    value: charset.to_owned(),
-                },
-            ),
-            (
-                ExpandedName::new("", "type"),
-                Attribute {
-                    prefix: None,
-                    value: "text/javascript".to_owned(),
-                },
-            ),
-        ],
-    );
-    script.append(node);
-    script
+//请求不经过 handle_response 就结束时，释放请求关联的域、修改后的请求、身份cookie和响应暂停标记
+async fn release_request(request_id: &str) {
+    CLIENT_MANAGER
+        .ctx_map_scope_keys
+        .write()
+        .await
+        .remove(request_id);
+    CLIENT_MANAGER.requests.write().await.remove(request_id);
+    CLIENT_MANAGER.cookies.write().await.remove(request_id);
+    BREAKPOINTS.take_response_mark(request_id).await;
+}
+//由代理直接响应的请求，下发分配到的身份cookie后释放关联
+async fn finish_early(request_id: &str, mut res: Response<Body>) -> Response<Body> {
+    append_scope_cookie(request_id, &mut res).await;
+    release_request(request_id).await;
+    res
+}
+//下发请求分配到的身份cookie
+async fn append_scope_cookie(request_id: &str, res: &mut Response<Body>) {
+    let cookie = auto_option!(CLIENT_MANAGER.cookies.write().await.remove(request_id), ());
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        res.headers_mut().append(SET_COOKIE, value);
+    }
+}
+
+//保存插件修改后的请求，响应钩子通过请求id取回
+async fn remember_request(request_id: &str, req: &JsRequest) {
+    let mut req = auto_result!(req.copy_self().await,err=>{
+        error!("copy request failed {err}");
+        return;
+    });
+    req.is_mut = false;
+    let mut requests = CLIENT_MANAGER.requests.write().await;
+    requests.insert(request_id.to_string(), req);
 }
 
 #[async_trait]
@@ -881,7 +964,6 @@ impl WebSocketHandler for Handler {
         msg: Message,
     ) -> Answer<Message, Message> {
         let uri = ctx.uri();
-        let addr = ctx.addr();
         let host = uri.host().unwrap_or_default();
         let allow = app_filter(host).await;
 
@@ -894,7 +976,9 @@ impl WebSocketHandler for Handler {
 
         let guard = CLIENT_MANAGER.ctx_map_scope_keys.read().await;
         //由于ws是长连接所以不能将scopekey直接取出，只能使用它的引用clone，否则下次消息就无法找到它的scopekey
-        let scope_key = auto_option!(guard.get(&(addr, uri)), Answer::Release(jsmsg.msg)).clone();
+        let scope_key = auto_option!(guard.get(ctx.request_id()), Answer::Release(jsmsg.msg)).clone();
+        drop(guard);
+        CLIENT_MANAGER.touch_scope(&scope_key.id).await;
 
         let action = on_message(&scope_key, jsmsg, client_to_server).await;
         match action.action {
@@ -906,6 +990,7 @@ impl WebSocketHandler for Handler {
                 });
                 let mut tasks = ASYNC_TASK_MANNAGER.tasks.write().await;
                 tasks.push(join);
+                drop(tasks);
 
                 tokio::time::sleep(tokio::time::Duration::from_millis(ms)).await;
                 Answer::Release(msg.msg)
@@ -932,4 +1017,10 @@ impl WebSocketHandler for Handler {
             }
         }
     }
+
+    async fn handle_close(&mut self, ctx: &WebSocketContext) {
+        //websocket关闭后释放连接与域的关联
+        let mut guard = CLIENT_MANAGER.ctx_map_scope_keys.write().await;
+        guard.remove(ctx.request_id());
+    }
 }
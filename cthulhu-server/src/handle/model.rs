@@ -3,9 +3,9 @@ use std::io;
 use chrono::Local;
 use serde::{Deserialize, Serialize};
 
-use crate::utils;
+use crate::{matcher::MatchRule, utils};
 
-#[derive(Serialize, Deserialize, sqlx::FromRow, Debug)]
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Plugin {
     pub id: String,
@@ -25,9 +25,16 @@ pub struct Plugin {
     pub content_paths: String,
     #[serde(skip)]
     pub dynamic_links: String,
+    //script.contents 的注入时机、环境和匹配规则，json 数组
+    #[serde(skip)]
+    pub content_scripts: String,
     pub matches: String,
+    //注入顺序，越大越先注入
+    pub priority: i64,
     pub net_monitor: i64, //网络监听权限
     pub net_modify: i64,  //网络修改权限
+    //修改带 integrity 的资源后的处理方式，rewrite 或 strip，为空时不处理
+    pub sri: String,
     pub enable: i64,
     pub install_time: chrono::DateTime<Local>,
 }
@@ -44,19 +51,65 @@ impl Plugin {
         let vec = utils::read_bytes(path)?;
         Ok(String::from_utf8(vec).unwrap())
     }
-    pub fn read_contents(&self) -> io::Result<Vec<String>> {
-        let mut js = vec![];
-        for ctx_path in self.content_paths.split(",") {
-            let relative_path = relative_path::RelativePath::new(ctx_path);
-            let path = relative_path.to_path(&self.path);
-            let vec = utils::read_bytes(path)?;
-            let from_utf8 = String::from_utf8(vec).unwrap();
-            js.push(from_utf8)
+    pub fn read_content(&self, path: &str) -> io::Result<String> {
+        let relative_path = relative_path::RelativePath::new(path);
+        let path = relative_path.to_path(&self.path);
+        let vec = utils::read_bytes(path)?;
+        Ok(String::from_utf8_lossy(&vec).to_string())
+    }
+    ///按 manifest 中的顺序返回内容脚本，旧版本只记录了路径，按默认配置处理
+    pub fn content_scripts(&self) -> Vec<ContentScript> {
+        if let Ok(scripts) = serde_json::from_str::<Vec<ContentScript>>(&self.content_scripts) {
+            return scripts;
         }
-        Ok(js)
+        self.content_paths
+            .split(",")
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|path| ContentScript {
+                path: path.to_string(),
+                ..Default::default()
+            })
+            .collect()
     }
 }
 
+///插件的内容脚本，对应 plugin.json 中 script.contents 的一项
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentScript {
+    pub path: String,
+    #[serde(default)]
+    pub run_at: RunAt,
+    #[serde(default)]
+    pub world: World,
+    //在插件 matches 的基础上再按页面筛选，为空时不限制
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<MatchRule>,
+}
+///内容脚本的执行时机
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunAt {
+    //在页面的所有脚本之前
+    #[default]
+    DocumentStart,
+    //文档解析完成后，DOMContentLoaded 之前
+    DocumentEnd,
+    //DOMContentLoaded 之后空闲时
+    DocumentIdle,
+}
+///内容脚本的执行环境
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum World {
+    //和页面共享全局变量
+    #[default]
+    Page,
+    //代理无法创建真正的隔离环境，只把脚本包在独立的作用域中，顶层变量不暴露给页面
+    Isolated,
+}
+
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
@@ -83,3 +136,33 @@ pub struct CertCfg {
     pub key: String,
     pub cert: String,
 }
+
+///调试规则，kind 为 local 本地映射、remote 远程映射、header 头部改写
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    #[serde(default)]
+    pub id: i64,
+    pub kind: String,
+    //匹配完整地址，默认为通配，regex 为1时是正则
+    pub pattern: String,
+    #[serde(default)]
+    pub regex: i64,
+    //local 为文件或目录路径，remote 为目标地址
+    #[serde(default)]
+    pub target: String,
+    //header 规则作用于 request 或 response
+    #[serde(default)]
+    pub phase: String,
+    //header 规则的操作 add remove replace
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default)]
+    pub enable: i64,
+    #[serde(default)]
+    pub remark: String,
+}
@@ -1,11 +1,7 @@
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-};
+use std::sync::Arc;
 
-use content_security_policy::{Directive, Policy, PolicyDisposition, PolicySource};
 use futures::future::Either;
-use rquickjs::{async_with, CatchResultExt, Object};
+use rquickjs::{async_with, CatchResultExt};
 
 use tracing::instrument;
 
@@ -18,19 +14,22 @@ use crate::{
         server::{self, Scope},
         ws::*,
     },
-    PLUGIN_MANAGER,
+    matcher::MatchTarget,
+    sri, METRICS, PLUGIN_MANAGER,
 };
 
 #[instrument(skip(jsreq))]
 pub async fn on_request(scope_key: &Scope, jsreq: JsRequest) -> JsHttpAction {
-    let (_all, _monitors, modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
+    let target = MatchTarget::from_request(&scope_key.host, &jsreq);
+    let (_all, _monitors, modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
     let modify = auto_option!(modify, JsHttpAction::release(jsreq));
     let ctx = &modify.ctx.as_ref().unwrap();
     let ctx = ctx.lock().await;
     let scope_key = scope_key.clone();
+    let plugin_id = modify.plugin.id.clone();
     return async_with!(ctx=>|ctx|{
 
-        let res=server::call_function::<JsHttpAction,_>(&ctx, "onRequest", ( jsreq.clone(),scope_key)).await
+        let res=server::call_hook::<JsHttpAction,_>(&ctx, "onRequest", ( jsreq.clone(),scope_key.clone()), &scope_key.id, &jsreq.id).await
         .catch(&ctx);
 
         let res= auto_result!(res,err=>{
@@ -38,22 +37,37 @@ pub async fn on_request(scope_key: &Scope, jsreq: JsRequest) -> JsHttpAction {
                 return JsHttpAction::release(jsreq);
         });
         match res {
-           Either::Left(v) => v,
+           Either::Left(v) => {
+                METRICS.plugins.count_action(&plugin_id, "onRequest", v.action.name());
+                v
+           },
            Either::Right((jsreq,_)) => JsHttpAction::release(jsreq),
         }
 })    .await;
 }
 
-#[instrument(skip(jsres))]
-pub async fn on_response(scope_key: &Scope, jsres: JsResponse) -> JsResponse {
-    let (_all, _monitors, modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
+///jsreq 为插件修改后实际发出的请求，info 为上游的耗时和连接信息
+#[instrument(skip(jsres, jsreq, info))]
+pub async fn on_response(
+    scope_key: &Scope,
+    jsres: JsResponse,
+    jsreq: Option<JsRequest>,
+    info: JsUpstreamInfo,
+) -> JsResponse {
+    let target = response_target(scope_key, &jsreq);
+    let (_all, _monitors, modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
     let modify = auto_option!(modify, jsres);
+    if !modify.on_response {
+        return jsres;
+    }
 
+    //插件替换了body时按修改后的内容处理页面中的 integrity
+    let original = sri::snapshot(&modify.plugin, &scope_key.id, &target, &jsres).await;
     let ctx = modify.ctx.as_ref().unwrap();
     let ctx = ctx.lock().await;
     let scope_key = scope_key.clone();
     let result: JsResponse = async_with!(ctx=>|ctx|{
-        let res=server::call_function::<JsResponse,_>(&ctx, "onResponse", ( jsres.clone(),scope_key)).await
+        let res=server::call_hook::<JsResponse,_>(&ctx, "onResponse", ( jsres.clone(),scope_key.clone(),jsreq,info), &scope_key.id, &jsres.id).await
                 .catch(&ctx);
 
         let res= auto_result!(res,err=>{
@@ -62,14 +76,70 @@ pub async fn on_response(scope_key: &Scope, jsres: JsResponse) -> JsResponse {
         });
         match res {
            Either::Left(v) => v,
-           Either::Right((jsres,_)) => jsres,
+           Either::Right((jsres, ..)) => jsres,
         }
 
     })
     .await;
+    if let Some(original) = original {
+        sri::track(&modify.plugin, &scope_key.id, &target, &original, &result).await;
+    }
     result
 }
 
+///定义了 onScript 并且有网络修改权限的插件，按注入顺序排列
+pub async fn script_plugins(target: &MatchTarget) -> Vec<Arc<PluginCtx>> {
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(target).await;
+    all.into_iter()
+        .filter(|v| v.plugin.net_modify >= 1 && v.on_script)
+        .collect()
+}
+
+///脚本源码依次交给插件的 onScript 改写，前一个插件的结果交给下一个，返回 None 表示没有插件修改
+#[instrument(skip(plugins, source))]
+pub async fn on_script(
+    scope_key: &Scope,
+    plugins: &[Arc<PluginCtx>],
+    url: &str,
+    request_id: &str,
+    source: String,
+) -> Option<String> {
+    let mut source = source;
+    let mut changed = vec![];
+    for plugin in plugins {
+        let ctx = plugin.ctx.as_ref().unwrap();
+        let ctx = ctx.lock().await;
+        let scope_key = scope_key.clone();
+        let (input, url, request_id) = (source.clone(), url.to_string(), request_id.to_string());
+        let res: Option<String> = async_with!(ctx=>|ctx|{
+            let res=server::call_hook::<Option<String>,_>(&ctx, "onScript", (input, url, scope_key.clone()), &scope_key.id, &request_id).await
+                .catch(&ctx);
+            let res= auto_result!(res,err=>{
+                jsbind::handle_js_error(err,&ctx);
+                return None;
+            });
+            match res {
+                Either::Left(v) => v,
+                Either::Right(_) => None,
+            }
+        })
+        .await;
+        //返回 undefined 或原样返回表示不修改
+        if let Some(res) = res {
+            if res != source {
+                source = res;
+                changed.push(&plugin.plugin);
+            }
+        }
+    }
+    if changed.is_empty() {
+        sri::forget(&scope_key.id, url).await;
+        return None;
+    }
+    sri::record(&changed, &scope_key.id, url, source.as_bytes()).await;
+    Some(source)
+}
+
 #[instrument(skip(msg))]
 pub async fn on_message(scope_key: &Scope, msg: JsMessage, client_to_server: bool) -> JsWsAction {
     let (_all, _monitors, modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
@@ -79,8 +149,9 @@ pub async fn on_message(scope_key: &Scope, msg: JsMessage, client_to_server: boo
     let ctx = ctx.lock().await;
 
     let scope_key = scope_key.clone();
+    let plugin_id = modify.plugin.id.clone();
     let result = async_with!(ctx=>|ctx|{
-        let res=server::call_function::<JsWsAction,_>(&ctx, "onMessage", ( msg.clone(),scope_key)).await
+        let res=server::call_hook::<JsWsAction,_>(&ctx, "onMessage", ( msg.clone(),scope_key.clone()), &scope_key.id, "").await
                 .catch(&ctx);
 
         let res= auto_result!(res,err=>{
@@ -88,7 +159,10 @@ pub async fn on_message(scope_key: &Scope, msg: JsMessage, client_to_server: boo
                 return JsWsAction::release(msg);
         });
         match res {
-            Either::Left(v) => v,
+            Either::Left(v) => {
+                METRICS.plugins.count_action(&plugin_id, "onMessage", v.action.name());
+                v
+            },
             Either::Right((msg, _)) => JsWsAction::release(msg),
          }
     })
@@ -99,7 +173,8 @@ pub async fn on_message(scope_key: &Scope, msg: JsMessage, client_to_server: boo
 
 #[instrument(skip(jsreq))]
 pub async fn watch_request(scope_key: &Scope, jsreq: JsRequest) {
-    let (_all, monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
+    let target = MatchTarget::from_request(&scope_key.host, &jsreq);
+    let (_all, monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
     if monitors.is_empty() {
         return;
     }
@@ -113,7 +188,8 @@ pub async fn watch_request(scope_key: &Scope, jsreq: JsRequest) {
             let ctx = ctx.lock().await;
             let scope_key = scope_key.clone();
             async_with!(ctx=> |ctx|{
-                let res=server::call_function::<(),_>(&ctx, "watchRequest", (jsreq,scope_key)).await
+                let request_id = jsreq.id.clone();
+                let res=server::call_hook::<(),_>(&ctx, "watchRequest", (jsreq,scope_key.clone()), &scope_key.id, &request_id).await
                 .catch(&ctx);
                  auto_result!(res,err=>{
                     jsbind::handle_js_error(err,&ctx);
@@ -127,9 +203,15 @@ pub async fn watch_request(scope_key: &Scope, jsreq: JsRequest) {
     futures::future::join_all(vec).await;
 }
 
-#[instrument(skip(jsres))]
-pub async fn watch_response(scope_key: &Scope, jsres: JsResponse) {
-    let (_all, monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
+#[instrument(skip(jsres, jsreq, info))]
+pub async fn watch_response(
+    scope_key: &Scope,
+    jsres: JsResponse,
+    jsreq: Option<JsRequest>,
+    info: JsUpstreamInfo,
+) {
+    let target = response_target(scope_key, &jsreq);
+    let (_all, monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
     if monitors.is_empty() {
         return;
     }
@@ -138,12 +220,15 @@ pub async fn watch_response(scope_key: &Scope, jsres: JsResponse) {
     jsres.is_mut = false;
     for plugin in monitors {
         let jsres = jsres.clone();
+        let jsreq = jsreq.clone();
+        let info = info.clone();
         let fut = async move {
             let ctx = plugin.ctx.as_ref().unwrap();
             let ctx = ctx.lock().await;
             let scope_key = scope_key.clone();
             async_with!(ctx=> |ctx|{
-                let res=server::call_function::<(),_>(&ctx, "watchResponse", ( jsres,scope_key)).await
+                let request_id = jsres.id.clone();
+                let res=server::call_hook::<(),_>(&ctx, "watchResponse", ( jsres,scope_key.clone(),jsreq,info), &scope_key.id, &request_id).await
                 .catch(&ctx);
                  auto_result!(res,err=>{
                      jsbind::handle_js_error(err,&ctx);
@@ -156,6 +241,13 @@ pub async fn watch_response(scope_key: &Scope, jsres: JsResponse) {
     }
     futures::future::join_all(vec).await;
 }
+//响应按对应的请求匹配插件，没有请求时只按域名匹配
+fn response_target(scope_key: &Scope, jsreq: &Option<JsRequest>) -> MatchTarget {
+    match jsreq {
+        Some(jsreq) => MatchTarget::from_request(&scope_key.host, jsreq),
+        None => MatchTarget::origin(&scope_key.host),
+    }
+}
 #[instrument(skip(jsmsg))]
 pub async fn watch_message(scope_key: &Scope, jsmsg: JsMessage, client_to_server: bool) {
     let (_all, monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
@@ -170,7 +262,7 @@ pub async fn watch_message(scope_key: &Scope, jsmsg: JsMessage, client_to_server
             let ctx = ctx.lock().await;
             let scope_key = scope_key.clone();
             async_with!(ctx=> |ctx|{
-                let res=server::call_function::<(),_>(&ctx, "watchMessage", (jsmsg,scope_key)).await
+                let res=server::call_hook::<(),_>(&ctx, "watchMessage", (jsmsg,scope_key.clone()), &scope_key.id, "").await
                 .catch(&ctx);
 
                  auto_result!(res,err=>{
@@ -186,123 +278,53 @@ pub async fn watch_message(scope_key: &Scope, jsmsg: JsMessage, client_to_server
     futures::future::join_all(vec).await;
 }
 
+///通知匹配该域的插件有新的域出现
 #[instrument]
-pub async fn content_security_policy(plugins: &Vec<Arc<PluginCtx>>, csp_str: &str) -> String {
-    let base_policy = Policy::parse(csp_str, PolicySource::Header, PolicyDisposition::Enforce);
-    let mut policies = vec![];
-
-    let script = Policy::parse(
-        "script-src 'self' 'unsafe-eval' 'unsafe-inline'  https://*.cthulhu.server",
-        PolicySource::Header,
-        PolicyDisposition::Enforce,
-    );
-    let script_elem = Policy::parse(
-        "script-src-elem 'self' 'unsafe-eval' 'unsafe-inline'  https://*.cthulhu.server",
-        PolicySource::Header,
-        PolicyDisposition::Enforce,
-    );
-    policies.push(script);
-    policies.push(script_elem);
-
-    let style = Policy::parse(
-        "style-src 'self' 'unsafe-inline' https://*.cthulhu.server",
-        PolicySource::Header,
-        PolicyDisposition::Enforce,
-    );
-    policies.push(style);
-    let connect = Policy::parse(
-        "connect-src 'self' 'unsafe-inline' https://*.cthulhu.server wss://*.cthulhu.server",
-        PolicySource::Header,
-        PolicyDisposition::Enforce,
-    );
-    policies.push(connect);
-    let items = vec![
-        "worker-src",
-        "object-src",
-        "frame-src",
-        "child-src",
-        "img-src",
-        // "font-src",
-    ];
-    for item in items {
-        let item = format!("{item} 'self' https://*.cthulhu.server");
-        let item = Policy::parse(
-            item.as_str(),
-            PolicySource::Header,
-            PolicyDisposition::Enforce,
-        );
-        policies.push(item);
-    }
-
-    for plugin in plugins {
-        let ctx = plugin.ctx.as_ref().unwrap();
-
-        let ctx = ctx.lock().await;
-
-        let csp = async_with!(ctx=> |ctx|{
-            let globals=ctx.globals();
-            let server=globals.get::<_,Object>("server").unwrap();
-            let csp= server.get::<_,String>("csp").unwrap_or_default();
-            let policy = Policy::parse(csp.as_str(), PolicySource::Header, PolicyDisposition::Enforce);
-            if !policy.is_valid() {
-                let _=ctx.throw(rquickjs::Value::from_string(rquickjs::String::from_str(ctx.clone(), "invalid content security policy").unwrap()));
-                return None;
-            }
-            Some(policy)
-        })
-        .await;
-        if let Some(csp) = csp {
-            policies.push(csp);
-        }
+pub async fn on_scope_create(scope_key: &Scope) {
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
+    let mut vec = vec![];
+    for plugin in all {
+        let fut = async move {
+            let ctx = plugin.ctx.as_ref().unwrap();
+            let ctx = ctx.lock().await;
+            let scope_key = scope_key.clone();
+            async_with!(ctx=> |ctx|{
+                let res=server::call_hook::<(),_>(&ctx, "onScopeCreate", (scope_key.clone(),), &scope_key.id, "").await
+                .catch(&ctx);
+                 auto_result!(res,err=>{
+                    jsbind::handle_js_error(err,&ctx);
+                    return;
+                });
+            })
+            .await;
+        };
+        vec.push(fut);
     }
+    futures::future::join_all(vec).await;
+}
 
-    let mut base_csp: HashMap<String, HashSet<String>> = HashMap::new();
-    let mut csp: HashMap<String, HashSet<String>> = HashMap::new();
-    fn to_map(policy: Policy, map: &mut HashMap<String, HashSet<String>>) {
-        for directive in policy.directive_set {
-            let name = directive.name;
-            let values = match map.get_mut(&name) {
-                Some(v) => v,
-                None => {
-                    map.insert(name.clone(), HashSet::new());
-                    map.get_mut(&name).unwrap()
-                }
-            };
-            directive.value.into_iter().for_each(|v| {
-                values.insert(v);
-            });
-        }
-    }
-    to_map(base_policy, &mut base_csp);
-    for policy in policies {
-        to_map(policy, &mut csp);
+///通知匹配该域的插件域已经关闭，reason 为 ttl 或 closed
+#[instrument]
+pub async fn on_scope_expire(scope_key: &Scope, reason: &str) {
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(&scope_key.host).await;
+    let mut vec = vec![];
+    for plugin in all {
+        let fut = async move {
+            let ctx = plugin.ctx.as_ref().unwrap();
+            let ctx = ctx.lock().await;
+            let scope_key = scope_key.clone();
+            let reason = reason.to_string();
+            async_with!(ctx=> |ctx|{
+                let res=server::call_hook::<(),_>(&ctx, "onScopeExpire", (scope_key.clone(), reason), &scope_key.id, "").await
+                .catch(&ctx);
+                 auto_result!(res,err=>{
+                    jsbind::handle_js_error(err,&ctx);
+                    return;
+                });
+            })
+            .await;
+        };
+        vec.push(fut);
     }
-    //将网页原本csp中没有的指令都删掉，避免加了来自插件的限制后影响到网页的资源加载
-    csp.into_iter().for_each(|(name, v)| {
-        if let Some(values) = base_csp.get_mut(&name) {
-            values.extend(v);
-        }
-    });
-
-    let directives = base_csp
-        .into_iter()
-        .map(|(name, mut values)| {
-            values.remove("'none'"); //none的意思是不加载任何资源，必须删除
-            if name == "script-src" || name == "script-src-elem" {
-                //不删除的话不能加载外部脚本
-                values.remove("'strict-dynamic'");
-                values.retain(|v| !(v.starts_with("'nonce-") || v.starts_with("'sha")));
-            }
-            Directive {
-                name,
-                value: Vec::from_iter(values.into_iter()),
-            }
-        })
-        .collect::<Vec<Directive>>();
-    let policy = Policy {
-        directive_set: directives,
-        disposition: PolicyDisposition::Enforce,
-        source: PolicySource::Header,
-    };
-    policy.to_string()
+    futures::future::join_all(vec).await;
 }
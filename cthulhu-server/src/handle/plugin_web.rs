@@ -10,11 +10,11 @@ use hyper::Body;
 use rquickjs::{async_with, CatchResultExt};
 use serde_json::json;
 
-use crate::{auto_option, auto_result, handle::scope_key_from_request, jsbind::{self, server}, PLUGIN_MANAGER};
+use crate::{auto_option, auto_result, handle::scope_key_from_request, jsbind::{self, server}, utils, PLUGIN_MANAGER};
 
 use super::api::{self, detect};
 use super::response_json;
-use super::{response_file, response_msg};
+use super::{inject, response_file, response_msg};
 fn split_path(path:&str)->(&str,&str){
     let mut iter = path.splitn(3, "/").skip(1);
   (  iter.next().unwrap_or_default(),iter.next().unwrap_or_default())
@@ -43,6 +43,18 @@ pub async fn handle_plugin(ctx: &HttpContext, req: Request<Body>) -> Response<Bo
   
     let file_path = relative_path::RelativePath::new(&path).to_logical_path(base);
     if file_path.is_file() {
+        //world 为 isolated 的内容脚本
+        if req.uri().query() == Some("world=isolated") {
+            let bytes = auto_result!(utils::read_bytes(&file_path),err=>{
+                return response_msg(404, err.to_string());
+            });
+            let code = String::from_utf8_lossy(&bytes);
+            return hyper::Response::builder()
+                .status(200)
+                .header(CONTENT_TYPE, "application/javascript; charset=utf-8")
+                .body(inject::isolate(&code).into())
+                .unwrap();
+        }
         return response_file(file_path.to_str().unwrap()).await;
     }
     if let Some((_,end))= path.split_once(".") {
@@ -62,9 +74,10 @@ pub async fn dynamic_script(ctx: &HttpContext, mut req: Request<Body>,id:&str) -
     let (_,link) = split_path(&path);
   
     let scope_key = {
-        auto_result!(scope_key_from_request(&ctx.client_addr,&mut req),err=>{
+        let (scope_key, _) = auto_result!(scope_key_from_request(&ctx.client_addr,&mut req).await,err=>{
             return super::response_msg(500, err);
-        })
+        });
+        scope_key
     };
     let ctx = auto_option!(
         PLUGIN_MANAGER.get_ctx(id).await,
@@ -73,7 +86,7 @@ pub async fn dynamic_script(ctx: &HttpContext, mut req: Request<Body>,id:&str) -
     let ctx = ctx.ctx.as_ref().unwrap();
     let ctx = ctx.lock().await;
     let response = async_with!(ctx=>|ctx|{
-        let res=server::call_function::<Option<String>,_>(&ctx, "dynamicScript", (link, scope_key)).await.catch(&ctx);
+        let res=server::call_hook::<Option<String>,_>(&ctx, "dynamicScript", (link, scope_key.clone()), &scope_key.id, "").await.catch(&ctx);
         let res=auto_result!(res,err=>{
             let e=err.to_string();
             jsbind::handle_js_error(err,&ctx);
@@ -117,7 +130,7 @@ async fn ask(_ctx: &HttpContext, req: Request<Body>,id:&str) -> Response<Body> {
     let ctx = ctx.lock().await;
     let json = async_with!(ctx=>|ctx|{
         let body=jsbind::json_to_js(data, &ctx).unwrap();
-        let res=server::call_function::<rquickjs::Value<'_>,_>(&ctx, "onAsk", (key, body, scope_key)).await.catch(&ctx);
+        let res=server::call_hook::<rquickjs::Value<'_>,_>(&ctx, "onAsk", (key, body, scope_key.clone()), &scope_key.id, "").await.catch(&ctx);
         let res=auto_result!(res,err=>{
             let e=err.to_string();
             jsbind::handle_js_error(err,&ctx);
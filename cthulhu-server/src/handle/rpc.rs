@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+use futures::future::Either;
+use hyper_tungstenite::tungstenite::Message;
+use rquickjs::{
+    async_with, function::This, CatchResultExt, Ctx, FromJs, Object, Persistent, Value as JsValue,
+};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tracing::error;
+
+use crate::{
+    auto_option,
+    core::PluginCtx,
+    jsbind::{
+        self,
+        server::{self, Scope},
+    },
+    CLIENT_MANAGER, PLUGIN_MANAGER,
+};
+
+//JSON-RPC 2.0 的错误码
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const SERVER_ERROR: i64 = -32000;
+
+///socket 会话，页面调用插件时带上会话所在的域
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub session_id: String,
+    pub scope: Scope,
+}
+
+//插件的 onCall 返回普通值或者迭代器，迭代器的每一项推送给页面
+enum Outcome {
+    Value(Value),
+    Stream(Persistent<Object<'static>>),
+}
+
+fn rpc_error<M: Into<String>>(code: i64, message: M) -> Value {
+    json!({"code":code,"message":message.into()})
+}
+//同一个rpc id在不同会话中可能重复，带上会话id区分
+fn rpc_key(session_id: &str, id: &Value) -> String {
+    format!("{session_id}/{id}")
+}
+async fn send(session_id: &str, value: Value) -> Result<(), String> {
+    CLIENT_MANAGER
+        .send_message(session_id, Message::Text(value.to_string()))
+        .await
+}
+
+///处理页面发来的 JSON-RPC 消息：调用插件的请求或通知、对插件调用的回复、取消流式结果
+pub async fn dispatch(session: Session, msg: Value) {
+    let id = msg.get("id").filter(|v| !v.is_null()).cloned();
+    let method = msg
+        .get("method")
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string());
+    let method = match method {
+        Some(method) => method,
+        None => {
+            if let Some(id) = id {
+                reply_received(&session.session_id, &id, &msg).await;
+            }
+            return;
+        }
+    };
+    if method == "rpc.cancel" {
+        let target = msg.pointer("/params/id").cloned().unwrap_or_default();
+        let mut streams = CLIENT_MANAGER.rpc_streams.lock().await;
+        streams.remove(&rpc_key(&session.session_id, &target));
+        return;
+    }
+    let params = msg.get("params").cloned().unwrap_or_default();
+    //插件处理期间socket还要继续收消息，比如插件在等待的页面回复
+    tokio::spawn(async move {
+        let res = call_plugin(&session, id.as_ref(), &method, params).await;
+        //通知不需要回复
+        let id = auto_option!(id, ());
+        let reply = match res {
+            Ok(result) => json!({"jsonrpc":"2.0","id":id,"result":result}),
+            Err(error) => json!({"jsonrpc":"2.0","id":id,"error":error}),
+        };
+        if let Err(err) = send(&session.session_id, reply).await {
+            error!("rpc回复发送失败：{err}");
+        }
+    });
+}
+
+//页面对插件调用的回复，交给等待中的 server.call
+async fn reply_received(session_id: &str, id: &Value, msg: &Value) {
+    let sender = CLIENT_MANAGER
+        .rpc_calls
+        .lock()
+        .await
+        .remove(&rpc_key(session_id, id));
+    let sender = auto_option!(sender, ());
+    let res = match msg.get("error") {
+        Some(error) if !error.is_null() => Err(error.clone()),
+        _ => Ok(msg.get("result").cloned().unwrap_or_default()),
+    };
+    let _ = sender.send(res);
+}
+
+//method 为 插件id.方法名，交给插件的 onCall
+async fn call_plugin(
+    session: &Session,
+    id: Option<&Value>,
+    method: &str,
+    params: Value,
+) -> Result<Value, Value> {
+    let (plugin_id, name) = method.split_once('.').ok_or_else(|| {
+        rpc_error(
+            INVALID_REQUEST,
+            format!("方法名应为 插件id.方法名：{method}"),
+        )
+    })?;
+    //只能调用匹配会话所在域的插件
+    let (plugins, _, _) = PLUGIN_MANAGER.ctxs_by_host(&session.scope.host).await;
+    let plugin = plugins
+        .into_iter()
+        .find(|v| v.plugin.id == plugin_id)
+        .ok_or_else(|| {
+            rpc_error(
+                METHOD_NOT_FOUND,
+                format!("插件 {plugin_id} 不存在、未启用或不匹配当前页面"),
+            )
+        })?;
+    let ctx = plugin
+        .ctx
+        .as_ref()
+        .ok_or_else(|| rpc_error(METHOD_NOT_FOUND, "插件没有server脚本"))?;
+    //onCall 中可以用 server.call 等待页面回复，这里不持有插件上下文的锁，
+    //等待期间插件可以继续处理其它钩子
+    let ctx = ctx.lock().await.clone();
+    let name = name.to_string();
+    let scope = session.scope.clone();
+    let session_id = session.session_id.clone();
+    let outcome = async_with!(ctx=>|ctx|{
+        let res: rquickjs::Result<Option<Outcome>> = async {
+            let params = jsbind::json_to_js(params, &ctx)?;
+            let args = (name, params, scope.clone(), session_id);
+            let res = server::call_hook::<JsValue, _>(&ctx, "onCall", args, &scope.id, "").await?;
+            let value = match res {
+                Either::Left(value) => value,
+                Either::Right(_args) => return Ok(None),
+            };
+            match value.as_object() {
+                Some(obj) if is_iterator(obj) => {
+                    let obj = obj.clone();
+                    Ok(Some(Outcome::Stream(Persistent::save(&ctx, obj))))
+                }
+                _ => Ok(Some(Outcome::Value(jsbind::js_to_json(value)?))),
+            }
+        }
+        .await;
+        res.catch(&ctx).map_err(|err| {
+            let msg = err.to_string();
+            jsbind::handle_js_error(err, &ctx);
+            rpc_error(SERVER_ERROR, msg)
+        })
+    })
+    .await?;
+    match outcome {
+        None => Err(rpc_error(METHOD_NOT_FOUND, "插件没有定义 onCall")),
+        Some(Outcome::Value(value)) => Ok(value),
+        Some(Outcome::Stream(iter)) => match id {
+            Some(id) => stream(&plugin, session, id, iter).await,
+            None => {
+                let _ = step(&plugin, &iter, "return").await;
+                Ok(Value::Null)
+            }
+        },
+    }
+}
+
+fn is_iterator(obj: &Object<'_>) -> bool {
+    obj.get::<_, JsValue>("next")
+        .map(|v| v.is_function())
+        .unwrap_or(false)
+}
+
+//值是promise时等待其完成
+async fn settle<'js>(ctx: &Ctx<'js>, value: JsValue<'js>) -> rquickjs::Result<JsValue<'js>> {
+    let is_promise = value
+        .as_object()
+        .map(|v| {
+            v.get::<_, JsValue>("then")
+                .map(|v| v.is_function())
+                .unwrap_or(false)
+        })
+        .unwrap_or(false);
+    if is_promise {
+        rquickjs::promise::Promise::<JsValue>::from_js(ctx, value)?.await
+    } else {
+        Ok(value)
+    }
+}
+
+//逐项推送迭代器的结果，每取一项重新进入插件上下文，推送期间插件可以处理其它钩子。
+//全部推送完后用 null 回复这次调用
+async fn stream(
+    plugin: &PluginCtx,
+    session: &Session,
+    id: &Value,
+    iter: Persistent<Object<'static>>,
+) -> Result<Value, Value> {
+    let key = rpc_key(&session.session_id, id);
+    CLIENT_MANAGER.rpc_streams.lock().await.insert(key.clone());
+    let mut finished = false;
+    let res = loop {
+        //页面取消或者会话已关闭
+        if !CLIENT_MANAGER.rpc_streams.lock().await.contains(&key) {
+            break Ok(Value::Null);
+        }
+        match step(plugin, &iter, "next").await {
+            Ok(Some(value)) => {
+                let msg =
+                    json!({"jsonrpc":"2.0","method":"rpc.stream","params":{"id":id,"value":value}});
+                if let Err(err) = send(&session.session_id, msg).await {
+                    break Err(rpc_error(SERVER_ERROR, err));
+                }
+            }
+            Ok(None) => {
+                finished = true;
+                break Ok(Value::Null);
+            }
+            Err(err) => {
+                finished = true;
+                break Err(err);
+            }
+        }
+    };
+    CLIENT_MANAGER.rpc_streams.lock().await.remove(&key);
+    //提前结束时让生成器执行 finally 中的清理
+    if !finished {
+        let _ = step(plugin, &iter, "return").await;
+    }
+    res
+}
+
+//调用迭代器的 next 或 return，迭代结束时返回 None
+async fn step(
+    plugin: &PluginCtx,
+    iter: &Persistent<Object<'static>>,
+    method: &'static str,
+) -> Result<Option<Value>, Value> {
+    let ctx = auto_option!(plugin.ctx.as_ref(), Ok(None));
+    let ctx = ctx.lock().await;
+    let iter = iter.clone();
+    async_with!(ctx=>|ctx|{
+        let res: rquickjs::Result<Option<Value>> = async {
+            let iter = iter.restore(&ctx)?;
+            let func = match iter.get::<_, JsValue>(method)?.into_function() {
+                Some(func) => func,
+                None => return Ok(None),
+            };
+            let result = func.call::<_, JsValue>((This(iter),))?;
+            let result = match settle(&ctx, result).await?.into_object() {
+                Some(result) => result,
+                None => return Ok(None),
+            };
+            if result.get::<_, Option<bool>>("done")?.unwrap_or(false) {
+                return Ok(None);
+            }
+            let value = settle(&ctx, result.get::<_, JsValue>("value")?).await?;
+            Ok(Some(jsbind::js_to_json(value)?))
+        }
+        .await;
+        res.catch(&ctx).map_err(|err| {
+            let msg = err.to_string();
+            jsbind::handle_js_error(err, &ctx);
+            rpc_error(SERVER_ERROR, msg)
+        })
+    })
+    .await
+}
+
+///插件调用页面用 CthulhuServer.handle 注册的方法，等待页面回复。
+///超时或者会话关闭时返回错误
+pub async fn call_page(
+    session_id: &str,
+    method: &str,
+    params: Value,
+    timeout: Duration,
+) -> Result<Value, String> {
+    let id = Value::from(uuid::Uuid::new_v4().simple().to_string());
+    let key = rpc_key(session_id, &id);
+    let (sender, receiver) = oneshot::channel();
+    CLIENT_MANAGER
+        .rpc_calls
+        .lock()
+        .await
+        .insert(key.clone(), sender);
+    let msg = json!({"jsonrpc":"2.0","id":id,"method":method,"params":params});
+    let res = match send(session_id, msg).await {
+        Ok(_) => tokio::time::timeout(timeout, receiver).await,
+        Err(err) => {
+            CLIENT_MANAGER.rpc_calls.lock().await.remove(&key);
+            return Err(err);
+        }
+    };
+    CLIENT_MANAGER.rpc_calls.lock().await.remove(&key);
+    match res {
+        Err(_) => Err(format!("调用页面方法 {method} 超时")),
+        Ok(Err(_)) => Err("页面会话已关闭".to_string()),
+        Ok(Ok(Ok(value))) => Ok(value),
+        Ok(Ok(Err(error))) => Err(error
+            .get("message")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| error.to_string())),
+    }
+}
+
+///通知页面，不等待回复
+pub async fn notify_page(session_id: &str, method: &str, params: Value) -> Result<(), String> {
+    let msg = json!({"jsonrpc":"2.0","method":method,"params":params});
+    send(session_id, msg).await
+}
+
+///会话关闭时结束等待中的调用和推送中的流
+pub async fn close_session(session_id: &str) {
+    let prefix = format!("{session_id}/");
+    let mut calls = CLIENT_MANAGER.rpc_calls.lock().await;
+    calls.retain(|k, _| !k.starts_with(&prefix));
+    drop(calls);
+    let mut streams = CLIENT_MANAGER.rpc_streams.lock().await;
+    streams.retain(|k| !k.starts_with(&prefix));
+}
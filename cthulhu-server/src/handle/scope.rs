@@ -0,0 +1,384 @@
+use std::net::SocketAddr;
+
+use futures::future::Either;
+use hyper::{
+    header::{HeaderValue, ACCEPT, COOKIE},
+    Body, Request,
+};
+use rquickjs::{async_with, CatchResultExt};
+
+use crate::{
+    auto_option,
+    jsbind::{self, http::*, server},
+    PLUGIN_MANAGER, SCOPE_IDENTITIES, SCOPE_STRATEGY,
+};
+
+use super::api::config;
+
+const DEFAULT_HEADER: &str = "cthulhu-profile";
+const DEFAULT_COOKIE: &str = "cthulhu_profile";
+//身份cookie的有效期，一年
+const COOKIE_MAX_AGE: u64 = 365 * 24 * 3600;
+
+///域的划分方式，对应配置 scopeStrategy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeStrategy {
+    //客户端地址、来源、UA 和 cthulhu-extra-scope 一起决定域
+    Default,
+    //同一个客户端地址访问同一个来源属于一个域
+    Ip,
+    //按代理下发的cookie区分浏览器配置
+    Cookie(String),
+    //按扩展或爬虫携带的请求头区分
+    Header(String),
+    //由插件的 resolveScope 返回身份标识，为空时使用插件所在域名的修改插件
+    Plugin(String),
+}
+impl ScopeStrategy {
+    ///每个请求都要用到，读取后缓存，配置修改后调用 invalidate
+    pub async fn load() -> Self {
+        if let Some(strategy) = SCOPE_STRATEGY.read().await.as_ref() {
+            return strategy.clone();
+        }
+        let mut cache = SCOPE_STRATEGY.write().await;
+        if let Some(strategy) = cache.as_ref() {
+            return strategy.clone();
+        }
+        let strategy = Self::read().await;
+        *cache = Some(strategy.clone());
+        strategy
+    }
+    pub async fn invalidate() {
+        *SCOPE_STRATEGY.write().await = None;
+    }
+    async fn read() -> Self {
+        let value = auto_option!(
+            config::get_config("scopeStrategy").await,
+            ScopeStrategy::Default
+        );
+        let str = |key: &str, default: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or(default.to_string())
+        };
+        match str("mode", "default").as_str() {
+            "ip" => ScopeStrategy::Ip,
+            "cookie" => ScopeStrategy::Cookie(str("cookie", DEFAULT_COOKIE)),
+            "header" => ScopeStrategy::Header(str("header", DEFAULT_HEADER).to_lowercase()),
+            "plugin" => ScopeStrategy::Plugin(str("plugin", "")),
+            _ => ScopeStrategy::Default,
+        }
+    }
+}
+
+///按策略得到的客户端身份
+#[derive(Debug, Default)]
+pub struct Identity {
+    //为空时使用默认的划分方式
+    pub value: String,
+    //需要通过响应下发的 Set-Cookie
+    pub set_cookie: Option<String>,
+}
+
+///从请求中解析客户端身份，会移除请求中只给代理使用的cookie和请求头。
+///scheme 和 host 是请求所在页面的来源
+pub async fn resolve_identity(
+    strategy: &ScopeStrategy,
+    addr: &SocketAddr,
+    scheme: &str,
+    host: &str,
+    req: &mut Request<Body>,
+) -> Identity {
+    match strategy {
+        ScopeStrategy::Default => Identity::default(),
+        ScopeStrategy::Ip => Identity {
+            value: addr.ip().to_string(),
+            set_cookie: None,
+        },
+        ScopeStrategy::Header(name) => {
+            let value = req
+                .headers_mut()
+                .remove(name.as_str())
+                .map(|v| v.to_str().unwrap_or("").trim().to_string())
+                .unwrap_or_default();
+            Identity {
+                value,
+                set_cookie: None,
+            }
+        }
+        ScopeStrategy::Cookie(name) => {
+            //同一个出口地址后面可能有多个浏览器配置，记录访问过这个页面的所有身份
+            let page = format!("{}|{scheme}://{host}", addr.ip());
+            let seen = SCOPE_IDENTITIES
+                .get_with(page, async { Default::default() })
+                .await;
+            if let Some(value) = take_cookie(req, name) {
+                seen.lock().unwrap().insert(value.clone());
+                return Identity {
+                    value,
+                    set_cookie: None,
+                };
+            }
+            //跨站的子资源请求不带页面的cookie，只有一个身份访问过该页面时才能确定沿用哪个，
+            //否则使用默认的划分方式
+            if !is_navigation(req) {
+                let seen = seen.lock().unwrap();
+                let value = match seen.len() {
+                    1 => seen.iter().next().cloned().unwrap_or_default(),
+                    _ => String::new(),
+                };
+                return Identity {
+                    value,
+                    set_cookie: None,
+                };
+            }
+            //只在顶层页面的导航请求中分配新的身份
+            let value = uuid::Uuid::new_v4().simple().to_string();
+            seen.lock().unwrap().insert(value.clone());
+            let set_cookie =
+                format!("{name}={value}; Path=/; Max-Age={COOKIE_MAX_AGE}; SameSite=Lax; HttpOnly");
+            Identity {
+                value,
+                set_cookie: Some(set_cookie),
+            }
+        }
+        ScopeStrategy::Plugin(id) => Identity {
+            value: resolve_by_plugin(id, addr, host, req)
+                .await
+                .unwrap_or_default(),
+            set_cookie: None,
+        },
+    }
+}
+
+//是否是顶层页面的导航请求，不带 sec-fetch-dest 的浏览器按 Accept 判断
+fn is_navigation(req: &Request<Body>) -> bool {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    };
+    let dest = header("sec-fetch-dest");
+    if !dest.is_empty() {
+        return dest == "document";
+    }
+    let accept = header(ACCEPT.as_str());
+    accept.starts_with("text/html") || accept.starts_with("application/xhtml+xml")
+}
+
+//取出指定名称的cookie，并从请求中删除，避免发给目标服务器
+fn take_cookie(req: &mut Request<Body>, name: &str) -> Option<String> {
+    let mut found = None;
+    let mut rest = vec![];
+    for value in req.headers().get_all(COOKIE) {
+        for item in value.to_str().unwrap_or("").split(';') {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            match item.split_once('=') {
+                Some((k, v)) if k.trim() == name => found = Some(v.trim().to_string()),
+                _ => rest.push(item.to_string()),
+            }
+        }
+    }
+    if found.is_none() {
+        return None;
+    }
+    let headers = req.headers_mut();
+    headers.remove(COOKIE);
+    if !rest.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&rest.join("; ")) {
+            headers.insert(COOKIE, value);
+        }
+    }
+    found.filter(|v| !v.is_empty())
+}
+
+async fn resolve_by_plugin(
+    id: &str,
+    addr: &SocketAddr,
+    host: &str,
+    req: &Request<Body>,
+) -> Option<String> {
+    let plugin = if id.is_empty() {
+        let (_all, _monitors, modify) = PLUGIN_MANAGER.ctxs_by_host(host).await;
+        modify?
+    } else {
+        PLUGIN_MANAGER.get_ctx(id).await?
+    };
+    let ctx = plugin.ctx.as_ref()?;
+    let ctx = ctx.lock().await;
+
+    //只把请求头交给插件，请求体还没有读取
+    let mut head = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version())
+        .body(Body::empty())
+        .ok()?;
+    *head.headers_mut() = req.headers().clone();
+    let mut js_req = JsRequest::from_hyper(head);
+    js_req.is_mut = false;
+    let ip = addr.ip().to_string();
+
+    async_with!(ctx=>|ctx|{
+        let res = server::call_hook::<Option<String>,_>(&ctx, "resolveScope", (js_req, ip), "", "").await
+            .catch(&ctx);
+        match res {
+            Ok(Either::Left(v)) => v,
+            Ok(Either::Right(_)) => None,
+            Err(err) => {
+                jsbind::handle_js_error(err, &ctx);
+                None
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsbind::server::Scope;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().uri("https://a.com/");
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn addr(ip: &str) -> SocketAddr {
+        format!("{ip}:1234").parse().unwrap()
+    }
+
+    #[test]
+    fn take_cookie_removes_only_the_identity() {
+        let mut req = request(&[("cookie", "a=1; cthulhu_profile=p1"), ("cookie", "b=2")]);
+        assert_eq!(
+            take_cookie(&mut req, DEFAULT_COOKIE),
+            Some("p1".to_string())
+        );
+        let cookies = req.headers().get_all(COOKIE).iter().collect::<Vec<_>>();
+        assert_eq!(cookies, ["a=1; b=2"]);
+
+        //只有身份cookie时删除整个请求头
+        let mut req = request(&[("cookie", "cthulhu_profile=p2")]);
+        assert_eq!(
+            take_cookie(&mut req, DEFAULT_COOKIE),
+            Some("p2".to_string())
+        );
+        assert!(req.headers().get(COOKIE).is_none());
+
+        //没有身份cookie时不改动请求
+        let mut req = request(&[("cookie", "a=1;b=2")]);
+        assert_eq!(take_cookie(&mut req, DEFAULT_COOKIE), None);
+        assert_eq!(req.headers().get(COOKIE).unwrap(), "a=1;b=2");
+
+        let mut req = request(&[("cookie", "cthulhu_profile=")]);
+        assert_eq!(take_cookie(&mut req, DEFAULT_COOKIE), None);
+    }
+
+    #[test]
+    fn navigation_is_detected() {
+        assert!(is_navigation(&request(&[("sec-fetch-dest", "document")])));
+        assert!(!is_navigation(&request(&[
+            ("sec-fetch-dest", "script"),
+            ("accept", "text/html")
+        ])));
+        assert!(is_navigation(&request(&[(
+            "accept",
+            "text/html,application/xhtml+xml"
+        )])));
+        assert!(!is_navigation(&request(&[("accept", "*/*")])));
+    }
+
+    #[tokio::test]
+    async fn simple_strategies() {
+        let addr = addr("10.0.0.1");
+        let mut req = request(&[("cthulhu-profile", " p1 ")]);
+        let identity =
+            resolve_identity(&ScopeStrategy::Default, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, "");
+
+        let identity =
+            resolve_identity(&ScopeStrategy::Ip, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, "10.0.0.1");
+
+        let strategy = ScopeStrategy::Header(DEFAULT_HEADER.to_string());
+        let identity = resolve_identity(&strategy, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, "p1");
+        //只给代理使用的请求头不发给目标服务器
+        assert!(req.headers().get(DEFAULT_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn cookie_strategy_assigns_and_reuses_identity() {
+        let strategy = ScopeStrategy::Cookie(DEFAULT_COOKIE.to_string());
+        let addr = addr("10.0.0.2");
+
+        //子资源请求不分配新的身份
+        let mut req = request(&[("sec-fetch-dest", "script")]);
+        let identity = resolve_identity(&strategy, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, "");
+        assert!(identity.set_cookie.is_none());
+
+        let mut req = request(&[("sec-fetch-dest", "document")]);
+        let page = resolve_identity(&strategy, &addr, "https", "a.com", &mut req).await;
+        assert!(!page.value.is_empty());
+        let set_cookie = page.set_cookie.unwrap();
+        assert!(set_cookie.starts_with(&format!("{DEFAULT_COOKIE}={};", page.value)));
+        assert!(set_cookie.ends_with("; HttpOnly"));
+
+        //不带cookie的子资源请求沿用页面的身份
+        let mut req = request(&[("sec-fetch-dest", "image")]);
+        let identity = resolve_identity(&strategy, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, page.value);
+
+        //带cookie的请求使用cookie中的身份，并记录给之后的子资源请求
+        let cookie = format!("{DEFAULT_COOKIE}=other");
+        let mut req = request(&[("sec-fetch-dest", "document"), ("cookie", &cookie)]);
+        let identity = resolve_identity(&strategy, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, "other");
+        assert!(identity.set_cookie.is_none());
+
+        //同一个地址有两个身份访问过该页面时无法确定子资源属于哪个，使用默认的划分方式
+        let mut req = request(&[("sec-fetch-dest", "image")]);
+        let identity = resolve_identity(&strategy, &addr, "https", "a.com", &mut req).await;
+        assert_eq!(identity.value, "");
+
+        //其它页面不受影响
+        let mut req = request(&[("sec-fetch-dest", "document"), ("cookie", &cookie)]);
+        resolve_identity(&strategy, &addr, "https", "b.com", &mut req).await;
+        let mut req = request(&[("sec-fetch-dest", "image")]);
+        let identity = resolve_identity(&strategy, &addr, "https", "b.com", &mut req).await;
+        assert_eq!(identity.value, "other");
+    }
+
+    //按身份划分时同一个域的地址和UA可能变化，例如切换网络后cookie不变，
+    //域的会话和代理配置以 Scope 为键保存，关闭域时要能用最新的 Scope 找到它们
+    #[test]
+    fn scopes_with_same_identity_are_equal() {
+        let scope = |ip: &str, ua: &str| {
+            let s = |v: &str| v.to_string();
+            Scope::new(s(ip), s("https"), s("a.com"), s(ua), s(""), s(""), 0, 0, 0)
+        };
+        let first = scope("10.0.0.1", "ua1").with_identity("p1");
+        let second = scope("10.0.0.2", "ua2").with_identity("p1");
+        assert_eq!(first, second);
+        let mut sessions = std::collections::HashMap::new();
+        sessions.insert(first, 1);
+        assert_eq!(sessions.get(&second), Some(&1));
+
+        assert_ne!(second, scope("10.0.0.2", "ua2").with_identity("p2"));
+        assert_ne!(scope("10.0.0.1", "ua1"), scope("10.0.0.2", "ua1"));
+    }
+}
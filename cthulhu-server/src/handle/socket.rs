@@ -17,7 +17,11 @@ use crate::{
     CLIENT_MANAGER, PLUGIN_MANAGER,
 };
 
-use super::{bad_request, scope_key_from_request};
+use super::{
+    bad_request,
+    rpc::{self, Session},
+    scope_key_from_request,
+};
 
 async fn linked(
     session_type: &String,
@@ -32,7 +36,7 @@ async fn linked(
         let ctx = plugin.ctx.as_ref().unwrap();
         let ctx = ctx.lock().await;
         let _: rquickjs::Result<()> = async_with!(ctx=>|ctx|{
-            let res=server::call_function::<(),_>(&ctx, "onClientOpen", (session_type.clone(),session_id.clone(),scope_key.clone())).await
+            let res=server::call_hook::<(),_>(&ctx, "onClientOpen", (session_type.clone(),session_id.clone(),scope_key.clone()), &scope_key.id, "").await
                     .catch(&ctx);
                      auto_result!(res,err=>{
                         return Err(jsbind::handle_js_error(err,&ctx));
@@ -44,24 +48,17 @@ async fn linked(
     Ok(())
 }
 
-#[instrument]
-pub async fn answer_text(_tabid: &String, text: String) -> Message {
-    // let tabid = client_key.tabid.to_string();
-    // let obj: HashMap<&str, serde_json::Value> = auto_result!(serde_json::from_str(&text),_err=>{
-    //     return response_msg(500,&"json格式错误","err",tabid,"".to_string(),0);
-    // });
-
-    // let empty = serde_json::Value::from_str(r#""""#).unwrap();
-    // let ty = obj
-    //     .get("type")
-    //     .unwrap_or(&empty)
-    //     .as_str()
-    //     .unwrap_or_default();
-
-    // if ty == "link" {
-    //     return link(client_key, obj).await;
-    // }
-    Message::from("<nothing todo>")
+#[instrument(skip(session))]
+pub async fn answer_text(session: &Session, text: String) -> Option<Message> {
+    let obj: serde_json::Value = auto_result!(serde_json::from_str(&text),_err=>{
+        return Some(Message::from("<nothing todo>"));
+    });
+    //JSON-RPC 消息的回复由 rpc 模块自己发送
+    if obj.get("jsonrpc").and_then(|v| v.as_str()) == Some("2.0") {
+        rpc::dispatch(session.clone(), obj).await;
+        return None;
+    }
+    Some(Message::from("<nothing todo>"))
 }
 
 pub fn answer_binary(_tabid: &String, _bin: Vec<u8>) -> Message {
@@ -87,7 +84,7 @@ pub async fn after_close(session_type: &String, session_id: &String, scope_key:
             let scope_key = scope_key.clone();
             let _: rquickjs::Result<()> = async_with!(ctx=>|ctx|{
 
-                let res=server::call_function::<(),_>(&ctx, "onClientClose",(session_type,session_id,scope_key)).await
+                let res=server::call_hook::<(),_>(&ctx, "onClientClose", (session_type,session_id,scope_key.clone()), &scope_key.id, "").await
                 .catch(&ctx);
                  auto_result!(res,err=>{
                     return Err(jsbind::handle_js_error(err,&ctx));
@@ -99,6 +96,7 @@ pub async fn after_close(session_type: &String, session_id: &String, scope_key:
         future_vec.push(f);
     }
     futures::future::join_all(future_vec).await;
+    rpc::close_session(session_id).await;
     let mut guard = CLIENT_MANAGER.sinks.write().await;
     guard.remove(session_id);
     let mut guard = CLIENT_MANAGER.sessions.write().await;
@@ -128,9 +126,21 @@ pub async fn handle_socket(ctx: &HttpContext, mut req: Request<Body>) -> Respons
     };
 
     let scope_key = {
-        auto_result!(scope_key_from_request(&ctx.client_addr,&mut req),err=>{
-            return super::response_msg(500, err);
-        })
+        //content 会话的id就是注入页面时分配的域id，按cookie等方式划分域时socket请求无法还原出同一个域
+        let known = if session_type == "content" {
+            CLIENT_MANAGER.scope_keys.read().await.get(&session_id).cloned()
+        } else {
+            None
+        };
+        match known {
+            Some(scope_key) => scope_key,
+            None => {
+                let (scope_key, _) = auto_result!(scope_key_from_request(&ctx.client_addr,&mut req).await,err=>{
+                    return super::response_msg(500, err);
+                });
+                scope_key
+            }
+        }
     };
 
     let mut req = {
@@ -165,6 +175,10 @@ pub async fn handle_socket(ctx: &HttpContext, mut req: Request<Body>) -> Respons
         if let Err(e) = linked(&session_type, &session_id, &scope_key).await {
             tracing::error!("{e}");
         }
+        let session = Session {
+            session_id: session_id.clone(),
+            scope: scope_key.clone(),
+        };
         while let Some(msg) = stream.next().await {
             let message = auto_result!(msg,err=>{
                 error!("WebSocket send error: {}", err);
@@ -172,7 +186,10 @@ pub async fn handle_socket(ctx: &HttpContext, mut req: Request<Body>) -> Respons
             });
             // debug!("收到消息,{:?}", &message);
             let response = match message {
-                Message::Text(text) => answer_text(&session_id, text).await,
+                Message::Text(text) => match answer_text(&session, text).await {
+                    Some(response) => response,
+                    None => continue,
+                },
                 Message::Binary(bin) => answer_binary(&session_id, bin),
                 Message::Frame(frame) => answer_frame(&session_id, frame),
                 Message::Ping(_) => Message::Pong(vec![]),
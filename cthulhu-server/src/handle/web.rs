@@ -1,14 +1,20 @@
-use std::path::Path;
+use std::{path::Path, str::FromStr};
 
-use crate::net_proxy::HttpContext;
-use hyper::http::{Request, Response};
-use hyper::Body;
+use crate::{matcher::MatchTarget, net_proxy::HttpContext, CLIENT_MANAGER, PLUGIN_MANAGER};
+use hyper::http::{header::CACHE_CONTROL, header::CONTENT_TYPE, Request, Response};
+use hyper::{Body, Uri};
 
-use crate::auto_option;
+use crate::{auto_option, auto_result};
 
-use super::{api::config, response_file, response_msg};
+use super::{api::config, api::detect, inject, response_file, response_headers, response_msg};
 
 pub async fn handle_web(_ctx: &HttpContext, req: Request<Body>) -> Response<Body> {
+    if req.uri().path() == "/frame.js" {
+        return response_headers(frame_bundle(req).await);
+    }
+    if req.uri().path() == "/worker-bundle.js" {
+        return response_headers(worker_bundle(req).await);
+    }
     let (parts, _) = req.into_parts();
     let path = parts.uri.path();
     let workspace = {
@@ -46,3 +52,73 @@ pub async fn handle_web(_ctx: &HttpContext, req: Request<Body>) -> Response<Body
     }
     return response_msg(404, "file not found");
 }
+
+///同源子页面（about:blank、srcdoc、blob:、document.write）不经过代理，
+///由 content.js 拉取这份脚本在子页面中执行，内容和html注入的脚本一致。
+///参数 scope 为域id，url 为父页面的地址
+async fn frame_bundle(req: Request<Body>) -> Response<Body> {
+    let (mut params, _) = auto_result!(detect(req).await);
+    let scope_id = params.remove("scope").unwrap_or_default();
+    let url = params.remove("url").unwrap_or_default();
+    let url = urlencoding::decode(&url)
+        .map(|v| v.into_owned())
+        .unwrap_or(url);
+    let scope_key = {
+        let guard = CLIENT_MANAGER.scope_keys.read().await;
+        auto_option!(guard.get(&scope_id), response_msg(500, "Invalid scope id")).clone()
+    };
+    let uri = auto_result!(Uri::from_str(&url),err=>{
+        return response_msg(500, format!("Invalid url {err}"));
+    });
+    let host = uri.host().unwrap_or_default();
+    let target = MatchTarget::origin(host)
+        .with_uri(&uri)
+        .with_dest(Some("iframe"));
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
+
+    //子页面创建时父页面已经加载，runAt 不再区分
+    let body = auto_result!(inject::bundle_scripts(&scope_key, &all, &target, false).await,err=>{
+        return response_msg(500, err);
+    });
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/javascript; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        .body(body.into())
+        .unwrap()
+}
+
+///模块 worker 通过 import 加载的注入脚本，内容和普通 worker 前面加上的脚本一致。
+///参数 scope 为域id，url 为 worker 脚本的地址，dest 为 worker 的类型
+async fn worker_bundle(req: Request<Body>) -> Response<Body> {
+    let (mut params, _) = auto_result!(detect(req).await);
+    let scope_id = params.remove("scope").unwrap_or_default();
+    let dest = params
+        .remove("dest")
+        .unwrap_or_else(|| "worker".to_string());
+    let url = params.remove("url").unwrap_or_default();
+    let url = urlencoding::decode(&url)
+        .map(|v| v.into_owned())
+        .unwrap_or(url);
+    let scope_key = {
+        let guard = CLIENT_MANAGER.scope_keys.read().await;
+        auto_option!(guard.get(&scope_id), response_msg(500, "Invalid scope id")).clone()
+    };
+    let uri = auto_result!(Uri::from_str(&url),err=>{
+        return response_msg(500, format!("Invalid url {err}"));
+    });
+    let host = uri.host().unwrap_or_default();
+    let target = MatchTarget::origin(host)
+        .with_uri(&uri)
+        .with_dest(Some(&dest));
+    let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_target(&target).await;
+    let body = auto_result!(inject::worker_bundle(&scope_key, &all).await,err=>{
+        return response_msg(500, err);
+    });
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/javascript; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        .body(body.into())
+        .unwrap()
+}
@@ -1,31 +1,213 @@
+use std::cell::RefCell;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use chrono::Local;
+use chrono::{DateTime, Local, NaiveDate};
 use rquickjs::class::Trace;
 use rquickjs::function::Rest;
 use rquickjs::{Class, Ctx};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
+use crate::handle::api::config;
 use crate::jsbind::js_to_string;
 
 use crate::{auto_result, utils as rs_utils};
 
+///当前日志文件，归档文件名为 cthulhu.<时间>.log
+pub const LOG_FILE: &str = "cthulhu.log";
+
+///一条插件日志，按json行写入文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub time: String,
+    pub level: String,
+    pub plugin: String,
+    pub scope: String,
+    pub request: String,
+    pub msg: String,
+}
+
+///日志轮转和保留策略，对应配置 pluginLog
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    //单个文件超过该大小时归档
+    pub max_size: u64,
+    //最多保留的归档文件数
+    pub max_files: usize,
+    //归档文件最多保留天数
+    pub max_days: i64,
+}
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            max_files: 10,
+            max_days: 7,
+        }
+    }
+}
+impl LogConfig {
+    pub async fn load() -> Self {
+        let mut log_config = Self::default();
+        let value = config::get_config("pluginLog").await.unwrap_or_default();
+        if let Some(v) = value.get("maxSize").and_then(|v| v.as_u64()) {
+            log_config.max_size = v * 1024 * 1024;
+        }
+        if let Some(v) = value.get("maxFiles").and_then(|v| v.as_u64()) {
+            log_config.max_files = v as usize;
+        }
+        if let Some(v) = value.get("maxDays").and_then(|v| v.as_i64()) {
+            log_config.max_days = v;
+        }
+        log_config
+    }
+}
+
+//正在执行的钩子所在的域和请求
+#[derive(Debug, Default)]
+struct LogContext {
+    scope: String,
+    request: String,
+}
+
+///按时间顺序返回日志目录下的文件，归档文件在前，当前文件在最后
+pub fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = auto_result!(fs::read_dir(dir), vec![])
+        .flatten()
+        .map(|v| v.path())
+        .filter(|v| {
+            let name = v.file_name().and_then(|v| v.to_str()).unwrap_or("");
+            name.starts_with("cthulhu.") && name.ends_with(".log") && name != LOG_FILE
+        })
+        .collect::<Vec<PathBuf>>();
+    //归档文件名中的时间可以直接按字符串排序
+    files.sort();
+    let current = dir.join(LOG_FILE);
+    if current.is_file() {
+        files.push(current);
+    }
+    files
+}
+
+//当前文件的大小和写入日期，只在第一次写入时读取文件信息，之后在内存中累加
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LogFileState {
+    size: u64,
+    day: NaiveDate,
+}
+impl LogFileState {
+    fn load(path: &Path) -> Self {
+        let today = Local::now().date_naive();
+        match fs::metadata(path) {
+            Ok(metadata) => Self {
+                size: metadata.len(),
+                day: metadata
+                    .modified()
+                    .map(|v| DateTime::<Local>::from(v).date_naive())
+                    .unwrap_or(today),
+            },
+            Err(_) => Self {
+                size: 0,
+                day: today,
+            },
+        }
+    }
+}
+
+//写入 len 字节前检查当前文件，写入后会过大或者不是今天写入的先归档
+fn rotate(
+    dir: &Path,
+    config: &LogConfig,
+    state: &mut Option<LogFileState>,
+    len: u64,
+) -> io::Result<()> {
+    let today = Local::now().date_naive();
+    let current = *state.get_or_insert_with(|| LogFileState::load(&dir.join(LOG_FILE)));
+    if current.size == 0 || (current.size + len <= config.max_size && current.day == today) {
+        return Ok(());
+    }
+    archive(dir, config)?;
+    *state = Some(LogFileState {
+        size: 0,
+        day: today,
+    });
+    Ok(())
+}
+
+//归档当前文件，再按保留策略清理旧文件
+fn archive(dir: &Path, config: &LogConfig) -> io::Result<()> {
+    let path = dir.join(LOG_FILE);
+    //日志可能已经被清空
+    let metadata = auto_result!(fs::metadata(&path), Ok(()));
+    let modified: DateTime<Local> = metadata.modified()?.into();
+    let now = Local::now();
+    let archive = format!("cthulhu.{}.log", modified.format("%Y%m%d%H%M%S%3f"));
+    fs::rename(&path, dir.join(archive))?;
+
+    let mut archives = log_files(dir);
+    archives.retain(|v| v != &path);
+    let expire = now - chrono::Duration::days(config.max_days);
+    let count = archives.len();
+    for (i, file) in archives.iter().enumerate() {
+        let too_many = count - i > config.max_files;
+        let too_old = fs::metadata(file)
+            .and_then(|v| v.modified())
+            .map(|v| DateTime::<Local>::from(v) < expire)
+            .unwrap_or(false);
+        if too_many || too_old {
+            let _ = fs::remove_file(file);
+        }
+    }
+    Ok(())
+}
+
 #[rquickjs::class(rename = "Console")]
 #[derive(Clone, Trace)]
 pub struct JsConsole {
     pub path: String,
+    pub id: String,
+    #[qjs(skip_trace)]
+    pub config: LogConfig,
+    #[qjs(skip_trace)]
+    current: Rc<RefCell<LogContext>>,
+    #[qjs(skip_trace)]
+    file: Rc<RefCell<Option<LogFileState>>>,
+    //日志同时广播给订阅者，例如repl和实时日志
+    #[qjs(skip_trace)]
+    pub tx: broadcast::Sender<LogRecord>,
 }
 
 #[rquickjs::methods]
 impl JsConsole {
     #[qjs(skip)]
-    pub fn write_to_log(&self, level: &str, mut msg: String) -> io::Result<()> {
-        let mut time = Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
-        time.push_str(" ");
-        msg.insert_str(0, time.as_str());
-        let path = format!("{}/LOGS/{}.log", self.path, level);
-        msg.push('\n');
-    
-        rs_utils::write_bytes(path.as_str(), msg.as_bytes(), Some(true))
+    pub fn write_to_log(&self, level: &str, msg: String) -> io::Result<()> {
+        let record = {
+            let current = self.current.borrow();
+            LogRecord {
+                time: Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+                level: level.to_string(),
+                plugin: self.id.clone(),
+                scope: current.scope.clone(),
+                request: current.request.clone(),
+                msg,
+            }
+        };
+        let _ = self.tx.send(record.clone());
+
+        let dir = Path::new(&self.path).join("LOGS");
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let len = line.len() as u64;
+        let mut file = self.file.borrow_mut();
+        rotate(&dir, &self.config, &mut file, len)?;
+        rs_utils::write_bytes(dir.join(LOG_FILE), line.as_bytes(), Some(true))?;
+        if let Some(file) = file.as_mut() {
+            file.size += len;
+        }
+        Ok(())
     }
     #[qjs(skip)]
     fn write<'js>(
@@ -67,9 +249,136 @@ impl JsConsole {
         self.write("debug", msgs, ctx)
     }
 }
-pub fn init_def(path: String, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
-    let console = JsConsole { path };
+///设置之后输出的日志所属的域和请求
+pub fn set_context(ctx: &Ctx<'_>, scope: &str, request: &str) {
+    if let Ok(console) = ctx.globals().get::<_, JsConsole>("console") {
+        let mut current = console.current.borrow_mut();
+        current.scope = scope.to_string();
+        current.request = request.to_string();
+    }
+}
+
+pub fn init_def(path: String, id: &str, config: LogConfig, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
+    let (tx, _) = broadcast::channel(256);
+    let console = JsConsole {
+        path,
+        id: id.to_string(),
+        config,
+        current: Default::default(),
+        file: Default::default(),
+        tx,
+    };
     let cls = Class::instance(ctx.clone(), console)?;
     ctx.globals().set("console", cls)?;
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+interface Console {
+    log(...msgs: any[]): void;
+    error(...msgs: any[]): void;
+    warn(...msgs: any[]): void;
+    debug(...msgs: any[]): void;
+}
+declare var console: Console;
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, len: usize) {
+        rs_utils::write_bytes(dir.join(name), &vec![b'x'; len], None).unwrap();
+    }
+
+    #[test]
+    fn log_files_are_ordered() {
+        let dir = rs_utils::temp_dir();
+        write(&dir, LOG_FILE, 1);
+        write(&dir, "cthulhu.20240102000000000.log", 1);
+        write(&dir, "cthulhu.20240101000000000.log", 1);
+        write(&dir, "other.log", 1);
+        let names = log_files(&dir)
+            .iter()
+            .map(|v| v.file_name().unwrap().to_str().unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            names,
+            [
+                "cthulhu.20240101000000000.log",
+                "cthulhu.20240102000000000.log",
+                LOG_FILE
+            ]
+        );
+        let _ = rs_utils::remove_path(&dir);
+    }
+
+    #[test]
+    fn small_log_is_kept() {
+        let dir = rs_utils::temp_dir();
+        write(&dir, LOG_FILE, 10);
+        let config = LogConfig {
+            max_size: 100,
+            ..LogConfig::default()
+        };
+        let mut state = None;
+        rotate(&dir, &config, &mut state, 1).unwrap();
+        assert_eq!(log_files(&dir), [dir.join(LOG_FILE)]);
+        assert_eq!(state.map(|v| v.size), Some(10));
+        let _ = rs_utils::remove_path(&dir);
+    }
+
+    #[test]
+    fn large_log_is_archived() {
+        let dir = rs_utils::temp_dir();
+        write(&dir, "cthulhu.20000101000000000.log", 1);
+        write(&dir, "cthulhu.20000102000000000.log", 1);
+        write(&dir, "cthulhu.20000103000000000.log", 1);
+        write(&dir, LOG_FILE, 100);
+        let config = LogConfig {
+            max_size: 100,
+            max_files: 2,
+            max_days: 7,
+        };
+        rotate(&dir, &config, &mut None, 1).unwrap();
+        let files = log_files(&dir);
+        //当前文件已归档，只保留最新的两个归档
+        assert_eq!(files.len(), 2);
+        assert!(!dir.join(LOG_FILE).exists());
+        assert_eq!(files[0], dir.join("cthulhu.20000103000000000.log"));
+        assert_eq!(fs::metadata(&files[1]).unwrap().len(), 100);
+        let _ = rs_utils::remove_path(&dir);
+    }
+
+    #[test]
+    fn size_is_tracked_in_memory() {
+        let dir = rs_utils::temp_dir();
+        write(&dir, LOG_FILE, 10);
+        let config = LogConfig {
+            max_size: 100,
+            ..LogConfig::default()
+        };
+        //记录的大小已经接近上限，不再读取文件的实际大小
+        let today = Local::now().date_naive();
+        let mut state = Some(LogFileState {
+            size: 99,
+            day: today,
+        });
+        rotate(&dir, &config, &mut state, 2).unwrap();
+        assert!(!dir.join(LOG_FILE).exists());
+        assert_eq!(
+            state,
+            Some(LogFileState {
+                size: 0,
+                day: today
+            })
+        );
+        //文件被清空后归档不会失败
+        let mut state = Some(LogFileState {
+            size: 99,
+            day: today,
+        });
+        rotate(&dir, &config, &mut state, 2).unwrap();
+        let _ = rs_utils::remove_path(&dir);
+    }
+}
@@ -258,3 +258,49 @@ pub fn init_def(_id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
     Class::<JsMetadata>::define(&globals)?;
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+declare class Metadata {
+    private constructor();
+    isDir(): boolean;
+    isFile(): boolean;
+    isSymlink(): boolean;
+    lastAccessTime(): number;
+    lastWriteTime(): number;
+    creationTime(): number;
+    fileSize(): number;
+    len(): number;
+}
+declare class Path {
+    constructor(path: string);
+    isDir(): boolean;
+    isFile(): boolean;
+    isSymlink(): boolean;
+    isAbsolute(): boolean;
+    isRelative(): boolean;
+    extension(): string | undefined;
+    fileName(): string | undefined;
+    fileStem(): string | undefined;
+    metadata(): Metadata | undefined;
+    toPath(base: string): Path;
+    toLogicalPath(base: string): Path;
+    exists(): boolean;
+    toString(): string;
+}
+interface OpenOptions {
+    read?: boolean;
+    write?: boolean;
+    create?: boolean;
+    createNew?: boolean;
+    append?: boolean;
+    truncate?: boolean;
+}
+declare class File {
+    constructor(path: Path, cfg?: OpenOptions);
+    readonly path: Path;
+    readonly metadata: Metadata;
+    writeBytes(buf: number[]): Promise<void>;
+    readBytes(): Promise<number[]>;
+    toString(): string;
+}
+"#;
@@ -19,6 +19,7 @@ use std::{
     mem,
     str::FromStr,
     sync::{Mutex, RwLock},
+    time::UNIX_EPOCH,
 };
 use chrono::format;
 use rquickjs::{
@@ -28,8 +29,8 @@ use rquickjs::{
 };
 
 use crate::{
-    auto_option, auto_result, core::ProxyCfg, create_client, reqwest_request_from_hyper,
-    reqwest_response_to_hyper,
+    auto_option, auto_result, core::ProxyCfg, create_client, net_proxy::UpstreamInfo,
+    reqwest_request_from_hyper, reqwest_response_to_hyper,
 };
 use rquickjs::Result;
 
@@ -56,6 +57,20 @@ pub enum HttpAction {
     Proxy(JsRequest, ProxyCfg),
     Respond(JsResponse),
     Release(JsRequest),
+    Pause(JsRequest, bool, bool), //断点，是否暂停请求、是否暂停响应
+}
+impl HttpAction {
+    ///动作名称，用于统计
+    pub fn name(&self) -> &'static str {
+        match self {
+            HttpAction::Reject => "reject",
+            HttpAction::Delay(..) => "delay",
+            HttpAction::Proxy(..) => "proxy",
+            HttpAction::Respond(_) => "respond",
+            HttpAction::Release(_) => "release",
+            HttpAction::Pause(..) => "pause",
+        }
+    }
 }
 
 #[rquickjs::class(rename = "Uri")]
@@ -353,6 +368,10 @@ impl JsBody {
 #[derive(Debug, Trace, Clone)]
 pub struct JsRequest {
     pub is_mut: bool,
+    //代理生成的请求id，插件自己创建的请求为空
+    pub id: String,
+    //代理识别出的资源类型，取值同 sec-fetch-dest，未知或插件创建的请求为空
+    pub dest: String,
     #[qjs(skip_trace)]
     pub parts: Arc<RwLock<(Method, JsUri, Version)>>,
     #[qjs(skip_trace)]
@@ -377,11 +396,21 @@ impl JsRequest {
         let body = body.0.unwrap_or_default();
         Ok(Self {
             is_mut: true,
+            id: String::new(),
+            dest: String::new(),
             parts: Arc::new(RwLock::new((method, uri, Version::HTTP_11))),
             inner: Arc::new(RwLock::new((headers, body))),
         })
     }
 
+    #[qjs(get, rename = "id", enumerable, configurable)]
+    pub fn get_id(&self) -> String {
+        self.id.clone()
+    }
+    #[qjs(get, rename = "dest", enumerable, configurable)]
+    pub fn get_dest(&self) -> String {
+        self.dest.clone()
+    }
     #[qjs(set, rename = "method", enumerable)]
     pub fn set_method(&self, method: String, ctx: Ctx<'_>) -> rquickjs::Result<()> {
         if !self.is_mut {
@@ -475,6 +504,8 @@ impl JsRequest {
 
         Ok(JsRequest {
             is_mut: self.is_mut,
+            id: self.id.clone(),
+            dest: self.dest.clone(),
             parts: Arc::new(RwLock::new(parts)),
             inner: Arc::new(RwLock::new((headers, JsBody::bytes(bytes)))),
         })
@@ -489,6 +520,8 @@ impl JsRequest {
 #[derive(Debug, Trace, Clone)]
 pub struct JsResponse {
     pub is_mut: bool,
+    //产生该响应的请求id
+    pub id: String,
     #[qjs(skip_trace)]
     pub parts: Arc<RwLock<(StatusCode, Version)>>,
     #[qjs(skip_trace)]
@@ -510,10 +543,15 @@ impl JsResponse {
         let body = body.0.unwrap_or_default();
         Ok(Self {
             is_mut: true,
+            id: String::new(),
             parts: Arc::new(RwLock::new((status, Version::HTTP_11))),
             inner: Arc::new(RwLock::new((headers, body))),
         })
     }
+    #[qjs(get, rename = "id", enumerable, configurable)]
+    pub fn get_id(&self) -> String {
+        self.id.clone()
+    }
     #[qjs(set, rename = "status", enumerable, configurable)]
     pub fn set_status(&self, status: u16, ctx: Ctx<'_>) -> rquickjs::Result<()> {
         if !self.is_mut {
@@ -589,6 +627,7 @@ impl JsResponse {
 
         Ok(JsResponse {
             is_mut: self.is_mut,
+            id: self.id.clone(),
             parts: Arc::new(RwLock::new(parts)),
             inner: Arc::new(RwLock::new((headers, JsBody::bytes(bytes)))),
         })
@@ -599,6 +638,63 @@ impl JsResponse {
     }
 }
 
+///上游请求的耗时和连接信息，作为响应钩子的参数
+#[rquickjs::class(rename = "UpstreamInfo")]
+#[derive(Debug, Trace, Clone, Default)]
+pub struct JsUpstreamInfo {
+    //发出请求的时间戳，毫秒
+    #[qjs(get, enumerable, configurable)]
+    start: f64,
+    //收到响应头的耗时，毫秒
+    #[qjs(get, enumerable, configurable)]
+    ttfb: f64,
+    //读取完响应体的耗时，毫秒
+    #[qjs(get, enumerable, configurable)]
+    end: f64,
+    #[qjs(get, rename = "remoteAddr", enumerable, configurable)]
+    remote_addr: String,
+    #[qjs(get, rename = "httpVersion", enumerable, configurable)]
+    http_version: String,
+    #[qjs(get, rename = "tlsVersion", enumerable, configurable)]
+    tls_version: String,
+    #[qjs(get, enumerable, configurable)]
+    alpn: String,
+}
+#[rquickjs::methods]
+impl JsUpstreamInfo {
+    #[qjs(rename = "toString")]
+    pub fn to_string(&self) -> String {
+        format!("{:?}", &self)
+    }
+}
+impl From<&UpstreamInfo> for JsUpstreamInfo {
+    fn from(info: &UpstreamInfo) -> Self {
+        let start = info
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        let (tls_version, alpn) = info
+            .tls
+            .as_ref()
+            .map(|v| (v.version.clone().unwrap_or_default(), v.alpn.clone()))
+            .unwrap_or_default();
+        Self {
+            start,
+            ttfb: info.ttfb.as_secs_f64() * 1000.0,
+            end: info.end.as_secs_f64() * 1000.0,
+            remote_addr: info
+                .remote_addr
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            http_version: format!("{:?}", info.version),
+            tls_version,
+            alpn,
+        }
+    }
+}
+
 fn opt_to_proxy_data(
     cfg: rquickjs::function::Opt<rquickjs::Value<'_>>,
 ) -> rquickjs::Result<ProxyCfg> {
@@ -678,6 +774,23 @@ impl JsHttpAction {
         }
     }
 
+    ///在断点处暂停，phase 为 request response both，默认 request
+    #[qjs(static)]
+    pub fn pause(
+        req: JsRequest,
+        phase: rquickjs::function::Opt<String>,
+        ctx: Ctx<'_>,
+    ) -> rquickjs::Result<Self> {
+        let (request, response) = match phase.0.as_deref().unwrap_or("request") {
+            "request" => (true, false),
+            "response" => (false, true),
+            "both" => (true, true),
+            _ => return Err(throw_js_err("invalid breakpoint phase", ctx)),
+        };
+        Ok(Self {
+            action: HttpAction::Pause(req, request, response),
+        })
+    }
     #[qjs(static)]
     pub fn proxy(
         req: JsRequest,
@@ -703,9 +816,29 @@ impl JsHttpAction {
             HttpAction::Reject => "reject",
             HttpAction::Respond(_) => "respond",
             HttpAction::Release(_) => "release",
+            HttpAction::Pause(_, _, _) => "pause",
         }
             .into()
     }
+    //放行或转发的请求
+    #[qjs(get)]
+    pub fn request(&self) -> Option<JsRequest> {
+        match &self.action {
+            HttpAction::Delay(req, _)
+            | HttpAction::Proxy(req, _)
+            | HttpAction::Release(req)
+            | HttpAction::Pause(req, _, _) => Some(req.clone()),
+            _ => None,
+        }
+    }
+    //直接返回的响应
+    #[qjs(get)]
+    pub fn response(&self) -> Option<JsResponse> {
+        match &self.action {
+            HttpAction::Respond(res) => Some(res.clone()),
+            _ => None,
+        }
+    }
     #[qjs(rename = "toString")]
     pub fn to_string(&self) -> String {
         format!("{:?}", &self.action)
@@ -837,6 +970,8 @@ impl Convert<hyper::Request<Body>> for JsRequest {
         };
         JsRequest {
             is_mut: true,
+            id: String::new(),
+            dest: String::new(),
             parts: Arc::new(RwLock::new((method, JsUri::from(uri), version))),
             inner: Arc::new(RwLock::new((headers, body))),
         }
@@ -879,6 +1014,7 @@ impl Convert<hyper::Response<Body>> for JsResponse {
         };
         JsResponse {
             is_mut: true,
+            id: String::new(),
             parts: Arc::new(RwLock::new((parts.status, parts.version))),
             inner: Arc::new(RwLock::new((headers, body))),
         }
@@ -892,6 +1028,7 @@ pub fn init_def(_id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
     Class::<'_, JsBody>::define(&globals)?;
     Class::<'_, JsRequest>::define(&globals)?;
     Class::<'_, JsResponse>::define(&globals)?;
+    Class::<'_, JsUpstreamInfo>::define(&globals)?;
     Class::<'_, JsHttpAction>::define(&globals)?;
     globals.set("fetch", Func::new(Async(fetch)))?;
     Ok(())
@@ -938,3 +1075,91 @@ pub fn init_def(_id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
 //         Ok(())
 //     }
 // }
+
+pub const DECLARE: &str = r#"
+declare class Uri {
+    constructor(url: string);
+    scheme: string;
+    authority: string;
+    host: string;
+    port: number;
+    path: string;
+    params: Record<string, string[]>;
+    toString(): string;
+}
+declare class Headers {
+    constructor();
+    get(key: string): string | undefined;
+    keys(): string[];
+    remove(key: string): string | undefined;
+    clear(): void;
+    append(key: string, value: string): void;
+    insert(key: string, value: string): void;
+    toString(): string;
+}
+declare class Body {
+    private constructor();
+    static empty(): Body;
+    static str(s: string): Body;
+    static bytes(bytes: number[]): Body;
+    static file(file: File): Body;
+    toBytes(): Promise<number[]>;
+}
+declare class Request {
+    constructor(method?: string, uri?: Uri, headers?: Headers, body?: Body);
+    //代理生成的请求id，插件创建的请求为空字符串
+    readonly id: string;
+    //资源类型，取值同 sec-fetch-dest，浏览器没有发送时由代理推断，未知时为空字符串
+    readonly dest: string;
+    method: string;
+    version: string;
+    uri: Uri;
+    headers: Headers;
+    body: Body;
+    toString(): string;
+}
+declare class Response {
+    constructor(status?: number, headers?: Headers, body?: Body);
+    //产生该响应的请求id，与对应 Request 的 id 相同
+    readonly id: string;
+    status: number;
+    version: string;
+    headers: Headers;
+    body: Body;
+    toString(): string;
+}
+//只在响应钩子中由代理传入
+interface UpstreamInfo {
+    //发出请求的时间戳，毫秒
+    readonly start: number;
+    //收到响应头的耗时，毫秒
+    readonly ttfb: number;
+    //读取完响应体的耗时，毫秒
+    readonly end: number;
+    readonly remoteAddr: string;
+    readonly httpVersion: string;
+    //未经过TLS时为空字符串
+    readonly tlsVersion: string;
+    readonly alpn: string;
+    toString(): string;
+}
+interface ProxyConfig {
+    proxy?: string;
+    ja3?: number;
+    h2?: number;
+}
+declare class HttpAction {
+    private constructor();
+    static reject(): HttpAction;
+    static release(req: Request): HttpAction;
+    static respond(res: Response): HttpAction;
+    static proxy(req: Request, cfg?: ProxyConfig): HttpAction;
+    //在断点处暂停，等待在断点列表中处理或超时
+    static pause(req: Request, phase?: "request" | "response" | "both"): HttpAction;
+    readonly name: "delay" | "proxy" | "reject" | "respond" | "release" | "pause";
+    readonly request: Request | undefined;
+    readonly response: Response | undefined;
+    toString(): string;
+}
+declare function fetch(req: Request, cfg?: ProxyConfig): Promise<Response>;
+"#;
@@ -0,0 +1,505 @@
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use relative_path::RelativePath;
+use rquickjs::{
+    loader::{BuiltinLoader, BuiltinResolver, Loader, Resolver},
+    Ctx, Module,
+};
+use serde_json::Value;
+
+use crate::{auto_option, utils};
+
+///内置模块前缀 例如 import { fetch } from "cthulhu:http"
+pub const BUILTIN_PREFIX: &str = "cthulhu:";
+
+///内置模块 名称 映射 从全局对象导出的成员
+pub(crate) const BUILTINS: &[(&str, &[&str])] = &[
+    (
+        "http",
+        &[
+            "fetch",
+            "Uri",
+            "Headers",
+            "Body",
+            "Request",
+            "Response",
+            "HttpAction",
+        ],
+    ),
+    ("ws", &["Message", "WsAction"]),
+    ("server", &["server", "UAParser"]),
+    ("store", &["store"]),
+    (
+        "timer",
+        &[
+            "setTimeout",
+            "clearTimeout",
+            "setInterval",
+            "clearInterval",
+        ],
+    ),
+    ("file", &["File", "Path", "Metadata"]),
+    ("script", &["Script"]),
+    ("utils", &["StrUtils"]),
+    ("console", &["console"]),
+    //只在 cthulhu plugin test 中有值
+    ("test", &["test", "assert", "fixtures"]),
+];
+
+//模块文件按顺序尝试的扩展名
+const EXTENSIONS: &[&str] = &["js", "mjs", "cjs", "json"];
+//package.json exports 中按顺序尝试的条件
+const CONDITIONS: &[&str] = &["import", "module", "default", "require"];
+
+//CommonJS 模块中的 require、module 和 exports
+const COMMON_JS_PRELUDE: &str = r#"function require(name) {
+    const ns = __modules[name];
+    if (!ns) throw new Error(`Cannot find module '${name}'`);
+    return ns.__commonjs ? ns.default : ns;
+}
+const module = { exports: {} };
+const exports = module.exports;
+"#;
+
+lazy_static! {
+    //CommonJS 中以字符串字面量调用的 require，动态参数的调用在运行时报错
+    static ref REQUIRE_CALL: Regex =
+        Regex::new(r#"(?:^|[^.\w$])require\s*\(\s*(?:"([^"]+)"|'([^']+)')\s*\)"#).unwrap();
+}
+
+fn builtin_source(members: &[&str]) -> String {
+    let mut source = String::new();
+    for member in members {
+        source.push_str(&format!(
+            "export const {member} = globalThis['{member}'];\n"
+        ));
+    }
+    source.push_str(&format!("export default {{ {} }};\n", members.join(", ")));
+    source
+}
+
+pub fn builtin_resolver() -> BuiltinResolver {
+    BUILTINS
+        .iter()
+        .fold(BuiltinResolver::default(), |rs, (name, _)| {
+            rs.with_module(format!("{BUILTIN_PREFIX}{name}"))
+        })
+}
+
+pub fn builtin_loader() -> BuiltinLoader {
+    BUILTINS
+        .iter()
+        .fold(BuiltinLoader::default(), |ld, (name, members)| {
+            ld.with_module(format!("{BUILTIN_PREFIX}{name}"), builtin_source(members))
+        })
+}
+
+fn read_package_json(dir: &Path) -> Option<Value> {
+    let bytes = utils::read_bytes(dir.join("package.json")).ok()?;
+    serde_json::from_slice::<Value>(&bytes).ok()
+}
+
+///按node的规则解析插件内的模块路径
+pub struct PluginResolver {
+    pub base_path: String,
+}
+
+impl PluginResolver {
+    fn base_dir(&self, base: &str) -> PathBuf {
+        let base = Path::new(base);
+        if base.is_file() {
+            if let Some(parent) = base.parent() {
+                return parent.to_path_buf();
+            }
+        }
+        Path::new(&self.base_path).to_path_buf()
+    }
+
+    fn resolve_file(path: &Path) -> Option<PathBuf> {
+        if path.is_file() {
+            return Some(path.to_path_buf());
+        }
+        let name = path.file_name()?.to_str()?;
+        for ext in EXTENSIONS {
+            let file = path.with_file_name(format!("{name}.{ext}"));
+            if file.is_file() {
+                return Some(file);
+            }
+        }
+        None
+    }
+
+    fn resolve_dir(dir: &Path) -> Option<PathBuf> {
+        if !dir.is_dir() {
+            return None;
+        }
+        if let Some(package) = read_package_json(dir) {
+            for field in ["module", "main"] {
+                let entry = package.get(field).and_then(|v| v.as_str()).unwrap_or("");
+                if entry.is_empty() {
+                    continue;
+                }
+                let entry = RelativePath::new(entry).to_logical_path(dir);
+                if let Some(file) =
+                    Self::resolve_file(&entry).or_else(|| Self::resolve_index(&entry))
+                {
+                    return Some(file);
+                }
+            }
+        }
+        Self::resolve_index(dir)
+    }
+
+    fn resolve_index(dir: &Path) -> Option<PathBuf> {
+        Self::resolve_file(&dir.join("index"))
+    }
+
+    fn resolve_path(path: &Path) -> Option<PathBuf> {
+        Self::resolve_file(path).or_else(|| Self::resolve_dir(path))
+    }
+
+    ///从 exports 的条件对象中挑出目标路径
+    fn export_target(value: &Value) -> Option<String> {
+        match value {
+            Value::String(v) => Some(v.clone()),
+            Value::Array(list) => list.iter().find_map(Self::export_target),
+            Value::Object(map) => CONDITIONS
+                .iter()
+                .find_map(|c| map.get(*c).and_then(Self::export_target)),
+            _ => None,
+        }
+    }
+
+    fn resolve_exports(dir: &Path, exports: &Value, subpath: &str) -> Option<PathBuf> {
+        let key = if subpath.is_empty() {
+            ".".to_string()
+        } else {
+            format!("./{subpath}")
+        };
+        let target = match exports {
+            Value::Object(map) if map.keys().any(|k| k.starts_with('.')) => {
+                if let Some(value) = map.get(&key) {
+                    Self::export_target(value)?
+                } else {
+                    //通配符导出 "./*": "./lib/*.js"
+                    map.iter().find_map(|(pattern, value)| {
+                        let (prefix, suffix) = pattern.split_once('*')?;
+                        let matched = key.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                        Self::export_target(value).map(|t| t.replace('*', matched))
+                    })?
+                }
+            }
+            _ if subpath.is_empty() => Self::export_target(exports)?,
+            _ => return None,
+        };
+        let path = RelativePath::new(&target).to_logical_path(dir);
+        Self::resolve_file(&path)
+    }
+
+    fn resolve_package(from: &Path, name: &str) -> Option<PathBuf> {
+        //@scope/pkg/sub/path 或 pkg/sub/path
+        let mut parts = name.splitn(if name.starts_with('@') { 3 } else { 2 }, '/');
+        let package = if name.starts_with('@') {
+            format!("{}/{}", parts.next()?, parts.next()?)
+        } else {
+            parts.next()?.to_string()
+        };
+        let subpath = parts.next().unwrap_or("");
+
+        for dir in from.ancestors() {
+            let package_dir = dir.join("node_modules").join(&package);
+            if !package_dir.is_dir() {
+                continue;
+            }
+            if let Some(exports) =
+                read_package_json(&package_dir).and_then(|p| p.get("exports").cloned())
+            {
+                return Self::resolve_exports(&package_dir, &exports, subpath);
+            }
+            if subpath.is_empty() {
+                return Self::resolve_dir(&package_dir);
+            }
+            return Self::resolve_path(&RelativePath::new(subpath).to_logical_path(&package_dir));
+        }
+        None
+    }
+}
+
+impl Resolver for PluginResolver {
+    fn resolve<'js>(
+        &mut self,
+        _ctx: &Ctx<'js>,
+        base: &str,
+        name: &str,
+    ) -> rquickjs::Result<String> {
+        let dir = self.base_dir(base);
+        let path = if name.starts_with("./") || name.starts_with("../") {
+            Self::resolve_path(&RelativePath::new(name).to_logical_path(&dir))
+        } else if name.starts_with('/') {
+            Self::resolve_path(&RelativePath::new(name).to_logical_path(&self.base_path))
+        } else if name.starts_with(BUILTIN_PREFIX) {
+            None
+        } else {
+            //兼容旧写法：裸路径找不到依赖包时按插件根目录的相对路径处理
+            Self::resolve_package(&dir, name).or_else(|| {
+                Self::resolve_path(&RelativePath::new(name).to_logical_path(&self.base_path))
+            })
+        };
+        //模块名是字符串，非UTF-8的路径无法作为模块名
+        match path.as_deref().and_then(|v| v.to_str()) {
+            Some(path) => Ok(path.to_string()),
+            None => Err(rquickjs::Error::new_resolving(base, name)),
+        }
+    }
+}
+
+///加载插件内的模块文件，json文件作为默认导出，CommonJS文件包装为ES模块
+#[derive(Default)]
+pub struct PluginLoader;
+
+impl PluginLoader {
+    ///和node一样按扩展名和最近的 package.json 的 type 判断，
+    ///插件自己的脚本没有声明 type 时按ES模块处理，依赖包中的按CommonJS处理
+    fn is_common_js(path: &Path) -> bool {
+        match path.extension().and_then(|v| v.to_str()) {
+            Some("cjs") => return true,
+            Some("mjs") => return false,
+            _ => {}
+        }
+        for dir in path.ancestors().skip(1) {
+            let package = auto_option!(read_package_json(dir), { continue });
+            match package.get("type").and_then(|v| v.as_str()) {
+                Some("module") => return false,
+                Some("commonjs") => return true,
+                _ => break,
+            }
+        }
+        path.components().any(|v| v.as_os_str() == "node_modules")
+    }
+
+    //require 的目标能否解析，解析不到的不生成导入，避免整个模块加载失败
+    fn can_require(dir: &Path, name: &str) -> bool {
+        if let Some(builtin) = name.strip_prefix(BUILTIN_PREFIX) {
+            return BUILTINS.iter().any(|(v, _)| *v == builtin);
+        }
+        if name.starts_with("./") || name.starts_with("../") {
+            return PluginResolver::resolve_path(&RelativePath::new(name).to_logical_path(dir))
+                .is_some();
+        }
+        PluginResolver::resolve_package(dir, name).is_some()
+    }
+
+    ///包装为ES模块，字面量 require 的依赖转为静态导入，由同一个解析器查找
+    fn wrap_common_js(path: &Path, source: &str) -> String {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut names: Vec<&str> = vec![];
+        for cap in REQUIRE_CALL.captures_iter(source) {
+            let name = auto_option!(cap.get(1).or(cap.get(2)), { continue }).as_str();
+            if !names.contains(&name) && Self::can_require(dir, name) {
+                names.push(name);
+            }
+        }
+        let mut wrapped = String::new();
+        let mut modules = vec![];
+        for (i, name) in names.iter().enumerate() {
+            let name = Value::from(*name).to_string();
+            wrapped.push_str(&format!("import * as __require_{i} from {name};\n"));
+            modules.push(format!("{name}: __require_{i}"));
+        }
+        wrapped.push_str(&format!(
+            "const __modules = {{ {} }};\n",
+            modules.join(", ")
+        ));
+        wrapped.push_str(COMMON_JS_PRELUDE);
+        wrapped.push_str(source);
+        wrapped.push_str("\nexport default module.exports;\nexport const __commonjs = true;\n");
+        wrapped
+    }
+}
+
+impl Loader for PluginLoader {
+    fn load<'js>(&mut self, ctx: &Ctx<'js>, name: &str) -> rquickjs::Result<Module<'js>> {
+        let path = Path::new(name);
+        let ext = path.extension().and_then(|v| v.to_str()).unwrap_or("");
+        if !EXTENSIONS.contains(&ext) || !path.is_file() {
+            return Err(rquickjs::Error::new_loading(name));
+        }
+        let bytes = utils::read_bytes(path)?;
+        let source = String::from_utf8(bytes).map_err(|_| rquickjs::Error::new_loading(name))?;
+        let source = if ext == "json" {
+            //require 一个json文件时得到的是它的内容
+            format!(
+                "export default {};\nexport const __commonjs = true;\n",
+                source
+            )
+        } else if Self::is_common_js(path) {
+            Self::wrap_common_js(path, &source)
+        } else {
+            source
+        };
+        Module::declare(ctx.clone(), name, source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rquickjs::{async_with, AsyncContext, AsyncRuntime};
+
+    use super::*;
+
+    fn write(root: &Path, path: &str, text: &str) -> PathBuf {
+        let path = root.join(path);
+        utils::write_bytes(&path, text.as_bytes(), None).unwrap();
+        path
+    }
+
+    #[test]
+    fn resolves_files_and_dirs() {
+        let root = utils::temp_dir();
+        let util = write(&root, "lib/util.js", "");
+        let index = write(&root, "lib/dir/index.js", "");
+        let data = write(&root, "data.json", "{}");
+        write(&root, "entry/package.json", r#"{"main": "src/main"}"#);
+        let main = write(&root, "entry/src/main.mjs", "");
+
+        let resolve = |path: &str| PluginResolver::resolve_path(&root.join(path));
+        assert_eq!(resolve("lib/util"), Some(util.clone()));
+        assert_eq!(resolve("lib/util.js"), Some(util));
+        assert_eq!(resolve("lib/dir"), Some(index));
+        assert_eq!(resolve("data"), Some(data));
+        assert_eq!(resolve("entry"), Some(main));
+        assert_eq!(resolve("missing"), None);
+        let _ = utils::remove_path(&root);
+    }
+
+    #[test]
+    fn resolves_packages_from_ancestors() {
+        let root = utils::temp_dir();
+        write(
+            &root,
+            "node_modules/pkg/package.json",
+            r#"{"main": "lib/entry"}"#,
+        );
+        let entry = write(&root, "node_modules/pkg/lib/entry.js", "");
+        let other = write(&root, "node_modules/pkg/lib/other.js", "");
+        let from = root.join("src/deep");
+
+        assert_eq!(PluginResolver::resolve_package(&from, "pkg"), Some(entry));
+        assert_eq!(
+            PluginResolver::resolve_package(&from, "pkg/lib/other"),
+            Some(other)
+        );
+        assert_eq!(PluginResolver::resolve_package(&from, "nothing"), None);
+        let _ = utils::remove_path(&root);
+    }
+
+    #[test]
+    fn resolves_package_exports() {
+        let root = utils::temp_dir();
+        write(
+            &root,
+            "node_modules/@s/exp/package.json",
+            r#"{"exports": {
+                ".": {"require": "./cjs/index.cjs", "import": "./esm/index.mjs"},
+                "./feature/*": "./src/*.js"
+            }}"#,
+        );
+        let esm = write(&root, "node_modules/@s/exp/esm/index.mjs", "");
+        write(&root, "node_modules/@s/exp/cjs/index.cjs", "");
+        let feature = write(&root, "node_modules/@s/exp/src/a.js", "");
+        write(&root, "node_modules/@s/exp/hidden.js", "");
+
+        let resolve = |name: &str| PluginResolver::resolve_package(&root, name);
+        //import 条件优先于 require
+        assert_eq!(resolve("@s/exp"), Some(esm));
+        assert_eq!(resolve("@s/exp/feature/a"), Some(feature));
+        //exports 之外的路径不能被导入
+        assert_eq!(resolve("@s/exp/hidden"), None);
+        let _ = utils::remove_path(&root);
+    }
+
+    #[test]
+    fn detects_common_js() {
+        let root = utils::temp_dir();
+        let cjs = |path: &str| PluginLoader::is_common_js(&root.join(path));
+        //插件自己的脚本默认是ES模块
+        assert!(!cjs("main.js"));
+        assert!(cjs("main.cjs"));
+        //依赖包中的脚本默认是CommonJS
+        write(&root, "node_modules/a/package.json", r#"{"main": "a.js"}"#);
+        assert!(cjs("node_modules/a/a.js"));
+        assert!(!cjs("node_modules/a/a.mjs"));
+        write(
+            &root,
+            "node_modules/b/package.json",
+            r#"{"type": "module"}"#,
+        );
+        assert!(!cjs("node_modules/b/lib/b.js"));
+        assert!(cjs("node_modules/b/lib/b.cjs"));
+        write(&root, "src/package.json", r#"{"type": "commonjs"}"#);
+        assert!(cjs("src/c.js"));
+        let _ = utils::remove_path(&root);
+    }
+
+    #[tokio::test]
+    async fn common_js_can_require() {
+        let root = utils::temp_dir();
+        write(
+            &root,
+            "node_modules/a/package.json",
+            r#"{"main": "index.js"}"#,
+        );
+        write(
+            &root,
+            "node_modules/a/index.js",
+            "const b = require('./b');\nconst data = require(\"./data.json\");\nconst { StrUtils } = require('cthulhu:utils');\nmodule.exports = { value: b.value + data.value, missing: () => require('./missing') };",
+        );
+        write(
+            &root,
+            "node_modules/a/b.js",
+            "exports.value = 1; // require('./nothing')",
+        );
+        write(&root, "node_modules/a/data.json", r#"{"value": 2}"#);
+        write(
+            &root,
+            "node_modules/esm/package.json",
+            r#"{"type": "module", "main": "index.js"}"#,
+        );
+        write(
+            &root,
+            "node_modules/esm/index.js",
+            "export const value = 4;",
+        );
+        write(
+            &root,
+            "node_modules/c/index.js",
+            "module.exports = require('esm').value;",
+        );
+        let main = write(
+            &root,
+            "main.js",
+            "import a from 'a';\nimport c from 'c';\nlet missing = '';\ntry { a.missing(); } catch (e) { missing = e.message; }\nglobalThis.result = `${a.value},${c},${missing}`;",
+        );
+
+        let rt = AsyncRuntime::new().unwrap();
+        let base_path = root.to_string_lossy().to_string();
+        rt.set_loader(
+            (builtin_resolver(), PluginResolver { base_path }),
+            (builtin_loader(), PluginLoader),
+        )
+        .await;
+        let ctx = AsyncContext::full(&rt).await.unwrap();
+        let code = std::fs::read_to_string(&main).unwrap();
+        let name = main.to_string_lossy().to_string();
+        let result: String = async_with!(ctx=>|ctx|{
+            ctx.globals().set("StrUtils", 1).unwrap();
+            ctx.clone().compile(name, code).unwrap();
+            ctx.globals().get("result").unwrap()
+        })
+        .await;
+        assert_eq!(result, "3,4,Cannot find module './missing'");
+        let _ = utils::remove_path(&root);
+    }
+}
@@ -1,22 +1,26 @@
-use std::error::Error;
+use std::{error::Error, time::Duration};
 
 use futures::future::Either;
 use relative_path::RelativePath;
 use rquickjs::{
     async_with,
     function::This,
-    loader::{BuiltinLoader, BuiltinResolver, ModuleLoader, NativeLoader, Resolver, ScriptLoader},
-    AsyncContext, AsyncRuntime, CatchResultExt, CaughtError, Ctx, Function, Symbol,
+    loader::{ModuleLoader, NativeLoader},
+    AsyncContext, AsyncRuntime, CatchResultExt, CaughtError, Ctx, FromJs, Function, Symbol,
 };
 use serde_json::json;
 
 use sled::Db;
+use tokio::sync::broadcast;
 use tracing::instrument;
 
 use crate::handle::model::Plugin;
-use crate::{auto_result, core::PluginCtx};
+use crate::{auto_option, auto_result, core::PluginCtx};
 
-use self::server::Scope;
+use self::{
+    loader::{PluginLoader, PluginResolver},
+    server::Scope,
+};
 
 //全局对象
 pub mod console;
@@ -27,38 +31,30 @@ pub mod timer;
 //模块
 pub mod file;
 pub mod http;
+pub mod loader;
+pub mod script;
+pub mod tester;
+pub mod typescript;
 pub mod utils;
 pub mod ws;
 
-struct PluginResolver {
-    pub base_path: String,
-}
-impl Resolver for PluginResolver {
-    fn resolve<'js>(
-        &mut self,
-        _ctx: &Ctx<'js>,
-        _base: &str,
-        name: &str,
-    ) -> rquickjs::Result<String> {
-        let name = if name.ends_with(".js") {
-            name.to_string()
-        } else {
-            format!("{name}.js")
-        };
-        let path = RelativePath::new(name.as_str()).to_logical_path(&self.base_path);
-        if path.is_file() {
-            Ok(path.to_str().unwrap().to_string())
-        } else {
-            Err(rquickjs::Error::new_resolving(&self.base_path, name))
-        }
-    }
+pub async fn content(plugin: &Plugin) -> Result<(AsyncContext, AsyncRuntime, Db), String> {
+    let redb = {
+        let db_path = format!("{}/STORE", &plugin.path);
+        sled::open(db_path).map_err(|v| v.to_string())?
+    };
+    content_with_db(plugin, redb).await
 }
 
-pub async fn content(plugin: &Plugin) -> Result<(AsyncContext, AsyncRuntime, Db), String> {
+///使用指定的存储创建插件上下文，测试时传入临时存储
+pub async fn content_with_db(
+    plugin: &Plugin,
+    redb: Db,
+) -> Result<(AsyncContext, AsyncRuntime, Db), String> {
     let path = plugin.path.clone();
     let rt = {
         let rt = AsyncRuntime::new().unwrap();
-        let rs = BuiltinResolver::default();
+        let rs = loader::builtin_resolver();
         let ld = ModuleLoader::default();
 
         let rs = (
@@ -68,18 +64,15 @@ pub async fn content(plugin: &Plugin) -> Result<(AsyncContext, AsyncRuntime, Db)
             },
         );
         let ld = (
-            BuiltinLoader::default(),
+            loader::builtin_loader(),
             NativeLoader::default(),
-            ScriptLoader::default(),
+            PluginLoader::default(),
             ld,
         );
         rt.set_loader(rs, ld).await;
         rt
     };
-    let redb = {
-        let db_path = format!("{}/STORE", &plugin.path);
-        sled::open(db_path).map_err(|v| v.to_string())?
-    };
+    let log_config = console::LogConfig::load().await;
     let full = AsyncContext::full(&rt).await.map_err(|v| v.to_string())?;
 
     let id = &plugin.id;
@@ -88,7 +81,7 @@ pub async fn content(plugin: &Plugin) -> Result<(AsyncContext, AsyncRuntime, Db)
 
     let res = async_with!(full=>|ctx|{
         let init=|ctx|{
-            console::init_def(path.clone(), &ctx)?;
+            console::init_def(path.clone(), id, log_config, &ctx)?;
             store::init_def(id, &ctx,db)?;
             server::init_def(id, &ctx)?;
             http::init_def(id, &ctx)?;
@@ -96,11 +89,15 @@ pub async fn content(plugin: &Plugin) -> Result<(AsyncContext, AsyncRuntime, Db)
             utils::init_def(id, &ctx)?;
             timer::init_def(id, &ctx)?;
             file::init_def(id, &ctx)?;
+            script::init_def(id, &ctx)?;
             let globals=ctx.globals();
             globals.set::<_,_>("server_dir", path)?;
             globals.set::<_,_>("global", globals.clone())?;
             if let Ok(code) = plugin.read_server() {
-                let _=ctx.clone().compile("server", code)?;
+                //以脚本的绝对路径作为模块名，相对导入和node_modules查找都以它所在目录为起点
+                let name = RelativePath::new(&plugin.server_path).to_logical_path(&plugin.path);
+                let name = name.to_str().ok_or_else(|| rquickjs::Error::new_loading(&plugin.server_path))?;
+                let _=ctx.clone().compile(name, code)?;
             }
             Ok(())
         };
@@ -326,7 +323,61 @@ pub fn throw_js_err<'js, E: Into<&'js str>>(e: E, ctx: Ctx<'js>) -> rquickjs::Er
     ctx.throw(rquickjs::String::from_str(ctx.clone(), e).unwrap().into())
 }
 
+//调试时执行代码的超时时间
+const EVAL_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl PluginCtx {
+    ///在插件上下文中执行一段代码，结果是promise时等待其完成，超过 EVAL_TIMEOUT 返回错误
+    pub async fn eval(&self, code: String) -> Result<String, String> {
+        let ctx = auto_option!(self.ctx.as_ref(), Err("插件没有server脚本".to_string()));
+        //和 onCall 一样不持有插件上下文的锁，等待promise期间插件的钩子照常执行
+        let ctx = ctx.lock().await.clone();
+        let res = async_with!(ctx=>|ctx|{
+            let res = async {
+                let value = ctx.eval::<rquickjs::Value, _>(code)?;
+                let is_promise = value
+                    .as_object()
+                    .map(|v| v.get::<_, rquickjs::Value>("then").map(|v| v.is_function()).unwrap_or(false))
+                    .unwrap_or(false);
+                let value = if is_promise {
+                    rquickjs::promise::Promise::<rquickjs::Value>::from_js(&ctx, value)?.await?
+                } else {
+                    value
+                };
+                js_to_string(value)
+            }
+            .await
+            .catch(&ctx);
+            res.map_err(|e| e.to_string())
+        });
+        match tokio::time::timeout(EVAL_TIMEOUT, res).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("执行超时，超过{}秒", EVAL_TIMEOUT.as_secs())),
+        }
+    }
+    ///插件是否定义了 server 上的钩子
+    pub async fn has_hook(&self, name: &str) -> bool {
+        let ctx = auto_option!(self.ctx.as_ref(), false);
+        let ctx = ctx.lock().await;
+        async_with!(ctx=>|ctx|{
+            ctx.globals()
+                .get::<_, rquickjs::Object>("server")
+                .and_then(|v| v.get::<_, rquickjs::Value>(name))
+                .map(|v| v.is_function())
+                .unwrap_or(false)
+        })
+        .await
+    }
+    ///订阅插件的console输出
+    pub async fn subscribe_console(&self) -> Option<broadcast::Receiver<console::LogRecord>> {
+        let ctx = self.ctx.as_ref()?;
+        let ctx = ctx.lock().await;
+        async_with!(ctx=>|ctx|{
+            let console = ctx.globals().get::<_, console::JsConsole>("console").ok()?;
+            Some(console.tx.subscribe())
+        })
+        .await
+    }
     pub async fn dynamic_scripts(&self, scope_key: Scope) -> Vec<String> {
         let mut scripts = vec![];
         let links = self
@@ -340,7 +391,7 @@ impl PluginCtx {
             let ctx = ctx.lock().await;
             let scope_key = scope_key.clone();
             let script = async_with!(ctx=>|ctx|{
-                let res=server::call_function::<Option<String>,_>(&ctx, "dynamicScript", (link, scope_key)).await.catch(&ctx);
+                let res=server::call_hook::<Option<String>,_>(&ctx, "dynamicScript", (link, scope_key.clone()), &scope_key.id, "").await.catch(&ctx);
                 let res=auto_result!(res,err=>{
                     let e= format!(r##"throw new Error("{err}")"##);
                     handle_js_error(err,&ctx);
@@ -0,0 +1,502 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use rquickjs::{class::Trace, Class, Ctx, Result};
+use serde_json::{json, Value};
+use swc_common::{sync::Lrc, BytePos, FileName, SourceMap, Span, Spanned};
+use swc_ecma_ast::{
+    CallExpr, Callee, EsVersion, ExportNamedSpecifier, Expr, ExprOrSpread, Ident,
+    ImportNamedSpecifier, Lit, MemberProp, ModuleExportName, OptCall, Program, Prop, PropName,
+    SuperProp,
+};
+use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use super::{json_to_js, throw_js_err};
+
+//每隔多少字节记录一次 utf16 偏移，换算偏移时最多扫描这么多字节
+const CHECKPOINT: usize = 1024;
+
+//标识符所在的位置，决定替换时需要保留的名称
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IdentKind {
+    Plain,
+    //对象字面量或解构中的简写 {a}
+    Shorthand,
+    //import {a}
+    Import,
+    //export {a}
+    Export,
+}
+
+#[derive(Debug)]
+struct Source {
+    text: String,
+    program: Program,
+    start: BytePos,
+    //(字节偏移, utf16偏移)
+    checkpoints: Vec<(usize, usize)>,
+}
+impl Source {
+    fn parse(text: String) -> std::result::Result<Self, String> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let fm = cm.new_source_file(FileName::Anon, text.clone());
+        let lexer = Lexer::new(
+            Syntax::Es(Default::default()),
+            EsVersion::latest(),
+            StringInput::from(&*fm),
+            None,
+        );
+        let mut parser = Parser::new_from(lexer);
+        let program = parser.parse_program().map_err(|e| {
+            let loc = cm.lookup_char_pos(e.span().lo);
+            format!(
+                "{}:{} {}",
+                loc.line,
+                loc.col_display + 1,
+                e.into_kind().msg()
+            )
+        })?;
+        let mut checkpoints = vec![(0, 0)];
+        let mut utf16 = 0;
+        for (i, c) in text.char_indices() {
+            if i >= checkpoints.last().unwrap().0 + CHECKPOINT {
+                checkpoints.push((i, utf16));
+            }
+            utf16 += c.len_utf16();
+        }
+        Ok(Self {
+            text,
+            program,
+            start: fm.start_pos,
+            checkpoints,
+        })
+    }
+    //节点在源码中的字节区间
+    fn range(&self, span: Span) -> (usize, usize) {
+        (
+            (span.lo - self.start).0 as usize,
+            (span.hi - self.start).0 as usize,
+        )
+    }
+    fn to_utf16(&self, byte: usize) -> usize {
+        let i = self.checkpoints.partition_point(|v| v.0 <= byte) - 1;
+        let (from, utf16) = self.checkpoints[i];
+        utf16 + self.text[from..byte].encode_utf16().count()
+    }
+    //utf16偏移落在字符中间或者超出源码时返回 None
+    fn to_byte(&self, utf16: usize) -> Option<usize> {
+        let i = self.checkpoints.partition_point(|v| v.1 <= utf16) - 1;
+        let (from, mut count) = self.checkpoints[i];
+        for (i, c) in self.text[from..].char_indices() {
+            if count == utf16 {
+                return Some(from + i);
+            }
+            if count > utf16 {
+                return None;
+            }
+            count += c.len_utf16();
+        }
+        (count == utf16).then_some(self.text.len())
+    }
+    fn range_json(&self, (start, end): (usize, usize)) -> Value {
+        json!({
+            "start": self.to_utf16(start),
+            "end": self.to_utf16(end),
+            "text": &self.text[start..end],
+        })
+    }
+}
+
+///包含 import 或 export 声明的脚本只能作为模块执行，解析失败时按普通脚本处理
+pub fn is_module(source: &str) -> bool {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Anon, source.to_string());
+    let lexer = Lexer::new(
+        Syntax::Es(Default::default()),
+        EsVersion::latest(),
+        StringInput::from(&*fm),
+        None,
+    );
+    let mut parser = Parser::new_from(lexer);
+    matches!(parser.parse_program(), Ok(Program::Module(_)))
+}
+
+//引用 name 的标识符，属性名和对象字面量的键不算
+struct IdentFinder<'a> {
+    name: &'a str,
+    found: Vec<(Span, IdentKind)>,
+}
+impl IdentFinder<'_> {
+    fn check(&mut self, ident: &Ident, kind: IdentKind) {
+        if &*ident.sym == self.name {
+            self.found.push((ident.span, kind));
+        }
+    }
+}
+impl Visit for IdentFinder<'_> {
+    fn visit_ident(&mut self, n: &Ident) {
+        self.check(n, IdentKind::Plain);
+    }
+    fn visit_member_prop(&mut self, n: &MemberProp) {
+        if let MemberProp::Computed(n) = n {
+            n.visit_with(self);
+        }
+    }
+    fn visit_super_prop(&mut self, n: &SuperProp) {
+        if let SuperProp::Computed(n) = n {
+            n.visit_with(self);
+        }
+    }
+    fn visit_prop_name(&mut self, n: &PropName) {
+        if let PropName::Computed(n) = n {
+            n.visit_with(self);
+        }
+    }
+    fn visit_prop(&mut self, n: &Prop) {
+        match n {
+            Prop::Shorthand(n) => self.check(n, IdentKind::Shorthand),
+            _ => n.visit_children_with(self),
+        }
+    }
+    fn visit_assign_pat_prop(&mut self, n: &swc_ecma_ast::AssignPatProp) {
+        self.check(&n.key, IdentKind::Shorthand);
+        n.value.visit_with(self);
+    }
+    fn visit_import_named_specifier(&mut self, n: &ImportNamedSpecifier) {
+        let kind = match n.imported {
+            Some(_) => IdentKind::Plain,
+            None => IdentKind::Import,
+        };
+        self.check(&n.local, kind);
+    }
+    fn visit_export_named_specifier(&mut self, n: &ExportNamedSpecifier) {
+        if let ModuleExportName::Ident(orig) = &n.orig {
+            let kind = match n.exported {
+                Some(_) => IdentKind::Plain,
+                None => IdentKind::Export,
+            };
+            self.check(orig, kind);
+        }
+    }
+}
+
+//被调用者是 path 表示的成员链，例如 ["document", "createElement"]
+fn is_callee(expr: &Expr, path: &[&str]) -> bool {
+    let (last, rest) = match path.split_last() {
+        Some(v) => v,
+        None => return false,
+    };
+    match expr {
+        Expr::Paren(n) => is_callee(&n.expr, path),
+        Expr::Ident(n) => rest.is_empty() && &*n.sym == *last,
+        Expr::This(_) => rest.is_empty() && *last == "this",
+        Expr::Member(n) => {
+            let prop = match &n.prop {
+                MemberProp::Ident(v) => &*v.sym,
+                MemberProp::Computed(v) => match &*v.expr {
+                    Expr::Lit(Lit::Str(v)) => &*v.value,
+                    _ => return false,
+                },
+                _ => return false,
+            };
+            prop == *last && is_callee(&n.obj, rest)
+        }
+        _ => false,
+    }
+}
+
+//(调用, 被调用者, 参数)
+type CallSpans = (Span, Span, Vec<Span>);
+
+struct CallFinder<'a> {
+    path: Vec<&'a str>,
+    found: Vec<CallSpans>,
+}
+impl CallFinder<'_> {
+    fn check(&mut self, span: Span, callee: &Expr, args: &[ExprOrSpread]) {
+        if is_callee(callee, &self.path) {
+            let args = args.iter().map(|v| v.span()).collect();
+            self.found.push((span, callee.span(), args));
+        }
+    }
+}
+impl Visit for CallFinder<'_> {
+    fn visit_call_expr(&mut self, n: &CallExpr) {
+        if let Callee::Expr(callee) = &n.callee {
+            self.check(n.span, callee, &n.args);
+        }
+        n.visit_children_with(self);
+    }
+    fn visit_opt_call(&mut self, n: &OptCall) {
+        self.check(n.span, &n.callee, &n.args);
+        n.visit_children_with(self);
+    }
+}
+
+///解析后的脚本，按语法树查找标识符和调用，替换时只改动对应的源码区间，其它部分保持原样。
+///偏移都按 utf16 计算，和js字符串的下标一致
+#[rquickjs::class(rename = "Script")]
+#[derive(Debug, Trace, Clone)]
+pub struct JsScript {
+    #[qjs(skip_trace)]
+    source: Rc<Source>,
+    //开始字节偏移 => (结束字节偏移, 替换的内容)
+    #[qjs(skip_trace)]
+    edits: Rc<RefCell<BTreeMap<usize, (usize, String)>>>,
+}
+impl JsScript {
+    fn identifiers(&self, name: &str) -> Vec<(Span, IdentKind)> {
+        let mut finder = IdentFinder {
+            name,
+            found: vec![],
+        };
+        self.source.program.visit_with(&mut finder);
+        finder.found
+    }
+    fn calls(&self, callee: &str) -> Vec<CallSpans> {
+        let mut finder = CallFinder {
+            path: callee.split('.').map(|v| v.trim()).collect(),
+            found: vec![],
+        };
+        self.source.program.visit_with(&mut finder);
+        finder.found
+    }
+    //和已有的替换重叠时不添加，先添加的优先
+    fn edit(&self, start: usize, end: usize, text: String) -> bool {
+        let mut edits = self.edits.borrow_mut();
+        if let Some((prev_start, (prev_end, _))) = edits.range(..=start).next_back() {
+            if *prev_start == start || *prev_end > start {
+                return false;
+            }
+        }
+        if let Some((next_start, _)) = edits.range(start..).next() {
+            if *next_start < end {
+                return false;
+            }
+        }
+        edits.insert(start, (end, text));
+        true
+    }
+}
+#[rquickjs::methods(rename_all = "camelCase")]
+impl JsScript {
+    #[qjs(constructor)]
+    pub fn new(source: String, ctx: Ctx<'_>) -> Result<Self> {
+        let source = match Source::parse(source) {
+            Ok(v) => v,
+            Err(e) => {
+                let e = rquickjs::String::from_str(ctx.clone(), &e)?;
+                return Err(ctx.throw(e.into()));
+            }
+        };
+        Ok(Self {
+            source: Rc::new(source),
+            edits: Default::default(),
+        })
+    }
+    #[qjs(get, rename = "source", enumerable)]
+    pub fn get_source(&self) -> String {
+        self.source.text.clone()
+    }
+    pub fn find_identifiers<'js>(
+        &self,
+        name: String,
+        ctx: Ctx<'js>,
+    ) -> Result<rquickjs::Value<'js>> {
+        let found = self
+            .identifiers(&name)
+            .into_iter()
+            .map(|(span, _)| self.source.range_json(self.source.range(span)))
+            .collect();
+        json_to_js(Value::Array(found), &ctx)
+    }
+    pub fn find_calls<'js>(&self, callee: String, ctx: Ctx<'js>) -> Result<rquickjs::Value<'js>> {
+        let source = &self.source;
+        let found = self
+            .calls(&callee)
+            .into_iter()
+            .map(|(call, callee, args)| {
+                let mut value = source.range_json(source.range(call));
+                value["callee"] = source.range_json(source.range(callee));
+                value["args"] = args
+                    .into_iter()
+                    .map(|v| source.range_json(source.range(v)))
+                    .collect();
+                value
+            })
+            .collect();
+        json_to_js(Value::Array(found), &ctx)
+    }
+    ///返回替换的个数
+    pub fn replace_identifiers(&self, name: String, replacement: String) -> usize {
+        let mut count = 0;
+        for (span, kind) in self.identifiers(&name) {
+            let text = match kind {
+                IdentKind::Plain => replacement.clone(),
+                IdentKind::Shorthand => format!("{name}: {replacement}"),
+                IdentKind::Import => format!("{name} as {replacement}"),
+                IdentKind::Export => format!("{replacement} as {name}"),
+            };
+            let (start, end) = self.source.range(span);
+            if self.edit(start, end, text) {
+                count += 1;
+            }
+        }
+        count
+    }
+    ///template 中的 $callee 和 $args 替换为原来的被调用者和参数的源码，返回替换的个数
+    pub fn replace_calls(&self, callee: String, template: String) -> usize {
+        let text = &self.source.text;
+        let mut count = 0;
+        for (call, callee, args) in self.calls(&callee) {
+            let (start, end) = self.source.range(call);
+            let (callee_start, callee_end) = self.source.range(callee);
+            let args = match (args.first(), args.last()) {
+                (Some(first), Some(last)) => {
+                    &text[self.source.range(*first).0..self.source.range(*last).1]
+                }
+                _ => "",
+            };
+            let replaced = template
+                .replace("$callee", &text[callee_start..callee_end])
+                .replace("$args", args);
+            if self.edit(start, end, replaced) {
+                count += 1;
+            }
+        }
+        count
+    }
+    ///按 utf16 偏移替换任意区间，和已有的替换重叠时返回 false
+    pub fn replace(&self, start: usize, end: usize, text: String, ctx: Ctx<'_>) -> Result<bool> {
+        let range = self
+            .source
+            .to_byte(start)
+            .zip(self.source.to_byte(end))
+            .filter(|(start, end)| start <= end);
+        let (start, end) = range.ok_or_else(|| throw_js_err("invalid range", ctx))?;
+        Ok(self.edit(start, end, text))
+    }
+    ///应用所有替换后的源码
+    #[qjs(rename = "toString")]
+    pub fn to_string_js(&self) -> String {
+        let text = &self.source.text;
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for (start, (end, replaced)) in self.edits.borrow().iter() {
+            out.push_str(&text[last..*start]);
+            out.push_str(replaced);
+            last = *end;
+        }
+        out.push_str(&text[last..]);
+        out
+    }
+}
+
+pub fn init_def(_id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
+    let globals = ctx.globals();
+    Class::<'_, JsScript>::define(&globals)?;
+    Ok(())
+}
+
+pub const DECLARE: &str = r#"
+interface ScriptRange {
+    start: number;
+    end: number;
+    text: string;
+}
+interface ScriptCall extends ScriptRange {
+    callee: ScriptRange;
+    args: ScriptRange[];
+}
+//按语法树查找和替换，偏移和js字符串下标一致，重叠的替换只保留先添加的
+declare class Script {
+    //解析失败时抛出异常
+    constructor(source: string);
+    readonly source: string;
+    //引用 name 的标识符，不包括属性名和对象字面量的键
+    findIdentifiers(name: string): ScriptRange[];
+    //callee 为标识符或 a.b.c 形式的成员链
+    findCalls(callee: string): ScriptCall[];
+    replaceIdentifiers(name: string, replacement: string): number;
+    //template 中的 $callee 和 $args 替换为原来的被调用者和参数的源码
+    replaceCalls(callee: string, template: string): number;
+    replace(start: number, end: number, text: string): boolean;
+    toString(): string;
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(text: &str) -> JsScript {
+        JsScript {
+            source: Rc::new(Source::parse(text.to_string()).unwrap()),
+            edits: Default::default(),
+        }
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        //跨过多个 checkpoint，并且包含多字节字符和代理对
+        let text = format!("let s = '{}';", "é😀a".repeat(CHECKPOINT));
+        let source = Source::parse(text.clone()).unwrap();
+        assert!(source.checkpoints.len() > 1);
+        let mut utf16 = 0;
+        for (byte, c) in text.char_indices() {
+            assert_eq!(source.to_utf16(byte), utf16);
+            assert_eq!(source.to_byte(utf16), Some(byte));
+            if c.len_utf16() == 2 {
+                //落在代理对中间
+                assert_eq!(source.to_byte(utf16 + 1), None);
+            }
+            utf16 += c.len_utf16();
+        }
+        assert_eq!(source.to_utf16(text.len()), utf16);
+        assert_eq!(source.to_byte(utf16), Some(text.len()));
+        assert_eq!(source.to_byte(utf16 + 1), None);
+    }
+
+    #[test]
+    fn overlapping_edits_are_rejected() {
+        let script = script("let abcdefgh = 1;");
+        assert!(script.edit(4, 8, "x".to_string()));
+        assert!(!script.edit(4, 6, "y".to_string()));
+        assert!(!script.edit(6, 10, "y".to_string()));
+        assert!(!script.edit(2, 5, "y".to_string()));
+        assert!(!script.edit(2, 12, "y".to_string()));
+        //紧挨着的区间不算重叠
+        assert!(script.edit(8, 12, "y".to_string()));
+        assert!(script.edit(0, 4, "const ".to_string()));
+        assert_eq!(script.to_string_js(), "const xy = 1;");
+    }
+
+    #[test]
+    fn renames_keep_bindings() {
+        let script = script(
+            "import { a } from \"m\";\nlet o = { a, b: o.a };\nlet f = ({ a }) => a;\nexport { a };\n",
+        );
+        assert_eq!(
+            script.replace_identifiers("a".to_string(), "z".to_string()),
+            5
+        );
+        assert_eq!(
+            script.to_string_js(),
+            "import { a as z } from \"m\";\nlet o = { a: z, b: o.a };\nlet f = ({ a: z }) => z;\nexport { z as a };\n",
+        );
+    }
+
+    #[test]
+    fn calls_are_rewritten_with_template() {
+        let script = script("document.createElement('div'); createElement(1);");
+        let count = script.replace_calls(
+            "document.createElement".to_string(),
+            "wrap($callee, $args)".to_string(),
+        );
+        assert_eq!(count, 1);
+        assert_eq!(
+            script.to_string_js(),
+            "wrap(document.createElement, 'div'); createElement(1);"
+        );
+    }
+}
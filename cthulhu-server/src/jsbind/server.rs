@@ -1,24 +1,27 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use futures::future::Either;
 use futures::SinkExt;
 
-use rquickjs::function::IntoArgs;
+use rquickjs::function::{IntoArgs, Opt};
 use rquickjs::{class::Trace, Class, Ctx};
 use rquickjs::{FromJs, IntoJs};
 
 use serde::Serialize;
 use serde_json::json;
 use tokio_tungstenite::tungstenite::Message;
+use tracing::{info_span, Instrument};
 
+use crate::handle::rpc;
 use crate::jsbind::throw_js_err;
 use crate::{auto_option, utils};
 
-use crate::CLIENT_MANAGER;
+use crate::{CLIENT_MANAGER, METRICS};
 
 use crate::UA_PARSER;
 
-use super::{http::*, to_js_err};
+use super::{console, http::*, js_to_json, json_to_js, to_js_err};
 
 use super::ws::*;
 
@@ -137,7 +140,7 @@ impl UAParser {
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Trace, Serialize)]
+#[derive(Debug, Clone, Trace, Serialize)]
 #[rquickjs::class(rename = "Scope")]
 pub struct Scope {
     #[qjs(get, enumerable, configurable)]
@@ -161,6 +164,18 @@ pub struct Scope {
     #[qjs(get, enumerable, configurable)]
     pub frame: i32,
 }
+//id 由划分域的信息计算得到，按cookie等身份划分时其它字段可能不同，只按 id 判断是否同一个域
+impl PartialEq for Scope {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Scope {}
+impl std::hash::Hash for Scope {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
 #[rquickjs::methods]
 impl Scope {
     #[qjs(skip)]
@@ -190,6 +205,13 @@ impl Scope {
         scope.id = scope.hash();
         scope
     }
+    ///按策略得到的客户端身份重新计算id，同一身份访问同一来源属于一个域
+    #[qjs(skip)]
+    pub fn with_identity(mut self, identity: &str) -> Self {
+        let s = format!("{}.{}.{}", &self.scheme, &self.host, identity);
+        self.id = utils::hash(s.as_bytes(), 16, 12);
+        self
+    }
     fn hash(&self) -> String {
         let s = format!(
             "{}.{}.{}.{}.{}.{}.{}.{}.{}",
@@ -224,7 +246,13 @@ impl Server {
     }
 
     #[qjs(rename = "watchResponse")]
-    pub async fn watch_response(&self, _res: JsResponse, _scope: Scope) -> rquickjs::Result<()> {
+    pub async fn watch_response(
+        &self,
+        _res: JsResponse,
+        _scope: Scope,
+        _req: Opt<JsRequest>,
+        _info: Opt<JsUpstreamInfo>,
+    ) -> rquickjs::Result<()> {
         Ok(())
     }
     #[qjs(rename = "watchMessage")]
@@ -246,6 +274,8 @@ impl Server {
         &self,
         res: JsResponse,
         _scope: Scope,
+        _req: Opt<JsRequest>,
+        _info: Opt<JsUpstreamInfo>,
     ) -> rquickjs::Result<JsResponse> {
         Ok(res)
     }
@@ -360,6 +390,47 @@ impl Server {
             .map_err(|e| to_js_err(e, ctx))?;
         Ok(())
     }
+    ///调用页面用 CthulhuServer.handle 注册的方法，等待页面回复，timeout 默认30秒。
+    ///只在 onCall 中使用：其它钩子执行期间持有插件上下文的锁，等待页面回复会阻塞插件的所有钩子
+    #[qjs(rename = "call")]
+    pub async fn call<'js>(
+        &self,
+        session_id: String,
+        method: String,
+        params: Opt<rquickjs::Value<'js>>,
+        timeout: Opt<u64>,
+        ctx: Ctx<'js>,
+    ) -> rquickjs::Result<rquickjs::Value<'js>> {
+        let params = match params.0 {
+            Some(params) => js_to_json(params)?,
+            None => serde_json::Value::Null,
+        };
+        //页面按 插件id.方法名 区分不同插件注册的方法
+        let method = format!("{}.{}", self.id, method);
+        let timeout = Duration::from_millis(timeout.0.unwrap_or(30_000));
+        let res = rpc::call_page(&session_id, &method, params, timeout).await;
+        let value = res
+            .map_err(|e| ctx.throw(rquickjs::String::from_str(ctx.clone(), &e).unwrap().into()))?;
+        json_to_js(value, &ctx)
+    }
+    ///通知页面注册的方法，不等待回复
+    #[qjs(rename = "notify")]
+    pub async fn notify<'js>(
+        &self,
+        session_id: String,
+        method: String,
+        params: Opt<rquickjs::Value<'js>>,
+        ctx: Ctx<'js>,
+    ) -> rquickjs::Result<()> {
+        let params = match params.0 {
+            Some(params) => js_to_json(params)?,
+            None => serde_json::Value::Null,
+        };
+        let method = format!("{}.{}", self.id, method);
+        rpc::notify_page(&session_id, &method, params)
+            .await
+            .map_err(|e| ctx.throw(rquickjs::String::from_str(ctx.clone(), &e).unwrap().into()))
+    }
 }
 
 pub async fn call_function<'js, T: FromJs<'js> + 'js, A: IntoArgs<'js>>(
@@ -389,6 +460,33 @@ pub async fn call_function<'js, T: FromJs<'js> + 'js, A: IntoArgs<'js>>(
     return Ok(Either::Left(promise));
 }
 
+///调用插件钩子，调用期间console输出的日志会记录所在的域和请求，同时统计耗时和异常
+pub async fn call_hook<'js, T: FromJs<'js> + 'js, A: IntoArgs<'js>>(
+    ctx: &Ctx<'js>,
+    name: &str,
+    args: A,
+    scope_id: &str,
+    request_id: &str,
+) -> rquickjs::Result<Either<T, A>> {
+    let plugin = ctx
+        .globals()
+        .get::<_, Server>("server")
+        .map(|v| v.id)
+        .unwrap_or_default();
+    let span = info_span!("plugin_hook", plugin = %plugin, hook = name, scope = scope_id);
+    console::set_context(ctx, scope_id, request_id);
+    let start = Instant::now();
+    let res = call_function(ctx, name, args).instrument(span).await;
+    console::set_context(ctx, "", "");
+    //没有定义的钩子不计入统计
+    if !matches!(res, Ok(Either::Right(_))) {
+        METRICS
+            .plugins
+            .observe_hook(&plugin, name, start.elapsed(), res.is_ok());
+    }
+    res
+}
+
 pub fn init_def(id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
     let server = Server { id: id.to_string() };
     let globals = ctx.globals();
@@ -400,3 +498,66 @@ pub fn init_def(id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
 
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+declare class UAParser {
+    constructor(ua: string);
+    readonly ua: string;
+    readonly product: Record<string, string>;
+    readonly os: Record<string, string>;
+    readonly device: Record<string, string>;
+    readonly cpu: Record<string, string>;
+    readonly engine: Record<string, string>;
+}
+//域只由代理创建，没有对应的全局构造函数
+interface Scope {
+    readonly id: string;
+    readonly ip: string;
+    readonly scheme: string;
+    readonly host: string;
+    readonly ua: string;
+    readonly email: string;
+    readonly custom: string;
+    readonly window: number;
+    readonly tab: number;
+    readonly frame: number;
+    toString(): string;
+}
+type SessionType = "content" | "worker";
+//插件通过覆盖这些方法来处理流量，未覆盖的方法使用默认行为
+interface Server {
+    csp?: string;
+    watchRequest(req: Request, scope: Scope): Promise<void> | void;
+    //req 为插件修改后实际发出的请求，插件直接响应时为 undefined
+    watchResponse(res: Response, scope: Scope, req?: Request, info?: UpstreamInfo): Promise<void> | void;
+    watchMessage(msg: Message, scope: Scope): Promise<void> | void;
+    onRequest(req: Request, scope: Scope): Promise<HttpAction> | HttpAction;
+    onResponse(res: Response, scope: Scope, req?: Request, info?: UpstreamInfo): Promise<Response> | Response;
+    onMessage(msg: Message, scope: Scope): Promise<WsAction> | WsAction;
+    //改写脚本响应的源码，包括模块脚本和 worker 脚本，按插件优先级依次调用。
+    //返回 undefined 表示不修改，需要网络修改权限
+    onScript?(source: string, url: string, scope: Scope): Promise<string | undefined> | string | undefined;
+    onClientOpen(sessionType: SessionType, sessionId: string, scope: Scope): Promise<void> | void;
+    onClientClose(sessionType: SessionType, sessionId: string, scope: Scope): Promise<void> | void;
+    //域第一次出现时调用
+    onScopeCreate?(scope: Scope): Promise<void> | void;
+    //域超时或被关闭时调用，reason 为 "ttl" 或 "closed"
+    onScopeExpire?(scope: Scope, reason: "ttl" | "closed"): Promise<void> | void;
+    //域的划分方式为 plugin 时调用，返回客户端身份标识，返回空时使用默认划分方式
+    resolveScope?(req: Request, ip: string): Promise<string | undefined> | string | undefined;
+    onAsk(key: string, value: any, scope: Scope): Promise<any> | any;
+    //页面通过 CthulhuServer 的 call 和 stream 调用，method 不含插件id。
+    //返回迭代器或异步迭代器时每一项都推送给页面
+    onCall?(method: string, params: any, scope: Scope, sessionId: string): Promise<any> | any;
+    dynamicScript(link: string, scope: Scope): Promise<string> | string;
+    sendEvent(sessionId: string, eventType: string, eventBody: Record<string, any>): Promise<void>;
+    sendScript(sessionId: string, script: string): Promise<void>;
+    //调用页面用 CthulhuServer.handle 注册的方法，timeout 默认30000毫秒。
+    //只在 onCall 中 await，其它钩子中等待页面回复会阻塞插件的所有钩子，需要时改用 notify
+    call(sessionId: string, method: string, params?: any, timeout?: number): Promise<any>;
+    notify(sessionId: string, method: string, params?: any): Promise<void>;
+}
+declare var server: Server;
+declare var server_dir: string;
+declare var global: typeof globalThis;
+"#;
@@ -278,7 +278,37 @@ pub fn init_def(id: &str, ctx: &Ctx<'_>, db: Db) -> rquickjs::Result<()> {
         id: id.to_string(),
         db,
     };
+    Class::<JsTree>::define(&ctx.globals())?;
     let cls = Class::instance(ctx.clone(), store)?;
     ctx.globals().set("store", cls)?;
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+declare class Tree {
+    private constructor();
+    set(key: string, value: any): void;
+    sets(kvs: Record<string, any>): void;
+    get(key: string): any;
+    gets(keys: string[]): Record<string, any>;
+    getWith(prefix: string): Record<string, any>;
+    remove(key: string): any | undefined;
+    removes(keys: string[]): Record<string, any>;
+    removeWith(prefix: string): Record<string, any>;
+    //回调返回 false 时停止遍历
+    iterWith(prefix: string, func: (key: string, value: any) => boolean | void): void;
+    countWith(prefix: string): number;
+    clear(): void;
+    flush(): void;
+    keys(): string[];
+    contains(key: string): boolean;
+    transaction<T>(func: () => T): T;
+}
+interface Store {
+    openTree(name: string): Tree;
+    dropTree(name: string): boolean;
+    treeNames(): string[];
+    toString(): string;
+}
+declare var store: Store;
+"#;
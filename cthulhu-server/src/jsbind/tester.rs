@@ -0,0 +1,291 @@
+use std::path::{Path, PathBuf};
+
+use rquickjs::{async_with, function::Func, function::Opt, CatchResultExt, Function, Object};
+
+use crate::{auto_option, auto_result, handle::api::plugin, utils};
+
+use super::{server::Scope, typescript};
+
+//测试文件所在目录，相对插件根目录
+const TEST_DIR: &str = "test";
+const TEST_SUFFIXES: &[&str] = &[".test.js", ".test.mjs", ".test.ts"];
+
+//测试用的全局对象 test/assert/fixtures，测试结果以json返回给rust
+const PRELUDE: &str = r#"
+(() => {
+    const tests = [];
+    const format = (v) => {
+        try {
+            return JSON.stringify(v);
+        } catch (_) {
+            return String(v);
+        }
+    };
+    class AssertionError extends Error {
+        constructor(message) {
+            super(message);
+            this.name = "AssertionError";
+        }
+    }
+    const fail = (msg, def) => {
+        throw new AssertionError(msg || def);
+    };
+    const assert = (value, msg) => {
+        if (!value) fail(msg, `expected truthy, got ${format(value)}`);
+    };
+    assert.ok = assert;
+    assert.equal = (actual, expected, msg) => {
+        if (actual !== expected) fail(msg, `expected ${format(expected)}, got ${format(actual)}`);
+    };
+    assert.notEqual = (actual, expected, msg) => {
+        if (actual === expected) fail(msg, `expected not ${format(expected)}`);
+    };
+    assert.deepEqual = (actual, expected, msg) => {
+        if (format(actual) !== format(expected)) fail(msg, `expected ${format(expected)}, got ${format(actual)}`);
+    };
+    assert.match = (actual, regexp, msg) => {
+        if (!regexp.test(actual)) fail(msg, `${format(actual)} does not match ${regexp}`);
+    };
+    assert.throws = async (fn, msg) => {
+        try {
+            await fn();
+        } catch (_) {
+            return;
+        }
+        fail(msg, "expected function to throw");
+    };
+    const toHeaders = (obj) => {
+        const headers = new Headers();
+        for (const [key, value] of Object.entries(obj || {})) {
+            for (const item of [].concat(value)) headers.append(key, String(item));
+        }
+        return headers;
+    };
+    const toBody = (body) => {
+        if (body === undefined || body === null) return Body.empty();
+        if (typeof body === "string") return Body.str(body);
+        if (Array.isArray(body)) return Body.bytes(body);
+        return Body.str(JSON.stringify(body));
+    };
+    const fixtures = {
+        request: (opts = {}) =>
+            new Request(opts.method || "GET", new Uri(opts.url || "https://example.com/"), toHeaders(opts.headers), toBody(opts.body)),
+        response: (opts = {}) => new Response(opts.status || 200, toHeaders(opts.headers), toBody(opts.body)),
+        scope: (opts = {}) => __cthulhu_scope(opts),
+        load: async (name) => {
+            const file = new File(new Path(`${server_dir}/test/fixtures/${name}`));
+            return JSON.parse(StrUtils.bytes_to_utf8(await file.readBytes()));
+        },
+    };
+    globalThis.test = (name, fn) => tests.push({ name, fn });
+    globalThis.assert = assert;
+    globalThis.fixtures = fixtures;
+    globalThis.__cthulhu_run = async () => {
+        const results = [];
+        for (const { name, fn } of tests.splice(0)) {
+            try {
+                await fn();
+                results.push({ name, ok: true });
+            } catch (e) {
+                const error = e && e.message !== undefined ? `${e.name}: ${e.message}` : String(e);
+                results.push({ name, ok: false, error });
+            }
+        }
+        return JSON.stringify(results);
+    };
+})();
+"#;
+
+//与上面的 PRELUDE 一一对应的 TypeScript 声明
+pub const DECLARE: &str = r#"
+//以下成员只在 cthulhu plugin test 中可用
+interface Assert {
+    (value: any, msg?: string): void;
+    ok(value: any, msg?: string): void;
+    equal<T>(actual: T, expected: T, msg?: string): void;
+    notEqual<T>(actual: T, expected: T, msg?: string): void;
+    deepEqual<T>(actual: T, expected: T, msg?: string): void;
+    match(actual: string, regexp: RegExp, msg?: string): void;
+    throws(fn: () => any, msg?: string): Promise<void>;
+}
+interface RequestFixture {
+    method?: string;
+    url?: string;
+    headers?: Record<string, string | string[]>;
+    body?: string | number[] | object;
+}
+interface ResponseFixture {
+    status?: number;
+    headers?: Record<string, string | string[]>;
+    body?: string | number[] | object;
+}
+interface ScopeFixture {
+    ip?: string;
+    scheme?: string;
+    host?: string;
+    ua?: string;
+    email?: string;
+    custom?: string;
+    window?: number;
+    tab?: number;
+    frame?: number;
+}
+interface Fixtures {
+    request(opts?: RequestFixture): Request;
+    response(opts?: ResponseFixture): Response;
+    scope(opts?: ScopeFixture): Scope;
+    //读取 test/fixtures 下的json文件
+    load<T = any>(name: string): Promise<T>;
+}
+declare function test(name: string, fn: () => any): void;
+declare var assert: Assert;
+declare var fixtures: Fixtures;
+"#;
+
+#[rquickjs::function]
+pub fn fixture_scope(opts: Opt<Object<'_>>) -> rquickjs::Result<Scope> {
+    let opts = opts.0;
+    let str = |key: &str, default: &str| {
+        opts.as_ref()
+            .and_then(|v| v.get::<_, Option<String>>(key).ok().flatten())
+            .unwrap_or(default.to_string())
+    };
+    let num = |key: &str| {
+        opts.as_ref()
+            .and_then(|v| v.get::<_, Option<i32>>(key).ok().flatten())
+            .unwrap_or(0)
+    };
+    Ok(Scope::new(
+        str("ip", "127.0.0.1"),
+        str("scheme", "https"),
+        str("host", "https://example.com"),
+        str(
+            "ua",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+        ),
+        str("email", ""),
+        str("custom", ""),
+        num("window"),
+        num("tab"),
+        num("frame"),
+    ))
+}
+
+fn find_tests(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = auto_result!(std::fs::read_dir(dir), {
+        return;
+    });
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_tests(&path, files);
+            continue;
+        }
+        let name = path.to_str().unwrap_or("");
+        if TEST_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+            files.push(path);
+        }
+    }
+}
+
+///运行插件目录 test 下的测试文件，全部通过时返回true
+pub async fn run(dir: &Path) -> bool {
+    let mut plugin = auto_option!(plugin::read_manifest(dir), false);
+    plugin.id = "test".to_string();
+
+    let mut files = vec![];
+    find_tests(&dir.join(TEST_DIR), &mut files);
+    files.sort();
+    if files.is_empty() {
+        println!("没有找到测试文件：{TEST_DIR}/**/*.test.js");
+        return false;
+    }
+    //ts测试文件使用编译目录中的js
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let rlt = file
+                .strip_prefix(dir)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .replace('\\', "/");
+            (rlt, file)
+        })
+        .collect::<Vec<(String, PathBuf)>>();
+    if files.iter().any(|(rlt, _)| typescript::is_ts(rlt)) {
+        auto_result!(typescript::build_plugin(dir),err=>{
+            println!("编译TypeScript脚本失败:{err}");
+            return false;
+        });
+    }
+    let tests = files.into_iter().map(|(rlt, file)| {
+        if typescript::is_ts(&rlt) {
+            let file = dir.join(typescript::output_path(&rlt));
+            (rlt, file)
+        } else {
+            (rlt, file)
+        }
+    });
+
+    let db = auto_result!(sled::Config::new().temporary(true).open(),err=>{
+        println!("创建临时存储失败:{err}");
+        return false;
+    });
+    let (ctx, _rt, _db) = auto_result!(super::content_with_db(&plugin, db).await,err=>{
+        println!("加载插件失败:{err}");
+        return false;
+    });
+    let init = async_with!(ctx=>|ctx|{
+        let init = || {
+            ctx.globals().set("__cthulhu_scope", Func::new(fixture_scope))?;
+            ctx.eval::<(), _>(PRELUDE)
+        };
+        init().catch(&ctx).map_err(|e| e.to_string())
+    })
+    .await;
+    auto_result!(init,err=>{
+        println!("初始化测试环境失败:{err}");
+        return false;
+    });
+
+    let (mut passed, mut failed) = (0, 0);
+    for (name, path) in tests {
+        println!("{name}");
+        let results = async_with!(ctx=>|ctx|{
+            let code = auto_result!(utils::read_bytes(&path),err=>{
+                return Err(err.to_string());
+            });
+            let code = String::from_utf8(code).map_err(|e| e.to_string())?;
+            let res = async {
+                let _ = ctx.clone().compile(path.to_str().unwrap(), code)?;
+                let run = ctx.globals().get::<_, Function>("__cthulhu_run")?;
+                let promise = run.call::<_, rquickjs::promise::Promise<'_, String>>(())?;
+                promise.await
+            }
+            .await
+            .catch(&ctx);
+            res.map_err(|e| e.to_string())
+        })
+        .await;
+        let results = auto_result!(results,err=>{
+            println!("  ✗ 加载失败\n      {err}");
+            failed += 1;
+            continue;
+        });
+        let results = serde_json::from_str::<Vec<serde_json::Value>>(&results).unwrap_or_default();
+        for result in results {
+            let name = result["name"].as_str().unwrap_or_default();
+            if result["ok"].as_bool().unwrap_or(false) {
+                passed += 1;
+                println!("  ✓ {name}");
+            } else {
+                failed += 1;
+                println!("  ✗ {name}");
+                println!("      {}", result["error"].as_str().unwrap_or_default());
+            }
+        }
+    }
+    println!("{passed} passed, {failed} failed");
+    failed == 0
+}
@@ -111,3 +111,14 @@ pub fn init_def(_id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
     globals.set("clearTimeout", Func::new(clear_timeout))?;
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+//由定时器函数返回
+interface CancellationToken {
+    cancel(): void;
+}
+declare function setInterval(func: () => void, delay?: number, immediately?: boolean): CancellationToken;
+declare function clearInterval(token: CancellationToken): void;
+declare function setTimeout(func: () => void, delay?: number): CancellationToken;
+declare function clearTimeout(token: CancellationToken): void;
+"#;
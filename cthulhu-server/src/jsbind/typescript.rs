@@ -0,0 +1,398 @@
+use std::path::Path;
+
+use swc_common::{sync::Lrc, FileName, Globals, Mark, SourceMap, GLOBALS};
+use swc_ecma_ast::EsVersion;
+use swc_ecma_codegen::{text_writer::JsWriter, Emitter};
+use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
+use swc_ecma_transforms_base::{fixer::fixer, hygiene::hygiene, resolver};
+use swc_ecma_transforms_typescript::strip;
+use swc_ecma_visit::FoldWith;
+
+use crate::utils;
+
+use super::{console, file, http, loader, script, server, store, tester, timer, ws};
+
+///ts脚本编译后输出的目录，相对插件根目录
+pub const BUILD_DIR: &str = ".cthulhu/build";
+
+//编译插件时不进入的目录
+const SKIP_DIRS: &[&str] = &["node_modules", ".cthulhu", ".git", "STORE", "LOGS"];
+//原样复制到编译目录的文件，保证编译后的脚本仍能相对导入它们
+const COPY_EXTENSIONS: &[&str] = &["js", "mjs", "cjs", "json"];
+
+///生成 cthulhu.d.ts 的内容
+pub fn declarations() -> String {
+    let mut dts = String::from("// 由 cthulhu 根据内置绑定生成，请勿手动修改\n");
+    for declare in [
+        http::DECLARE,
+        ws::DECLARE,
+        server::DECLARE,
+        store::DECLARE,
+        timer::DECLARE,
+        file::DECLARE,
+        script::DECLARE,
+        super::utils::DECLARE,
+        console::DECLARE,
+        tester::DECLARE,
+    ] {
+        dts.push_str(declare);
+    }
+    for (name, members) in loader::BUILTINS {
+        dts.push_str(&format!(
+            "\ndeclare module \"{}{name}\" {{\n",
+            loader::BUILTIN_PREFIX
+        ));
+        for member in members.iter() {
+            dts.push_str(&format!(
+                "    export const {member}: typeof globalThis.{member};\n"
+            ));
+        }
+        dts.push_str(&format!(
+            "    const _default: {{ {} }};\n    export default _default;\n}}\n",
+            members
+                .iter()
+                .map(|m| format!("{m}: typeof globalThis.{m}"))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ));
+    }
+    dts
+}
+
+pub fn is_ts(path: &str) -> bool {
+    (path.ends_with(".ts") || path.ends_with(".mts")) && !path.ends_with(".d.ts")
+}
+
+///ts脚本在编译目录中对应的js路径，例如 src/server.ts => .cthulhu/build/src/server.js
+pub fn output_path(path: &str) -> String {
+    let stem = path
+        .strip_suffix(".mts")
+        .or_else(|| path.strip_suffix(".ts"))
+        .unwrap_or(path);
+    format!("{BUILD_DIR}/{}.js", stem.trim_start_matches("./"))
+}
+
+///只去掉类型标注，不做降级和打包
+pub fn strip_types(name: &str, source: String) -> Result<String, String> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Custom(name.to_string()), source);
+    let lexer = Lexer::new(
+        Syntax::Typescript(TsConfig {
+            decorators: true,
+            ..Default::default()
+        }),
+        EsVersion::latest(),
+        StringInput::from(&*fm),
+        None,
+    );
+    let mut parser = Parser::new_from(lexer);
+    let program = parser.parse_program().map_err(|e| {
+        let loc = cm.lookup_char_pos(e.span().lo);
+        format!(
+            "{name}:{}:{} {}",
+            loc.line,
+            loc.col_display + 1,
+            e.into_kind().msg()
+        )
+    })?;
+
+    GLOBALS.set(&Globals::default(), || {
+        let unresolved_mark = Mark::new();
+        let top_level_mark = Mark::new();
+        let program = program
+            .fold_with(&mut resolver(unresolved_mark, top_level_mark, true))
+            .fold_with(&mut strip(top_level_mark))
+            .fold_with(&mut hygiene())
+            .fold_with(&mut fixer(None));
+
+        let mut buf = vec![];
+        {
+            let mut emitter = Emitter {
+                cfg: Default::default(),
+                cm: cm.clone(),
+                comments: None,
+                wr: JsWriter::new(cm.clone(), "\n", &mut buf, None),
+            };
+            emitter
+                .emit_program(&program)
+                .map_err(|e| format!("{name}: {e}"))?;
+        }
+        String::from_utf8(buf).map_err(|e| format!("{name}: {e}"))
+    })
+}
+
+///把插件目录中的ts脚本编译到 BUILD_DIR，目录结构保持不变
+pub fn build_plugin(dir: &Path) -> Result<(), String> {
+    let out = dir.join(BUILD_DIR);
+    if out.exists() {
+        utils::remove_path(&out).map_err(|e| e.to_string())?;
+    }
+    build_dir(dir, dir, &out)
+}
+
+fn build_dir(root: &Path, dir: &Path, out: &Path) -> Result<(), String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| e.to_string())?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let name = path.file_name().and_then(|v| v.to_str()).unwrap_or("");
+        if path.is_dir() {
+            if SKIP_DIRS.contains(&name) {
+                continue;
+            }
+            build_dir(root, &path, out)?;
+            continue;
+        }
+        let rlt = path.strip_prefix(root).map_err(|e| e.to_string())?;
+        let rlt = rlt.to_str().unwrap().replace('\\', "/");
+        let ext = path.extension().and_then(|v| v.to_str()).unwrap_or("");
+        if is_ts(&rlt) {
+            let bytes = utils::read_bytes(&path).map_err(|e| e.to_string())?;
+            let source = String::from_utf8(bytes).map_err(|e| format!("{rlt}: {e}"))?;
+            let code = strip_types(&rlt, source)?;
+            let target = root.join(output_path(&rlt));
+            utils::write_bytes(target, code.as_bytes(), None).map_err(|e| e.to_string())?;
+        } else if COPY_EXTENSIONS.contains(&ext) {
+            let target = out.join(&rlt);
+            let bytes = utils::read_bytes(&path).map_err(|e| e.to_string())?;
+            utils::write_bytes(target, &bytes, None).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rquickjs::{async_with, AsyncContext, AsyncRuntime};
+
+    use super::*;
+
+    fn ident(s: &str) -> &str {
+        s.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .next()
+            .unwrap_or("")
+    }
+
+    //把声明翻译成在插件上下文里应当为真的表达式，可选成员和构造函数不检查
+    fn expectations(declare: &str) -> Vec<String> {
+        let mut checks = vec![];
+        let mut interfaces: HashMap<String, Vec<String>> = HashMap::new();
+        let mut vars = vec![];
+        //当前所在的 class 或 interface，以及是否是 class
+        let mut current: Option<(String, bool)> = None;
+        for line in declare.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("declare class ") {
+                let name = ident(rest).to_string();
+                checks.push(format!("typeof {name} === 'function'"));
+                current = Some((name, true));
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("interface ") {
+                current = Some((ident(rest).to_string(), false));
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("declare function ") {
+                checks.push(format!("typeof {} === 'function'", ident(rest)));
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("declare var ") {
+                let (name, ty) = rest.split_once(':').unwrap();
+                checks.push(format!("typeof {} !== 'undefined'", name.trim()));
+                vars.push((name.trim().to_string(), ident(ty.trim()).to_string()));
+                continue;
+            }
+            if line == "}" {
+                current = None;
+                continue;
+            }
+            let Some((owner, is_class)) = &current else {
+                continue;
+            };
+            let member = line.trim_start_matches("readonly ");
+            let (is_static, member) = match member.strip_prefix("static ") {
+                Some(member) => (true, member),
+                None => (false, member),
+            };
+            let name = ident(member);
+            if name.is_empty()
+                || name == "constructor"
+                || name == "private"
+                || member[name.len()..].starts_with('?')
+            {
+                continue;
+            }
+            if !is_class {
+                interfaces
+                    .entry(owner.clone())
+                    .or_default()
+                    .push(name.to_string());
+            } else if is_static {
+                checks.push(format!("'{name}' in {owner}"));
+            } else {
+                checks.push(format!("'{name}' in {owner}.prototype"));
+            }
+        }
+        for (var, ty) in vars {
+            for member in interfaces.get(&ty).into_iter().flatten() {
+                checks.push(format!("'{member}' in {var}"));
+            }
+        }
+        checks
+    }
+
+    //声明中每个 class 的实例成员和静态成员，包括可选成员，按名称排序
+    fn declared_classes(declare: &str) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let mut classes = vec![];
+        let mut current: Option<(String, Vec<String>, Vec<String>)> = None;
+        for line in declare.lines().map(str::trim) {
+            if let Some(rest) = line.strip_prefix("declare class ") {
+                current = Some((ident(rest).to_string(), vec![], vec![]));
+                continue;
+            }
+            if line == "}" {
+                classes.extend(current.take());
+                continue;
+            }
+            let Some((_, members, statics)) = &mut current else {
+                continue;
+            };
+            if line.starts_with("//") {
+                continue;
+            }
+            let member = line.trim_start_matches("readonly ");
+            let (list, member) = match member.strip_prefix("static ") {
+                Some(member) => (statics, member),
+                None => (members, member),
+            };
+            let name = ident(member);
+            if !name.is_empty() && name != "constructor" && name != "private" {
+                list.push(name.to_string());
+            }
+        }
+        for (_, members, statics) in classes.iter_mut() {
+            members.sort();
+            statics.sort();
+        }
+        classes
+    }
+
+    #[test]
+    fn expectations_follow_declarations() {
+        let checks = expectations(
+            "declare class A {\n    private constructor();\n    static make(): A;\n    readonly id: string;\n    opt?: number;\n}\ninterface B {\n    run(): void;\n    hook?(): void;\n}\ndeclare var b: B;\ndeclare function f(): void;\n",
+        );
+        assert_eq!(
+            checks,
+            [
+                "typeof A === 'function'",
+                "'make' in A",
+                "'id' in A.prototype",
+                "typeof b !== 'undefined'",
+                "typeof f === 'function'",
+                "'run' in b",
+            ]
+        );
+    }
+
+    #[test]
+    fn classes_follow_declarations() {
+        let classes = declared_classes(
+            "declare class A {\n    private constructor();\n    static make(): A;\n    //注释\n    readonly id: string;\n    opt?: number;\n    run(): void;\n}\ninterface B {\n    run(): void;\n}\n",
+        );
+        assert_eq!(
+            classes,
+            [(
+                "A".to_string(),
+                vec!["id".to_string(), "opt".to_string(), "run".to_string()],
+                vec!["make".to_string()]
+            )]
+        );
+    }
+
+    //tester 的声明只在测试运行器里有对应的全局变量，不在这里检查
+    #[tokio::test]
+    async fn declarations_match_bindings() {
+        let rt = AsyncRuntime::new().unwrap();
+        let full = AsyncContext::full(&rt).await.unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        let failures: Vec<String> = async_with!(full=>|ctx|{
+            let id = "declarations";
+            console::init_def(dir.clone(), id, console::LogConfig::default(), &ctx).unwrap();
+            store::init_def(id, &ctx, db).unwrap();
+            server::init_def(id, &ctx).unwrap();
+            http::init_def(id, &ctx).unwrap();
+            ws::init_def(id, &ctx).unwrap();
+            super::super::utils::init_def(id, &ctx).unwrap();
+            timer::init_def(id, &ctx).unwrap();
+            file::init_def(id, &ctx).unwrap();
+            script::init_def(id, &ctx).unwrap();
+            let globals = ctx.globals();
+            globals.set("server_dir", dir).unwrap();
+            globals.set("global", globals.clone()).unwrap();
+            let mut failures = vec![];
+            for declare in [
+                http::DECLARE,
+                ws::DECLARE,
+                server::DECLARE,
+                store::DECLARE,
+                timer::DECLARE,
+                file::DECLARE,
+                script::DECLARE,
+                super::super::utils::DECLARE,
+                console::DECLARE,
+            ] {
+                for check in expectations(declare) {
+                    if !ctx.eval::<bool, _>(check.as_str()).unwrap_or(false) {
+                        failures.push(check);
+                    }
+                }
+                //绑定上多出或缺少的成员都说明声明已经过时
+                for (class, members, statics) in declared_classes(declare) {
+                    let names = |target: &str, skip: &str| {
+                        let code = format!(
+                            "Object.getOwnPropertyNames({target}).filter(v => !{skip}.includes(v)).sort().join(',')"
+                        );
+                        ctx.eval::<String, _>(code).unwrap_or_default()
+                    };
+                    let bound = names(&format!("{class}.prototype"), "['constructor']");
+                    if bound != members.join(",") {
+                        failures.push(format!("{class}.prototype: [{bound}] != [{}]", members.join(",")));
+                    }
+                    let bound = names(&class, "['length', 'name', 'prototype']");
+                    if bound != statics.join(",") {
+                        failures.push(format!("{class}: [{bound}] != [{}]", statics.join(",")));
+                    }
+                }
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "声明与绑定不一致: {failures:#?}");
+    }
+
+    #[test]
+    fn strips_type_annotations() {
+        let js = strip_types(
+            "a.ts",
+            "interface Opt { id: string }\ntype Id = string | number;\nexport function run(opt: Opt, n?: number): Id {\n    const list: Array<Id> = [opt.id as Id];\n    return list[n!] ?? 0;\n}\n".to_string(),
+        )
+        .unwrap();
+        assert!(!js.contains("interface"), "{js}");
+        assert!(!js.contains("type Id"), "{js}");
+        assert!(!js.contains(": Opt"), "{js}");
+        assert!(!js.contains(" as "), "{js}");
+        assert!(js.contains("export function run(opt, n)"), "{js}");
+        assert!(js.contains("return list[n] ?? 0;"), "{js}");
+    }
+
+    #[test]
+    fn reports_syntax_error_position() {
+        let err = strip_types("b.ts", "let a = 1;\nlet b: = 2;\n".to_string()).unwrap_err();
+        assert!(err.starts_with("b.ts:2:"), "{err}");
+    }
+}
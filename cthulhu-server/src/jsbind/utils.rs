@@ -64,3 +64,17 @@ pub fn init_def(_id: &str, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
     Class::<'_, StrUtils>::define(&globals)?;
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+declare class StrUtils {
+    private constructor();
+    static hashstr(s: string, size: number): string;
+    static str_to_bytes(s: string): number[];
+    static bytes_to_utf8(bytes: number[]): string;
+    static mini_match(pattern: string, target: string): boolean;
+    static url_encode(s: string): string;
+    static url_decode(s: string): string;
+    static base64_encode(bytes: number[]): string;
+    static base64_decode(s: string): number[];
+}
+"#;
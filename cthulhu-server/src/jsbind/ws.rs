@@ -81,6 +81,7 @@ pub struct JsFrameHeader {
 }
 #[rquickjs::methods]
 impl JsFrameHeader {
+    #[qjs(constructor)]
     pub fn new(
         is_final: bool,
         rsv1: bool,
@@ -224,6 +225,17 @@ pub enum WsAction {
     Respond(JsMessage),
     Release(JsMessage),
 }
+impl WsAction {
+    ///动作名称，用于统计
+    pub fn name(&self) -> &'static str {
+        match self {
+            WsAction::Ignore => "ignore",
+            WsAction::Delay(..) => "delay",
+            WsAction::Respond(_) => "respond",
+            WsAction::Release(_) => "release",
+        }
+    }
+}
 #[rquickjs::class(rename = "WsAction")]
 #[derive(Debug, Clone, Trace)]
 pub struct JsWsAction {
@@ -297,7 +309,37 @@ impl std::fmt::Display for JsWsAction {
 
 pub fn init_def<'js>(_id: &str, ctx: &Ctx<'js>) -> rquickjs::Result<()> {
     let globals = ctx.globals();
+    Class::<'js, JsFrameHeader>::define(&globals)?;
     Class::<'js, JsMessage>::define(&globals)?;
     Class::<'js, JsWsAction>::define(&globals)?;
     Ok(())
 }
+
+pub const DECLARE: &str = r#"
+declare class FrameHeader {
+    constructor(is_final: boolean, rsv1: boolean, rsv2: boolean, rsv3: boolean, opcode: `${"data" | "control"}:${string}`, mask?: number[]);
+    is_final: boolean;
+    rsv1: boolean;
+    rsv2: boolean;
+    rsv3: boolean;
+}
+declare class Message {
+    private constructor();
+    static text(text: string): Message;
+    static binary(bin: number[]): Message;
+    static frame(header: FrameHeader, payload: number[]): Message;
+    readonly type: "text" | "binary" | "ping" | "pong" | "close" | "frame";
+    toText(): string;
+    toBytes(): number[];
+    len(): number;
+    toString(): string;
+}
+declare class WsAction {
+    private constructor();
+    static ignore(): WsAction;
+    static respond(msg: Message): WsAction;
+    static release(msg: Message): WsAction;
+    readonly name: "delay" | "ignore" | "respond" | "release";
+    toString(): string;
+}
+"#;
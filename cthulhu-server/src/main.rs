@@ -7,41 +7,47 @@ use clap::{arg, ArgAction};
 use futures::stream::SplitSink;
 use handle::{api::config::set_system_proxy, Handler};
 
-use hyper::{upgrade::Upgraded, Body, Uri};
+use hyper::{upgrade::Upgraded, Body};
 
 use hyper_tungstenite::tungstenite::Message;
 use lazy_static::lazy_static;
 use local_ip_address::local_ip;
-use markup5ever::tendril::fmt::Slice;
 
 use moka::future::Cache;
 use net_proxy::{certificate_authority::RcgenAuthority, CustomProxy};
 use rand::{rngs::StdRng, Rng};
 use reqwest::redirect;
-use time::macros::format_description;
 
 use tokio_tungstenite::WebSocketStream;
-use tracing::Level;
-use tracing_subscriber::{fmt::time::LocalTime, FmtSubscriber};
 
 use rustls_pemfile as pemfile;
 use user_agent_parser::UserAgentParser;
 
 use crate::{
+    breakpoint::BreakpointManager,
     core::{AsyncTaskManager, ClientManager, PluginCtx},
+    metrics::Metrics,
     handle::api::{config, plugin},
-    net_proxy::AddrListenerServer,
+    net_proxy::{AddrListenerServer, HttpContext},
+    rules::RuleCache,
 };
 
 
+mod breakpoint;
 mod core;
 mod handle;
 
 mod ja3;
 mod jsbind;
+mod matcher;
+mod metrics;
+mod migrate;
 mod net_proxy;
 mod proxy;
 mod rcgen;
+mod rules;
+mod sri;
+mod telemetry;
 mod utils;
 
 mod macros;
@@ -65,6 +71,10 @@ lazy_static! {
 
     ///客户端上下文管理器
     pub static ref CLIENT_MANAGER:ClientManager=ClientManager::default();
+    //断点规则和被暂停的请求
+    pub static ref BREAKPOINTS: BreakpointManager = BreakpointManager::default();
+    //启用的映射和头部改写规则
+    pub static ref RULES: RuleCache = RuleCache::default();
     //插件hash 映射 对应的js上下文
     pub static ref PLUGIN_MANAGER: PluginManager =PluginManager::default();
     //异步任务管理器
@@ -76,6 +86,21 @@ lazy_static! {
     .time_to_live(std::time::Duration::from_secs(60*10))
     .build();
 
+    ///被插件修改过的脚本和样式，按地址记录修改后内容的摘要
+    pub static ref MODIFIED_ASSETS: Cache<String,sri::ModifiedAsset> =Cache::builder()
+    .max_capacity(10000)
+    .time_to_live(std::time::Duration::from_secs(60*30))
+    .build();
+
+    ///域的划分方式，第一次使用时从配置读取
+    pub static ref SCOPE_STRATEGY: tokio::sync::RwLock<Option<handle::scope::ScopeStrategy>> =tokio::sync::RwLock::new(None);
+
+    ///按cookie划分域时，客户端地址和页面来源 映射 访问过该页面的身份，给不带cookie的跨站子资源请求沿用
+    pub static ref SCOPE_IDENTITIES: Cache<String,Arc<std::sync::Mutex<std::collections::HashSet<String>>>> =Cache::builder()
+    .max_capacity(10000)
+    .time_to_idle(std::time::Duration::from_secs(60*60*24))
+    .build();
+
     ///sever http客户端
     pub static ref HTTP_CLIENT: NetClient = {
        let client= create_client(ProxyCfg::default());
@@ -91,6 +116,9 @@ lazy_static! {
 
     pub static ref DOC_URL:&'static str="https://lxs2000.github.io/cthulhurs-doc/dist/";
 
+    ///运行指标，通过 /metrics 以 Prometheus 格式输出
+    pub static ref METRICS:Metrics=Metrics::default();
+
 }
 
 pub async fn reqwest_response_to_hyper(
@@ -136,6 +164,7 @@ async fn shutdown_signal() {
         }
     }
     futures::future::join_all(tasks).await;
+    telemetry::shutdown();
     println!("exit...");
 }
 
@@ -146,17 +175,48 @@ fn get_cmd() -> clap::Command {
         .arg_required_else_help(true)
         .allow_external_subcommands(true)
         .subcommand(
-            clap::Command::new("run").about("run the server").arg(
-                arg!(sys: -s "set server to be system proxy")
-                    .required(false)
-                    .action(ArgAction::SetTrue),
-            ),
+            clap::Command::new("run")
+                .about("run the server")
+                .arg(
+                    arg!(sys: -s "set server to be system proxy")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(arg!(--"log-level" <LEVEL> "log level, overrides config 'log.level'").required(false))
+                .arg(arg!(--"log-format" <FORMAT> "pretty, compact or json, overrides config 'log.format'").required(false))
+                .arg(arg!(--otlp <ENDPOINT> "OTLP collector endpoint, overrides config 'log.otlpEndpoint'").required(false)),
         )
         .subcommand(
             clap::Command::new("install")
                 .about("install plugin from current directory")
                 .arg(arg!(<DIR> "target dir for install").required(false)),
         )
+        .subcommand(
+            clap::Command::new("plugin")
+                .about("plugin development tools")
+                .subcommand(
+                    clap::Command::new("init")
+                        .about("scaffold a typescript plugin")
+                        .arg(arg!(<DIR> "target dir for the plugin").required(false)),
+                )
+                .subcommand(
+                    clap::Command::new("test")
+                        .about("run the plugin's test/*.test.js files")
+                        .arg(arg!(<DIR> "target dir for the plugin").required(false)),
+                )
+                .subcommand(
+                    clap::Command::new("types")
+                        .about("write cthulhu.d.ts into the plugin dir")
+                        .arg(arg!(<DIR> "target dir for the plugin").required(false)),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            clap::Command::new("repl")
+                .about("evaluate js in a running plugin's context")
+                .arg(arg!(<ID> "plugin id"))
+                .arg_required_else_help(true),
+        )
         .subcommand(
             clap::Command::new("cagen")
                 .about("generate self signed cert with random privkey")
@@ -180,6 +240,56 @@ fn get_cmd() -> clap::Command {
                 )
                 .arg_required_else_help(true),
         )
+        .subcommand(
+            clap::Command::new("rule")
+                .about("manage map local, map remote and header rewrite rules")
+                .subcommand(clap::Command::new("list").about("list the all rules"))
+                .subcommand(
+                    clap::Command::new("add")
+                        .about("add a rule")
+                        .arg(arg!(<KIND> "local, remote or header"))
+                        .arg(arg!(<PATTERN> "url pattern, wildcard unless --regex is set"))
+                        .arg(arg!([TARGET] "file or dir for local, url for remote"))
+                        .arg(
+                            arg!(--regex "treat the pattern as a regular expression")
+                                .required(false)
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(arg!(--phase <PHASE> "request or response, for header rules").required(false))
+                        .arg(arg!(--action <ACTION> "add, remove or replace, for header rules").required(false))
+                        .arg(arg!(--name <NAME> "header name, for header rules").required(false))
+                        .arg(arg!(--value <VALUE> "header value, for header rules").required(false))
+                        .arg(arg!(--remark <REMARK> "remark of the rule").required(false))
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    clap::Command::new("del")
+                        .about("delete a rule by id")
+                        .arg(arg!(<ID> "rule id"))
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    clap::Command::new("enable")
+                        .about("enable or disable a rule by id")
+                        .arg(arg!(<ID> "rule id"))
+                        .arg_required_else_help(true),
+                )
+                .arg_required_else_help(true),
+        )
+}
+
+//命令行中的目录参数，缺省为当前目录
+fn cmd_dir(subcmd: &clap::ArgMatches) -> std::path::PathBuf {
+    let current_dir = std::env::current_dir().unwrap();
+    subcmd
+        .get_one::<String>("DIR")
+        .map(|dir| {
+            if dir.starts_with(".") {
+                return relative_path::RelativePath::new(dir).to_logical_path(&current_dir);
+            }
+            relative_path::RelativePath::new(dir).to_path("")
+        })
+        .unwrap_or(current_dir)
 }
 
 fn create_client(key: ProxyCfg) -> NetClient {
@@ -214,7 +324,7 @@ fn create_client(key: ProxyCfg) -> NetClient {
     }
     builder.build().unwrap()
 }
-async fn get_client(addr: SocketAddr, uri: Uri) -> NetClient {
+async fn get_client(ctx: HttpContext) -> NetClient {
     // let key = {
     //     let mut random = rand::thread_rng();
     //     ProxyData {
@@ -227,7 +337,7 @@ async fn get_client(addr: SocketAddr, uri: Uri) -> NetClient {
     //=======
     let guard = CLIENT_MANAGER.ctx_map_scope_keys.read().await;
 
-    let scope_key = auto_option!(guard.get(&(addr, uri)), HTTP_CLIENT.clone());
+    let scope_key = auto_option!(guard.get(&ctx.request_id), HTTP_CLIENT.clone());
 
     let keys = CLIENT_MANAGER.proxy_datas.read().await;
 
@@ -283,8 +393,8 @@ async fn run_server() {
             let key = utils::read_bytes(key).expect("读取密钥文件失败!");
             let cert = utils::read_bytes(cert).expect("读取证书文件失败!");
 
-            let mut private_key_bytes: &[u8] = key.as_bytes();
-            let mut ca_cert_bytes: &[u8] = cert.as_bytes();
+            let mut private_key_bytes: &[u8] = key.as_slice();
+            let mut ca_cert_bytes: &[u8] = cert.as_slice();
 
             let private_key = rustls::PrivateKey(
                 pemfile::pkcs8_private_keys(&mut private_key_bytes)
@@ -312,6 +422,20 @@ async fn run_server() {
             PLUGIN_MANAGER.set_ctx(ctx).await;
         }
     }
+    //定时清理长时间没有活动的域
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let ttl = config::get_config("scopeTtl")
+                .await
+                .map(|v| v.as_u64().unwrap_or(1800))
+                .unwrap_or(1800);
+            for scope in CLIENT_MANAGER.evict_expired_scopes(Duration::from_secs(ttl)).await {
+                handle::net_agent::on_scope_expire(&scope, "ttl").await;
+            }
+        }
+    });
 
     let addr = SocketAddr::from(([0, 0, 0, 0], *port));
 
@@ -333,6 +457,94 @@ async fn run_server() {
     }
 }
 
+//通过本地代理连接插件的repl
+async fn repl(id: &str) {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let port = config::get_config("port")
+        .await
+        .map(|v| v.as_i64().unwrap_or(3000) as u16)
+        .unwrap_or(3000);
+    let mut stream = auto_result!(tokio::net::TcpStream::connect(("127.0.0.1", port)).await,err=>{
+        println!("连接代理服务失败，请先运行 cthulhu run :{err}");
+        return;
+    });
+    //先建立到 api.cthulhu.server 的隧道，再在隧道上握手websocket
+    let connect = "CONNECT api.cthulhu.server:80 HTTP/1.1\r\nHost: api.cthulhu.server:80\r\n\r\n";
+    auto_result!(stream.write_all(connect.as_bytes()).await,err=>{
+        println!("连接代理服务失败:{err}");
+        return;
+    });
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let byte = auto_result!(stream.read_u8().await,err=>{
+            println!("连接代理服务失败:{err}");
+            return;
+        });
+        head.push(byte);
+    }
+    if !head.starts_with(b"HTTP/1.1 200") && !head.starts_with(b"HTTP/1.0 200") {
+        println!("连接代理服务失败:{}", String::from_utf8_lossy(&head));
+        return;
+    }
+    let url = format!("ws://api.cthulhu.server/plugin/repl?id={id}");
+    let (ws, _) = auto_result!(tokio_tungstenite::client_async(url, stream).await,err=>{
+        println!("连接插件失败:{err}");
+        return;
+    });
+    let (mut sink, mut stream) = ws.split();
+    let printer = tokio::spawn(async move {
+        while let Some(Ok(msg)) = stream.next().await {
+            let text = match msg {
+                Message::Text(text) => text,
+                Message::Close(frame) => {
+                    if let Some(frame) = frame {
+                        println!("{}", frame.reason);
+                    }
+                    break;
+                }
+                _ => continue,
+            };
+            let value = serde_json::from_str::<serde_json::Value>(&text).unwrap_or_default();
+            let msg = value["value"].as_str().unwrap_or_default();
+            match value["type"].as_str().unwrap_or_default() {
+                "result" => println!("{msg}"),
+                "error" => println!("Uncaught {msg}"),
+                "log" => println!("[{}] {msg}", value["level"].as_str().unwrap_or_default()),
+                _ => println!("{text}"),
+            }
+        }
+        println!("连接已断开");
+        std::process::exit(0);
+    });
+
+    println!("已连接插件 {id}，输入 .exit 退出，行尾输入 \\ 可以继续输入多行");
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut code = String::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if code.is_empty() && line.trim() == ".exit" {
+            break;
+        }
+        if let Some(line) = line.strip_suffix('\\') {
+            code.push_str(line);
+            code.push('\n');
+            continue;
+        }
+        code.push_str(&line);
+        if code.trim().is_empty() {
+            code.clear();
+            continue;
+        }
+        if let Err(err) = sink.send(Message::Text(std::mem::take(&mut code))).await {
+            println!("发送失败:{err}");
+            break;
+        }
+    }
+    let _ = sink.close().await;
+    printer.abort();
+}
+
 async fn list_configs() {
     let configs = auto_result!(config::get_configs().await,err=>{
          println!("系统异常:{err}");
@@ -393,6 +605,72 @@ async fn set_config(full_key: &str, mut values: Vec<String>) {
     });
     println!("set config by key '{full_key}' success")
 }
+async fn list_rules() {
+    let rules = auto_result!(handle::api::rules::get_rules().await,err=>{
+         println!("系统异常:{err}");
+         return ;
+    });
+    println!("RULES:");
+    for rule in rules {
+        let state = if rule.enable == 1 { "启用" } else { "停用" };
+        let pattern = if rule.regex == 1 {
+            format!("/{}/", rule.pattern)
+        } else {
+            rule.pattern
+        };
+        let detail = match rule.kind.as_str() {
+            "header" => format!(
+                "{} {} {} {}",
+                rule.phase, rule.action, rule.name, rule.value
+            ),
+            _ => format!("-> {}", rule.target),
+        };
+        println!(
+            "\t[{}] {} {pattern} {detail}\t---{state} {}",
+            rule.id, rule.kind, rule.remark
+        )
+    }
+}
+async fn add_rule(subcmd: &clap::ArgMatches) {
+    let str = |key: &str| subcmd.get_one::<String>(key).cloned().unwrap_or_default();
+    let kind = str("KIND");
+    let phase = if kind == "header" {
+        let phase = str("phase");
+        if phase.is_empty() {
+            "request".to_string()
+        } else {
+            phase
+        }
+    } else {
+        String::new()
+    };
+    let rule = handle::model::Rule {
+        id: 0,
+        kind,
+        pattern: str("PATTERN"),
+        regex: subcmd.get_flag("regex") as i64,
+        target: str("TARGET"),
+        phase,
+        action: str("action"),
+        name: str("name"),
+        value: str("value"),
+        enable: 1,
+        remark: str("remark"),
+    };
+    let id = auto_result!(handle::api::rules::save_rule(&rule).await,err=>{
+        println!("添加规则失败:{err}");
+        return;
+    });
+    println!("添加规则成功，id: {id}")
+}
+//命令行中的规则id
+fn rule_id(subcmd: &clap::ArgMatches) -> Option<i64> {
+    let id = subcmd.get_one::<String>("ID")?.parse::<i64>().ok();
+    if id.is_none() {
+        println!("'id' is invalid");
+    }
+    id
+}
 #[tokio::main]
 async fn main() {
     //初始化环境变量
@@ -403,21 +681,30 @@ async fn main() {
 
     let matches = cmd.get_matches();
     let subcmd = matches.subcommand();
+    //用到数据库的命令先把旧版本的数据库升级到当前结构
+    if !matches!(subcmd, None | Some(("cagen", _)) | Some(("plugin", _))) {
+        auto_result!(migrate::migrate(&DBPOOL).await,err=>{
+            println!("升级数据库失败:{err}");
+            return;
+        });
+    }
     match subcmd {
         Some(("run", subcmd)) => {
-            //注册日志
-            let timer = LocalTime::new(format_description!(
-                "[year]-[month padding:zero]-[day padding:zero] [hour]:[minute]:[second]"
-            ));
-            let subscriber = FmtSubscriber::builder()
-                .with_max_level(Level::ERROR) // 设置最大日志级别为INFO
-                .with_timer(timer)
-                .pretty()
-                .with_ansi(false)
-                .finish();
-
-            tracing::subscriber::set_global_default(subscriber)
-                .expect("setting default subscriber failed");
+            //注册日志，命令行参数优先于配置
+            let mut log_config = telemetry::LogConfig::load().await;
+            if let Some(v) = subcmd.get_one::<String>("log-level") {
+                log_config.level = v.clone();
+            }
+            if let Some(v) = subcmd.get_one::<String>("log-format") {
+                log_config.format = v.clone();
+            }
+            if let Some(v) = subcmd.get_one::<String>("otlp") {
+                log_config.otlp_endpoint = v.clone();
+            }
+            auto_result!(telemetry::init(&log_config),err=>{
+                println!("初始化日志失败:{err}");
+                return;
+            });
 
             //注册系统代理
             if subcmd.get_flag("sys") {
@@ -431,23 +718,33 @@ async fn main() {
             rcgen::ca_gen(dir);
         }
         Some(("install", subcmd)) => {
-            let dir = {
-                let current_dir = std::env::current_dir().unwrap();
-                let dir = subcmd
-                    .get_one::<String>("DIR")
-                    // .map(|v| std::path::Path::new(v))
-                    .map(|dir| {
-                        if dir.starts_with(".") {
-                            return relative_path::RelativePath::new(dir)
-                                .to_logical_path(&current_dir);
-                        }
-                        relative_path::RelativePath::new(dir).to_path("")
-                    })
-                    .unwrap_or(current_dir);
-                dir
-            };
+            let dir = cmd_dir(subcmd);
             plugin::install(dir.to_str().unwrap()).await;
         }
+        Some(("repl", subcmd)) => {
+            let id = subcmd.get_one::<String>("ID").unwrap();
+            repl(id).await;
+        }
+        Some(("plugin", subcmd)) => match subcmd.subcommand() {
+            Some(("init", subcmd)) => plugin::init(&cmd_dir(subcmd)),
+            Some(("test", subcmd)) => {
+                if !jsbind::tester::run(&cmd_dir(subcmd)).await {
+                    std::process::exit(1);
+                }
+            }
+            Some(("types", subcmd)) => {
+                let dir = cmd_dir(subcmd);
+                auto_result!(plugin::write_declarations(&dir),err=>{
+                    println!("写入 cthulhu.d.ts 失败:{err}");
+                    return;
+                });
+                println!("已写入 {}", dir.join("cthulhu.d.ts").to_str().unwrap());
+            }
+            Some((&_, _)) => println!("unknown option"),
+            None => {
+                println!("unknown option")
+            }
+        },
         Some(("config", subcmd)) => match subcmd.subcommand() {
             Some(("list", _)) => list_configs().await,
             Some(("set", subcmd)) => {
@@ -471,6 +768,30 @@ async fn main() {
                 println!("unknown option")
             }
         },
+        Some(("rule", subcmd)) => match subcmd.subcommand() {
+            Some(("list", _)) => list_rules().await,
+            Some(("add", subcmd)) => add_rule(subcmd).await,
+            Some(("del", subcmd)) => {
+                let id = auto_option!(rule_id(subcmd), ());
+                match handle::api::rules::del_rule(id).await {
+                    Ok(true) => println!("删除规则成功"),
+                    Ok(false) => println!("规则不存在"),
+                    Err(err) => println!("删除规则失败:{err}"),
+                }
+            }
+            Some(("enable", subcmd)) => {
+                let id = auto_option!(rule_id(subcmd), ());
+                match handle::api::rules::toggle_rule(id).await {
+                    Ok(1) => println!("规则 {id} 已启用"),
+                    Ok(_) => println!("规则 {id} 已停用"),
+                    Err(err) => println!("修改规则失败:{err}"),
+                }
+            }
+            Some((&_, _)) => println!("unknown option"),
+            None => {
+                println!("unknown option")
+            }
+        },
         Some((c, _)) => println!("unknown option {c}"),
         None => {
             println!("unknown option")
@@ -0,0 +1,390 @@
+use hyper::Uri;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{jsbind::http::JsRequest, utils};
+
+///插件 manifest 中 matches 的一项，字段为空时不参与匹配
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchRule {
+    //请求所属域的域名，即发起请求的页面
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub origin: String,
+    //以下为请求地址本身的各个部分
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub scheme: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub query: String,
+    //完整地址
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    //为true时以上的模式都是正则，否则为通配
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub regex: bool,
+    //资源类型，取自 sec-fetch-dest，浏览器没有发送时由代理推断，例如 document xhr script image
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    //为true时命中的请求不交给插件
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub exclude: bool,
+}
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+//manifest 中可以直接写域名通配，以 ! 开头表示排除
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MatchEntry {
+    Origin(String),
+    Rule(MatchRule),
+}
+impl From<MatchEntry> for MatchRule {
+    fn from(entry: MatchEntry) -> Self {
+        match entry {
+            MatchEntry::Rule(rule) => rule,
+            MatchEntry::Origin(origin) => {
+                let origin = origin.trim();
+                match origin.strip_prefix('!') {
+                    Some(origin) => MatchRule {
+                        origin: origin.trim().to_string(),
+                        exclude: true,
+                        ..Default::default()
+                    },
+                    None => MatchRule {
+                        origin: origin.to_string(),
+                        ..Default::default()
+                    },
+                }
+            }
+        }
+    }
+}
+
+///解析 manifest 中的 matches 数组
+pub fn parse_manifest(value: &serde_json::Value) -> Result<Vec<MatchRule>, String> {
+    let entries = serde_json::from_value::<Vec<MatchEntry>>(value.clone())
+        .map_err(|e| format!("matches 格式错误:{e}"))?;
+    let rules = entries
+        .into_iter()
+        .map(MatchRule::from)
+        .filter(|v| !(v.origin.is_empty() && is_empty_rule(v)))
+        .collect::<Vec<MatchRule>>();
+    PluginMatcher::compile(&rules)?;
+    Ok(rules)
+}
+fn is_empty_rule(rule: &MatchRule) -> bool {
+    rule.scheme.is_empty()
+        && rule.host.is_empty()
+        && rule.port.is_none()
+        && rule.path.is_empty()
+        && rule.query.is_empty()
+        && rule.url.is_empty()
+        && rule.types.is_empty()
+        && rule.methods.is_empty()
+}
+
+///解析数据库中保存的 matches，兼容旧版本用逗号或分号分隔的域名通配
+pub fn parse_stored(matches: &str) -> Result<Vec<MatchRule>, String> {
+    let matches = matches.trim();
+    if matches.starts_with('[') {
+        let value = serde_json::from_str::<serde_json::Value>(matches)
+            .map_err(|e| format!("matches 格式错误:{e}"))?;
+        return parse_manifest(&value);
+    }
+    let rules = matches
+        .split([',', ';'])
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| MatchRule::from(MatchEntry::Origin(v.to_string())))
+        .collect();
+    Ok(rules)
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Glob(String),
+    Regex(Regex),
+}
+impl Pattern {
+    fn new(pattern: &str, regex: bool) -> Result<Option<Self>, String> {
+        if pattern.is_empty() {
+            return Ok(None);
+        }
+        if !regex {
+            return Ok(Some(Pattern::Glob(pattern.to_string())));
+        }
+        let regex = Regex::new(pattern).map_err(|e| format!("正则 '{pattern}' 无效:{e}"))?;
+        Ok(Some(Pattern::Regex(regex)))
+    }
+    fn is_match(&self, value: &str) -> bool {
+        match self {
+            Pattern::Glob(pattern) => utils::mini_match(pattern, value),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    origin: Option<Pattern>,
+    scheme: Option<Pattern>,
+    host: Option<Pattern>,
+    port: Option<u16>,
+    path: Option<Pattern>,
+    query: Option<Pattern>,
+    url: Option<Pattern>,
+    types: Vec<String>,
+    methods: Vec<String>,
+}
+impl CompiledRule {
+    fn new(rule: &MatchRule) -> Result<Self, String> {
+        let pattern = |v: &str| Pattern::new(v, rule.regex);
+        Ok(Self {
+            origin: pattern(&rule.origin)?,
+            scheme: pattern(&rule.scheme)?,
+            host: pattern(&rule.host)?,
+            port: rule.port,
+            path: pattern(&rule.path)?,
+            query: pattern(&rule.query)?,
+            url: pattern(&rule.url)?,
+            types: rule.types.iter().map(|v| v.to_lowercase()).collect(),
+            methods: rule.methods.iter().map(|v| v.to_uppercase()).collect(),
+        })
+    }
+    //请求中没有的信息，strict 为true时视为不匹配，否则忽略该条件
+    fn is_match(&self, target: &MatchTarget, strict: bool) -> bool {
+        let check = |pattern: &Option<Pattern>, value: &Option<String>| match (pattern, value) {
+            (None, _) => true,
+            (Some(pattern), Some(value)) => pattern.is_match(value),
+            (Some(_), None) => !strict,
+        };
+        let port = match (self.port, target.port) {
+            (None, _) => true,
+            (Some(port), Some(value)) => port == value,
+            (Some(_), None) => !strict,
+        };
+        let types = match &target.dest {
+            _ if self.types.is_empty() => true,
+            Some(dest) => self.types.iter().any(|v| dest_matches(v, dest)),
+            None => !strict,
+        };
+        let methods = match &target.method {
+            _ if self.methods.is_empty() => true,
+            Some(method) => self.methods.iter().any(|v| v == method),
+            None => !strict,
+        };
+        port && types
+            && methods
+            && check(&self.origin, &Some(target.origin.clone()))
+            && check(&self.scheme, &target.scheme)
+            && check(&self.host, &target.host)
+            && check(&self.path, &target.path)
+            && check(&self.query, &target.query)
+            && check(&self.url, &target.url)
+    }
+}
+
+//资源类型和 sec-fetch-dest 的对应关系
+fn dest_matches(ty: &str, dest: &str) -> bool {
+    match ty {
+        "xhr" | "fetch" => dest == "empty",
+        "frame" => dest == "iframe" || dest == "frame",
+        "worker" => dest.ends_with("worker"),
+        "media" => dest == "audio" || dest == "video" || dest == "track",
+        _ => ty == dest,
+    }
+}
+
+///编译后的插件匹配规则，排除规则优先，没有包含规则时匹配所有请求
+#[derive(Debug, Default)]
+pub struct PluginMatcher {
+    includes: Vec<CompiledRule>,
+    excludes: Vec<CompiledRule>,
+}
+impl PluginMatcher {
+    pub fn new(matches: &str) -> Result<Self, String> {
+        Self::compile(&parse_stored(matches)?)
+    }
+    pub fn compile(rules: &[MatchRule]) -> Result<Self, String> {
+        let mut matcher = Self::default();
+        for rule in rules {
+            let compiled = CompiledRule::new(rule)?;
+            if rule.exclude {
+                matcher.excludes.push(compiled);
+            } else {
+                matcher.includes.push(compiled);
+            }
+        }
+        Ok(matcher)
+    }
+    ///排除规则缺少信息时不排除。包含规则缺少信息时不匹配，
+    ///只有限定了来源的规则在来源匹配后忽略缺少的信息，供只知道域名的调用方判断插件是否作用于该域
+    pub fn is_match(&self, target: &MatchTarget) -> bool {
+        if self.excludes.iter().any(|v| v.is_match(target, true)) {
+            return false;
+        }
+        self.includes.is_empty()
+            || self
+                .includes
+                .iter()
+                .any(|v| v.is_match(target, v.origin.is_none()))
+    }
+}
+
+///参与匹配的请求信息，为None的部分不参与匹配
+#[derive(Debug, Default, Clone)]
+pub struct MatchTarget {
+    pub origin: String,
+    pub scheme: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub path: Option<String>,
+    pub query: Option<String>,
+    pub url: Option<String>,
+    pub dest: Option<String>,
+    pub method: Option<String>,
+}
+impl MatchTarget {
+    ///只知道请求所属域的域名
+    pub fn origin(origin: &str) -> Self {
+        Self {
+            origin: origin.to_string(),
+            ..Default::default()
+        }
+    }
+    pub fn with_uri(mut self, uri: &Uri) -> Self {
+        let scheme = uri.scheme_str().unwrap_or("").to_string();
+        self.port = uri.port_u16().or(match scheme.as_str() {
+            "http" | "ws" => Some(80),
+            "https" | "wss" => Some(443),
+            _ => None,
+        });
+        self.scheme = Some(scheme);
+        self.host = uri.host().map(|v| v.to_string());
+        self.path = Some(uri.path().to_string());
+        self.query = Some(uri.query().unwrap_or("").to_string());
+        self.url = Some(uri.to_string());
+        self
+    }
+    pub fn with_method(mut self, method: &str) -> Self {
+        self.method = Some(method.to_uppercase());
+        self
+    }
+    pub fn with_dest(mut self, dest: Option<&str>) -> Self {
+        self.dest = dest
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty());
+        self
+    }
+    ///从插件拿到的请求中取出地址、方法和 sec-fetch-dest，没有这个头部时用代理推断的类型
+    pub fn from_request(origin: &str, req: &JsRequest) -> Self {
+        let target = Self::origin(origin);
+        let (method, uri) = {
+            let parts = req.parts.read().unwrap();
+            (parts.0.to_string(), parts.1.assemble().ok())
+        };
+        let dest = {
+            let inner = req.inner.read().unwrap();
+            let headers = inner.0.inner.read().unwrap();
+            headers
+                .get("sec-fetch-dest")
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| req.dest.clone())
+        };
+        let target = match uri.and_then(|v| v.parse::<Uri>().ok()) {
+            Some(uri) => target.with_uri(&uri),
+            None => target,
+        };
+        target.with_method(&method).with_dest(Some(&dest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn matcher(value: serde_json::Value) -> PluginMatcher {
+        PluginMatcher::compile(&parse_manifest(&value).unwrap()).unwrap()
+    }
+
+    fn target(origin: &str, url: &str, method: &str, dest: &str) -> MatchTarget {
+        MatchTarget::origin(origin)
+            .with_uri(&url.parse::<Uri>().unwrap())
+            .with_method(method)
+            .with_dest(Some(dest))
+    }
+
+    #[test]
+    fn legacy_origins() {
+        let rules = parse_stored("a.com, *.b.com; !c.b.com").unwrap();
+        assert_eq!(rules.len(), 3);
+        assert!(rules[2].exclude);
+        let matcher = PluginMatcher::new("a.com, *.b.com; !c.b.com").unwrap();
+        assert!(matcher.is_match(&MatchTarget::origin("a.com")));
+        assert!(matcher.is_match(&MatchTarget::origin("x.b.com")));
+        assert!(!matcher.is_match(&MatchTarget::origin("c.b.com")));
+        assert!(!matcher.is_match(&MatchTarget::origin("d.com")));
+        //没有规则时匹配所有请求
+        assert!(PluginMatcher::new("")
+            .unwrap()
+            .is_match(&MatchTarget::origin("d.com")));
+    }
+
+    #[test]
+    fn request_rules() {
+        let matcher = matcher(json!([{
+            "host": "api.a.com",
+            "path": "/v1/*",
+            "methods": ["post"],
+            "types": ["xhr"]
+        }]));
+        let url = "https://api.a.com/v1/items";
+        assert!(matcher.is_match(&target("a.com", url, "POST", "empty")));
+        assert!(!matcher.is_match(&target("a.com", url, "GET", "empty")));
+        assert!(!matcher.is_match(&target("a.com", url, "POST", "script")));
+        assert!(!matcher.is_match(&target("a.com", "https://api.a.com/v2", "POST", "empty")));
+        //没有限定来源的规则，只知道域名时不匹配
+        assert!(!matcher.is_match(&MatchTarget::origin("a.com")));
+    }
+
+    #[test]
+    fn origin_rules_ignore_missing_info() {
+        let matcher = matcher(json!([{"origin": "*.a.com", "types": ["worker"]}]));
+        assert!(matcher.is_match(&MatchTarget::origin("www.a.com")));
+        let url = "https://cdn.com/w.js";
+        assert!(matcher.is_match(&target("www.a.com", url, "GET", "serviceworker")));
+        assert!(!matcher.is_match(&target("www.a.com", url, "GET", "script")));
+        assert!(!matcher.is_match(&target("www.b.com", url, "GET", "worker")));
+    }
+
+    #[test]
+    fn regex_rules() {
+        let matcher = matcher(json!([{"url": "^https://a\\.com/\\d+$", "regex": true}]));
+        assert!(matcher.is_match(&target("a.com", "https://a.com/12", "GET", "document")));
+        assert!(!matcher.is_match(&target("a.com", "https://a.com/ab", "GET", "document")));
+        assert!(parse_manifest(&json!([{"url": "(", "regex": true}])).is_err());
+    }
+
+    #[test]
+    fn excludes_need_full_info() {
+        let matcher = matcher(json!(["*", {"path": "/admin*", "exclude": true}]));
+        //缺少请求地址时不排除
+        assert!(matcher.is_match(&MatchTarget::origin("a.com")));
+        assert!(matcher.is_match(&target("a.com", "https://a.com/", "GET", "document")));
+        assert!(!matcher.is_match(&target("a.com", "https://a.com/admin/x", "GET", "document")));
+    }
+}
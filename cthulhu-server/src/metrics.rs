@@ -0,0 +1,431 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::CLITENT_POOL;
+
+///耗时直方图的桶上限，单位秒
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+///最多单独统计的上游host数，之后出现的host计入 other
+const MAX_UPSTREAM_HOSTS: usize = 256;
+///输出时只展开请求数最多的host，其余合并到 other
+const TOP_UPSTREAM_HOSTS: usize = 20;
+const OTHER_HOST: &str = "other";
+
+///耗时直方图，按 Prometheus 的累计桶输出
+#[derive(Debug, Default, Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+impl Histogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (i, le) in BUCKETS.iter().enumerate() {
+            if secs <= *le {
+                self.buckets[i] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+        self.max = self.max.max(secs);
+    }
+    fn merge(&mut self, other: &Histogram) {
+        for (i, count) in other.buckets.iter().enumerate() {
+            self.buckets[i] += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        for (i, le) in BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"{le}\"}} {}",
+                self.buckets[i]
+            );
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct HookMetric {
+    latency: Histogram,
+    errors: u64,
+    //钩子返回的动作，例如 release respond reject proxy delay
+    actions: BTreeMap<String, u64>,
+}
+
+///单个插件钩子的统计，供 /plugin/stats 返回
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookStat {
+    pub plugin: String,
+    pub hook: String,
+    pub calls: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    pub actions: BTreeMap<String, u64>,
+}
+
+///插件钩子的耗时、异常和动作统计，按 (插件id, 钩子名) 分组
+#[derive(Debug, Default)]
+pub struct PluginMetrics {
+    hooks: Mutex<HashMap<(String, String), HookMetric>>,
+}
+impl PluginMetrics {
+    ///记录一次钩子调用，js抛出异常时 ok 为false
+    pub fn observe_hook(&self, plugin: &str, hook: &str, elapsed: Duration, ok: bool) {
+        let mut hooks = self.hooks.lock().unwrap();
+        let metric = hooks
+            .entry((plugin.to_string(), hook.to_string()))
+            .or_default();
+        metric.latency.observe(elapsed);
+        if !ok {
+            metric.errors += 1;
+            *metric.actions.entry("error".to_string()).or_default() += 1;
+        }
+    }
+    ///记录钩子返回的动作
+    pub fn count_action(&self, plugin: &str, hook: &str, action: &str) {
+        let mut hooks = self.hooks.lock().unwrap();
+        let metric = hooks
+            .entry((plugin.to_string(), hook.to_string()))
+            .or_default();
+        *metric.actions.entry(action.to_string()).or_default() += 1;
+    }
+    ///插件删除或重新加载时清空统计
+    pub fn remove(&self, plugin: &str) {
+        self.hooks.lock().unwrap().retain(|(id, _), _| id != plugin);
+    }
+    pub fn stats(&self, plugin: Option<&str>) -> Vec<HookStat> {
+        let hooks = self.hooks.lock().unwrap();
+        let mut stats = hooks
+            .iter()
+            .filter(|((id, _), _)| plugin.map(|v| v == id).unwrap_or(true))
+            .map(|((id, hook), metric)| {
+                let calls = metric.latency.count;
+                let avg = if calls == 0 {
+                    0.0
+                } else {
+                    metric.latency.sum / calls as f64
+                };
+                HookStat {
+                    plugin: id.clone(),
+                    hook: hook.clone(),
+                    calls,
+                    errors: metric.errors,
+                    error_rate: if calls == 0 {
+                        0.0
+                    } else {
+                        metric.errors as f64 / calls as f64
+                    },
+                    avg_ms: avg * 1000.0,
+                    max_ms: metric.latency.max * 1000.0,
+                    actions: metric.actions.clone(),
+                }
+            })
+            .collect::<Vec<HookStat>>();
+        stats.sort_by(|a, b| (&a.plugin, &a.hook).cmp(&(&b.plugin, &b.hook)));
+        stats
+    }
+    fn render(&self, out: &mut String) {
+        let hooks = self.hooks.lock().unwrap();
+        let mut keys = hooks.keys().collect::<Vec<_>>();
+        keys.sort();
+
+        let _ = writeln!(
+            out,
+            "# HELP cthulhu_plugin_hook_duration_seconds 插件钩子耗时"
+        );
+        let _ = writeln!(out, "# TYPE cthulhu_plugin_hook_duration_seconds histogram");
+        for key in keys.iter() {
+            let labels = format!("plugin=\"{}\",hook=\"{}\"", escape(&key.0), escape(&key.1));
+            hooks[*key]
+                .latency
+                .render("cthulhu_plugin_hook_duration_seconds", &labels, out);
+        }
+        let _ = writeln!(
+            out,
+            "# HELP cthulhu_plugin_hook_errors_total 插件钩子抛出的异常数"
+        );
+        let _ = writeln!(out, "# TYPE cthulhu_plugin_hook_errors_total counter");
+        for key in keys.iter() {
+            let _ = writeln!(
+                out,
+                "cthulhu_plugin_hook_errors_total{{plugin=\"{}\",hook=\"{}\"}} {}",
+                escape(&key.0),
+                escape(&key.1),
+                hooks[*key].errors
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP cthulhu_plugin_actions_total 插件钩子返回的动作数"
+        );
+        let _ = writeln!(out, "# TYPE cthulhu_plugin_actions_total counter");
+        for key in keys.iter() {
+            for (action, count) in hooks[*key].actions.iter() {
+                let _ = writeln!(
+                    out,
+                    "cthulhu_plugin_actions_total{{plugin=\"{}\",hook=\"{}\",action=\"{action}\"}} {count}",
+                    escape(&key.0),
+                    escape(&key.1),
+                );
+            }
+        }
+    }
+}
+
+///当前数量和累计数量，例如活跃连接
+#[derive(Debug, Default)]
+pub struct Gauge {
+    current: AtomicI64,
+    total: AtomicU64,
+}
+impl Gauge {
+    ///数量加一，返回的 guard 释放时减一
+    pub fn enter(&'static self) -> GaugeGuard {
+        self.current.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {name}_active {help}");
+        let _ = writeln!(out, "# TYPE {name}_active gauge");
+        let _ = writeln!(
+            out,
+            "{name}_active {}",
+            self.current.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# HELP {name}_total {help}（累计）");
+        let _ = writeln!(out, "# TYPE {name}_total counter");
+        let _ = writeln!(out, "{name}_total {}", self.total.load(Ordering::Relaxed));
+    }
+}
+pub struct GaugeGuard(&'static Gauge);
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+///代理核心的运行指标
+#[derive(Debug, Default)]
+pub struct ProxyMetrics {
+    pub connections: Gauge,
+    pub tunnels: Gauge,
+    pub websockets: Gauge,
+    //与客户端握手失败
+    pub tls_accept_failures: AtomicU64,
+    //与上游握手失败
+    pub tls_upstream_failures: AtomicU64,
+    //CONNECT时解析或连接上游失败
+    pub upstream_connect_failures: AtomicU64,
+    pub cert_cache_hits: AtomicU64,
+    pub cert_cache_misses: AtomicU64,
+    //从上游接收的字节数
+    pub bytes_in: AtomicU64,
+    //发往上游的字节数
+    pub bytes_out: AtomicU64,
+    pub upstream_errors: AtomicU64,
+    upstream: Mutex<HashMap<String, Histogram>>,
+}
+impl ProxyMetrics {
+    ///记录一次上游请求的耗时，host数量达到上限后新的host计入 other
+    pub fn observe_upstream(&self, host: &str, elapsed: Duration) {
+        let mut upstream = self.upstream.lock().unwrap();
+        let host = if upstream.contains_key(host) || upstream.len() < MAX_UPSTREAM_HOSTS {
+            host
+        } else {
+            OTHER_HOST
+        };
+        upstream
+            .entry(host.to_string())
+            .or_default()
+            .observe(elapsed);
+    }
+    //请求数最多的host，其余合并到 other
+    fn top_upstream(&self) -> Vec<(String, Histogram)> {
+        let upstream = self.upstream.lock().unwrap();
+        let mut hosts = upstream
+            .iter()
+            .filter(|(host, _)| host.as_str() != OTHER_HOST)
+            .collect::<Vec<_>>();
+        hosts.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(b.0)));
+        let mut other = upstream.get(OTHER_HOST).cloned().unwrap_or_default();
+        for (_, histogram) in hosts.iter().skip(TOP_UPSTREAM_HOSTS) {
+            other.merge(histogram);
+        }
+        let mut top = hosts
+            .into_iter()
+            .take(TOP_UPSTREAM_HOSTS)
+            .map(|(host, histogram)| (host.clone(), histogram.clone()))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| a.0.cmp(&b.0));
+        if other.count > 0 {
+            top.push((OTHER_HOST.to_string(), other));
+        }
+        top
+    }
+    fn render(&self, out: &mut String) {
+        self.connections
+            .render("cthulhu_proxy_connections", "客户端连接数", out);
+        self.tunnels
+            .render("cthulhu_proxy_connect_tunnels", "CONNECT隧道数", out);
+        self.websockets
+            .render("cthulhu_proxy_websocket_sessions", "WebSocket会话数", out);
+
+        let counter = |out: &mut String, name: &str, help: &str, labels: &[(&str, &AtomicU64)]| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (label, value) in labels {
+                let value = value.load(Ordering::Relaxed);
+                if label.is_empty() {
+                    let _ = writeln!(out, "{name} {value}");
+                } else {
+                    let _ = writeln!(out, "{name}{{{label}}} {value}");
+                }
+            }
+        };
+        counter(
+            out,
+            "cthulhu_proxy_tls_handshake_failures_total",
+            "TLS握手失败次数",
+            &[
+                ("side=\"client\"", &self.tls_accept_failures),
+                ("side=\"upstream\"", &self.tls_upstream_failures),
+            ],
+        );
+        counter(
+            out,
+            "cthulhu_proxy_upstream_connect_failures_total",
+            "CONNECT时连接上游失败次数",
+            &[("", &self.upstream_connect_failures)],
+        );
+        counter(
+            out,
+            "cthulhu_proxy_cert_cache_total",
+            "证书缓存命中情况",
+            &[
+                ("result=\"hit\"", &self.cert_cache_hits),
+                ("result=\"miss\"", &self.cert_cache_misses),
+            ],
+        );
+        counter(
+            out,
+            "cthulhu_proxy_bytes_total",
+            "与上游之间传输的字节数",
+            &[
+                ("direction=\"in\"", &self.bytes_in),
+                ("direction=\"out\"", &self.bytes_out),
+            ],
+        );
+        counter(
+            out,
+            "cthulhu_proxy_upstream_errors_total",
+            "上游请求失败次数",
+            &[("", &self.upstream_errors)],
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP cthulhu_proxy_client_pool_size 上游http客户端池大小"
+        );
+        let _ = writeln!(out, "# TYPE cthulhu_proxy_client_pool_size gauge");
+        let _ = writeln!(
+            out,
+            "cthulhu_proxy_client_pool_size {}",
+            CLITENT_POOL.entry_count()
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP cthulhu_proxy_upstream_duration_seconds 上游请求耗时"
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE cthulhu_proxy_upstream_duration_seconds histogram"
+        );
+        for (host, histogram) in self.top_upstream() {
+            let labels = format!("host=\"{}\"", escape(&host));
+            histogram.render("cthulhu_proxy_upstream_duration_seconds", &labels, out);
+        }
+    }
+}
+
+///所有指标
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub plugins: PluginMetrics,
+    pub proxy: ProxyMetrics,
+}
+impl Metrics {
+    ///输出 Prometheus 文本格式
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.proxy.render(&mut out);
+        self.plugins.render(&mut out);
+        out
+    }
+}
+
+//标签值中的反斜杠、引号和换行需要转义
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_hosts_are_capped() {
+        let metrics = ProxyMetrics::default();
+        for i in 0..MAX_UPSTREAM_HOSTS + 10 {
+            //越靠前的host请求越多
+            for _ in 0..=(MAX_UPSTREAM_HOSTS + 10 - i) {
+                metrics.observe_upstream(&format!("host{i}"), Duration::from_millis(1));
+            }
+        }
+        assert_eq!(
+            metrics.upstream.lock().unwrap().len(),
+            MAX_UPSTREAM_HOSTS + 1
+        );
+
+        let top = metrics.top_upstream();
+        assert_eq!(top.len(), TOP_UPSTREAM_HOSTS + 1);
+        assert!(top.iter().any(|(host, _)| host == "host0"));
+        assert!(!top
+            .iter()
+            .any(|(host, _)| host == &format!("host{TOP_UPSTREAM_HOSTS}")));
+        //所有请求都被统计，没有因为合并丢失
+        let total = top.iter().map(|(_, v)| v.count).sum::<u64>();
+        let expected = (0..MAX_UPSTREAM_HOSTS + 10)
+            .map(|i| (MAX_UPSTREAM_HOSTS + 11 - i) as u64)
+            .sum::<u64>();
+        assert_eq!(total, expected);
+        assert_eq!(top.last().unwrap().0, OTHER_HOST);
+    }
+}
@@ -0,0 +1,45 @@
+use sqlx::{migrate::Migrator, SqlitePool};
+
+//migrations 目录下的脚本按版本号依次执行，执行过的版本记在 _sqlx_migrations 表中，
+//已有的数据库只会执行之后新增的脚本
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+///启动时把旧版本的数据库升级到当前结构
+pub async fn migrate(pool: &SqlitePool) -> Result<(), String> {
+    MIGRATOR.run(pool).await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    use super::*;
+
+    async fn connect(options: SqliteConnectOptions) -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrates_empty_database() {
+        let pool = connect(SqliteConnectOptions::new().in_memory(true)).await;
+        migrate(&pool).await.unwrap();
+        //已经执行过的脚本不会重复执行
+        migrate(&pool).await.unwrap();
+    }
+
+    //随程序发布的数据库没有迁移记录，要能直接在它上面升级
+    #[tokio::test]
+    async fn migrates_shipped_database() {
+        let path =
+            std::env::temp_dir().join(format!("cthulhu-{}.db", uuid::Uuid::new_v4().simple()));
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/cthulhu.db"), &path).unwrap();
+        let pool = connect(SqliteConnectOptions::new().filename(&path)).await;
+        migrate(&pool).await.unwrap();
+        pool.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
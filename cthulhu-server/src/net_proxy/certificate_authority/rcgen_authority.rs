@@ -1,6 +1,9 @@
-use crate::net_proxy::{
-    certificate_authority::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS},
-    error::Error,
+use crate::{
+    net_proxy::{
+        certificate_authority::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS},
+        error::Error,
+    },
+    METRICS,
 };
 use async_trait::async_trait;
 
@@ -11,7 +14,7 @@ use rcgen::{DistinguishedName, DnType, KeyPair, RcgenError, SanType};
 use std::{
     net::{Ipv4Addr, Ipv6Addr},
     str::FromStr,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
 };
 use time::{Duration, OffsetDateTime};
 use tokio_rustls::rustls::{self, ServerConfig};
@@ -134,8 +137,10 @@ impl CertificateAuthority for RcgenAuthority {
     async fn gen_server_config(&self, authority: &Authority, alpn: Vec<Vec<u8>>) -> Arc<ServerConfig> {
         if let Some(server_cfg) = self.cache.get(authority).await {
             debug!("Using cached server config");
+            METRICS.proxy.cert_cache_hits.fetch_add(1, Ordering::Relaxed);
             return server_cfg;
         }
+        METRICS.proxy.cert_cache_misses.fetch_add(1, Ordering::Relaxed);
         debug!("Generating server config:{:?}", &authority);
 
         let certs = vec![self.gen_cert(authority)];
@@ -25,9 +25,13 @@ pub mod certificate_authority;
 
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use hyper::Body;
-use hyper::{Request, Response, StatusCode, Uri};
+use hyper::{Request, Response, StatusCode, Uri, Version};
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite::{self, Message};
 use tracing::error;
@@ -62,6 +66,48 @@ pub struct HttpContext {
     /// Address of the client that is sending the request.
     pub client_addr: SocketAddr,
     pub uri: Uri,
+    /// Unique id of the request, shared by its response and errors.
+    pub request_id: String,
+}
+
+/// Details of the upstream exchange, attached to proxied responses as an extension.
+#[derive(Clone, Debug)]
+pub struct UpstreamInfo {
+    /// When the request was sent upstream.
+    pub start: SystemTime,
+    /// Time until the response headers arrived.
+    pub ttfb: Duration,
+    /// Time until the response body was fully read.
+    pub end: Duration,
+    pub remote_addr: Option<SocketAddr>,
+    pub version: Version,
+    /// TLS parameters of the connection that carried this request, if it used TLS.
+    pub tls: Option<UpstreamTls>,
+}
+
+/// TLS parameters of the upstream connection that served the request.
+#[derive(Clone, Debug)]
+pub struct UpstreamTls {
+    /// Negotiated TLS version, `None` when the client does not expose it.
+    pub version: Option<String>,
+    /// Protocol selected by ALPN, derived from the HTTP version of the response.
+    pub alpn: String,
+}
+
+impl UpstreamTls {
+    /// TLS parameters known from a response received over `https`.
+    pub fn from_response(version: Version) -> Self {
+        let alpn = match version {
+            Version::HTTP_2 => "h2",
+            Version::HTTP_11 => "http/1.1",
+            Version::HTTP_10 => "http/1.0",
+            _ => "",
+        };
+        Self {
+            version: None,
+            alpn: alpn.to_string(),
+        }
+    }
 }
 
 /// Context for websocket messages.
@@ -73,6 +119,8 @@ pub enum WebSocketContext {
         src: SocketAddr,
         /// URI of the server.
         dst: Uri,
+        /// Id of the upgrade request.
+        request_id: String,
     },
     #[non_exhaustive]
     ServerToClient {
@@ -80,6 +128,8 @@ pub enum WebSocketContext {
         src: Uri,
         /// Address of the client.
         dst: SocketAddr,
+        /// Id of the upgrade request.
+        request_id: String,
     },
 }
 impl WebSocketContext {
@@ -97,6 +147,12 @@ impl WebSocketContext {
         }
         .clone()
     }
+    pub fn request_id(&self) -> &str {
+        match self {
+            WebSocketContext::ClientToServer { request_id, .. } => request_id,
+            WebSocketContext::ServerToClient { request_id, .. } => request_id,
+        }
+    }
     pub fn client_to_server(&self) -> bool {
         match self {
             WebSocketContext::ClientToServer { .. } => true,
@@ -213,4 +269,7 @@ pub trait WebSocketHandler: Clone + Send + Sync + 'static {
     ) -> Answer<Message, Message> {
         Answer::Release(message)
     }
+
+    /// This handler will be called once, after the streams of both directions have ended.
+    async fn handle_close(&mut self, _ctx: &WebSocketContext) {}
 }
@@ -1,10 +1,13 @@
 mod net;
 
-use crate::net_proxy::{
-    certificate_authority::CertificateAuthority, Error, HttpHandler, WebSocketHandler,
+use crate::{
+    net_proxy::{
+        certificate_authority::CertificateAuthority, Error, HttpContext, HttpHandler,
+        WebSocketHandler,
+    },
+    METRICS,
 };
 
-use hyper::Uri;
 use hyper::{
     server::conn::{AddrIncoming, AddrStream},
     service::{make_service_fn, service_fn},
@@ -168,7 +171,7 @@ where
     H: HttpHandler,
     W: WebSocketHandler,
     Fu: Future<Output = Client> + Send + Sync + 'static,
-    P: Fn(SocketAddr, Uri) -> Fu + Send + 'static + Clone + Sync,
+    P: Fn(HttpContext) -> Fu + Send + 'static + Clone + Sync,
 {
     pub fn new(
         ca: Arc<CA>,
@@ -198,7 +201,10 @@ where
             let client_addr = conn.remote_addr();
             let client_provider = self.client_provider.clone();
             async move {
+                //service随连接释放，连接数随之减一
+                let connection = Arc::new(METRICS.proxy.connections.enter());
                 let service = service_fn(move |req| {
+                    let _ = &connection;
                     let net_proxy = NetProxy {
                         ca: Arc::clone(&ca),
                         client_provider: client_provider.clone(),
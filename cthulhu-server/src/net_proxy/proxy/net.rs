@@ -1,13 +1,14 @@
 use crate::{
     auto_result, ja3,
+    metrics::GaugeGuard,
     net_proxy::{
         certificate_authority::CertificateAuthority, rewind::Rewind, Answer, HttpContext,
-        HttpHandler, WebSocketContext, WebSocketHandler,
+        HttpHandler, UpstreamInfo, UpstreamTls, WebSocketContext, WebSocketHandler,
     },
-    reqwest_request_from_hyper, reqwest_response_to_hyper,
+    reqwest_request_from_hyper, reqwest_response_to_hyper, METRICS,
 };
 
-use futures::{Sink, Stream, StreamExt};
+use futures::{Sink, Stream, StreamExt, TryStreamExt};
 
 use hyper::{http::{
     header::SEC_WEBSOCKET_EXTENSIONS,
@@ -23,7 +24,8 @@ use std::{
     io,
     net::{IpAddr, SocketAddr},
     str::FromStr,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
+    time::{Instant, SystemTime},
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite},
@@ -57,7 +59,10 @@ async fn connect_to_dns_tcp(
     authority: &Authority,
     ca: Arc<ClientConfig>,
 ) -> io::Result<TlsStream<TcpStream>> {
-    let stream = TcpStream::connect(authority.as_ref()).await?;
+    let stream = TcpStream::connect(authority.as_ref()).await.map_err(|e| {
+        METRICS.proxy.upstream_connect_failures.fetch_add(1, Ordering::Relaxed);
+        e
+    })?;
     let connector = TlsConnector::from(ca);
     let host = authority.host();
     let server_name = match DnsName::try_from_ascii(host.as_bytes()) {
@@ -73,7 +78,10 @@ async fn connect_to_dns_tcp(
             ip_address
         }
     };
-    let stream = connector.connect(server_name, stream).await?;
+    let stream = connector.connect(server_name, stream).await.map_err(|e| {
+        METRICS.proxy.tls_upstream_failures.fetch_add(1, Ordering::Relaxed);
+        e
+    })?;
     Ok(stream)
 }
 async fn connect_to_dns_udp(
@@ -133,12 +141,13 @@ where
     H: HttpHandler,
     W: WebSocketHandler,
     Fut: Future<Output=Client> + Send + Sync + 'static,
-    P: Fn(SocketAddr, Uri) -> Fut + Send + 'static + Clone + Sync,
+    P: Fn(HttpContext) -> Fut + Send + 'static + Clone + Sync,
 {
     fn context(&self, req: &Request<Body>) -> HttpContext {
         HttpContext {
             client_addr: self.client_addr,
             uri: req.uri().clone(),
+            request_id: uuid::Uuid::new_v4().simple().to_string(),
         }
     }
 
@@ -168,14 +177,27 @@ where
         if req.method() == Method::CONNECT {
             Ok(self.process_connect(req))
         } else if hyper_tungstenite::is_upgrade_request(&req) {
-            Ok(self.upgrade_websocket(req))
+            Ok(self.upgrade_websocket(req, ctx.request_id))
         } else {
             // let version = req.version();
             // *req.version_mut()=Version::HTTP_11;
             // let method = req.method().to_string();
             let client_provider = self.client_provider;
-            let mut client = client_provider(self.client_addr, req.uri().clone()).await;
+            let mut client = client_provider(ctx.clone()).await;
+            let host = req.uri().host().unwrap_or_default().to_string();
+            //在读取请求体时计数，流式上传的请求体也能统计到
+            let req = req.map(|body| {
+                Body::wrap_stream(body.inspect_ok(|chunk| {
+                    METRICS
+                        .proxy
+                        .bytes_out
+                        .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }))
+            });
             let req = reqwest_request_from_hyper(req).await;
+            let https = req.url().scheme() == "https";
+            let start = Instant::now();
+            let start_time = SystemTime::now();
             let res = client
                 .call(req)
                 .instrument(info_span!("proxy_request"))
@@ -183,7 +205,24 @@ where
 
             match res {
                 Ok(res) => {
-                    let res = reqwest_response_to_hyper(res).await.unwrap();
+                    let ttfb = start.elapsed();
+                    let remote_addr = res.remote_addr();
+                    let version = res.version();
+                    let mut res = reqwest_response_to_hyper(res).await.unwrap();
+                    let end = start.elapsed();
+                    METRICS.proxy.observe_upstream(&host, end);
+                    res.extensions_mut().insert(UpstreamInfo {
+                        start: start_time,
+                        ttfb,
+                        end,
+                        remote_addr,
+                        version,
+                        //请求由客户端连接池里的连接发出，TLS参数只能取自这次响应，
+                        //不能用CONNECT时探测上游得到的握手结果
+                        tls: https.then(|| UpstreamTls::from_response(version)),
+                    });
+                    let received = hyper::body::HttpBody::size_hint(res.body()).exact().unwrap_or(0);
+                    METRICS.proxy.bytes_in.fetch_add(received, Ordering::Relaxed);
                     Ok(self
                         .http_handler
                         .handle_response(&ctx, res)
@@ -191,6 +230,7 @@ where
                         .await)
                 }
                 Err(err) => {
+                    METRICS.proxy.upstream_errors.fetch_add(1, Ordering::Relaxed);
                     let error = err.to_string();
                     Ok(self
                         .http_handler
@@ -212,6 +252,7 @@ where
 
         let span = info_span!("process_connect");
         let fut = async move {
+            let _tunnel = METRICS.proxy.tunnels.enter();
             let mut upgraded = auto_result!( hyper::upgrade::on(&mut req).await ,err=>{
                error!("Upgrade error: {err}");
                return;
@@ -270,8 +311,12 @@ where
             }
             warn!("Unknown protocol, read '{:02X?}' from upgraded connection",&buffer[..bytes_read]);
 
-            if let Err(e) = tokio::io::copy_bidirectional(&mut upgraded, &mut stream).await {
-                error!("Failed to tunnel to {}: {}", authority, e);
+            match tokio::io::copy_bidirectional(&mut upgraded, &mut stream).await {
+                Ok((sent, received)) => {
+                    METRICS.proxy.bytes_out.fetch_add(sent, Ordering::Relaxed);
+                    METRICS.proxy.bytes_in.fetch_add(received, Ordering::Relaxed);
+                }
+                Err(e) => error!("Failed to tunnel to {}: {}", authority, e),
             }
         };
 
@@ -306,6 +351,7 @@ where
         let stream = match TlsAcceptor::from(server_config).accept(upgraded).await {
             Ok(stream) => stream,
             Err(e) => {
+                METRICS.proxy.tls_accept_failures.fetch_add(1, Ordering::Relaxed);
                 error!("Failed to establish TLS connection: {e},URI:{uri}");
                 return;
             }
@@ -318,7 +364,7 @@ where
         }
     }
     // #[instrument(skip_all)]
-    fn upgrade_websocket(self, req: Request<Body>) -> Response<Body> {
+    fn upgrade_websocket(self, req: Request<Body>, request_id: String) -> Response<Body> {
         let mut req = {
             let (mut parts, _) = req.into_parts();
             parts.uri = {
@@ -356,7 +402,7 @@ where
             async move {
                 match websocket.await {
                     Ok(ws) => {
-                        if let Err(e) = self.handle_websocket(ws, req).await {
+                        if let Err(e) = self.handle_websocket(ws, req, request_id).await {
                             error!("Failed to handle WebSocket: {}", e);
                             return;
                         }
@@ -378,6 +424,7 @@ where
         self,
         client_socket: WebSocketStream<Upgraded>,
         req: Request<()>,
+        request_id: String,
     ) -> Result<(), tungstenite::Error> {
         let uri = req.uri().clone();
         let mut cfg = WebSocketConfig::default();
@@ -397,29 +444,43 @@ where
         let NetProxy {
             websocket_handler, ..
         } = self;
+        //两个方向的转发都结束后会话才结束
+        let session = Arc::new(METRICS.proxy.websockets.enter());
+        let client_to_server = WebSocketContext::ClientToServer {
+            src: self.client_addr,
+            dst: uri.clone(),
+            request_id: request_id.clone(),
+        };
 
-        spawn_message_forwarder(
+        let sending = spawn_message_forwarder(
             client_stream,
             client_sink.clone(),
             server_sink.clone(),
             websocket_handler.clone(),
-            WebSocketContext::ClientToServer {
-                src: self.client_addr,
-                dst: uri.clone(),
-            },
+            client_to_server.clone(),
+            session.clone(),
         );
 
-        spawn_message_forwarder(
+        let receiving = spawn_message_forwarder(
             server_stream,
             server_sink,
             client_sink,
-            websocket_handler,
+            websocket_handler.clone(),
             WebSocketContext::ServerToClient {
                 src: uri,
                 dst: self.client_addr,
+                request_id,
             },
+            session,
         );
 
+        //一个方向先结束时另一个方向可能还在转发，等两边都结束再清理
+        let mut handler = websocket_handler;
+        tokio::spawn(async move {
+            let _ = futures::future::join(sending, receiving).await;
+            handler.handle_close(&client_to_server).await;
+        });
+
         Ok(())
     }
 
@@ -478,10 +539,17 @@ fn spawn_message_forwarder(
     dst_sink: Arc<Mutex<impl Sink<Message, Error=tungstenite::Error> + Unpin + Send + 'static>>,
     handler: impl WebSocketHandler,
     ctx: WebSocketContext,
-) {
+    session: Arc<GaugeGuard>,
+) -> JoinHandle<()> {
     let span = info_span!("message_forwarder", context = ?ctx);
     let fut = handler.handle_websocket(ctx, stream, src_sink, dst_sink);
-    spawn_with_trace(fut, span);
+    spawn_with_trace(
+        async move {
+            let _session = session;
+            fut.await
+        },
+        span,
+    )
 }
 
 #[instrument(skip_all)]
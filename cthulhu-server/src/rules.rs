@@ -0,0 +1,319 @@
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, HOST},
+    Body, HeaderMap, Request, Response, Uri,
+};
+use regex::Regex;
+use tokio::sync::RwLock;
+use tracing::error;
+
+use crate::{
+    auto_result,
+    handle::{api::rules::enabled_rules, content_type_by_ext, model::Rule, response_content},
+    utils, RULES,
+};
+
+///校验规则，保存前调用
+pub fn validate(rule: &Rule) -> Result<(), String> {
+    if rule.pattern.trim().is_empty() {
+        return Err("匹配地址不能为空".to_string());
+    }
+    if rule.regex == 1 {
+        Regex::new(&rule.pattern).map_err(|e| format!("正则 '{}' 无效:{e}", rule.pattern))?;
+    }
+    match rule.kind.as_str() {
+        "local" => {
+            if rule.target.trim().is_empty() {
+                return Err("本地映射需要文件或目录路径".to_string());
+            }
+        }
+        "remote" => {
+            if rule.target.trim().is_empty() {
+                return Err("远程映射需要目标地址".to_string());
+            }
+            //正则规则的目标地址可以包含 $1 之类的分组引用，替换后才能解析
+            if rule.regex != 1 {
+                let uri = Uri::from_str(&rule.target)
+                    .map_err(|e| format!("目标地址 '{}' 无效:{e}", rule.target))?;
+                if uri.scheme().is_none() || uri.authority().is_none() {
+                    return Err(format!("目标地址 '{}' 需要包含协议和域名", rule.target));
+                }
+            }
+        }
+        "header" => {
+            if rule.phase != "request" && rule.phase != "response" {
+                return Err("头部改写的阶段只能是 request 或 response".to_string());
+            }
+            HeaderName::from_str(&rule.name)
+                .map_err(|e| format!("头部名称 '{}' 无效:{e}", rule.name))?;
+            match rule.action.as_str() {
+                "remove" => {}
+                "add" | "replace" => {
+                    HeaderValue::from_str(&rule.value)
+                        .map_err(|e| format!("头部 '{}' 的值无效:{e}", rule.name))?;
+                }
+                v => return Err(format!("无效的头部操作 '{v}'，可选 add remove replace")),
+            }
+        }
+        v => return Err(format!("无效的规则类型 '{v}'，可选 local remote header")),
+    }
+    Ok(())
+}
+
+///启用的规则，正则规则带上编译好的正则
+pub struct ActiveRule {
+    rule: Rule,
+    regex: Option<Regex>,
+}
+impl ActiveRule {
+    fn new(rule: Rule) -> Option<Self> {
+        if rule.regex != 1 {
+            return Some(Self { rule, regex: None });
+        }
+        let regex = auto_result!(Regex::new(&rule.pattern),err=>{
+            error!("rule {} regex invalid {err}", rule.id);
+            return None;
+        });
+        Some(Self {
+            rule,
+            regex: Some(regex),
+        })
+    }
+    //规则是否匹配完整地址
+    fn is_match(&self, url: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(url),
+            None => utils::mini_match(&self.rule.pattern, url),
+        }
+    }
+}
+
+///启用规则的缓存，每个请求都要用到规则，只在第一次使用和规则修改后查询数据库
+#[derive(Default)]
+pub struct RuleCache {
+    rules: RwLock<Option<Arc<Vec<ActiveRule>>>>,
+}
+impl RuleCache {
+    pub async fn get(&self) -> Arc<Vec<ActiveRule>> {
+        if let Some(rules) = self.rules.read().await.as_ref() {
+            return rules.clone();
+        }
+        //持有写锁加载，加载期间的修改会等加载完成后再清空缓存
+        let mut cache = self.rules.write().await;
+        if let Some(rules) = cache.as_ref() {
+            return rules.clone();
+        }
+        let rules = enabled_rules()
+            .await
+            .into_iter()
+            .filter_map(ActiveRule::new)
+            .collect::<Vec<ActiveRule>>();
+        let rules = Arc::new(rules);
+        *cache = Some(rules.clone());
+        rules
+    }
+    ///规则新增、修改、删除或切换启用状态后调用
+    pub async fn invalidate(&self) {
+        *self.rules.write().await = None;
+    }
+}
+
+fn apply_header(headers: &mut HeaderMap, rule: &Rule) {
+    let name = auto_result!(HeaderName::from_str(&rule.name), ());
+    if rule.action == "remove" {
+        headers.remove(&name);
+        return;
+    }
+    let value = auto_result!(HeaderValue::from_str(&rule.value), ());
+    match rule.action.as_str() {
+        "add" => {
+            headers.append(name, value);
+        }
+        "replace" => {
+            headers.insert(name, value);
+        }
+        _ => {}
+    }
+}
+
+fn apply_headers(rules: &[ActiveRule], phase: &str, url: &str, headers: &mut HeaderMap) {
+    rules
+        .iter()
+        .filter(|v| v.rule.kind == "header" && v.rule.phase == phase && v.is_match(url))
+        .for_each(|v| apply_header(headers, &v.rule));
+}
+
+//本地映射，目标为目录时按请求路径查找文件
+async fn map_local(rule: &Rule, uri: &Uri) -> Response<Body> {
+    let mut path = PathBuf::from(&rule.target);
+    if path.is_dir() {
+        let segments = uri
+            .path()
+            .split('/')
+            .map(|v| {
+                urlencoding::decode(v)
+                    .map(|v| v.into_owned())
+                    .unwrap_or(v.to_string())
+            })
+            //忽略 . 和 .. 避免访问映射目录之外的文件
+            .filter(|v| !v.is_empty() && v != "." && v != ".." && !v.contains(['/', '\\']));
+        for segment in segments {
+            path.push(segment);
+        }
+        if path.is_dir() {
+            path.push("index.html");
+        }
+    }
+    let bytes = auto_result!(tokio::fs::read(&path).await,err=>{
+        error!("map local failed {err} {}", path.display());
+        return response_content(404, "<map local file not found>");
+    });
+    let ext = path
+        .extension()
+        .and_then(|v| v.to_str())
+        .unwrap_or_default();
+    let mut res = Response::new(Body::from(bytes));
+    if let Ok(content_type) = HeaderValue::from_str(&content_type_by_ext(ext)) {
+        if !content_type.is_empty() {
+            res.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+    }
+    res
+}
+
+//远程映射，目标地址没有路径时保留原请求的路径和参数
+fn map_remote(active: &ActiveRule, url: &str, req: &mut Request<Body>) -> Result<(), String> {
+    let rule = &active.rule;
+    let target = if let Some(regex) = &active.regex {
+        regex.replace(url, rule.target.as_str()).to_string()
+    } else {
+        let target = Uri::from_str(&rule.target).map_err(|e| e.to_string())?;
+        let origin = req.uri();
+        let path_and_query = origin.path_and_query().map(|v| v.as_str()).unwrap_or("/");
+        match (target.path(), target.query(), origin.query()) {
+            ("" | "/", None, _) => format!(
+                "{}://{}{path_and_query}",
+                target.scheme_str().unwrap_or("https"),
+                target.authority().map(|v| v.as_str()).unwrap_or("")
+            ),
+            (_, None, Some(query)) => format!("{}?{query}", rule.target),
+            _ => rule.target.clone(),
+        }
+    };
+    let uri = Uri::from_str(&target).map_err(|e| format!("映射地址 '{target}' 无效:{e}"))?;
+    let authority = uri
+        .authority()
+        .filter(|_| uri.scheme().is_some())
+        .ok_or(format!("映射地址 '{target}' 需要包含协议和域名"))?;
+    if req.headers().contains_key(HOST) {
+        let host = HeaderValue::from_str(authority.as_str()).map_err(|e| e.to_string())?;
+        req.headers_mut().insert(HOST, host);
+    }
+    *req.uri_mut() = uri;
+    Ok(())
+}
+
+///在插件之前应用请求阶段的规则，返回Some时不再发往上游，直接把响应返回给客户端
+pub async fn apply_request(req: &mut Request<Body>) -> Option<Response<Body>> {
+    let rules = RULES.get().await;
+    if rules.is_empty() {
+        return None;
+    }
+    let url = req.uri().to_string();
+    apply_headers(&rules, "request", &url, req.headers_mut());
+    //本地映射和远程映射只应用第一条匹配的规则
+    let rule = rules
+        .iter()
+        .find(|v| (v.rule.kind == "local" || v.rule.kind == "remote") && v.is_match(&url))?;
+    if rule.rule.kind == "local" {
+        let mut res = map_local(&rule.rule, req.uri()).await;
+        apply_headers(&rules, "response", &url, res.headers_mut());
+        return Some(res);
+    }
+    if let Err(err) = map_remote(rule, &url, req) {
+        error!("map remote failed {err}");
+        return Some(response_content(500, &err));
+    }
+    None
+}
+
+///在插件之前应用响应阶段的头部改写，按原始请求地址匹配
+pub async fn apply_response(uri: &Uri, headers: &mut HeaderMap) {
+    let rules = RULES.get().await;
+    if rules.is_empty() {
+        return;
+    }
+    apply_headers(&rules, "response", &uri.to_string(), headers);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rule(value: serde_json::Value) -> Rule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn validate_rules() {
+        let ok = [
+            json!({"kind": "local", "pattern": "*.a.com/*", "target": "/tmp"}),
+            json!({"kind": "remote", "pattern": "*", "target": "https://b.com"}),
+            json!({"kind": "remote", "pattern": "^https://a.com/(.*)$", "regex": 1, "target": "$1"}),
+            json!({"kind": "header", "pattern": "*", "phase": "request", "action": "remove", "name": "x-a"}),
+            json!({"kind": "header", "pattern": "*", "phase": "response", "action": "add", "name": "x-a", "value": "1"}),
+        ];
+        for value in ok {
+            assert_eq!(validate(&rule(value.clone())), Ok(()), "{value}");
+        }
+        let bad = [
+            json!({"kind": "local", "pattern": " ", "target": "/tmp"}),
+            json!({"kind": "local", "pattern": "*", "target": ""}),
+            json!({"kind": "local", "pattern": "(", "regex": 1, "target": "/tmp"}),
+            json!({"kind": "remote", "pattern": "*", "target": "/path"}),
+            json!({"kind": "header", "pattern": "*", "phase": "both", "action": "remove", "name": "x-a"}),
+            json!({"kind": "header", "pattern": "*", "phase": "request", "action": "remove", "name": "x a"}),
+            json!({"kind": "header", "pattern": "*", "phase": "request", "action": "add", "name": "x-a", "value": "a\nb"}),
+            json!({"kind": "header", "pattern": "*", "phase": "request", "action": "set", "name": "x-a"}),
+            json!({"kind": "other", "pattern": "*"}),
+        ];
+        for value in bad {
+            assert!(validate(&rule(value.clone())).is_err(), "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn map_local_stays_in_dir() {
+        let root = utils::temp_dir();
+        let www = root.join("www");
+        utils::write_bytes(www.join("index.html"), b"index", None).unwrap();
+        utils::write_bytes(www.join("sub/a.txt"), b"a", None).unwrap();
+        utils::write_bytes(root.join("secret.txt"), b"secret", None).unwrap();
+        let rule = rule(json!({"kind": "local", "pattern": "*", "target": www.to_str().unwrap()}));
+
+        let get = |path: &'static str| {
+            let rule = rule.clone();
+            async move {
+                let res = map_local(&rule, &Uri::from_static(path)).await;
+                let status = res.status().as_u16();
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                (status, String::from_utf8_lossy(&body).to_string())
+            }
+        };
+        assert_eq!(get("/").await, (200, "index".to_string()));
+        assert_eq!(get("/sub/a.txt").await, (200, "a".to_string()));
+        assert_eq!(get("/sub/%61.txt").await, (200, "a".to_string()));
+        for path in [
+            "/../secret.txt",
+            "/sub/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/sub/..%2F..%2Fsecret.txt",
+        ] {
+            assert_eq!(get(path).await.0, 404, "{path}");
+        }
+        let _ = utils::remove_path(&root);
+    }
+}
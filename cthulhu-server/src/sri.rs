@@ -0,0 +1,318 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use content_security_policy::{
+    parse_subresource_integrity_metadata, HashAlgorithm, SubresourceIntegrityMetadata,
+};
+use hyper::{header::CONTENT_TYPE, Body, Uri};
+use tracing::error;
+use url::Url;
+
+use crate::{
+    auto_result,
+    core::PluginCtx,
+    handle::model::Plugin,
+    jsbind::http::{JsBody, JsResponse},
+    matcher::MatchTarget,
+    MODIFIED_ASSETS, PLUGIN_MANAGER,
+};
+
+///插件修改了带 integrity 的脚本或样式后页面的处理方式，对应 plugin.json 中的 permissions.sri
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SriPolicy {
+    //不处理，修改后的资源会被浏览器拒绝
+    Keep,
+    //按修改后的内容重新计算 integrity，还不知道修改后的内容时删除
+    Rewrite,
+    //删除 integrity
+    Strip,
+}
+impl SriPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "" => Ok(SriPolicy::Keep),
+            "rewrite" => Ok(SriPolicy::Rewrite),
+            "strip" => Ok(SriPolicy::Strip),
+            v => Err(format!("无效的 sri 配置 '{v}'，可选 rewrite strip")),
+        }
+    }
+    pub fn of(plugin: &Plugin) -> Self {
+        Self::parse(&plugin.sri).unwrap_or(SriPolicy::Keep)
+    }
+}
+
+///被插件修改过的资源，按域和地址记录修改后内容的摘要
+#[derive(Debug, Clone)]
+pub struct ModifiedAsset {
+    policy: SriPolicy,
+    sha256: String,
+    sha384: String,
+    sha512: String,
+}
+impl ModifiedAsset {
+    fn digest(&self, algorithm: HashAlgorithm) -> &str {
+        match algorithm {
+            HashAlgorithm::Sha256 => &self.sha256,
+            HashAlgorithm::Sha384 => &self.sha384,
+            HashAlgorithm::Sha512 => &self.sha512,
+        }
+    }
+}
+
+//同一个资源在不同域中可能被不同的插件修改，按域区分。
+//地址统一按 Url 的格式并去掉片段，和页面中按 Url 解析出的地址一致
+fn asset_key(scope_id: &str, url: &str) -> String {
+    format!("{scope_id} {}", asset_url(url))
+}
+fn asset_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            url.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+///资源这次没有被插件修改，删除之前的记录
+pub async fn forget(scope_id: &str, url: &str) {
+    MODIFIED_ASSETS.invalidate(&asset_key(scope_id, url)).await;
+}
+
+//只有脚本和样式会带 integrity
+fn is_subresource(res: &JsResponse, target: &MatchTarget) -> bool {
+    if let Some(dest) = &target.dest {
+        if dest == "script" || dest == "style" {
+            return true;
+        }
+    }
+    let inner = res.inner.read().unwrap();
+    let headers = inner.0.inner.read().unwrap();
+    let ctype = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    ctype.contains("javascript") || ctype.contains("ecmascript") || ctype.starts_with("text/css")
+}
+
+///交给插件的 onResponse 之前取出原来的body，只有脚本或样式并且修改后需要处理 integrity，
+///或者之前记录过修改时才返回，其它响应不需要之后再读取和计算摘要
+pub async fn snapshot(
+    plugin: &Plugin,
+    scope_id: &str,
+    target: &MatchTarget,
+    res: &JsResponse,
+) -> Option<JsBody> {
+    let url = target.url.as_ref()?;
+    if !is_subresource(res, target) {
+        return None;
+    }
+    if SriPolicy::of(plugin) == SriPolicy::Keep
+        && !MODIFIED_ASSETS.contains_key(&asset_key(scope_id, url))
+    {
+        return None;
+    }
+    Some(res.inner.read().unwrap().1.clone())
+}
+
+///插件的 onResponse 替换了脚本或样式的内容时，记录修改后内容的摘要。
+///original 为 snapshot 取出的body，没有被替换并且和 onScript 记录的内容不一致时删除记录
+pub async fn track(
+    plugin: &Plugin,
+    scope_id: &str,
+    target: &MatchTarget,
+    original: &JsBody,
+    res: &JsResponse,
+) {
+    let url = match &target.url {
+        Some(url) => url.clone(),
+        None => return,
+    };
+    let body = res.inner.read().unwrap().1.clone();
+    let replaced = !Arc::ptr_eq(&original.inner, &body.inner);
+    let recorded = MODIFIED_ASSETS.get(&asset_key(scope_id, &url)).await;
+    if !replaced && recorded.is_none() {
+        return;
+    }
+    let bytes = auto_result!(body.to_bytes().await,err=>{
+        error!("read modified asset failed {err}");
+        return;
+    });
+    //读取后body已经被消费，放回去继续响应
+    body.replace(Body::from(bytes.clone()));
+    if replaced {
+        record(&[plugin], scope_id, &url, &bytes).await;
+        return;
+    }
+    if let Some(asset) = recorded {
+        if asset.sha256 != HashAlgorithm::Sha256.apply_bytes(&bytes) {
+            forget(scope_id, &url).await;
+        }
+    }
+}
+
+///记录插件修改后的资源内容，多个插件修改同一个资源时 strip 优先。
+///修改它的插件都不处理 integrity 时删除之前的记录
+pub async fn record(plugins: &[&Plugin], scope_id: &str, url: &str, bytes: &[u8]) {
+    let policies = plugins.iter().map(|v| SriPolicy::of(v));
+    let policy = policies.fold(SriPolicy::Keep, |a, b| match (a, b) {
+        (SriPolicy::Strip, _) | (_, SriPolicy::Strip) => SriPolicy::Strip,
+        (SriPolicy::Rewrite, _) | (_, SriPolicy::Rewrite) => SriPolicy::Rewrite,
+        _ => SriPolicy::Keep,
+    });
+    if policy == SriPolicy::Keep {
+        forget(scope_id, url).await;
+        return;
+    }
+    let asset = ModifiedAsset {
+        policy,
+        sha256: HashAlgorithm::Sha256.apply_bytes(bytes),
+        sha384: HashAlgorithm::Sha384.apply_bytes(bytes),
+        sha512: HashAlgorithm::Sha512.apply_bytes(bytes),
+    };
+    MODIFIED_ASSETS
+        .insert(asset_key(scope_id, url), asset)
+        .await;
+}
+
+///改写html中脚本和样式的 integrity。
+///用到的修改记录和插件在开始改写页面之前取出，改写时只查这些数据，不再等待锁
+#[derive(Debug, Clone)]
+pub struct SriRewriter {
+    //页面所属域的域名，用来匹配处理资源的插件
+    origin: String,
+    page: Option<Url>,
+    //页面中第一个 <base href>
+    base: Option<Url>,
+    //页面所属的域中被插件修改过的资源，按地址索引
+    assets: HashMap<String, ModifiedAsset>,
+    //作用于该域名、可能修改资源并且要求处理 integrity 的插件
+    plugins: Vec<Arc<PluginCtx>>,
+}
+impl SriRewriter {
+    pub async fn new(scope_id: &str, origin: &str, page: &Uri) -> Self {
+        let prefix = format!("{scope_id} ");
+        let assets = MODIFIED_ASSETS
+            .iter()
+            .filter_map(|(key, asset)| Some((key.strip_prefix(&prefix)?.to_string(), asset)))
+            .collect();
+        //按域名匹配得到的插件包含所有可能作用于页面中资源的插件，改写时再按资源地址匹配
+        let (all, _monitors, _modify) = PLUGIN_MANAGER.ctxs_by_host(origin).await;
+        //onResponse 和 onScript 都可能修改资源
+        let plugins = all
+            .into_iter()
+            .filter(|v| v.plugin.net_modify >= 1 && SriPolicy::of(&v.plugin) != SriPolicy::Keep)
+            .collect();
+        Self {
+            origin: origin.to_string(),
+            page: Url::parse(&page.to_string()).ok(),
+            base: None,
+            assets,
+            plugins,
+        }
+    }
+
+    ///页面中的 <base href>，只有第一个生效
+    pub fn set_base(&mut self, href: &str) {
+        if self.base.is_some() {
+            return;
+        }
+        let page = match &self.page {
+            Some(page) => page,
+            None => return,
+        };
+        if let Ok(base) = page.join(href.trim()) {
+            self.base = Some(base);
+        }
+    }
+
+    ///返回新的 integrity，None 表示删除该属性，原样返回表示不需要改动。
+    ///dest 为资源类型 script 或 style
+    pub fn rewrite(&self, src: &str, integrity: &str, dest: &str) -> Option<String> {
+        let url = auto_result!(self.resolve(src),_err=>{
+            return Some(integrity.to_string());
+        });
+        if let Some(asset) = self.assets.get(&asset_url(&url.to_string())) {
+            return match asset.policy {
+                SriPolicy::Strip => None,
+                _ => rehash(integrity, asset),
+            };
+        }
+        //还没有经过代理，修改后的内容未知，会修改它的插件允许时先删除
+        let target = MatchTarget::origin(&self.origin)
+            .with_uri(&url)
+            .with_method("GET")
+            .with_dest(Some(dest));
+        let opted = self.plugins.iter().any(|v| v.matcher.is_match(&target));
+        if opted {
+            None
+        } else {
+            Some(integrity.to_string())
+        }
+    }
+
+    //相对地址按 <base href> 或页面地址解析，和浏览器一样处理 ../ 和 ./
+    fn resolve(&self, src: &str) -> Result<Uri, String> {
+        let base = self
+            .base
+            .as_ref()
+            .or(self.page.as_ref())
+            .ok_or("页面地址无效".to_string())?;
+        let mut url = base.join(src.trim()).map_err(|e| e.to_string())?;
+        url.set_fragment(None);
+        Uri::from_str(url.as_str()).map_err(|e| e.to_string())
+    }
+}
+
+//保留原来使用的摘要算法，用修改后的内容重新计算
+fn rehash(integrity: &str, asset: &ModifiedAsset) -> Option<String> {
+    let sources = match parse_subresource_integrity_metadata(integrity) {
+        SubresourceIntegrityMetadata::IntegritySources(sources) if !sources.is_empty() => sources,
+        _ => return None,
+    };
+    let mut algorithms = vec![];
+    for source in sources {
+        if !algorithms.contains(&source.algorithm()) {
+            algorithms.push(source.algorithm());
+        }
+    }
+    let integrity = algorithms
+        .into_iter()
+        .map(|v| format!("{}-{}", v.name(), asset.digest(v)))
+        .collect::<Vec<String>>()
+        .join(" ");
+    Some(integrity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rewriter_uses_assets_of_its_scope() {
+        let asset = ModifiedAsset {
+            policy: SriPolicy::Strip,
+            sha256: String::new(),
+            sha384: String::new(),
+            sha512: String::new(),
+        };
+        MODIFIED_ASSETS
+            .insert(asset_key("sri-test", "https://a.com/a.js"), asset)
+            .await;
+        let page = Uri::from_static("https://a.com/index.html");
+        let sri = SriRewriter::new("sri-test", "a.com", &page).await;
+        //改写时只查创建时取出的记录
+        forget("sri-test", "https://a.com/a.js").await;
+        assert_eq!(sri.rewrite("/a.js#main", "sha256-x", "script"), None);
+        assert_eq!(
+            sri.rewrite("/b.js", "sha256-x", "script"),
+            Some("sha256-x".to_string())
+        );
+
+        let other = SriRewriter::new("sri-other", "a.com", &page).await;
+        assert_eq!(
+            other.rewrite("/a.js", "sha256-x", "script"),
+            Some("sha256-x".to_string())
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use time::macros::format_description;
+use tracing_subscriber::{
+    fmt::time::LocalTime, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+use crate::handle::api::config;
+
+///日志配置，对应配置 log
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    //日志级别，支持 EnvFilter 语法，例如 info,cthulhu::net_proxy=debug
+    pub level: String,
+    //输出格式 pretty compact json
+    pub format: String,
+    //OTLP collector 地址，为空时不导出链路
+    pub otlp_endpoint: String,
+    //导出链路的级别，和日志级别分开配置
+    pub otlp_level: String,
+}
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "error".to_string(),
+            format: "pretty".to_string(),
+            otlp_endpoint: String::new(),
+            otlp_level: "info".to_string(),
+        }
+    }
+}
+impl LogConfig {
+    pub async fn load() -> Self {
+        let mut log_config = Self::default();
+        let value = config::get_config("log").await.unwrap_or_default();
+        let str = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        if let Some(v) = str("level") {
+            log_config.level = v;
+        }
+        if let Some(v) = str("format") {
+            log_config.format = v;
+        }
+        if let Some(v) = str("otlpEndpoint") {
+            log_config.otlp_endpoint = v;
+        }
+        if let Some(v) = str("otlpLevel") {
+            log_config.otlp_level = v;
+        }
+        log_config
+    }
+}
+
+///注册日志和链路导出，环境变量 RUST_LOG 优先于配置中的日志级别，只影响日志输出
+pub fn init(log_config: &LogConfig) -> Result<(), String> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&log_config.level))
+        .map_err(|e| format!("无效的日志级别 '{}':{e}", log_config.level))?;
+
+    let timer = LocalTime::new(format_description!(
+        "[year]-[month padding:zero]-[day padding:zero] [hour]:[minute]:[second]"
+    ));
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_timer(timer)
+        .with_ansi(false);
+    let fmt = match log_config.format.as_str() {
+        "json" => fmt.json().with_current_span(true).boxed(),
+        "compact" => fmt.compact().boxed(),
+        "pretty" => fmt.pretty().boxed(),
+        v => return Err(format!("无效的日志格式 '{v}'，可选 pretty compact json")),
+    };
+
+    let otlp = if log_config.otlp_endpoint.is_empty() {
+        None
+    } else {
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&log_config.otlp_endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", "cthulhu"),
+                KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            ])))
+            .install_batch(runtime::Tokio)
+            .map_err(|e| format!("初始化OTLP导出失败:{e}"))?;
+        let otlp_filter = EnvFilter::try_new(&log_config.otlp_level)
+            .map_err(|e| format!("无效的链路级别 '{}':{e}", log_config.otlp_level))?;
+        Some(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(otlp_filter),
+        )
+    };
+
+    //日志和链路各自过滤，日志级别为 error 时仍能导出 info 级别的链路
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(otlp)
+        .try_init()
+        .map_err(|e| e.to_string())
+}
+
+///退出前把尚未发送的链路数据推送给 collector
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
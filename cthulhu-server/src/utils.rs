@@ -162,6 +162,13 @@ pub fn exists_path<P: AsRef<Path>>(path: P) -> bool {
     let path = path.as_ref();
     path.exists()
 }
+///测试用的临时目录，每次返回一个新的空目录
+#[cfg(test)]
+pub fn temp_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("cthulhu-{}", uuid::Uuid::new_v4().simple()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
 #[test]
 fn a() {
     // assert_eq!(matches_pattern("a.cff.555.*", "a.cff.555.com"), true);
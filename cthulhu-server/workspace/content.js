@@ -22,6 +22,7 @@
     self.CthulhuServer = class Server {
         constructor(id) {
             if (!id) throw new Error("插件id不能为空")
+            this.id = id;
             this.url = `"https://${id}plugin.cthulhu.server"`;
             this.scopeId = self['CTHULHU_SCOPE_ID'] || ''
         }
@@ -31,15 +32,129 @@
             let url = `${this.url}/ask?key=${key}&scopeId=${this.scopeId || ''}`
             return post(url, json)
         }
+        //通过socket调用插件的 onCall，得到插件的返回值
+        call(method, params) {
+            return rpc.request(`${this.id}.${method}`, params).promise
+        }
+        //插件返回迭代器时逐项得到结果，提前退出 for await 会通知插件停止
+        stream(method, params) {
+            return rpc.stream(`${this.id}.${method}`, params)
+        }
+        //注册给插件 server.call 和 server.notify 调用的方法
+        handle(method, func) {
+            rpc.handlers.set(`${this.id}.${method}`, func)
+        }
     };
     const scopeId = self["CTHULHU_SCOPE_ID"] || ''
     const domain = "cthulhu.server"
+    //注入的脚本都带有nonce，页面的CSP只放行带这个nonce的脚本
+    const currentScript = document.currentScript;
+    const nonce = currentScript && currentScript.nonce || '';
 
     let wsURL = `wss://socket.${domain}/content/${scopeId}`;
     const webSocket = new WebSocket(wsURL)
 
+    //socket连上之前要发送的消息先存起来
+    const pendingMessages = [];
+    function sendMessage(data) {
+        let json = JSON.stringify(data);
+        if (webSocket.readyState === WebSocket.OPEN) {
+            webSocket.send(json);
+        } else {
+            pendingMessages.push(json);
+        }
+    }
+    //socket 上的 JSON-RPC 2.0：页面调用插件的 onCall，插件调用页面注册的方法
+    const rpc = {
+        seq: 0,
+        //等待插件回复的调用，id 映射回调
+        pending: new Map(),
+        //页面注册的方法，"插件id.方法名" 映射函数
+        handlers: new Map(),
+        request(method, params, onItem) {
+            let id = ++this.seq;
+            let promise = new Promise((resolve, reject) => {
+                this.pending.set(id, { resolve, reject, onItem });
+                sendMessage({ jsonrpc: "2.0", id, method, params });
+            });
+            return { id, promise }
+        },
+        stream(method, params) {
+            const items = [];
+            let done = false, error = null, wake = null;
+            const notify = () => wake && wake();
+            const { id, promise } = this.request(method, params, (value) => {
+                items.push(value);
+                notify();
+            });
+            //插件没有返回迭代器时，结果作为唯一的一项
+            promise.then((value) => {
+                if (value !== null && value !== undefined) items.push(value);
+                done = true;
+                notify();
+            }, (e) => {
+                error = e;
+                done = true;
+                notify();
+            });
+            return {
+                [Symbol.asyncIterator]() { return this },
+                async next() {
+                    while (!items.length && !done) await new Promise(rs => wake = rs);
+                    if (items.length) return { value: items.shift(), done: false };
+                    if (error) throw error;
+                    return { value: undefined, done: true };
+                },
+                async return() {
+                    if (!done) {
+                        done = true;
+                        rpc.pending.delete(id);
+                        sendMessage({ jsonrpc: "2.0", method: "rpc.cancel", params: { id } });
+                    }
+                    return { value: undefined, done: true };
+                }
+            }
+        },
+        async answer(obj) {
+            let { id, method, params } = obj;
+            if (!method) {
+                let call = this.pending.get(id);
+                if (!call) return;
+                this.pending.delete(id);
+                if (obj.error) {
+                    let e = new Error(obj.error.message);
+                    e.code = obj.error.code;
+                    call.reject(e);
+                } else {
+                    call.resolve(obj.result);
+                }
+                return
+            }
+            if (method === "rpc.stream") {
+                let call = this.pending.get(params.id);
+                if (call && call.onItem) call.onItem(params.value);
+                return
+            }
+            let reply = { jsonrpc: "2.0", id };
+            try {
+                let handler = this.handlers.get(method);
+                if (!handler) {
+                    let e = new Error(`method not found: ${method}`);
+                    e.code = -32601;
+                    throw e;
+                }
+                let result = await handler(params);
+                reply.result = result === undefined ? null : result;
+            } catch (e) {
+                reply.error = { code: e && e.code || -32000, message: String(e && e.message || e) };
+            }
+            //插件的 notify 不需要回复
+            if (id !== undefined && id !== null) sendMessage(reply);
+        }
+    };
     webSocket.onopen = () => {
         console.log("socket.cthulhu.server linked");
+        pendingMessages.splice(0).forEach(v => webSocket.send(v));
     }
     webSocket.onerror = (e) => {
         console.log("socket.cthulhu.server error:", e);
@@ -50,10 +165,18 @@
         if (data === '<nothing todo>') return;
         if (!data) return
         let obj = { type } = JSON.parse(data);
+        if (obj.jsonrpc === "2.0") {
+            rpc.answer(obj);
+            return
+        }
 
         if (type === 'script') {
-            let script = obj.script;
-            eval(script)
+            //页面的CSP不一定允许eval，用带nonce的脚本执行
+            let script = document.createElement("script");
+            script.nonce = nonce;
+            script.textContent = obj.script;
+            document.documentElement.appendChild(script);
+            script.remove();
             return
         }
         if (type === 'event') {
@@ -73,6 +196,7 @@
         // ballScript.type = "text/javascript";
         ballScript.type = "module";
         ballScript.crossOrigin = ''
+        if (nonce) ballScript.nonce = nonce
         ballScript.a
         const ballCss = document.createElement("link");
         ballCss.href = base + "inject/main.css"
@@ -217,16 +341,227 @@
             return Reflect.apply(target, self, args);
         }, { enableLog: false, nullSkip: false });
     }
+    //不带 sec-fetch-dest 的浏览器识别不出 worker 请求，在脚本地址上标记 worker 的类型，代理去掉后再发出
+    function markWorker(url, dest) {
+        if (url.protocol !== "http:" && url.protocol !== "https:") return url.href;
+        url.searchParams.set("CTHULHU_DEST", dest);
+        return url.href;
+    }
+    function proxyConstructor(target, key, construct) {
+        let descriptor = Object.getOwnPropertyDescriptor(target, key);
+        if (!descriptor || typeof descriptor.value !== 'function') return;
+        descriptor.value = new Proxy(descriptor.value, { construct });
+        proxyInfo.add(descriptor.value)
+        Object.defineProperty(target, key, descriptor)
+    }
+    for (let [key, dest] of [["Worker", "worker"], ["SharedWorker", "sharedworker"]]) {
+        proxyConstructor(window, key, (target, args, newTarget) => {
+            try {
+                args[0] = markWorker(new URL(args[0], location.href), dest);
+            } catch (e) { }
+            return Reflect.construct(target, args, newTarget)
+        });
+    }
     if(self.ServiceWorkerContainer){
         proxyFunc(ServiceWorkerContainer.prototype, "register", (target, self, args) => {
-            let urlArg = args[0];
-            let url = new URL(urlArg, location.href);
-            let search = (url.search.startsWith("?") ? "" : "?") + url.search + ("SCOPE_ID=" + scopeId);
-            args[0] = `${url.origin}${url.pathname}${search}`;
+            let url = new URL(args[0], location.href);
+            url.searchParams.set("CTHULHU_SCOPE_ID", scopeId);
+            args[0] = markWorker(url, "serviceworker");
             return Reflect.apply(target, self, args)
         });
     }
 
+    function proxyAccessor(target, key, type, apply) {
+        let descriptor = Object.getOwnPropertyDescriptor(target, key);
+        if (!descriptor || !descriptor[type]) return;
+        descriptor[type] = new Proxy(descriptor[type], { apply });
+        proxyInfo.add(descriptor[type])
+        Object.defineProperty(target, key, descriptor)
+    }
+    //动态创建的同源子页面（about:blank、srcdoc、blob:、document.write）不经过代理，
+    //由父页面把scope id、content.js和插件脚本注入进去
+    if (scopeId) {
+        //内联注入时这段脚本就是完整的注入内容，不用再请求。
+        //其中 document_end 和 document_idle 的脚本在子页面解析完成后注入时会立即执行
+        let bundle = nonce && !currentScript.src ? currentScript.textContent : null;
+        const bundleURL = () => {
+            let url = location.protocol.startsWith("http") ? location.href : location.origin + "/";
+            return `${base}frame.js?scope=${scopeId}&url=${encodeURIComponent(url)}`
+        };
+        //先异步预取，大多数子页面在它返回之后才创建，不会阻塞页面
+        if (bundle === null) {
+            let httpRequest = new XMLHttpRequest();
+            httpRequest.open("GET", bundleURL(), true);
+            httpRequest.onload = () => {
+                if (bundle === null && httpRequest.status == 200) bundle = httpRequest.responseText;
+            };
+            httpRequest.send();
+        }
+        //子页面创建后会被同步访问，预取还没返回时只能退回同步请求，结果只取一次
+        function frameBundle() {
+            if (bundle !== null) return bundle;
+            let httpRequest = new XMLHttpRequest();
+            httpRequest.open("GET", bundleURL(), false);
+            try {
+                httpRequest.send();
+                bundle = httpRequest.status == 200 ? httpRequest.responseText : '';
+            } catch (e) {
+                console.log("load frame bundle error:", e);
+                bundle = '';
+            }
+            return bundle
+        }
+        //作为内联脚本插入html时不能提前结束
+        function frameBundleTag() {
+            let code = frameBundle();
+            if (!code) return '';
+            let attr = nonce ? ` nonce="${nonce}"` : '';
+            return `<script${attr}>${code.replace(/<\/script/gi, "<\\/script")}</script>`
+        }
+
+        const injectedWindows = new WeakSet();
+        const watchedFrames = new WeakSet();
+        function injectWindow(win) {
+            if (!win || win === self || injectedWindows.has(win)) return;
+            try {
+                //跨域的子页面会抛出异常，它们经过代理时已经注入
+                if (win['CTHULHU_SCOPE_ID']) {
+                    injectedWindows.add(win);
+                    return;
+                }
+            } catch (e) {
+                return;
+            }
+            let code = frameBundle();
+            if (!code) return;
+            injectedWindows.add(win);
+            try {
+                let doc = win.document;
+                if (nonce && doc.documentElement) {
+                    //页面的CSP不允许eval，用带nonce的脚本在子页面中执行
+                    let script = doc.createElement("script");
+                    script.nonce = nonce;
+                    script.textContent = code;
+                    doc.documentElement.appendChild(script);
+                    script.remove();
+                } else {
+                    win.eval(code);
+                }
+            } catch (e) {
+                console.log("inject frame error:", e);
+            }
+        }
+        const frameProtos = [HTMLIFrameElement.prototype, self.HTMLFrameElement && HTMLFrameElement.prototype].filter(v => v);
+        //原始的 contentWindow，避免和下面的代理互相调用
+        const windowGetters = new Map(frameProtos.map(proto => [proto, Object.getOwnPropertyDescriptor(proto, "contentWindow").get]));
+        const FRAME_NAMES = ["IFRAME", "FRAME"];
+        function injectFrame(frame) {
+            if (!watchedFrames.has(frame)) {
+                watchedFrames.add(frame);
+                //子页面导航到 blob: 之类的地址后是新的window
+                frame.addEventListener("load", () => injectFrame(frame));
+            }
+            for (let [proto, getter] of windowGetters) {
+                if (proto.isPrototypeOf(frame)) return injectWindow(Reflect.apply(getter, frame, []));
+            }
+        }
+        //插入节点之前先找出其中的子页面，DocumentFragment 插入后就空了
+        function framesOf(nodes) {
+            let frames = [];
+            for (let node of nodes) {
+                if (!node || typeof node !== 'object') continue;
+                if (FRAME_NAMES.includes(node.nodeName)) frames.push(node);
+                if (typeof node.querySelectorAll === 'function') frames.push(...node.querySelectorAll("iframe,frame"));
+            }
+            return frames
+        }
+        function injectFrames(frames) {
+            frames.forEach(frame => frame.isConnected && injectFrame(frame));
+        }
+
+        for (let proto of frameProtos) {
+            proxyAccessor(proto, "contentWindow", "get", (target, self, args) => {
+                let win = Reflect.apply(target, self, args);
+                if (self.isConnected) injectFrame(self);
+                return win
+            });
+            proxyAccessor(proto, "contentDocument", "get", (target, self, args) => {
+                if (self.isConnected) injectFrame(self);
+                return Reflect.apply(target, self, args)
+            });
+        }
+        let inserts = [
+            [Node.prototype, ["appendChild", "insertBefore", "replaceChild"]],
+            [Element.prototype, ["append", "prepend", "before", "after", "replaceWith", "insertAdjacentElement"]],
+        ];
+        for (let [proto, keys] of inserts) {
+            for (let key of keys) {
+                if (typeof proto[key] !== 'function') continue;
+                proxyFunc(proto, key, (target, self, args) => {
+                    let frames = framesOf(args);
+                    let result = Reflect.apply(target, self, args);
+                    injectFrames(frames);
+                    return result
+                });
+            }
+        }
+        //通过html字符串创建的子页面
+        proxyAccessor(Element.prototype, "innerHTML", "set", (target, self, args) => {
+            let result = Reflect.apply(target, self, args);
+            injectFrames(framesOf([self]));
+            return result
+        });
+        proxyAccessor(Element.prototype, "outerHTML", "set", (target, self, args) => {
+            let parent = self.parentNode;
+            let result = Reflect.apply(target, self, args);
+            injectFrames(framesOf([parent]));
+            return result
+        });
+        proxyFunc(Element.prototype, "insertAdjacentHTML", (target, self, args) => {
+            let result = Reflect.apply(target, self, args);
+            injectFrames(framesOf([self.parentNode || self]));
+            return result
+        });
+        for (let key of ["write", "writeln"]) {
+            proxyFunc(Document.prototype, key, (target, self, args) => {
+                let result = Reflect.apply(target, self, args);
+                injectFrames(framesOf([self]));
+                return result
+            });
+        }
+        //srcdoc 的内容会在新的window中解析执行，注入只能放到内容的最前面
+        proxyAccessor(HTMLIFrameElement.prototype, "srcdoc", "set", (target, self, args) => {
+            args[0] = frameBundleTag() + args[0];
+            return Reflect.apply(target, self, args)
+        });
+        proxyFunc(Element.prototype, "setAttribute", (target, self, args) => {
+            if (self.nodeName === "IFRAME" && String(args[0]).toLowerCase() === "srcdoc") {
+                args[1] = frameBundleTag() + args[1];
+            }
+            return Reflect.apply(target, self, args)
+        });
+        //blob: 页面同样在新的window中执行，在html内容前补上注入脚本
+        proxyFunc(URL, "createObjectURL", (target, self, args) => {
+            let blob = args[0];
+            if (blob instanceof Blob && blob.type.startsWith("text/html")) {
+                let tag = frameBundleTag();
+                if (tag) args[0] = new Blob([tag, blob], { type: blob.type });
+            }
+            return Reflect.apply(target, self, args)
+        });
+        proxyFunc(window, "open", (target, self, args) => {
+            let win = Reflect.apply(target, self, args);
+            injectWindow(win);
+            return win
+        });
+        //其它方式创建的子页面，比如解析器创建的，在变化通知中补上
+        new MutationObserver(records => {
+            for (let record of records) {
+                injectFrames(framesOf(record.addedNodes));
+            }
+        }).observe(document, { childList: true, subtree: true });
+    }
+
 })()
 
 
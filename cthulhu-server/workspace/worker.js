@@ -22,6 +22,7 @@
     self.CthulhuServer = class Server {
         constructor(id) {
             if (!id) throw new Error("插件id不能为空")
+            this.id = id;
             this.url = `"https://${id}plugin.cthulhu.server"`;
             this.scopeId = self['CTHULHU_SCOPE_ID'] || ''
         }
@@ -31,6 +32,18 @@
             let url = `${this.url}/ask?key=${key}&scopeId=${this.scopeId || ''}`
             return post(url, json)
         }
+        //通过socket调用插件的 onCall，得到插件的返回值
+        call(method, params) {
+            return rpc.request(`${this.id}.${method}`, params).promise
+        }
+        //插件返回迭代器时逐项得到结果，提前退出 for await 会通知插件停止
+        stream(method, params) {
+            return rpc.stream(`${this.id}.${method}`, params)
+        }
+        //注册给插件 server.call 和 server.notify 调用的方法
+        handle(method, func) {
+            rpc.handlers.set(`${this.id}.${method}`, func)
+        }
     };
 
     const domain = "cthulhu.server"
@@ -52,11 +65,138 @@
         default:
             break;
     }
+    //service worker 的请求不带页面的身份信息，importScripts 加载的脚本也在地址中带上 CTHULHU_SCOPE_ID
+    if (type === "serviceworker" && scopeId && self.importScripts) {
+        const importScripts = self.importScripts;
+        self.importScripts = function (...urls) {
+            urls = urls.map((v) => {
+                let url = new URL(v, location.href);
+                url.searchParams.set("CTHULHU_SCOPE_ID", scopeId);
+                return url.href;
+            });
+            return importScripts.apply(this, urls);
+        };
+    }
     let wsURL = `wss://socket.${domain}/${type}/${scopeId}`;
     const webSocket = new WebSocket(wsURL)
 
+    //socket连上之前要发送的消息先存起来
+    const pendingMessages = [];
+    function sendMessage(data) {
+        let json = JSON.stringify(data);
+        if (webSocket.readyState === WebSocket.OPEN) {
+            webSocket.send(json);
+        } else {
+            pendingMessages.push(json);
+        }
+    }
+    //socket 上的 JSON-RPC 2.0：页面调用插件的 onCall，插件调用页面注册的方法
+    const rpc = {
+        seq: 0,
+        //等待插件回复的调用，id 映射回调
+        pending: new Map(),
+        //页面注册的方法，"插件id.方法名" 映射函数
+        handlers: new Map(),
+        request(method, params, onItem) {
+            let id = ++this.seq;
+            let promise = new Promise((resolve, reject) => {
+                this.pending.set(id, { resolve, reject, onItem });
+                sendMessage({ jsonrpc: "2.0", id, method, params });
+            });
+            return { id, promise }
+        },
+        stream(method, params) {
+            const items = [];
+            let done = false, error = null, wake = null;
+            const notify = () => wake && wake();
+            const { id, promise } = this.request(method, params, (value) => {
+                items.push(value);
+                notify();
+            });
+            //插件没有返回迭代器时，结果作为唯一的一项
+            promise.then((value) => {
+                if (value !== null && value !== undefined) items.push(value);
+                done = true;
+                notify();
+            }, (e) => {
+                error = e;
+                done = true;
+                notify();
+            });
+            return {
+                [Symbol.asyncIterator]() { return this },
+                async next() {
+                    while (!items.length && !done) await new Promise(rs => wake = rs);
+                    if (items.length) return { value: items.shift(), done: false };
+                    if (error) throw error;
+                    return { value: undefined, done: true };
+                },
+                async return() {
+                    if (!done) {
+                        done = true;
+                        rpc.pending.delete(id);
+                        sendMessage({ jsonrpc: "2.0", method: "rpc.cancel", params: { id } });
+                    }
+                    return { value: undefined, done: true };
+                }
+            }
+        },
+        async answer(obj) {
+            let { id, method, params } = obj;
+            if (!method) {
+                let call = this.pending.get(id);
+                if (!call) return;
+                this.pending.delete(id);
+                if (obj.error) {
+                    let e = new Error(obj.error.message);
+                    e.code = obj.error.code;
+                    call.reject(e);
+                } else {
+                    call.resolve(obj.result);
+                }
+                return
+            }
+            if (method === "rpc.stream") {
+                let call = this.pending.get(params.id);
+                if (call && call.onItem) call.onItem(params.value);
+                return
+            }
+            let reply = { jsonrpc: "2.0", id };
+            try {
+                let handler = this.handlers.get(method);
+                if (!handler) {
+                    let e = new Error(`method not found: ${method}`);
+                    e.code = -32601;
+                    throw e;
+                }
+                let result = await handler(params);
+                reply.result = result === undefined ? null : result;
+            } catch (e) {
+                reply.error = { code: e && e.code || -32000, message: String(e && e.message || e) };
+            }
+            //插件的 notify 不需要回复
+            if (id !== undefined && id !== null) sendMessage(reply);
+        }
+    };
     webSocket.onopen = () => {
         console.log("socket.cthulhu.server linked");
+        pendingMessages.splice(0).forEach(v => webSocket.send(v));
+    }
+    //worker 中创建的子 worker 同样在脚本地址上标记类型
+    if (self.Worker) {
+        const Worker = self.Worker;
+        self.Worker = new Proxy(Worker, {
+            construct(target, args, newTarget) {
+                try {
+                    let url = new URL(args[0], location.href);
+                    if (url.protocol === "http:" || url.protocol === "https:") {
+                        url.searchParams.set("CTHULHU_DEST", "worker");
+                        args[0] = url.href;
+                    }
+                } catch (e) { }
+                return Reflect.construct(target, args, newTarget)
+            }
+        });
     }
 
     webSocket.onmessage = (msg) => {
@@ -64,6 +204,10 @@
         if (data === '<nothing todo>') return;
         if (!data) return
         let obj = { type } = JSON.parse(data);
+        if (obj.jsonrpc === "2.0") {
+            rpc.answer(obj);
+            return
+        }
         console.log('onmessage', obj);
         if (type === 'script') {
             let script = obj.script;
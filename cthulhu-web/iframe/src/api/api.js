@@ -14,7 +14,14 @@ export const reloadPlugin = (data) => http.get("/plugin/reload", data)
 export const delPlugin = (data) => http.get("/plugin/del", data)
 export const pluginLog = (data) => http.get("/plugin/log", data)
 export const clearLog = (data) => http.get("/plugin/clearLog", data)
+export const logTailUrl = (data) => http.makeUrl("/plugin/logTail", data).replace(/^http/, "ws")
 export const pluginDetail = (data) => http.get("/plugin/detail", data)
 export const pluginLogo = (id,logo) => `https://${id}.plugin.cthulhu.server/${logo}`
 export const treeNames = (id) => http.get("/plugin/treeNames", {id})
 export const treeList = (id, tree) => http.get("/plugin/treeList", {id, tree})
+export const breakpointList = () => http.get("/breakpoint/list", {})
+export const breakpointRules = () => http.get("/breakpoint/rules", {})
+export const setBreakpointRules = (data) => http.post("/breakpoint/setRules", data)
+export const resumeBreakpoint = (data) => http.post("/breakpoint/resume", data)
+export const respondBreakpoint = (data) => http.post("/breakpoint/respond", data)
+export const dropBreakpoint = (data) => http.get("/breakpoint/drop", data)
@@ -60,6 +60,11 @@ const router = createRouter({
                     name: 'Intro',
                     component: () => import('./pages/home/Intro.vue')
                 },
+                {
+                    path: 'breakpoint',
+                    name: 'Breakpoint',
+                    component: () => import('./pages/home/Breakpoint.vue')
+                },
                 {
                     path: 'setting',
                     name: 'Setting',